@@ -1,6 +1,6 @@
 mod forward;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, time::SystemTime};
 
 use affinidi_did_resolver_cache_sdk::DIDCacheClient;
 use serde::{Deserialize, Serialize};
@@ -261,7 +261,15 @@ fn build_forward_message(
         }
     }
 
-    msg_builder = msg_builder.attachment(attachment).to(next.to_string());
+    // created_time lets mediators reject replayed forward messages
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0);
+    msg_builder = msg_builder
+        .attachment(attachment)
+        .to(next.to_string())
+        .created_time(now);
 
     let msg = msg_builder.finalize();
 
@@ -0,0 +1,246 @@
+//! Migrates an existing mediator database to the Redis Cluster compatible key layout
+//!
+//! Run this against the existing (single node) database while the mediators are stopped.
+//! Once migrated, the data can be moved into a cluster (e.g. via RDB import or MIGRATE).
+//!
+//! Usage:
+//!   cargo run --example migrate_cluster_keys -- <redis_url> [--dry-run] [--retention-minutes <minutes>]
+//!
+//! --retention-minutes: `message_expiry_minutes` of the mediator (default 10080), queued messages
+//! expire this long after they were stored
+//!
+//! e.g. cargo run --example migrate_cluster_keys -- redis://127.0.0.1/ --dry-run
+use affinidi_messaging_mediator::database::keys;
+use redis::{aio::MultiplexedConnection, AsyncCommands, RedisResult};
+use std::collections::HashMap;
+
+/// Default `message_expiry_minutes` of the mediator
+const DEFAULT_RETENTION_MINUTES: u64 = 10080;
+
+struct Migration {
+    conn: MultiplexedConnection,
+    dry_run: bool,
+    /// Retention of queued messages in milliseconds
+    retention_ms: u64,
+}
+
+impl Migration {
+    /// Returns all keys matching the pattern that have not yet been migrated
+    async fn scan(&mut self, pattern: &str) -> RedisResult<Vec<String>> {
+        let mut cursor: u64 = 0;
+        let mut found = Vec::new();
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(&mut self.conn)
+                .await?;
+            // Migrated keys always contain a hash tag
+            found.extend(batch.into_iter().filter(|k| !k.contains('{')));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(found)
+    }
+
+    /// Renames a key, the TTL (if any) is kept by Redis
+    async fn rename(&mut self, from: &str, to: &str) -> RedisResult<()> {
+        if !self.conn.exists::<_, bool>(from).await? {
+            println!("  WARN: {} doesn't exist, skipping", from);
+            return Ok(());
+        }
+        println!("  RENAME {} -> {}", from, to);
+        if !self.dry_run {
+            let _: () = self.conn.rename(from, to).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves messages and metadata into the recipient slot
+    /// Returns a map of msg_id -> to_did_hash for the expiry records
+    async fn migrate_messages(&mut self) -> RedisResult<HashMap<String, String>> {
+        println!("Migrating messages...");
+        let mut recipients = HashMap::new();
+
+        for meta_key in self.scan("MSG:META:*").await? {
+            let msg_id = meta_key.trim_start_matches("MSG:META:").to_string();
+            let meta: HashMap<String, String> = self.conn.hgetall(&meta_key).await?;
+            let Some(to_hash) = meta.get("TO") else {
+                println!("  WARN: {} has no TO field, skipping", meta_key);
+                continue;
+            };
+
+            self.rename(
+                &["MSG:", &msg_id].concat(),
+                &keys::message_key(to_hash, &msg_id),
+            )
+            .await?;
+            self.rename(&meta_key, &keys::message_meta_key(to_hash, &msg_id))
+                .await?;
+
+            // Existing message IDs are the content hash of the message
+            let content_index = keys::content_index_key(to_hash);
+            let meta_key = keys::message_meta_key(to_hash, &msg_id);
+            println!("  HSET {} {} {}", content_index, msg_id, msg_id);
+            if !self.dry_run {
+                let _: () = self.conn.hset(&content_index, &msg_id, &msg_id).await?;
+                let _: () = self.conn.hset(&meta_key, "CONTENT_HASH", &msg_id).await?;
+            }
+
+            // Index so that the sender can find the message in the recipient slot
+            if let (Some(from_hash), Some(_)) = (meta.get("FROM"), meta.get("SEND_ID")) {
+                let index = keys::send_index_key(from_hash);
+                println!("  HSET {} {} {}", index, msg_id, to_hash);
+                if !self.dry_run {
+                    let _: () = self.conn.hset(&index, &msg_id, to_hash).await?;
+                }
+            }
+
+            recipients.insert(msg_id, to_hash.to_string());
+        }
+
+        Ok(recipients)
+    }
+
+    /// Moves the per DID records (queues and counters) into the DID slot
+    async fn migrate_did_records(&mut self) -> RedisResult<()> {
+        for (prefix, key_fn) in [
+            ("RECEIVE_Q:", keys::receive_queue_key as fn(&str) -> String),
+            ("SEND_Q:", keys::send_queue_key),
+            ("DID:", keys::did_key),
+        ] {
+            println!("Migrating {}* records...", prefix);
+            for key in self.scan(&[prefix, "*"].concat()).await? {
+                let did_hash = key.trim_start_matches(prefix).to_string();
+                self.rename(&key, &key_fn(&did_hash)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Folds the old GLOBAL counters into the first shard, they are aggregated on read
+    async fn migrate_global(&mut self) -> RedisResult<()> {
+        println!("Migrating GLOBAL counters...");
+        let global: HashMap<String, i64> = self.conn.hgetall("GLOBAL").await?;
+        let target = keys::global_shard_key("00");
+        for (field, value) in global {
+            println!("  HINCRBY {} {} {}", target, field, value);
+            if !self.dry_run {
+                let _: () = self.conn.hincr(&target, field, value).await?;
+            }
+        }
+        if !self.dry_run {
+            let _: () = self.conn.del("GLOBAL").await?;
+        }
+        Ok(())
+    }
+
+    /// Redistributes the message expiry records into the recipient shard
+    /// The old `<msg_id>:<time>` list entries become `<to_hash>:<msg_id>` members scored by expiry time
+    async fn migrate_expiry(&mut self, recipients: &HashMap<String, String>) -> RedisResult<()> {
+        println!("Migrating MSG_EXPIRY records...");
+        let records: Vec<String> = self.conn.lrange("MSG_EXPIRY", 0, -1).await?;
+        for record in records {
+            let Some((msg_id, time)) = record.split_once(':') else {
+                println!("  WARN: invalid MSG_EXPIRY record ({}), skipping", record);
+                continue;
+            };
+            let Some(to_hash) = recipients.get(msg_id) else {
+                // Message no longer exists
+                continue;
+            };
+            let Ok(time) = time.parse::<u64>() else {
+                println!(
+                    "  WARN: invalid time in MSG_EXPIRY record ({}), skipping",
+                    record
+                );
+                continue;
+            };
+            let target = keys::message_expiry_key(to_hash);
+            let member = [to_hash, ":", msg_id].concat();
+            let expires_at = time + self.retention_ms;
+            println!("  ZADD {} {} {}", target, expires_at, member);
+            if !self.dry_run {
+                let _: () = self.conn.zadd(&target, &member, expires_at).await?;
+            }
+        }
+        if !self.dry_run {
+            let _: () = self.conn.del("MSG_EXPIRY").await?;
+        }
+        Ok(())
+    }
+
+    async fn migrate_sessions(&mut self) -> RedisResult<()> {
+        println!("Migrating sessions...");
+        for key in self.scan("SESSION:*").await? {
+            let session_id = key.trim_start_matches("SESSION:").to_string();
+            self.rename(&key, &keys::session_key(&session_id)).await?;
+        }
+        Ok(())
+    }
+
+    /// Streaming state is rebuilt when clients reconnect, so it is removed rather than migrated
+    async fn remove_streaming(&mut self) -> RedisResult<()> {
+        println!("Removing streaming state...");
+        let mut stale = self.scan("STREAMING_SESSIONS:*").await?;
+        stale.push("GLOBAL_STREAMING".to_string());
+        for key in stale {
+            println!("  DEL {}", key);
+            if !self.dry_run {
+                let _: () = self.conn.del(&key).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> RedisResult<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let Some(redis_url) = args.get(1) else {
+        eprintln!(
+            "Usage: migrate_cluster_keys <redis_url> [--dry-run] [--retention-minutes <minutes>]"
+        );
+        std::process::exit(1);
+    };
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let retention_minutes = match args.iter().position(|a| a == "--retention-minutes") {
+        Some(index) => match args.get(index + 1).and_then(|m| m.parse::<u64>().ok()) {
+            Some(minutes) => minutes,
+            None => {
+                eprintln!("--retention-minutes requires a number of minutes");
+                std::process::exit(1);
+            }
+        },
+        None => DEFAULT_RETENTION_MINUTES,
+    };
+
+    let client = redis::Client::open(redis_url.as_str())?;
+    let mut migration = Migration {
+        conn: client.get_multiplexed_async_connection().await?,
+        dry_run,
+        retention_ms: retention_minutes * 60_000,
+    };
+
+    if dry_run {
+        println!("DRY RUN: no changes will be made");
+    }
+
+    let recipients = migration.migrate_messages().await?;
+    migration.migrate_did_records().await?;
+    migration.migrate_global().await?;
+    migration.migrate_expiry(&recipients).await?;
+    migration.migrate_sessions().await?;
+    migration.remove_streaming().await?;
+
+    println!(
+        "Migration complete: {} messages. Restart the mediators to load the updated functions library",
+        recipients.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,47 @@
+//! Local stand-in for a push notification endpoint (webhook), prints each notification it receives
+//!
+//! Enable push in the mediator with `PUSH_ENABLED=true` and `PUSH_ALLOW_HTTP=true`, then register
+//! `http://127.0.0.1:<port>/push` as the push endpoint of a DID.
+//!
+//! Usage:
+//!   cargo run --example push_webhook_receiver -- [port] [status]
+//!
+//! status is the HTTP status returned to the mediator (default 200), e.g. 503 to test retries
+//! or 410 to test that the registration is removed.
+//!
+//! e.g. cargo run --example push_webhook_receiver -- 7040 200
+use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+
+async fn push(State(status): State<StatusCode>, headers: HeaderMap, body: String) -> StatusCode {
+    println!("--- push notification received ---");
+    for (name, value) in headers.iter() {
+        if name.as_str().starts_with("apns-") || name == "authorization" {
+            println!("{}: {}", name, value.to_str().unwrap_or_default());
+        }
+    }
+    println!("{}", body);
+    println!("responding with {}", status);
+    status
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let port = args.get(1).map(|p| p.as_str()).unwrap_or("7040");
+    let status = args
+        .get(2)
+        .and_then(|s| s.parse::<u16>().ok())
+        .and_then(|s| StatusCode::from_u16(s).ok())
+        .unwrap_or(StatusCode::OK);
+
+    let app = Router::new().route("/push", post(push)).with_state(status);
+
+    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", port))
+        .await
+        .expect("Couldn't bind to port");
+    println!(
+        "Listening for push notifications on http://127.0.0.1:{}/push",
+        port
+    );
+    axum::serve(listener, app).await.unwrap();
+}
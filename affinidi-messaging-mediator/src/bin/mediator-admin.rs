@@ -0,0 +1,271 @@
+//! Administration CLI for the mediator database
+//!
+//! Uses the mediator configuration (`conf/mediator.toml` by default) to connect to the same database
+//! as the mediator, so operators don't need to know the key layout to inspect or fix mediator state.
+//!
+//! Usage:
+//!   cargo run --bin mediator-admin -- [--config <file>] [--tenant <namespace>] <command>
+//!
+//! e.g. cargo run --bin mediator-admin -- did did:example:alice
+use affinidi_messaging_mediator::{
+    common::config::Config,
+    database::{keys, DatabaseHandler},
+    init_from_file,
+};
+use affinidi_messaging_sdk::messages::{list::ListOptions, Folder};
+use clap::{Parser, Subcommand};
+use std::{error::Error, time::SystemTime};
+
+/// Session ID used for database operations made by this tool
+const SESSION_ID: &str = "mediator-admin";
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Affinidi Messaging Mediator administration", long_about = None)]
+struct Args {
+    /// Mediator configuration file
+    #[arg(short, long, default_value = "conf/mediator.toml")]
+    config: String,
+    /// Namespace of the tenant that DIDs belong to (default tenant if not set)
+    #[arg(short, long, default_value = "")]
+    tenant: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Shows the global statistics of the mediator database
+    Stats,
+    /// Lists the DIDs that have authenticated with the tenant
+    Accounts {
+        /// Cursor returned by the previous page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// Maximum number of accounts to list
+        #[arg(short, long, default_value_t = 100)]
+        limit: u32,
+    },
+    /// Shows the mailbox statistics of a DID
+    Did { did: String },
+    /// Lists the queued (or sent) messages of a DID
+    Queue {
+        did: String,
+        /// List the outbox instead of the inbox
+        #[arg(long)]
+        outbox: bool,
+        /// Maximum number of messages to list
+        #[arg(short, long, default_value_t = 20)]
+        limit: u32,
+    },
+    /// Deletes all messages queued for a DID, or a single message
+    Purge {
+        did: String,
+        /// Only delete this message
+        #[arg(long)]
+        msg_id: Option<String>,
+    },
+    /// Deletes messages queued for a DID that are older than the retention of its mailbox tier
+    Expire {
+        did: String,
+        /// Retention in minutes to apply instead of the retention of the tier
+        #[arg(long)]
+        older_than: Option<u64>,
+    },
+    /// Lists the streaming services and their connections
+    Streaming,
+    /// Blocks a DID, it can no longer authenticate and its tokens are rejected
+    Block { did: String },
+    /// Unblocks a DID
+    Unblock { did: String },
+    /// Checks that the atm functions library of this mediator version is loaded
+    CheckLua,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let config = init_from_file(&args.config, None).await?;
+    let database = DatabaseHandler::connect(&config).await?;
+
+    match args.command {
+        Command::Stats => {
+            println!("{}", database.get_db_metadata().await?);
+            let nodes = database.streaming_nodes().await?;
+            println!(
+                "    Streaming: services({}) alive({}) connections({})",
+                nodes.len(),
+                nodes.iter().filter(|node| node.alive).count(),
+                nodes
+                    .iter()
+                    .map(|node| node.connections.len())
+                    .sum::<usize>()
+            );
+        }
+        Command::Accounts { cursor, limit } => {
+            if config.find_tenant(&args.tenant).is_none() {
+                return Err(format!("Tenant ({}) isn't configured", args.tenant).into());
+            }
+            let list = database
+                .list_accounts(SESSION_ID, &args.tenant, cursor.as_deref(), limit)
+                .await?;
+            for account in &list.accounts {
+                println!("{}  {}", account.did_hash, account.did);
+            }
+            println!("{} accounts", list.accounts.len());
+            if let Some(cursor) = list.cursor {
+                println!("(more accounts may exist, use --cursor {})", cursor);
+            }
+        }
+        Command::Did { did } => {
+            let did_hash = did_hash(&config, &args.tenant, &did)?;
+            let stats = database.did_stats(SESSION_ID, &did, &did_hash).await?;
+            println!("DID:       {}", stats.did);
+            println!("DID hash:  {}", stats.did_hash);
+            println!("Tier:      {}", stats.tier);
+            println!(
+                "Inbox:     {} messages, {} bytes",
+                stats.receive_queue_count, stats.receive_queue_bytes
+            );
+            println!(
+                "Outbox:    {} messages, {} bytes",
+                stats.send_queue_count, stats.send_queue_bytes
+            );
+            println!("Streaming: {} connections", stats.streaming_connections);
+            match stats.blocked {
+                Some(blocked) => println!("Blocked:   since {}", blocked),
+                None => println!("Blocked:   no"),
+            }
+        }
+        Command::Queue { did, outbox, limit } => {
+            let did_hash = did_hash(&config, &args.tenant, &did)?;
+            let folder = if outbox {
+                Folder::Outbox
+            } else {
+                Folder::Inbox
+            };
+            let list = database
+                .list_messages(
+                    &did_hash,
+                    &args.tenant,
+                    folder,
+                    &ListOptions::default(),
+                    limit,
+                )
+                .await?;
+            for message in &list.messages {
+                println!(
+                    "{}  {}  {:>8} bytes  from({})  to({})",
+                    message.timestamp,
+                    message.msg_id,
+                    message.size,
+                    message.from_address.as_deref().unwrap_or("-"),
+                    message.to_address.as_deref().unwrap_or("-")
+                );
+            }
+            println!("{} messages", list.messages.len());
+            if list.next_cursor.is_some() {
+                println!("(more messages not shown, use --limit)");
+            }
+        }
+        Command::Purge { did, msg_id } => {
+            let did_hash = did_hash(&config, &args.tenant, &did)?;
+            if let Some(msg_id) = msg_id {
+                database
+                    .expire_message(SESSION_ID, &did_hash, &msg_id)
+                    .await?;
+                println!("Deleted msg_id({})", msg_id);
+            } else {
+                let deleted = database.purge_messages(SESSION_ID, &did_hash).await?;
+                println!("Deleted {} messages", deleted);
+            }
+        }
+        Command::Expire { did, older_than } => {
+            let did_hash = did_hash(&config, &args.tenant, &did)?;
+            let minutes = match older_than {
+                Some(minutes) => minutes,
+                None => {
+                    database
+                        .did_tier(SESSION_ID, &did_hash)
+                        .await?
+                        .message_expiry_minutes as u64
+                }
+            };
+            let expired_before = (SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis() as u64)
+                .saturating_sub(minutes * 60_000);
+            let deleted = database
+                .expire_messages(SESSION_ID, &did_hash, expired_before)
+                .await?;
+            println!(
+                "Deleted {} messages older than {} minutes",
+                deleted, minutes
+            );
+        }
+        Command::Streaming => {
+            for node in database.streaming_nodes().await? {
+                println!(
+                    "{}  {}  {} connections",
+                    node.uuid,
+                    if node.alive { "alive" } else { "expired" },
+                    node.connections.len()
+                );
+                for connection in node.connections {
+                    println!("    {}", connection);
+                }
+            }
+        }
+        Command::Block { did } => {
+            let did_hash = did_hash(&config, &args.tenant, &did)?;
+            if database
+                .did_set_blocked(SESSION_ID, &did_hash, true)
+                .await?
+            {
+                println!("Blocked {}", did);
+            } else {
+                println!("{} was already blocked", did);
+            }
+        }
+        Command::Unblock { did } => {
+            let did_hash = did_hash(&config, &args.tenant, &did)?;
+            if database
+                .did_set_blocked(SESSION_ID, &did_hash, false)
+                .await?
+            {
+                println!("Unblocked {}", did);
+            } else {
+                println!("{} wasn't blocked", did);
+            }
+        }
+        Command::CheckLua => {
+            let status = database.functions_status().await?;
+            if !status.loaded {
+                println!("atm functions library is NOT loaded");
+                std::process::exit(1);
+            }
+            println!(
+                "atm functions library is loaded ({})",
+                if status.current {
+                    "current version"
+                } else {
+                    "different version"
+                }
+            );
+            if !status.missing.is_empty() {
+                println!("Missing functions: {}", status.missing.join(", "));
+                std::process::exit(1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the DID hash of a DID within a configured tenant
+fn did_hash(config: &Config, namespace: &str, did: &str) -> Result<String, Box<dyn Error>> {
+    if config.find_tenant(namespace).is_none() {
+        return Err(format!("Tenant ({}) isn't configured", namespace).into());
+    }
+    Ok(keys::did_hash(namespace, did))
+}
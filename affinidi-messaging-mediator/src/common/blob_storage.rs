@@ -0,0 +1,328 @@
+//! Storage backends for out-of-band blobs (large attachments)
+//!
+//! Blobs are uploaded in chunks, which are assembled when the upload is completed
+//! - file://<path>          : Local disk, chunks are kept in `<path>/<blob_id>.parts/` until completed
+//! - s3://<bucket>/<prefix> : S3 compatible storage, each chunk is a part of a multipart upload
+use super::errors::MediatorError;
+use aws_config::SdkConfig;
+use aws_sdk_s3::{
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use axum::body::{Body, Bytes};
+use futures::stream;
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use tracing::{debug, event, Level};
+
+/// Size of the reads when streaming a blob from local disk
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub enum BlobStorage {
+    Local {
+        path: PathBuf,
+    },
+    S3 {
+        client: aws_sdk_s3::Client,
+        bucket: String,
+        prefix: String,
+    },
+}
+
+impl BlobStorage {
+    /// Creates the storage backend from the `storage` config value
+    /// - s3_endpoint: Endpoint of an S3 compatible service (e.g. MinIO), None uses AWS S3
+    pub async fn new(
+        storage: &str,
+        s3_endpoint: Option<&str>,
+        aws_config: &SdkConfig,
+    ) -> Result<Self, MediatorError> {
+        let parts: Vec<&str> = storage.split("://").collect();
+        if parts.len() != 2 {
+            return Err(MediatorError::ConfigError(
+                "NA".into(),
+                "Invalid `blobs.storage` format".into(),
+            ));
+        }
+        match parts[0] {
+            "file" => {
+                let path = PathBuf::from(parts[1]);
+                fs::create_dir_all(&path).await.map_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        "Couldn't create blob storage directory({}). {}",
+                        parts[1],
+                        err
+                    );
+                    MediatorError::ConfigError(
+                        "NA".into(),
+                        format!(
+                            "Couldn't create blob storage directory({}). {}",
+                            parts[1], err
+                        ),
+                    )
+                })?;
+                Ok(BlobStorage::Local { path })
+            }
+            "s3" => {
+                let (bucket, prefix) = match parts[1].split_once('/') {
+                    Some((bucket, prefix)) => (bucket, prefix.trim_end_matches('/')),
+                    None => (parts[1], ""),
+                };
+                let mut s3_config = aws_sdk_s3::config::Builder::from(aws_config);
+                if let Some(endpoint) = s3_endpoint {
+                    s3_config = s3_config.endpoint_url(endpoint).force_path_style(true);
+                }
+                Ok(BlobStorage::S3 {
+                    client: aws_sdk_s3::Client::from_conf(s3_config.build()),
+                    bucket: bucket.into(),
+                    prefix: prefix.into(),
+                })
+            }
+            _ => Err(MediatorError::ConfigError(
+                "NA".into(),
+                "Invalid `blobs.storage` format! Expected `file://` or `s3://`".into(),
+            )),
+        }
+    }
+
+    /// Starts an upload, returns the upload ID for S3 (empty for local disk)
+    pub async fn create_upload(&self, blob_id: &str) -> Result<String, MediatorError> {
+        match self {
+            BlobStorage::Local { path } => {
+                fs::create_dir_all(Self::_parts_path(path, blob_id))
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "create upload", err))?;
+                Ok(String::new())
+            }
+            BlobStorage::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                let upload = client
+                    .create_multipart_upload()
+                    .bucket(bucket)
+                    .key(Self::_key(prefix, blob_id))
+                    .send()
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "create upload", err))?;
+                Ok(upload.upload_id().unwrap_or_default().to_string())
+            }
+        }
+    }
+
+    /// Stores a chunk of an upload, chunks can be sent in any order and sent again
+    /// Returns the ETag of the chunk for S3 (empty for local disk)
+    pub async fn put_chunk(
+        &self,
+        blob_id: &str,
+        upload_id: &str,
+        index: u64,
+        data: Bytes,
+    ) -> Result<String, MediatorError> {
+        match self {
+            BlobStorage::Local { path } => {
+                let chunk = Self::_parts_path(path, blob_id).join(index.to_string());
+                fs::write(&chunk, &data)
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "store chunk", err))?;
+                Ok(String::new())
+            }
+            BlobStorage::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                let part = client
+                    .upload_part()
+                    .bucket(bucket)
+                    .key(Self::_key(prefix, blob_id))
+                    .upload_id(upload_id)
+                    .part_number(index as i32 + 1)
+                    .body(ByteStream::from(data))
+                    .send()
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "store chunk", err))?;
+                Ok(part.e_tag().unwrap_or_default().to_string())
+            }
+        }
+    }
+
+    /// Assembles the chunks into the blob
+    /// - etags: ETag of each chunk in order (S3 only)
+    pub async fn complete_upload(
+        &self,
+        blob_id: &str,
+        upload_id: &str,
+        etags: Vec<String>,
+    ) -> Result<(), MediatorError> {
+        match self {
+            BlobStorage::Local { path } => {
+                let parts = Self::_parts_path(path, blob_id);
+                let mut blob = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(path.join(blob_id))
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "complete upload", err))?;
+                for index in 0..etags.len() {
+                    let chunk = fs::read(parts.join(index.to_string()))
+                        .await
+                        .map_err(|err| Self::_error(blob_id, "complete upload", err))?;
+                    blob.write_all(&chunk)
+                        .await
+                        .map_err(|err| Self::_error(blob_id, "complete upload", err))?;
+                }
+                blob.flush()
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "complete upload", err))?;
+                let _ = fs::remove_dir_all(parts).await;
+                Ok(())
+            }
+            BlobStorage::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                let parts = etags
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, etag)| {
+                        CompletedPart::builder()
+                            .part_number(index as i32 + 1)
+                            .e_tag(etag)
+                            .build()
+                    })
+                    .collect();
+                client
+                    .complete_multipart_upload()
+                    .bucket(bucket)
+                    .key(Self::_key(prefix, blob_id))
+                    .upload_id(upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(parts))
+                            .build(),
+                    )
+                    .send()
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "complete upload", err))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Streams a completed blob
+    pub async fn download(&self, blob_id: &str) -> Result<Body, MediatorError> {
+        match self {
+            BlobStorage::Local { path } => {
+                let file = File::open(path.join(blob_id))
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "open blob", err))?;
+                Ok(Body::from_stream(stream::unfold(
+                    file,
+                    |mut file| async move {
+                        let mut buffer = vec![0; READ_BUFFER_SIZE];
+                        match file.read(&mut buffer).await {
+                            Ok(0) => None,
+                            Ok(len) => {
+                                buffer.truncate(len);
+                                Some((Ok(Bytes::from(buffer)), file))
+                            }
+                            Err(err) => Some((Err(err), file)),
+                        }
+                    },
+                )))
+            }
+            BlobStorage::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                let object = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(Self::_key(prefix, blob_id))
+                    .send()
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "open blob", err))?;
+                Ok(Body::from_stream(stream::unfold(
+                    object.body,
+                    |mut body| async move { body.next().await.map(|chunk| (chunk, body)) },
+                )))
+            }
+        }
+    }
+
+    /// Removes a blob, or an upload that was never completed
+    pub async fn delete(&self, blob_id: &str, upload_id: &str) -> Result<(), MediatorError> {
+        match self {
+            BlobStorage::Local { path } => {
+                let _ = fs::remove_dir_all(Self::_parts_path(path, blob_id)).await;
+                match fs::remove_file(path.join(blob_id)).await {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        Err(Self::_error(blob_id, "delete blob", err))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            BlobStorage::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                if !upload_id.is_empty() {
+                    // Aborting a completed upload fails, which is expected
+                    let _ = client
+                        .abort_multipart_upload()
+                        .bucket(bucket)
+                        .key(Self::_key(prefix, blob_id))
+                        .upload_id(upload_id)
+                        .send()
+                        .await;
+                }
+                client
+                    .delete_object()
+                    .bucket(bucket)
+                    .key(Self::_key(prefix, blob_id))
+                    .send()
+                    .await
+                    .map_err(|err| Self::_error(blob_id, "delete blob", err))?;
+                debug!("blob({}) deleted", blob_id);
+                Ok(())
+            }
+        }
+    }
+
+    fn _parts_path(path: &Path, blob_id: &str) -> PathBuf {
+        path.join(format!("{}.parts", blob_id))
+    }
+
+    fn _key(prefix: &str, blob_id: &str) -> String {
+        if prefix.is_empty() {
+            blob_id.to_string()
+        } else {
+            format!("{}/{}", prefix, blob_id)
+        }
+    }
+
+    fn _error<E: std::fmt::Debug>(blob_id: &str, operation: &str, err: E) -> MediatorError {
+        event!(
+            Level::ERROR,
+            "Couldn't {} for blob({}): {:?}",
+            operation,
+            blob_id,
+            err
+        );
+        MediatorError::InternalError(
+            "NA".into(),
+            format!("Couldn't {} for blob({})", operation, blob_id),
+        )
+    }
+}
@@ -1,4 +1,11 @@
-use super::errors::MediatorError;
+use super::{
+    blob_storage::BlobStorage,
+    endpoint_guard::EndpointGuard,
+    errors::MediatorError,
+    secret_box::SecretBox,
+    tenant::Tenant,
+    tier::{MailboxTier, Tiers, DEFAULT_TIER},
+};
 use crate::resolvers::affinidi_secrets::AffinidiSecrets;
 use affinidi_did_resolver_cache_sdk::config::{ClientConfig, ClientConfigBuilder};
 use async_convert::{async_trait, TryFrom};
@@ -18,26 +25,38 @@ use std::{
     fs::{self, File},
     io::{self, BufRead},
     path::Path,
+    str::FromStr,
 };
 use tracing::{event, info, Level};
 use tracing_subscriber::filter::LevelFilter;
 
+/// Smallest blob chunk size, the minimum part size of an S3 multipart upload
+const MIN_BLOB_CHUNK_SIZE: u64 = 5 * 1024 * 1024;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub api_prefix: String,
     pub http_size_limit: String,
     pub ws_size_limit: String,
+    pub fetch_wait_max: String,
+    pub ws_ticket_expiry: String,
+    pub ws_auth_timeout: String,
+    pub ws_ping_interval: String,
+    pub ws_idle_timeout: String,
 }
 
 /// Database Struct contains database and storage of messages related configuration details
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DatabaseConfig {
     pub database_url: String,
+    pub database_cluster: String,
     pub database_pool_size: String,
     pub database_timeout: String,
     pub max_message_size: String,
     pub max_queued_messages: String,
+    pub max_queued_bytes: String,
     pub message_expiry_minutes: String,
+    pub message_rate_limit: String,
     pub max_listed_messages: String,
     pub max_deleted_messages: String,
 }
@@ -50,6 +69,14 @@ pub struct SecurityConfig {
     pub ssl_key_file: String,
     pub jwt_authorization_secret: String,
     pub cors_allow_origin: Option<String>,
+    pub admin_dids: Option<String>,
+    pub mailbox_trusted_mediators: Option<String>,
+    pub replay_window: String,
+    pub tls_min_version: String,
+    pub tls_cipher_suites: Option<String>,
+    pub tls_alpn: String,
+    pub tls_client_ca_file: Option<String>,
+    pub tls_reload_interval: String,
 }
 
 /// StreamingConfig Struct contains live streaming related configuration details
@@ -57,6 +84,134 @@ pub struct SecurityConfig {
 pub struct StreamingConfig {
     pub enabled: String,
     pub uuid: String,
+    pub heartbeat_interval: String,
+    pub heartbeat_ttl: String,
+}
+
+/// ReceiptsConfig Struct contains delivery and read receipt related configuration details
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReceiptsConfig {
+    pub policy: String,
+    pub include_timestamp: String,
+}
+
+/// Which receipts the mediator sends to senders that request them
+/// - Disabled  : No receipts are sent
+/// - Delivered : Only delivery receipts (message was fetched or live streamed)
+/// - All       : Delivery and read receipts (message was acknowledged)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReceiptPolicy {
+    Disabled,
+    Delivered,
+    #[default]
+    All,
+}
+
+impl ReceiptPolicy {
+    fn parse(policy: &str) -> Result<Self, MediatorError> {
+        match policy {
+            "none" => Ok(ReceiptPolicy::Disabled),
+            "delivered" => Ok(ReceiptPolicy::Delivered),
+            "all" => Ok(ReceiptPolicy::All),
+            _ => Err(MediatorError::ConfigError(
+                "NA".into(),
+                format!(
+                    "Invalid receipts policy ({}), expecting none, delivered or all",
+                    policy
+                ),
+            )),
+        }
+    }
+}
+
+/// Minimum TLS protocol version that the mediator accepts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn parse(version: &str) -> Result<Self, MediatorError> {
+        match version {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(MediatorError::ConfigError(
+                "NA".into(),
+                format!(
+                    "Invalid TLS minimum version ({}), expecting 1.2 or 1.3",
+                    version
+                ),
+            )),
+        }
+    }
+}
+
+/// PushConfig Struct contains push notification (webhook) related configuration details
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PushConfig {
+    pub enabled: String,
+    pub allow_http: String,
+    pub batch_interval: String,
+    pub rate_limit: String,
+    pub retries: String,
+    pub timeout: String,
+    pub max_registrations: String,
+    pub max_concurrent: String,
+    pub allow_private: String,
+    pub allowed_hosts: Option<String>,
+    pub denied_hosts: Option<String>,
+}
+
+/// BlobsConfig Struct contains out-of-band blob (large attachment) storage related configuration details
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlobsConfig {
+    pub enabled: String,
+    pub storage: String,
+    pub s3_endpoint: Option<String>,
+    pub max_size: String,
+    pub chunk_size: String,
+}
+
+/// OOBConfig Struct contains Out-of-Band invitation hosting related configuration details
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OOBConfig {
+    pub max_expiry: String,
+    pub max_size: String,
+    pub max_invitations: String,
+    pub redirect_url: Option<String>,
+}
+
+/// ForwardConfig Struct contains anonymous forward message related configuration details
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ForwardConfig {
+    pub enabled: String,
+    pub max_size: String,
+    pub rate_limit: String,
+    pub pow_difficulty: String,
+}
+
+/// TenantConfig Struct contains the details of an additional tenant (`[[tenants]]`)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TenantConfig {
+    pub namespace: String,
+    pub did: String,
+    pub secrets: String,
+    pub api_prefix: Option<String>,
+    pub host: Option<String>,
+}
+
+/// TierConfig Struct contains the limits of a mailbox tier (`[[tiers]]`)
+/// Limits that aren't set are taken from the default tier
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TierConfig {
+    pub name: String,
+    pub max_queued_messages: Option<String>,
+    pub max_queued_bytes: Option<String>,
+    pub message_expiry_minutes: Option<String>,
+    pub max_message_size: Option<String>,
+    pub message_rate_limit: Option<String>,
 }
 
 /// DIDResolverConfig Struct contains live streaming related configuration details
@@ -105,8 +260,17 @@ pub struct ConfigRaw {
     pub database: DatabaseConfig,
     pub security: SecurityConfig,
     pub streaming: StreamingConfig,
+    pub receipts: ReceiptsConfig,
+    pub push: PushConfig,
+    pub blobs: BlobsConfig,
+    pub oob: OOBConfig,
+    pub forward: ForwardConfig,
     pub did_resolver: DIDResolverConfig,
     pub other: OtherConfig,
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+    #[serde(default)]
+    pub tiers: Vec<TierConfig>,
 }
 
 #[derive(Clone)]
@@ -115,29 +279,87 @@ pub struct Config {
     pub listen_address: String,
     pub mediator_did: String,
     pub mediator_secrets: AffinidiSecrets,
+    pub tenants: Vec<Tenant>,
     pub database_url: String,
+    pub database_cluster: bool,
     pub database_pool_size: usize,
     pub database_timeout: u32,
     pub api_prefix: String,
     pub http_size_limit: u32,
     pub ws_size_limit: u32,
+    pub fetch_wait_max: u64,
+    pub ws_ticket_expiry: u64,
+    pub ws_auth_timeout: u64,
+    pub ws_ping_interval: u64,
+    pub ws_idle_timeout: u64,
     pub max_message_size: u32,
     pub max_queued_messages: u32,
+    pub max_queued_bytes: u64,
     pub message_expiry_minutes: u32,
+    pub message_rate_limit: u64,
+    pub tiers: Tiers,
     pub max_listed_messages: u32,
     pub max_deleted_messages: u32,
     pub use_ssl: bool,
     pub ssl_certificate_file: String,
     pub ssl_key_file: String,
+    pub tls_min_version: TlsVersion,
+    pub tls_cipher_suites: Vec<String>,
+    pub tls_alpn: Vec<String>,
+    pub tls_client_ca_file: Option<String>,
+    pub tls_reload_interval: u64,
+    pub admin_dids: Vec<String>,
+    pub mailbox_trusted_mediators: Vec<String>,
     pub jwt_encoding_key: Option<EncodingKey>,
     pub jwt_decoding_key: Option<DecodingKey>,
     pub streaming_enabled: bool,
     pub streaming_uuid: String,
+    pub streaming_heartbeat_interval: u64,
+    pub streaming_heartbeat_ttl: u64,
+    pub receipts_policy: ReceiptPolicy,
+    pub receipts_include_timestamp: bool,
+    pub push_enabled: bool,
+    pub push_allow_http: bool,
+    pub push_batch_interval: u64,
+    pub push_rate_limit: u64,
+    pub push_retries: u32,
+    pub push_timeout: u64,
+    pub push_max_registrations: usize,
+    pub push_max_concurrent: usize,
+    pub push_allow_private: bool,
+    pub push_allowed_hosts: Vec<String>,
+    pub push_denied_hosts: Vec<String>,
+    pub push_secret_box: Option<SecretBox>,
+    pub blobs_enabled: bool,
+    pub blob_storage: Option<BlobStorage>,
+    pub blob_max_size: u64,
+    pub blob_chunk_size: u64,
+    pub oob_max_expiry: u64,
+    pub oob_max_size: usize,
+    pub oob_max_invitations: usize,
+    pub oob_redirect_url: Option<String>,
+    pub forward_enabled: bool,
+    pub forward_max_size: usize,
+    pub forward_rate_limit: u64,
+    pub forward_pow_difficulty: u32,
     pub did_resolver_config: ClientConfig,
     pub to_recipients_limit: usize,
     pub cors_allow_origin: CorsLayer,
     pub crypto_operations_per_message_limit: usize,
     pub to_keys_per_recipient_limit: usize,
+    pub replay_window: u64,
+}
+
+impl Config {
+    /// Checks the push endpoints that the mediator may call
+    pub fn push_guard(&self) -> EndpointGuard {
+        EndpointGuard {
+            allow_http: self.push_allow_http,
+            allow_private: self.push_allow_private,
+            allowed_hosts: self.push_allowed_hosts.clone(),
+            denied_hosts: self.push_denied_hosts.clone(),
+        }
+    }
 }
 
 impl fmt::Debug for Config {
@@ -151,27 +373,79 @@ impl fmt::Debug for Config {
                 "mediator_secrets",
                 &format!("({}) secrets loaded", self.mediator_secrets.len()),
             )
+            .field("tenants", &self.tenants)
             .field("use_ssl", &self.use_ssl)
             .field("cors_allow_origin", &self.cors_allow_origin)
+            .field("replay_window", &self.replay_window)
+            .field("admin_dids", &self.admin_dids)
+            .field("mailbox_trusted_mediators", &self.mailbox_trusted_mediators)
             .field("database_url", &self.database_url)
+            .field("database_cluster", &self.database_cluster)
             .field("database_pool_size", &self.database_pool_size)
             .field("database_timeout", &self.database_timeout)
             .field("max_message_size", &self.max_message_size)
             .field("max_listed_messages", &self.max_listed_messages)
             .field("message_expiry_minutes", &self.message_expiry_minutes)
             .field("max_queued_messages", &self.max_queued_messages)
+            .field("max_queued_bytes", &self.max_queued_bytes)
+            .field("message_rate_limit", &self.message_rate_limit)
+            .field("tiers", &self.tiers)
             .field("max_deleted_messages", &self.max_deleted_messages)
             .field("ssl_certificate_file", &self.ssl_certificate_file)
             .field("ssl_key_file", &self.ssl_key_file)
+            .field("tls_min_version", &self.tls_min_version)
+            .field("tls_cipher_suites", &self.tls_cipher_suites)
+            .field("tls_alpn", &self.tls_alpn)
+            .field("tls_client_ca_file", &self.tls_client_ca_file)
+            .field("tls_reload_interval", &self.tls_reload_interval)
             .field("jwt_encoding_key?", &self.jwt_encoding_key.is_some())
             .field("jwt_decoding_key?", &self.jwt_decoding_key.is_some())
             .field("streaming_enabled?", &self.streaming_enabled)
             .field("streaming_uuid", &self.streaming_uuid)
+            .field(
+                "streaming_heartbeat_interval",
+                &self.streaming_heartbeat_interval,
+            )
+            .field("streaming_heartbeat_ttl", &self.streaming_heartbeat_ttl)
+            .field("receipts_policy", &self.receipts_policy)
+            .field(
+                "receipts_include_timestamp",
+                &self.receipts_include_timestamp,
+            )
+            .field("push_enabled", &self.push_enabled)
+            .field("push_allow_http", &self.push_allow_http)
+            .field("push_batch_interval", &self.push_batch_interval)
+            .field("push_rate_limit", &self.push_rate_limit)
+            .field("push_retries", &self.push_retries)
+            .field("push_timeout", &self.push_timeout)
+            .field("push_max_registrations", &self.push_max_registrations)
+            .field("push_max_concurrent", &self.push_max_concurrent)
+            .field("push_allow_private", &self.push_allow_private)
+            .field("push_allowed_hosts", &self.push_allowed_hosts)
+            .field("push_denied_hosts", &self.push_denied_hosts)
+            .field("push_secret_box?", &self.push_secret_box.is_some())
+            .field("blobs_enabled", &self.blobs_enabled)
+            .field("blob_storage", &self.blob_storage)
+            .field("blob_max_size", &self.blob_max_size)
+            .field("blob_chunk_size", &self.blob_chunk_size)
+            .field("oob_max_expiry", &self.oob_max_expiry)
+            .field("oob_max_size", &self.oob_max_size)
+            .field("oob_max_invitations", &self.oob_max_invitations)
+            .field("oob_redirect_url", &self.oob_redirect_url)
+            .field("forward_enabled", &self.forward_enabled)
+            .field("forward_max_size", &self.forward_max_size)
+            .field("forward_rate_limit", &self.forward_rate_limit)
+            .field("forward_pow_difficulty", &self.forward_pow_difficulty)
             .field("DID Resolver config", &self.did_resolver_config)
             .field("to_recipients_limit", &self.to_recipients_limit)
             .field("api_prefix", &self.api_prefix)
             .field("http_size_limit", &self.http_size_limit)
             .field("ws_size_limit", &self.ws_size_limit)
+            .field("fetch_wait_max", &self.fetch_wait_max)
+            .field("ws_ticket_expiry", &self.ws_ticket_expiry)
+            .field("ws_auth_timeout", &self.ws_auth_timeout)
+            .field("ws_ping_interval", &self.ws_ping_interval)
+            .field("ws_idle_timeout", &self.ws_idle_timeout)
             .field(
                 "crypto_operations_per_message_limit",
                 &self.crypto_operations_per_message_limit,
@@ -198,29 +472,82 @@ impl Default for Config {
             listen_address: "".into(),
             mediator_did: "".into(),
             mediator_secrets: AffinidiSecrets::new(vec![]),
+            tenants: vec![Tenant::default()],
             database_url: "redis://127.0.0.1/".into(),
+            database_cluster: false,
             database_pool_size: 10,
             database_timeout: 2,
             max_message_size: 1048576,
             max_queued_messages: 100,
+            max_queued_bytes: 0,
             message_expiry_minutes: 10080,
+            message_rate_limit: 0,
+            tiers: Tiers::new(MailboxTier {
+                name: DEFAULT_TIER.into(),
+                max_queued_messages: 100,
+                max_queued_bytes: 0,
+                message_expiry_minutes: 10080,
+                max_message_size: 1048576,
+                message_rate_limit: 0,
+            }),
             max_listed_messages: 100,
             max_deleted_messages: 100,
             use_ssl: true,
             ssl_certificate_file: "".into(),
             ssl_key_file: "".into(),
+            tls_min_version: TlsVersion::Tls12,
+            tls_cipher_suites: vec![],
+            tls_alpn: vec!["h2".into(), "http/1.1".into()],
+            tls_client_ca_file: None,
+            tls_reload_interval: 60,
+            admin_dids: vec![],
+            mailbox_trusted_mediators: vec![],
             jwt_encoding_key: None,
             jwt_decoding_key: None,
             streaming_enabled: true,
             streaming_uuid: "".into(),
+            streaming_heartbeat_interval: 10,
+            streaming_heartbeat_ttl: 30,
+            receipts_policy: ReceiptPolicy::All,
+            receipts_include_timestamp: false,
+            push_enabled: false,
+            push_allow_http: false,
+            push_batch_interval: 1000,
+            push_rate_limit: 30,
+            push_retries: 3,
+            push_timeout: 5,
+            push_max_registrations: 5,
+            push_max_concurrent: 50,
+            push_allow_private: false,
+            push_allowed_hosts: vec![],
+            push_denied_hosts: vec![],
+            push_secret_box: None,
+            blobs_enabled: false,
+            blob_storage: None,
+            blob_max_size: 104857600,
+            blob_chunk_size: 5242880,
+            oob_max_expiry: 604800,
+            oob_max_size: 65536,
+            oob_max_invitations: 100,
+            oob_redirect_url: None,
+            forward_enabled: false,
+            forward_max_size: 65536,
+            forward_rate_limit: 60,
+            forward_pow_difficulty: 0,
             did_resolver_config,
             to_recipients_limit: 100,
             cors_allow_origin: CorsLayer::new().allow_origin(Any),
             ws_size_limit: 10485760,
+            fetch_wait_max: 30,
+            ws_ticket_expiry: 30,
+            ws_auth_timeout: 10,
+            ws_ping_interval: 30,
+            ws_idle_timeout: 90,
             api_prefix: "/mediator/v1/".into(),
             http_size_limit: 10485760,
             crypto_operations_per_message_limit: 1_000,
             to_keys_per_recipient_limit: 100,
+            replay_window: 300,
         }
     }
 }
@@ -252,22 +579,84 @@ impl TryFrom<ConfigRaw> for Config {
             listen_address: raw.listen_address,
             mediator_did: read_did_config(&raw.mediator_did, &aws_config).await?,
             database_url: raw.database.database_url,
+            database_cluster: raw.database.database_cluster.parse().unwrap_or(false),
             database_pool_size: raw.database.database_pool_size.parse().unwrap_or(10),
             database_timeout: raw.database.database_timeout.parse().unwrap_or(2),
             max_message_size: raw.database.max_message_size.parse().unwrap_or(1048576),
             max_queued_messages: raw.database.max_queued_messages.parse().unwrap_or(100),
+            max_queued_bytes: raw.database.max_queued_bytes.parse().unwrap_or(0),
+            message_rate_limit: raw.database.message_rate_limit.parse().unwrap_or(0),
             max_listed_messages: raw.database.max_listed_messages.parse().unwrap_or(100),
             max_deleted_messages: raw.database.max_deleted_messages.parse().unwrap_or(100),
             message_expiry_minutes: raw.database.message_expiry_minutes.parse().unwrap_or(10080),
             use_ssl: raw.security.use_ssl.parse().unwrap_or(true),
             ssl_certificate_file: raw.security.ssl_certificate_file,
             ssl_key_file: raw.security.ssl_key_file,
+            tls_min_version: TlsVersion::parse(&raw.security.tls_min_version)?,
+            tls_cipher_suites: raw
+                .security
+                .tls_cipher_suites
+                .map(|suites| parse_list(&suites))
+                .unwrap_or_default(),
+            tls_alpn: parse_list(&raw.security.tls_alpn),
+            tls_client_ca_file: raw.security.tls_client_ca_file,
+            tls_reload_interval: raw.security.tls_reload_interval.parse().unwrap_or(60),
+            replay_window: raw.security.replay_window.parse().unwrap_or(300),
+            admin_dids: raw
+                .security
+                .admin_dids
+                .map(|dids| parse_list(&dids))
+                .unwrap_or_default(),
+            mailbox_trusted_mediators: raw
+                .security
+                .mailbox_trusted_mediators
+                .map(|dids| parse_list(&dids))
+                .unwrap_or_default(),
             streaming_enabled: raw.streaming.enabled.parse().unwrap_or(true),
+            streaming_heartbeat_interval: raw.streaming.heartbeat_interval.parse().unwrap_or(10),
+            streaming_heartbeat_ttl: raw.streaming.heartbeat_ttl.parse().unwrap_or(30),
+            receipts_policy: ReceiptPolicy::parse(&raw.receipts.policy)?,
+            receipts_include_timestamp: raw.receipts.include_timestamp.parse().unwrap_or(false),
+            push_enabled: raw.push.enabled.parse().unwrap_or(false),
+            push_allow_http: raw.push.allow_http.parse().unwrap_or(false),
+            push_batch_interval: raw.push.batch_interval.parse().unwrap_or(1000),
+            push_rate_limit: raw.push.rate_limit.parse().unwrap_or(30),
+            push_retries: raw.push.retries.parse().unwrap_or(3),
+            push_timeout: raw.push.timeout.parse().unwrap_or(5),
+            push_max_registrations: raw.push.max_registrations.parse().unwrap_or(5),
+            push_max_concurrent: raw.push.max_concurrent.parse().unwrap_or(50),
+            push_allow_private: raw.push.allow_private.parse().unwrap_or(false),
+            push_allowed_hosts: raw
+                .push
+                .allowed_hosts
+                .map(|hosts| parse_list(&hosts))
+                .unwrap_or_default(),
+            push_denied_hosts: raw
+                .push
+                .denied_hosts
+                .map(|hosts| parse_list(&hosts))
+                .unwrap_or_default(),
+            blobs_enabled: raw.blobs.enabled.parse().unwrap_or(false),
+            blob_max_size: raw.blobs.max_size.parse().unwrap_or(104857600),
+            blob_chunk_size: raw.blobs.chunk_size.parse().unwrap_or(5242880),
+            oob_max_expiry: raw.oob.max_expiry.parse().unwrap_or(604800),
+            oob_max_size: raw.oob.max_size.parse().unwrap_or(65536),
+            oob_max_invitations: raw.oob.max_invitations.parse().unwrap_or(100),
+            oob_redirect_url: raw.oob.redirect_url,
+            forward_enabled: raw.forward.enabled.parse().unwrap_or(false),
+            forward_max_size: raw.forward.max_size.parse().unwrap_or(65536),
+            forward_rate_limit: raw.forward.rate_limit.parse().unwrap_or(60),
+            forward_pow_difficulty: raw.forward.pow_difficulty.parse().unwrap_or(0),
             did_resolver_config: raw.did_resolver.convert(),
             to_recipients_limit: raw.other.to_recipients_limit.parse().unwrap_or(100),
             api_prefix: raw.server.api_prefix,
             http_size_limit: raw.server.http_size_limit.parse().unwrap_or(10485760),
             ws_size_limit: raw.server.ws_size_limit.parse().unwrap_or(10485760),
+            fetch_wait_max: raw.server.fetch_wait_max.parse().unwrap_or(30),
+            ws_ticket_expiry: raw.server.ws_ticket_expiry.parse().unwrap_or(30),
+            ws_auth_timeout: raw.server.ws_auth_timeout.parse().unwrap_or(10),
+            ws_ping_interval: raw.server.ws_ping_interval.parse().unwrap_or(30),
+            ws_idle_timeout: raw.server.ws_idle_timeout.parse().unwrap_or(90),
             crypto_operations_per_message_limit: raw
                 .other
                 .crypto_operations_per_message_limit
@@ -289,6 +678,40 @@ impl TryFrom<ConfigRaw> for Config {
         // Load mediator secrets
         config.mediator_secrets = load_secrets(&raw.mediator_secrets, &aws_config).await?;
 
+        // The mediator DID is the default tenant, additional tenants have their own DID, secrets and namespace
+        config.tenants = vec![Tenant {
+            did: config.mediator_did.clone(),
+            secrets: config.mediator_secrets.clone(),
+            ..Default::default()
+        }];
+        for tenant in raw.tenants {
+            let tenant = Tenant {
+                did: read_did_config(&tenant.did, &aws_config).await?,
+                secrets: load_secrets(&tenant.secrets, &aws_config).await?,
+                namespace: tenant.namespace,
+                api_prefix: tenant.api_prefix,
+                host: tenant.host.map(|host| host.to_ascii_lowercase()),
+            };
+            check_tenant(&config, &tenant)?;
+            config.tenants.push(tenant);
+        }
+
+        // The global limits are the default tier, named tiers inherit the limits they don't set
+        config.tiers = Tiers::new(MailboxTier {
+            name: DEFAULT_TIER.into(),
+            max_queued_messages: config.max_queued_messages,
+            max_queued_bytes: config.max_queued_bytes,
+            message_expiry_minutes: config.message_expiry_minutes,
+            max_message_size: config.max_message_size,
+            message_rate_limit: config.message_rate_limit,
+        });
+        check_tier_message_size(&config.tiers.default, config.http_size_limit)?;
+        for tier in raw.tiers {
+            let tier = parse_tier(&config.tiers, tier)?;
+            check_tier_message_size(&tier, config.http_size_limit)?;
+            config.tiers.tiers.insert(tier.name.clone(), tier);
+        }
+
         // Create the JWT encoding and decoding keys
         let jwt_secret =
             config_jwt_secret(&raw.security.jwt_authorization_secret, &aws_config).await?;
@@ -304,9 +727,34 @@ impl TryFrom<ConfigRaw> for Config {
         })?;
         config.jwt_decoding_key = Some(DecodingKey::from_ed_der(pair.public_key().as_ref()));
 
+        // Push endpoint secrets (Authorization headers) are encrypted with a key derived from the JWT secret
+        if config.push_enabled {
+            config.push_secret_box = Some(SecretBox::new(&jwt_secret, "push authorization")?);
+        }
+
+        // Set up the blob storage
+        if config.blobs_enabled {
+            config.blob_storage = Some(
+                BlobStorage::new(
+                    &raw.blobs.storage,
+                    raw.blobs.s3_endpoint.as_deref(),
+                    &aws_config,
+                )
+                .await?,
+            );
+        }
+
         // Get Subscriber unique hostname
         if config.streaming_enabled {
             config.streaming_uuid = get_hostname(&raw.streaming.uuid)?;
+            check_heartbeat(
+                config.streaming_heartbeat_interval,
+                config.streaming_heartbeat_ttl,
+            )?;
+        }
+
+        if config.blobs_enabled {
+            check_blob_chunk_size(config.blob_chunk_size, config.http_size_limit)?;
         }
 
         Ok(config)
@@ -322,6 +770,179 @@ fn parse_cors_allow_origin(cors_allow_origin: &str) -> Result<Vec<HeaderValue>,
     Ok(origins)
 }
 
+/// Checks that a streaming service heartbeat outlives the interval between heartbeats,
+/// otherwise live streaming services are reaped as dead
+fn check_heartbeat(interval: u64, ttl: u64) -> Result<(), MediatorError> {
+    if interval == 0 || ttl <= interval {
+        let message = format!(
+            "streaming heartbeat_ttl ({}) must be greater than heartbeat_interval ({}), and heartbeat_interval can't be 0",
+            ttl, interval
+        );
+        event!(Level::ERROR, "{}", message);
+        return Err(MediatorError::ConfigError("NA".into(), message));
+    }
+    Ok(())
+}
+
+/// Checks that blob chunks can be uploaded as parts of an S3 multipart upload
+/// Every part except the last must be at least 5MB, and each chunk is a request limited to `http_size_limit` bytes
+fn check_blob_chunk_size(chunk_size: u64, http_size_limit: u32) -> Result<(), MediatorError> {
+    if chunk_size < MIN_BLOB_CHUNK_SIZE {
+        let message = format!(
+            "blobs chunk_size ({}) must be at least {} bytes (5MB)",
+            chunk_size, MIN_BLOB_CHUNK_SIZE
+        );
+        event!(Level::ERROR, "{}", message);
+        return Err(MediatorError::ConfigError("NA".into(), message));
+    }
+    if chunk_size > http_size_limit as u64 {
+        let message = format!(
+            "blobs chunk_size ({}) exceeds http_size_limit ({})",
+            chunk_size, http_size_limit
+        );
+        event!(Level::ERROR, "{}", message);
+        return Err(MediatorError::ConfigError("NA".into(), message));
+    }
+    Ok(())
+}
+
+/// Checks that messages of a tier fit in a request, requests are limited to `http_size_limit` bytes
+/// A tier without a message size limit (0) is still limited by `http_size_limit`
+fn check_tier_message_size(tier: &MailboxTier, http_size_limit: u32) -> Result<(), MediatorError> {
+    if tier.max_message_size > http_size_limit {
+        let message = format!(
+            "Tier ({}) max_message_size ({}) exceeds http_size_limit ({})",
+            tier.name, tier.max_message_size, http_size_limit
+        );
+        event!(Level::ERROR, "{}", message);
+        return Err(MediatorError::ConfigError("NA".into(), message));
+    }
+    Ok(())
+}
+
+/// Checks that an additional tenant doesn't clash with the tenants already configured
+fn check_tenant(config: &Config, tenant: &Tenant) -> Result<(), MediatorError> {
+    let error = |message: String| {
+        event!(Level::ERROR, "{}", message);
+        Err(MediatorError::ConfigError("NA".into(), message))
+    };
+
+    if tenant.namespace.is_empty()
+        || !tenant
+            .namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return error(format!(
+            "Invalid tenant namespace ({}), expecting letters, digits, '-' or '_'",
+            tenant.namespace
+        ));
+    }
+    if tenant.api_prefix.as_ref() == Some(&config.api_prefix) {
+        return error(format!(
+            "Tenant ({}) api_prefix clashes with the mediator api_prefix",
+            tenant.namespace
+        ));
+    }
+
+    for existing in &config.tenants {
+        if existing.namespace == tenant.namespace {
+            return error(format!("Duplicate tenant namespace ({})", tenant.namespace));
+        }
+        if existing.did == tenant.did {
+            return error(format!(
+                "Tenant ({}) DID is already used by another tenant",
+                tenant.namespace
+            ));
+        }
+        if tenant.api_prefix.is_some() && existing.api_prefix == tenant.api_prefix {
+            return error(format!(
+                "Tenant ({}) api_prefix is already used by another tenant",
+                tenant.namespace
+            ));
+        }
+        if tenant.host.is_some() && existing.host == tenant.host {
+            return error(format!(
+                "Tenant ({}) host is already used by another tenant",
+                tenant.namespace
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds a named mailbox tier, limits that aren't set are taken from the default tier
+fn parse_tier(tiers: &Tiers, raw: TierConfig) -> Result<MailboxTier, MediatorError> {
+    if raw.name.is_empty() || tiers.contains(&raw.name) {
+        let message = format!("Invalid or duplicate tier name ({})", raw.name);
+        event!(Level::ERROR, "{}", message);
+        return Err(MediatorError::ConfigError("NA".into(), message));
+    }
+
+    let default = &tiers.default;
+    let name = &raw.name;
+    Ok(MailboxTier {
+        max_queued_messages: parse_tier_limit(
+            name,
+            "max_queued_messages",
+            raw.max_queued_messages,
+            default.max_queued_messages,
+        )?,
+        max_queued_bytes: parse_tier_limit(
+            name,
+            "max_queued_bytes",
+            raw.max_queued_bytes,
+            default.max_queued_bytes,
+        )?,
+        message_expiry_minutes: parse_tier_limit(
+            name,
+            "message_expiry_minutes",
+            raw.message_expiry_minutes,
+            default.message_expiry_minutes,
+        )?,
+        max_message_size: parse_tier_limit(
+            name,
+            "max_message_size",
+            raw.max_message_size,
+            default.max_message_size,
+        )?,
+        message_rate_limit: parse_tier_limit(
+            name,
+            "message_rate_limit",
+            raw.message_rate_limit,
+            default.message_rate_limit,
+        )?,
+        name: raw.name,
+    })
+}
+
+/// Parses a limit of a tier, the default tier's limit is used if it isn't set
+fn parse_tier_limit<T: FromStr>(
+    tier: &str,
+    field: &str,
+    value: Option<String>,
+    default: T,
+) -> Result<T, MediatorError> {
+    match value {
+        Some(value) => value.parse().map_err(|_| {
+            let message = format!("Tier ({}) {} ({}) isn't a valid number", tier, field, value);
+            event!(Level::ERROR, "{}", message);
+            MediatorError::ConfigError("NA".into(), message)
+        }),
+        None => Ok(default),
+    }
+}
+
+/// Splits a comma separated list, ignoring empty entries
+fn parse_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect()
+}
+
 /// Loads the secret data into the Config file.
 async fn load_secrets(
     secrets: &str,
@@ -624,3 +1245,202 @@ fn get_hostname(host_name: &str) -> Result<String, MediatorError> {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receipt_policy_parse() {
+        assert_eq!(
+            ReceiptPolicy::parse("none").unwrap(),
+            ReceiptPolicy::Disabled
+        );
+        assert_eq!(
+            ReceiptPolicy::parse("delivered").unwrap(),
+            ReceiptPolicy::Delivered
+        );
+        assert_eq!(ReceiptPolicy::parse("all").unwrap(), ReceiptPolicy::All);
+    }
+
+    #[test]
+    fn receipt_policy_rejects_unknown_values() {
+        for policy in ["", "All", "read", "disabled"] {
+            assert!(
+                matches!(
+                    ReceiptPolicy::parse(policy),
+                    Err(MediatorError::ConfigError(..))
+                ),
+                "policy({})",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn tls_version_parse() {
+        assert_eq!(TlsVersion::parse("1.2").unwrap(), TlsVersion::Tls12);
+        assert_eq!(TlsVersion::parse("1.3").unwrap(), TlsVersion::Tls13);
+    }
+
+    #[test]
+    fn tls_version_rejects_unknown_values() {
+        for version in ["", "1.1", "1.4", "tls1.3", "13"] {
+            assert!(
+                matches!(
+                    TlsVersion::parse(version),
+                    Err(MediatorError::ConfigError(..))
+                ),
+                "version({})",
+                version
+            );
+        }
+    }
+
+    fn default_tier() -> MailboxTier {
+        MailboxTier {
+            name: DEFAULT_TIER.into(),
+            max_queued_messages: 100,
+            max_queued_bytes: 0,
+            message_expiry_minutes: 10080,
+            max_message_size: 1048576,
+            message_rate_limit: 0,
+        }
+    }
+
+    fn tier_config(name: &str) -> TierConfig {
+        TierConfig {
+            name: name.into(),
+            max_queued_messages: None,
+            max_queued_bytes: None,
+            message_expiry_minutes: None,
+            max_message_size: None,
+            message_rate_limit: None,
+        }
+    }
+
+    #[test]
+    fn tier_parse() {
+        let tiers = Tiers::new(default_tier());
+        let tier = parse_tier(
+            &tiers,
+            TierConfig {
+                max_queued_messages: Some("10000".into()),
+                max_queued_bytes: Some("1073741824".into()),
+                message_expiry_minutes: Some("43200".into()),
+                max_message_size: Some("0".into()),
+                message_rate_limit: Some("600".into()),
+                ..tier_config("premium")
+            },
+        )
+        .unwrap();
+
+        assert_eq!(tier.name, "premium");
+        assert_eq!(tier.max_queued_messages, 10000);
+        assert_eq!(tier.max_queued_bytes, 1073741824);
+        assert_eq!(tier.message_expiry_minutes, 43200);
+        assert_eq!(tier.max_message_size, 0);
+        assert_eq!(tier.message_rate_limit, 600);
+    }
+
+    #[test]
+    fn tier_inherits_default_limits() {
+        let tiers = Tiers::new(default_tier());
+        let tier = parse_tier(
+            &tiers,
+            TierConfig {
+                message_rate_limit: Some("60".into()),
+                ..tier_config("limited")
+            },
+        )
+        .unwrap();
+
+        assert_eq!(tier.name, "limited");
+        assert_eq!(tier.message_rate_limit, 60);
+        assert_eq!(tier.max_queued_messages, 100);
+        assert_eq!(tier.max_queued_bytes, 0);
+        assert_eq!(tier.message_expiry_minutes, 10080);
+        assert_eq!(tier.max_message_size, 1048576);
+    }
+
+    #[test]
+    fn tier_rejects_invalid_config() {
+        let mut tiers = Tiers::new(default_tier());
+        let premium = parse_tier(&tiers, tier_config("premium")).unwrap();
+        tiers.tiers.insert(premium.name.clone(), premium);
+
+        for name in ["", DEFAULT_TIER, "premium"] {
+            assert!(
+                matches!(
+                    parse_tier(&tiers, tier_config(name)),
+                    Err(MediatorError::ConfigError(..))
+                ),
+                "name({})",
+                name
+            );
+        }
+
+        for value in ["", "-1", "ten", "1.5"] {
+            assert!(matches!(
+                parse_tier(
+                    &tiers,
+                    TierConfig {
+                        max_queued_messages: Some(value.into()),
+                        ..tier_config("basic")
+                    },
+                ),
+                Err(MediatorError::ConfigError(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn tier_lookup_falls_back_to_default() {
+        let mut tiers = Tiers::new(default_tier());
+        let premium = parse_tier(&tiers, tier_config("premium")).unwrap();
+        tiers.tiers.insert(premium.name.clone(), premium);
+
+        assert_eq!(tiers.get(Some("premium")).name, "premium");
+        assert_eq!(tiers.get(Some("removed")).name, DEFAULT_TIER);
+        assert_eq!(tiers.get(None).name, DEFAULT_TIER);
+        assert!(tiers.contains(DEFAULT_TIER));
+        assert!(!tiers.contains("removed"));
+    }
+
+    #[test]
+    fn tier_message_size_fits_http_size_limit() {
+        let tier = default_tier();
+        assert!(check_tier_message_size(&tier, 1048576).is_ok());
+        assert!(matches!(
+            check_tier_message_size(&tier, 1048575),
+            Err(MediatorError::ConfigError(..))
+        ));
+        let unlimited = MailboxTier {
+            max_message_size: 0,
+            ..default_tier()
+        };
+        assert!(check_tier_message_size(&unlimited, 1024).is_ok());
+    }
+
+    #[test]
+    fn blob_chunk_size_must_be_an_s3_part_size() {
+        let http_size_limit = 16 * 1024 * 1024;
+        assert!(check_blob_chunk_size(5 * 1024 * 1024, http_size_limit).is_ok());
+        assert!(check_blob_chunk_size(16 * 1024 * 1024, http_size_limit).is_ok());
+        assert!(matches!(
+            check_blob_chunk_size(5 * 1024 * 1024 - 1, http_size_limit),
+            Err(MediatorError::ConfigError(..))
+        ));
+        assert!(check_blob_chunk_size(0, http_size_limit).is_err());
+    }
+
+    #[test]
+    fn blob_chunk_size_fits_http_size_limit() {
+        assert!(check_blob_chunk_size(10485760, 10485760).is_ok());
+        assert!(matches!(
+            check_blob_chunk_size(10485761, 10485760),
+            Err(MediatorError::ConfigError(..))
+        ));
+        assert!(check_blob_chunk_size(5 * 1024 * 1024, 4 * 1024 * 1024).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+//! Outbound requests to client supplied URLs (push endpoints)
+//!
+//! The mediator calls these URLs from inside its own network, so they must not reach internal
+//! services (SSRF). Hosts are checked against the configured allow/deny lists, and every address
+//! that a host resolves to must be public unless private addresses are allowed.
+//! The guard is also the DNS resolver of the HTTP client, so a host can't resolve to a public
+//! address when registered and to an internal address when called.
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use url::{Host, Url};
+
+/// Checks the URLs and addresses that the mediator may call
+/// - allow_http: plain http:// URLs are allowed
+/// - allow_private: internal addresses (loopback, private, link-local, ...) are allowed
+/// - allowed_hosts: if not empty, only these hosts (and their subdomains) are allowed
+/// - denied_hosts: these hosts (and their subdomains) are never allowed
+#[derive(Clone, Debug, Default)]
+pub struct EndpointGuard {
+    pub allow_http: bool,
+    pub allow_private: bool,
+    pub allowed_hosts: Vec<String>,
+    pub denied_hosts: Vec<String>,
+}
+
+impl EndpointGuard {
+    /// Checks the scheme and host of a URL, returns the reason if the URL isn't allowed
+    /// Hostnames are checked again when they are resolved
+    pub fn check_url(&self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|err| format!("invalid URL ({})", err))?;
+
+        match url.scheme() {
+            "https" => {}
+            "http" if self.allow_http => {}
+            _ => return Err("must be an https:// URL".into()),
+        }
+
+        match url.host() {
+            Some(Host::Domain(domain)) => self.check_host(domain),
+            Some(Host::Ipv4(ip)) => self.check_ip(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => self.check_ip(IpAddr::V6(ip)),
+            None => Err("URL has no host".into()),
+        }
+    }
+
+    fn check_host(&self, host: &str) -> Result<(), String> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let matches = |entry: &String| {
+            let entry = entry.trim_end_matches('.').to_ascii_lowercase();
+            host == entry || host.ends_with(&[".", &entry].concat())
+        };
+
+        if self.denied_hosts.iter().any(matches) {
+            return Err(format!("host ({}) is denied", host));
+        }
+        if !self.allowed_hosts.is_empty() && !self.allowed_hosts.iter().any(matches) {
+            return Err(format!("host ({}) isn't allowed", host));
+        }
+        Ok(())
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<(), String> {
+        self.check_host(&ip.to_string())?;
+        if self.allow_private || is_public(ip) {
+            Ok(())
+        } else {
+            Err(format!("address ({}) isn't a public address", ip))
+        }
+    }
+
+    /// Resolves a host, and checks every address it resolves to
+    pub async fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>, String> {
+        self.check_host(host)?;
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|err| format!("couldn't resolve host ({}): {}", host, err))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("host ({}) has no addresses", host));
+        }
+        for addr in &addrs {
+            self.check_ip(addr.ip())?;
+        }
+        Ok(addrs)
+    }
+}
+
+impl Resolve for EndpointGuard {
+    fn resolve(&self, name: Name) -> Resolving {
+        let guard = self.clone();
+        Box::pin(async move {
+            let addrs = EndpointGuard::resolve(&guard, name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Is this address reachable on the public internet?
+/// Loopback, private, shared (CGNAT), link-local, multicast, documentation and reserved ranges are not
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => _is_public_v4(ip),
+        IpAddr::V6(ip) => _is_public_v6(ip),
+    }
+}
+
+fn _is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (CGNAT) 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved 240.0.0.0/4
+        || a >= 240)
+}
+
+fn _is_public_v6(ip: Ipv6Addr) -> bool {
+    // IPv4 mapped (::ffff:a.b.c.d) and NAT64 (64:ff9b::a.b.c.d) addresses reach IPv4 hosts
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return _is_public_v4(v4);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let octets = ip.octets();
+        return _is_public_v4(Ipv4Addr::new(
+            octets[12], octets[13], octets[14], octets[15],
+        ));
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local fc00::/7
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local fe80::/10
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Site-local (deprecated) fec0::/10
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation 2001:db8::/32
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        // IPv4 compatible (deprecated) ::a.b.c.d
+        || segments[..6] == [0, 0, 0, 0, 0, 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> EndpointGuard {
+        EndpointGuard::default()
+    }
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn public_addresses_are_public() {
+        for ip in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "2606:4700:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn check_url_scheme() {
+        assert!(guard().check_url("https://push.example.com/notify").is_ok());
+        assert!(guard().check_url("http://push.example.com/notify").is_err());
+        assert!(guard().check_url("ftp://push.example.com").is_err());
+        assert!(guard().check_url("not a url").is_err());
+
+        let guard = EndpointGuard {
+            allow_http: true,
+            ..Default::default()
+        };
+        assert!(guard.check_url("http://push.example.com/notify").is_ok());
+    }
+
+    #[test]
+    fn check_url_rejects_internal_ip_literals() {
+        assert!(guard().check_url("https://127.0.0.1/").is_err());
+        assert!(guard().check_url("https://169.254.169.254/latest").is_err());
+        assert!(guard().check_url("https://[::1]:8443/").is_err());
+        assert!(guard().check_url("https://1.1.1.1/").is_ok());
+
+        let guard = EndpointGuard {
+            allow_private: true,
+            ..Default::default()
+        };
+        assert!(guard.check_url("https://127.0.0.1/").is_ok());
+    }
+
+    #[test]
+    fn check_url_host_lists() {
+        let guard = EndpointGuard {
+            allowed_hosts: vec!["example.com".into()],
+            denied_hosts: vec!["internal.example.com".into()],
+            ..Default::default()
+        };
+        assert!(guard.check_url("https://example.com/").is_ok());
+        assert!(guard.check_url("https://push.example.com/").is_ok());
+        assert!(guard.check_url("https://PUSH.Example.com./").is_ok());
+        assert!(guard
+            .check_url("https://api.internal.example.com/")
+            .is_err());
+        assert!(guard.check_url("https://notexample.com/").is_err());
+        assert!(guard.check_url("https://example.org/").is_err());
+    }
+}
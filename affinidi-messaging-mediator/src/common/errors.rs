@@ -58,6 +58,12 @@ pub enum MediatorError {
     SessionError(SessId, String),
     #[error("Anonymous message error: {1}")]
     AnonymousMessageError(SessId, String),
+    #[error("Message replay detected: {1}")]
+    MessageReplay(SessId, String),
+    #[error("Message recall too late: {1}")]
+    MessageRecallTooLate(SessId, String),
+    #[error("Not found: {1}")]
+    NotFound(SessId, String),
 }
 
 impl IntoResponse for AppError {
@@ -239,6 +245,39 @@ impl IntoResponse for AppError {
                 event!(Level::WARN, "{}", response.to_string());
                 response
             }
+            MediatorError::MessageReplay(session_id, message) => {
+                let response = ErrorResponse {
+                    httpCode: StatusCode::CONFLICT.as_u16(),
+                    sessionId: session_id.to_string(),
+                    errorCode: 17,
+                    errorCodeStr: "MessageReplay".to_string(),
+                    message,
+                };
+                event!(Level::WARN, "{}", response.to_string());
+                response
+            }
+            MediatorError::MessageRecallTooLate(session_id, message) => {
+                let response = ErrorResponse {
+                    httpCode: StatusCode::CONFLICT.as_u16(),
+                    sessionId: session_id.to_string(),
+                    errorCode: 18,
+                    errorCodeStr: "MessageRecallTooLate".to_string(),
+                    message,
+                };
+                event!(Level::WARN, "{}", response.to_string());
+                response
+            }
+            MediatorError::NotFound(session_id, message) => {
+                let response = ErrorResponse {
+                    httpCode: StatusCode::NOT_FOUND.as_u16(),
+                    sessionId: session_id.to_string(),
+                    errorCode: 19,
+                    errorCodeStr: "NotFound".to_string(),
+                    message,
+                };
+                event!(Level::WARN, "{}", response.to_string());
+                response
+            }
         };
         (
             StatusCode::from_u16(response.httpCode).ok().unwrap(),
@@ -254,7 +293,8 @@ pub struct Session {
     pub authenticated: bool,            // Has this session been authenticated?
     pub challenge_sent: Option<String>, // Challenge sent to the client
     pub did: String,                    // DID of the client
-    pub did_hash: String,               // Sha256 hash of the DID
+    pub did_hash: String,               // Sha256 hash of the DID (within the tenant namespace)
+    pub tenant: String,                 // Namespace of the tenant the session authenticated with
 }
 
 #[derive(Serialize, Debug)]
@@ -1,4 +1,7 @@
-use super::errors::{ErrorResponse, Session};
+use super::{
+    errors::{ErrorResponse, Session},
+    tenant::RequestTenant,
+};
 use crate::{database::session::SessionClaims, SharedData};
 use axum::{
     async_trait,
@@ -14,7 +17,6 @@ use http::{request::Parts, StatusCode};
 use jsonwebtoken::{TokenData, Validation};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sha256::digest;
 use std::{
     fmt::{Debug, Display},
     net::SocketAddr,
@@ -42,6 +44,7 @@ pub enum AuthError {
     MissingCredentials,
     InvalidToken,
     ExpiredToken,
+    Blocked,
     InternalServerError(String),
 }
 
@@ -52,6 +55,7 @@ impl Display for AuthError {
             AuthError::MissingCredentials => write!(f, "Missing credentials"),
             AuthError::InvalidToken => write!(f, "Invalid token"),
             AuthError::ExpiredToken => write!(f, "Expired token"),
+            AuthError::Blocked => write!(f, "DID is blocked"),
             AuthError::InternalServerError(message) => {
                 write!(f, "Internal Server Error: {}", message)
             }
@@ -66,6 +70,7 @@ impl IntoResponse for AuthError {
             AuthError::MissingCredentials => StatusCode::BAD_REQUEST,
             AuthError::InvalidToken => StatusCode::BAD_REQUEST,
             AuthError::ExpiredToken => StatusCode::UNAUTHORIZED,
+            AuthError::Blocked => StatusCode::FORBIDDEN,
             AuthError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
         let body = Json(json!(ErrorResponse {
@@ -135,9 +140,35 @@ where
             return Err(AuthError::MissingCredentials);
         };
 
+        // Tokens are only valid for the tenant they were issued by
+        let RequestTenant(request_tenant) = parts
+            .extract_with_state::<RequestTenant, _>(_state)
+            .await
+            .unwrap_or_default();
+        let tenant = match state.config.find_tenant(&token_data.claims.tenant) {
+            Some(tenant) if tenant.namespace == request_tenant => tenant,
+            _ => {
+                warn!(
+                    "JWT tenant ({}) doesn't match the request tenant ({})",
+                    token_data.claims.tenant, request_tenant
+                );
+                return Err(AuthError::WrongCredentials);
+            }
+        };
+
         let session_id = token_data.claims.session_id.clone();
         let did = token_data.claims.sub.clone();
-        let did_hash = digest(&did);
+        let did_hash = tenant.did_hash(&did);
+
+        // Tokens issued before the DID was blocked are rejected as well
+        match state.database.did_blocked(&did_hash).await {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                warn!("{}: did_hash({}) is blocked", &session_id, &did_hash);
+                return Err(AuthError::Blocked);
+            }
+            Err(err) => return Err(AuthError::InternalServerError(err.to_string())),
+        }
 
         info!(
             "{}: Protected connection accepted from did_hash({})",
@@ -150,6 +181,7 @@ where
             challenge_sent: None,
             did,
             did_hash,
+            tenant: tenant.namespace.clone(),
         };
 
         Ok(session)
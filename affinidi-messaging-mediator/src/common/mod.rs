@@ -1,3 +1,9 @@
+pub mod blob_storage;
 pub mod config;
+pub mod endpoint_guard;
 pub mod errors;
 pub mod jwt_auth;
+pub mod secret_box;
+pub mod tenant;
+pub mod tier;
+pub mod tls;
@@ -0,0 +1,125 @@
+//! Encryption of secrets that the mediator stores in the database on behalf of clients
+//! (e.g. the Authorization header of a push endpoint)
+//!
+//! Values are sealed with AES-256-GCM, the key is derived from a mediator secret with HKDF-SHA256.
+//! Sealed values are base64 encoded `nonce || ciphertext`.
+use super::errors::MediatorError;
+use base64::prelude::*;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{fmt, sync::Arc};
+
+/// Seals and opens values with a key derived from a mediator secret
+#[derive(Clone)]
+pub struct SecretBox {
+    key: Arc<LessSafeKey>,
+}
+
+impl fmt::Debug for SecretBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBox")
+    }
+}
+
+impl SecretBox {
+    /// Derives the key from a mediator secret
+    /// - purpose: Separates the keys of different uses of the same secret
+    pub fn new(secret: &[u8], purpose: &str) -> Result<Self, MediatorError> {
+        let error = || {
+            MediatorError::InternalError(
+                "NA".into(),
+                format!("Couldn't derive the {} encryption key", purpose),
+            )
+        };
+
+        let prk =
+            hkdf::Salt::new(hkdf::HKDF_SHA256, b"affinidi-messaging-mediator").extract(secret);
+        let info = [purpose.as_bytes()];
+        let okm = prk.expand(&info, &AES_256_GCM).map_err(|_| error())?;
+        Ok(SecretBox {
+            key: Arc::new(LessSafeKey::new(UnboundKey::from(okm))),
+        })
+    }
+
+    /// Encrypts a value
+    pub fn seal(&self, value: &str) -> Result<String, MediatorError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| {
+            MediatorError::InternalError("NA".into(), "Couldn't create a nonce".into())
+        })?;
+
+        let mut sealed = value.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .map_err(|_| {
+                MediatorError::InternalError("NA".into(), "Couldn't encrypt value".into())
+            })?;
+
+        Ok(BASE64_URL_SAFE_NO_PAD.encode([nonce.as_slice(), &sealed].concat()))
+    }
+
+    /// Decrypts a value sealed by `seal()`
+    pub fn open(&self, sealed: &str) -> Result<String, MediatorError> {
+        let error = || {
+            MediatorError::InternalError(
+                "NA".into(),
+                "Couldn't decrypt value, the mediator secret may have changed".into(),
+            )
+        };
+
+        let mut sealed = BASE64_URL_SAFE_NO_PAD.decode(sealed).map_err(|_| error())?;
+        if sealed.len() < NONCE_LEN + aead::MAX_TAG_LEN {
+            return Err(error());
+        }
+        let mut ciphertext = sealed.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&sealed).map_err(|_| error())?;
+
+        let value = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+            .map_err(|_| error())?;
+        String::from_utf8(value.to_vec()).map_err(|_| error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecretBox;
+
+    #[test]
+    fn seal_and_open() {
+        let secret_box = SecretBox::new(b"secret", "test").unwrap();
+        let sealed = secret_box.seal("Bearer abc").unwrap();
+        assert!(!sealed.contains("abc"));
+        assert_eq!(secret_box.open(&sealed).unwrap(), "Bearer abc");
+        // Each seal uses a new nonce
+        assert_ne!(secret_box.seal("Bearer abc").unwrap(), sealed);
+    }
+
+    #[test]
+    fn open_fails_with_another_key() {
+        let sealed = SecretBox::new(b"secret", "test")
+            .unwrap()
+            .seal("Bearer abc")
+            .unwrap();
+        assert!(SecretBox::new(b"other", "test")
+            .unwrap()
+            .open(&sealed)
+            .is_err());
+        assert!(SecretBox::new(b"secret", "other")
+            .unwrap()
+            .open(&sealed)
+            .is_err());
+        assert!(SecretBox::new(b"secret", "test")
+            .unwrap()
+            .open("garbage")
+            .is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Tenants served by the mediator
+//!
+//! A single mediator instance can serve several mediator DIDs (tenants). The `mediator_did` is the default
+//! tenant, additional tenants are configured in `[[tenants]]` with their own DID, secrets and storage namespace.
+//!
+//! The tenant of a request is selected by the tenant `api_prefix` the request was routed through, then by the
+//! `Host` header (`:authority` on HTTP/2), otherwise the default tenant is used. Sessions are bound to the tenant they authenticated with.
+use super::config::Config;
+use crate::{database::keys, resolvers::affinidi_secrets::AffinidiSecrets, SharedData};
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+};
+use http::{header, request::Parts};
+use serde_json::Value;
+use std::{convert::Infallible, fmt};
+
+#[derive(Clone)]
+pub struct Tenant {
+    /// Storage namespace, empty for the default tenant
+    pub namespace: String,
+    pub did: String,
+    pub secrets: AffinidiSecrets,
+    pub api_prefix: Option<String>,
+    pub host: Option<String>,
+}
+
+impl Tenant {
+    /// Returns the hash of a DID within this tenant, records of a DID are keyed by this hash
+    pub fn did_hash(&self, did: &str) -> String {
+        keys::did_hash(&self.namespace, did)
+    }
+}
+
+impl Default for Tenant {
+    fn default() -> Self {
+        Tenant {
+            namespace: "".into(),
+            did: "".into(),
+            secrets: AffinidiSecrets::new(vec![]),
+            api_prefix: None,
+            host: None,
+        }
+    }
+}
+
+impl fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tenant")
+            .field("namespace", &self.namespace)
+            .field("did", &self.did)
+            .field(
+                "secrets",
+                &format!("({}) secrets loaded", self.secrets.len()),
+            )
+            .field("api_prefix", &self.api_prefix)
+            .field("host", &self.host)
+            .finish()
+    }
+}
+
+impl Config {
+    /// Returns the tenant of a namespace, None if the tenant isn't configured
+    pub fn find_tenant(&self, namespace: &str) -> Option<&Tenant> {
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.namespace == namespace)
+    }
+
+    /// Returns the tenant of a namespace
+    /// Sessions are only created for configured tenants, unknown namespaces fall back to the default tenant
+    pub fn tenant(&self, namespace: &str) -> &Tenant {
+        self.find_tenant(namespace).unwrap_or(&self.tenants[0])
+    }
+
+    /// Returns the tenant that serves the given host (port is ignored)
+    pub fn tenant_by_host(&self, host: &str) -> Option<&Tenant> {
+        let host = host.split(':').next().unwrap_or(host).to_ascii_lowercase();
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.host.as_deref() == Some(host.as_str()))
+    }
+
+    /// Returns the tenant that a DIDComm message is addressed to
+    /// Encrypted messages are matched on the recipient key IDs (`<did>#<key>`), other messages use the tenant
+    /// of the request (namespace). Returns None if the namespace isn't configured
+    pub fn message_tenant(&self, message: &str, namespace: &str) -> Option<&Tenant> {
+        let recipients = serde_json::from_str::<Value>(message)
+            .ok()
+            .and_then(|jwe| jwe.get("recipients").cloned());
+
+        if let Some(Value::Array(recipients)) = recipients {
+            for kid in recipients
+                .iter()
+                .filter_map(|recipient| recipient.pointer("/header/kid")?.as_str())
+            {
+                let did = kid.split('#').next().unwrap_or(kid);
+                if let Some(tenant) = self.tenants.iter().find(|tenant| tenant.did == did) {
+                    return Some(tenant);
+                }
+            }
+        }
+
+        self.find_tenant(namespace)
+    }
+}
+
+/// Namespace of the tenant that a request was made to
+/// Tenant routes (`api_prefix`) add this as a request extension, otherwise it is selected by the `Host` header
+/// (or the URI authority of HTTP/2 requests)
+#[derive(Clone, Debug, Default)]
+pub struct RequestTenant(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestTenant
+where
+    SharedData: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(tenant) = parts.extensions.get::<RequestTenant>() {
+            return Ok(tenant.clone());
+        }
+
+        let state = SharedData::from_ref(state);
+        let tenant = request_host(parts)
+            .and_then(|host| state.config.tenant_by_host(host))
+            .map(|tenant| tenant.namespace.clone())
+            .unwrap_or_default();
+
+        Ok(RequestTenant(tenant))
+    }
+}
+
+/// Host that a request was made to, from the `Host` header or the URI authority
+/// HTTP/2 requests carry the host in the `:authority` pseudo-header, which is the URI authority
+fn request_host(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| parts.uri.authority().map(|authority| authority.host()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::request_host;
+    use http::{header, Request};
+
+    #[test]
+    fn request_host_from_header_or_authority() {
+        let (parts, _) = Request::builder()
+            .uri("/atm/v1/inbound")
+            .header(header::HOST, "acme.example.com:7037")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(request_host(&parts), Some("acme.example.com:7037"));
+
+        let (parts, _) = Request::builder()
+            .uri("https://acme.example.com:7037/atm/v1/inbound")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(request_host(&parts), Some("acme.example.com"));
+
+        let (parts, _) = Request::builder()
+            .uri("/atm/v1/inbound")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(request_host(&parts), None);
+    }
+}
@@ -0,0 +1,37 @@
+//! Mailbox policies (tiers)
+//!
+//! Queue quotas, retention, message size and rate limits are grouped into named tiers (`[[tiers]]`).
+//! The global limits in `[database]` are the `default` tier, which applies to every DID without a tier.
+//! Admin DIDs assign a tier to a DID, which is stored in the `DID:<hash>` record of the DID.
+pub use affinidi_messaging_sdk::protocols::admin::MailboxTier;
+use std::collections::HashMap;
+
+/// Name of the tier that is built from the global limits
+pub const DEFAULT_TIER: &str = "default";
+
+#[derive(Clone, Debug, Default)]
+pub struct Tiers {
+    pub default: MailboxTier,
+    pub tiers: HashMap<String, MailboxTier>,
+}
+
+impl Tiers {
+    pub fn new(default: MailboxTier) -> Self {
+        Tiers {
+            default,
+            tiers: HashMap::new(),
+        }
+    }
+
+    /// Returns the tier with the given name
+    /// DIDs without a tier, or with a tier that is no longer configured, get the default tier
+    pub fn get(&self, name: Option<&str>) -> &MailboxTier {
+        name.and_then(|name| self.tiers.get(name))
+            .unwrap_or(&self.default)
+    }
+
+    /// Returns true if a tier with this name is configured (including the default tier)
+    pub fn contains(&self, name: &str) -> bool {
+        name == DEFAULT_TIER || self.tiers.contains_key(name)
+    }
+}
@@ -0,0 +1,112 @@
+//! TLS configuration of the mediator
+//!
+//! Builds the rustls server configuration from the `[security]` section:
+//! - Minimum protocol version and the enabled cipher suites
+//! - ALPN protocols (HTTP/2 and HTTP/1.1 by default)
+//! - Optional client certificate verification (mutual TLS) against a CA bundle
+use super::{
+    config::{Config, TlsVersion},
+    errors::MediatorError,
+};
+use rustls::{
+    crypto::aws_lc_rs,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use std::{fs::File, io::BufReader, sync::Arc};
+
+/// Builds the TLS configuration of the mediator
+/// Certificate, key and CA files are read from disk each time, so this is also used to reload them
+pub fn server_config(config: &Config) -> Result<ServerConfig, MediatorError> {
+    let mut provider = aws_lc_rs::default_provider();
+    if !config.tls_cipher_suites.is_empty() {
+        for suite in &config.tls_cipher_suites {
+            if !provider
+                .cipher_suites
+                .iter()
+                .any(|s| &format!("{:?}", s.suite()) == suite)
+            {
+                return Err(_error(format!("Unknown TLS cipher suite ({})", suite)));
+            }
+        }
+        provider.cipher_suites.retain(|s| {
+            config
+                .tls_cipher_suites
+                .contains(&format!("{:?}", s.suite()))
+        });
+    }
+    let provider = Arc::new(provider);
+
+    let versions: &[&rustls::SupportedProtocolVersion] = match config.tls_min_version {
+        TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(versions)
+        .map_err(|err| {
+            _error(format!(
+                "TLS cipher suites don't support the TLS versions: {}",
+                err
+            ))
+        })?;
+
+    let builder = if let Some(ca_file) = &config.tls_client_ca_file {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certificates(ca_file)? {
+            roots
+                .add(cert)
+                .map_err(|err| _error(format!("Invalid client CA ({}): {}", ca_file, err)))?;
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .map_err(|err| _error(format!("Couldn't create client verifier: {}", err)))?;
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let mut server_config = builder
+        .with_single_cert(
+            load_certificates(&config.ssl_certificate_file)?,
+            load_private_key(&config.ssl_key_file)?,
+        )
+        .map_err(|err| _error(format!("Invalid certificate/key: {}", err)))?;
+    server_config.alpn_protocols = config
+        .tls_alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+
+    Ok(server_config)
+}
+
+/// Loads all certificates from a PEM file
+fn load_certificates(file_name: &str) -> Result<Vec<CertificateDer<'static>>, MediatorError> {
+    let file = File::open(file_name)
+        .map_err(|err| _error(format!("Couldn't open ({}): {}", file_name, err)))?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| _error(format!("Couldn't parse ({}): {}", file_name, err)))?;
+    if certs.is_empty() {
+        return Err(_error(format!("No certificates found in ({})", file_name)));
+    }
+
+    Ok(certs)
+}
+
+/// Loads the first private key from a PEM file
+fn load_private_key(file_name: &str) -> Result<PrivateKeyDer<'static>, MediatorError> {
+    let file = File::open(file_name)
+        .map_err(|err| _error(format!("Couldn't open ({}): {}", file_name, err)))?;
+
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| _error(format!("Couldn't parse ({}): {}", file_name, err)))?
+        .ok_or_else(|| _error(format!("No private key found in ({})", file_name)))
+}
+
+fn _error(message: String) -> MediatorError {
+    MediatorError::ConfigError("NA".into(), message)
+}
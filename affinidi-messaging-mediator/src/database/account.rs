@@ -0,0 +1,441 @@
+use super::{keys, DatabaseHandler};
+use crate::common::errors::MediatorError;
+use affinidi_messaging_sdk::messages::AccountDeletionReport;
+use redis::{from_redis_value, Value};
+use tracing::{debug, event, info, span, warn, Instrument, Level};
+
+/// Number of queued messages deleted per `delete_account_messages` call
+const DELETE_ACCOUNT_BATCH: usize = 100;
+
+/// Number of times the remaining records are deleted when messages arrive while the account is deleted
+/// The deletion can be run again to resume once the attempts are used up
+const DELETE_ACCOUNT_ATTEMPTS: usize = 5;
+
+impl DatabaseHandler {
+    /// Deletes everything the mediator holds for a DID
+    /// - session_id: authentication session ID
+    /// - did: DID that is deleted (only used for the report)
+    /// - did_hash: DID hash of the DID that is deleted
+    ///
+    /// Queued messages are deleted in batches by `delete_account_messages`, then the remaining records in
+    /// the slot of the DID (queues, counters, tier, push, forward consent) are removed by `delete_account`.
+    /// The account registry entry, sessions, streaming registrations, blobs, hosted invitations and the
+    /// sender records of deleted messages are removed alongside.
+    ///
+    /// Each batch is atomic and keeps the counters and deduplication records of the mailbox consistent,
+    /// so a deletion that stops part way leaves a working account, and running it again resumes it.
+    /// Sessions are removed last, so the DID can resume the deletion with the same token.
+    pub async fn delete_account(
+        &self,
+        session_id: &str,
+        did: &str,
+        did_hash: &str,
+    ) -> Result<AccountDeletionReport, MediatorError> {
+        let _span = span!(Level::DEBUG, "delete_account", did_hash = did_hash);
+        async move {
+            let mut report = AccountDeletionReport {
+                did: did.into(),
+                ..Default::default()
+            };
+
+            let mut attempt = 1;
+            let records = loop {
+                while let Some(batch) = self
+                    ._queued_messages(session_id, did_hash, DELETE_ACCOUNT_BATCH)
+                    .await?
+                {
+                    if batch.is_empty() {
+                        continue;
+                    }
+                    let (messages, bytes) = self
+                        ._delete_account_messages(session_id, did_hash, &batch)
+                        .await?;
+                    report.messages += messages;
+                    report.bytes += bytes;
+                }
+
+                match self._delete_account(session_id, did_hash).await? {
+                    Some(records) => break records,
+                    None if attempt < DELETE_ACCOUNT_ATTEMPTS => {
+                        debug!("attempt({}): messages arrived during the deletion", attempt);
+                        attempt += 1;
+                    }
+                    None => {
+                        return Err(MediatorError::DatabaseError(
+                            session_id.into(),
+                            "Messages kept arriving while the account was deleted, the deletion can be run again to resume it".into(),
+                        ))
+                    }
+                }
+            };
+
+            let Some((sent, push, acl, sessions)) = _account_records(&records) else {
+                return Err(MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Unexpected response from delete_account: {:?}", records),
+                ));
+            };
+            report.sent = sent;
+            report.push_registrations = push;
+            report.forward_acl = acl;
+
+            self._delete_registration(session_id, did_hash).await?;
+            report.sessions = self._delete_sessions(session_id, &sessions).await?;
+            report.streaming_connections = self.streaming_remove_did(did_hash).await?;
+            report.blobs = self.blob_expire_owner(session_id, did_hash).await?;
+            report.oob_invitations = self.oob_invite_delete_all(session_id, did_hash).await?;
+
+            info!(
+                "account deleted: messages({}) bytes({}) sent({}) sessions({}) streaming({}) push({}) forward_acl({}) blobs({}) oob_invitations({})",
+                report.messages,
+                report.bytes,
+                report.sent,
+                report.sessions,
+                report.streaming_connections,
+                report.push_registrations,
+                report.forward_acl,
+                report.blobs,
+                report.oob_invitations
+            );
+            Ok(report)
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Reads the next batch of queued messages of the DID, entries without a message are removed
+    /// Returns (stream_id, msg_id) of each message, None if the inbox is empty
+    async fn _queued_messages(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        count: usize,
+    ) -> Result<Option<Vec<(String, String)>>, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let entries: Vec<(String, Vec<(String, String)>)> = deadpool_redis::redis::cmd("XRANGE")
+            .arg(keys::receive_queue_key(did_hash))
+            .arg("-")
+            .arg("+")
+            .arg("COUNT")
+            .arg(count)
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't read inbox of did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't read inbox: {}", err),
+                )
+            })?;
+
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        let (batch, invalid) = _queued(entries);
+        if !invalid.is_empty() {
+            warn!(
+                "Removing ({}) inbox entries without a msg_id of did_hash({})",
+                invalid.len(),
+                did_hash
+            );
+            deadpool_redis::redis::cmd("XDEL")
+                .arg(keys::receive_queue_key(did_hash))
+                .arg(&invalid)
+                .query_async::<()>(&mut conn)
+                .await
+                .map_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        "Couldn't remove inbox entries of did_hash({}): {}",
+                        did_hash,
+                        err
+                    );
+                    MediatorError::DatabaseError(
+                        session_id.into(),
+                        format!("Couldn't remove inbox entries: {}", err),
+                    )
+                })?;
+        }
+
+        Ok(Some(batch))
+    }
+
+    /// Deletes a batch of queued messages, and the sender records and blob references of each message
+    /// Returns (messages, bytes) that were deleted
+    async fn _delete_account_messages(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        batch: &[(String, String)],
+    ) -> Result<(usize, u64), MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let mut cmd = deadpool_redis::redis::cmd("FCALL");
+        cmd.arg("delete_account_messages")
+            .arg(5 + batch.len() * 2)
+            .arg(keys::receive_queue_key(did_hash))
+            .arg(keys::did_key(did_hash))
+            .arg(keys::global_key(did_hash))
+            .arg(keys::content_index_key(did_hash))
+            .arg(keys::message_expiry_key(did_hash));
+        for (_, msg_id) in batch {
+            cmd.arg(keys::message_key(did_hash, msg_id))
+                .arg(keys::message_meta_key(did_hash, msg_id));
+        }
+        cmd.arg(did_hash);
+        for (stream_id, msg_id) in batch {
+            cmd.arg(stream_id).arg(msg_id);
+        }
+
+        let (messages, bytes, senders): (usize, u64, Vec<String>) =
+            cmd.query_async(&mut conn).await.map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't delete messages of did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't delete messages: {}", err),
+                )
+            })?;
+
+        // senders = [msg_id, from_did_hash, send_id, bytes, ...]
+        for sender in senders.chunks(4) {
+            if let [msg_id, from_hash, send_id, bytes] = sender {
+                if let Err(err) = self
+                    .delete_message_sender(
+                        session_id,
+                        from_hash,
+                        msg_id,
+                        send_id,
+                        bytes.parse().unwrap_or(0),
+                        None,
+                    )
+                    .await
+                {
+                    warn!(
+                        "Couldn't delete sender records of msg_id({}): {}",
+                        msg_id, err
+                    );
+                }
+            }
+        }
+
+        // Blobs that are no longer referenced by any message can be removed
+        for (_, msg_id) in batch {
+            if let Err(err) = self.blob_release_message(msg_id).await {
+                warn!("Couldn't release blobs of msg_id({}): {}", msg_id, err);
+            }
+        }
+
+        Ok((messages, bytes))
+    }
+
+    /// Runs `delete_account` once the queued messages have been deleted
+    /// Returns the response of `delete_account`, None if messages arrived since the last batch
+    async fn _delete_account(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+    ) -> Result<Option<Vec<Value>>, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let response = deadpool_redis::redis::cmd("FCALL")
+            .arg("delete_account")
+            .arg(11)
+            .arg(keys::receive_queue_key(did_hash))
+            .arg(keys::send_queue_key(did_hash))
+            .arg(keys::send_index_key(did_hash))
+            .arg(keys::content_index_key(did_hash))
+            .arg(keys::send_delivered_key(did_hash))
+            .arg(keys::did_key(did_hash))
+            .arg(keys::mailbox_rate_key(did_hash))
+            .arg(keys::push_key(did_hash))
+            .arg(keys::push_rate_key(did_hash))
+            .arg(keys::forward_acl_key(did_hash))
+            .arg(keys::did_sessions_key(did_hash))
+            .arg(did_hash)
+            .query_async(&mut conn)
+            .await;
+
+        match response {
+            Ok(response) => Ok(Some(response)),
+            Err(err) if err.to_string().contains("MAILBOX_NOT_EMPTY") => Ok(None),
+            Err(err) => {
+                event!(
+                    Level::ERROR,
+                    "Couldn't delete account of did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                Err(MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't delete account: {}", err),
+                ))
+            }
+        }
+    }
+
+    /// Removes the DID from the account registry, it is registered again if the DID authenticates
+    async fn _delete_registration(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+    ) -> Result<(), MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("HDEL")
+            .arg(keys::accounts_key(did_hash))
+            .arg(did_hash)
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't remove did_hash({}) from the account registry: {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't remove account registration: {}", err),
+                )
+            })
+    }
+
+    /// Removes sessions, each session lives in its own slot
+    /// Returns the number of sessions that still existed
+    async fn _delete_sessions(
+        &self,
+        session_id: &str,
+        sessions: &[String],
+    ) -> Result<usize, MediatorError> {
+        if sessions.is_empty() {
+            return Ok(0);
+        }
+
+        let mut conn = self.get_async_connection().await?;
+        let cmds = sessions
+            .iter()
+            .map(|session| {
+                let mut cmd = deadpool_redis::redis::cmd("DEL");
+                cmd.arg(keys::session_key(session));
+                cmd
+            })
+            .collect();
+
+        let removed = self.query_batch(&mut conn, cmds).await.map_err(|err| {
+            event!(Level::ERROR, "Couldn't delete sessions: {}", err);
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!("Couldn't delete sessions: {}", err),
+            )
+        })?;
+
+        Ok(removed
+            .iter()
+            .map(|removed| from_redis_value::<usize>(removed).unwrap_or(0))
+            .sum())
+    }
+}
+
+/// Extracts (stream_id, msg_id) of each XRANGE entry of an inbox
+/// Returns the messages, and the stream IDs of entries without a MSG_ID
+fn _queued(entries: Vec<(String, Vec<(String, String)>)>) -> (Vec<(String, String)>, Vec<String>) {
+    let mut messages = Vec::new();
+    let mut invalid = Vec::new();
+    for (stream_id, fields) in entries {
+        match fields.into_iter().find(|(k, _)| k == "MSG_ID") {
+            Some((_, msg_id)) => messages.push((stream_id, msg_id)),
+            None => invalid.push(stream_id),
+        }
+    }
+    (messages, invalid)
+}
+
+/// Parses the response of `delete_account`
+/// response = [sent, push_registrations, forward_acl, {session_id, ...}]
+fn _account_records(response: &[Value]) -> Option<(usize, usize, bool, Vec<String>)> {
+    match response {
+        [sent, push, acl, sessions] => Some((
+            from_redis_value(sent).unwrap_or(0),
+            from_redis_value(push).unwrap_or(0),
+            from_redis_value::<i64>(acl).unwrap_or(0) == 1,
+            from_redis_value(sessions).unwrap_or_default(),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{_account_records, _queued};
+    use redis::Value;
+
+    #[test]
+    fn queued_messages_keep_the_stream_id() {
+        let entries = vec![
+            (
+                "1-0".to_string(),
+                vec![("MSG_ID".to_string(), "msg-1".to_string())],
+            ),
+            (
+                "2-0".to_string(),
+                vec![("BYTES".to_string(), "10".to_string())],
+            ),
+            (
+                "3-0".to_string(),
+                vec![
+                    ("BYTES".to_string(), "10".to_string()),
+                    ("MSG_ID".to_string(), "msg-3".to_string()),
+                ],
+            ),
+        ];
+        assert_eq!(
+            _queued(entries),
+            (
+                vec![
+                    ("1-0".to_string(), "msg-1".to_string()),
+                    ("3-0".to_string(), "msg-3".to_string())
+                ],
+                vec!["2-0".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn account_records_are_parsed() {
+        let response = vec![
+            Value::Int(3),
+            Value::Int(1),
+            Value::Int(1),
+            Value::Array(vec![Value::BulkString(b"session-1".to_vec())]),
+        ];
+        assert_eq!(
+            _account_records(&response),
+            Some((3, 1, true, vec!["session-1".to_string()]))
+        );
+
+        let response = vec![
+            Value::Int(0),
+            Value::Int(0),
+            Value::Int(0),
+            Value::Array(vec![]),
+        ];
+        assert_eq!(_account_records(&response), Some((0, 0, false, vec![])));
+    }
+
+    #[test]
+    fn unexpected_account_records_are_rejected() {
+        assert_eq!(_account_records(&[Value::Int(3), Value::Int(1)]), None);
+    }
+}
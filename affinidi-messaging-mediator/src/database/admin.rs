@@ -0,0 +1,382 @@
+use super::{keys, DatabaseHandler};
+use crate::common::errors::MediatorError;
+use affinidi_messaging_sdk::protocols::admin::{AdminAccount, AdminAccountList, AdminDidStats};
+use redis::Value;
+use std::time::SystemTime;
+use tracing::{debug, event, info, warn, Level};
+
+/// Number of inbox entries read per page when purging or expiring messages
+const PURGE_PAGE_SIZE: usize = 100;
+
+impl DatabaseHandler {
+    /// Returns the mailbox statistics of a DID
+    /// - did: DID the statistics are for (only used for the result)
+    /// - did_hash: DID hash of the DID within its tenant
+    pub async fn did_stats(
+        &self,
+        session_id: &str,
+        did: &str,
+        did_hash: &str,
+    ) -> Result<AdminDidStats, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        // Only the streaming connections are held in a different slot
+        let (counters, blocked): (Vec<Option<u64>>, Option<u64>) = deadpool_redis::redis::pipe()
+            .cmd("HMGET")
+            .arg(keys::did_key(did_hash))
+            .arg("RECEIVE_QUEUE_COUNT")
+            .arg("RECEIVE_QUEUE_BYTES")
+            .arg("SEND_QUEUE_COUNT")
+            .arg("SEND_QUEUE_BYTES")
+            .cmd("GET")
+            .arg(keys::blocked_key(did_hash))
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get stats for did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't get stats: {}", err),
+                )
+            })?;
+
+        let streaming_connections: usize = deadpool_redis::redis::cmd("HLEN")
+            .arg(keys::streaming_did_key(did_hash))
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get streaming connections for did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't get streaming connections: {}", err),
+                )
+            })?;
+
+        let counter = |i: usize| counters.get(i).copied().flatten().unwrap_or(0);
+        Ok(AdminDidStats {
+            did: did.into(),
+            did_hash: did_hash.into(),
+            receive_queue_count: counter(0),
+            receive_queue_bytes: counter(1),
+            send_queue_count: counter(2),
+            send_queue_bytes: counter(3),
+            tier: self.did_tier(session_id, did_hash).await?.name,
+            streaming_connections,
+            blocked,
+        })
+    }
+
+    /// Lists the DIDs of a tenant that have authenticated with the mediator
+    /// - namespace: namespace of the tenant, DIDs of other tenants are skipped
+    /// - cursor: cursor returned by the previous page (`<shard>:<scan cursor>`), None starts from the first shard
+    /// - limit: number of accounts to return, a page can be slightly larger as registries are scanned in batches
+    ///
+    /// Returns the accounts, and the cursor of the next page if there are more shards to scan
+    pub async fn list_accounts(
+        &self,
+        session_id: &str,
+        namespace: &str,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> Result<AdminAccountList, MediatorError> {
+        let (mut shard, mut scan_cursor) = match cursor {
+            Some(cursor) => cursor
+                .split_once(':')
+                .and_then(|(shard, scan_cursor)| {
+                    Some((shard.parse::<usize>().ok()?, scan_cursor.to_string()))
+                })
+                .ok_or_else(|| {
+                    MediatorError::RequestDataError(
+                        session_id.into(),
+                        format!("Account list cursor ({}) isn't valid", cursor),
+                    )
+                })?,
+            None => (0, "0".to_string()),
+        };
+
+        let mut conn = self.get_async_connection().await?;
+        let mut list = AdminAccountList::default();
+
+        while shard < keys::SHARD_BUCKETS && list.accounts.len() < limit as usize {
+            let (next, entries): (String, Vec<String>) = deadpool_redis::redis::cmd("HSCAN")
+                .arg(keys::accounts_shard_key(&format!("{:02x}", shard)))
+                .arg(&scan_cursor)
+                .arg("COUNT")
+                .arg(limit)
+                .query_async(&mut conn)
+                .await
+                .map_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        "Couldn't scan accounts of shard({}): {}",
+                        shard,
+                        err
+                    );
+                    MediatorError::DatabaseError(
+                        session_id.into(),
+                        format!("Couldn't list accounts: {}", err),
+                    )
+                })?;
+
+            // entries = [did_hash, did, ...]
+            for entry in entries.chunks(2) {
+                if let [did_hash, did] = entry {
+                    if keys::did_hash(namespace, did) == *did_hash {
+                        list.accounts.push(AdminAccount {
+                            did: did.to_owned(),
+                            did_hash: did_hash.to_owned(),
+                        });
+                    }
+                }
+            }
+
+            if next == "0" {
+                shard += 1;
+                scan_cursor = "0".into();
+            } else {
+                scan_cursor = next;
+            }
+        }
+
+        if shard < keys::SHARD_BUCKETS {
+            list.cursor = Some(format!("{}:{}", shard, scan_cursor));
+        }
+        Ok(list)
+    }
+
+    /// Returns the time a DID was blocked (seconds since epoch), None if the DID isn't blocked
+    pub async fn did_blocked(&self, did_hash: &str) -> Result<Option<u64>, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("GET")
+            .arg(keys::blocked_key(did_hash))
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't check if did_hash({}) is blocked: {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!("Couldn't check if DID is blocked: {}", err),
+                )
+            })
+    }
+
+    /// Blocks or unblocks a DID, a blocked DID can't authenticate and its tokens are rejected
+    /// Queued messages are kept, and other DIDs can still send messages to it
+    /// Returns true if the block status of the DID changed
+    pub async fn did_set_blocked(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        blocked: bool,
+    ) -> Result<bool, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let cmd = if blocked {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let mut cmd = deadpool_redis::redis::cmd("SET");
+            cmd.arg(keys::blocked_key(did_hash)).arg(now).arg("NX");
+            cmd
+        } else {
+            let mut cmd = deadpool_redis::redis::cmd("DEL");
+            cmd.arg(keys::blocked_key(did_hash));
+            cmd
+        };
+
+        let changed: Value = cmd.query_async(&mut conn).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't set blocked({}) for did_hash({}): {}",
+                blocked,
+                did_hash,
+                err
+            );
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!("Couldn't change block status: {}", err),
+            )
+        })?;
+
+        // SET NX replies OK (or nil if already blocked), DEL replies with the number of keys removed
+        let changed = match changed {
+            Value::Nil => false,
+            Value::Int(count) => count > 0,
+            _ => true,
+        };
+        info!(
+            "did_hash({}) blocked({}) changed({})",
+            did_hash, blocked, changed
+        );
+        Ok(changed)
+    }
+
+    /// Deletes all messages queued for a DID
+    /// Returns the number of messages deleted
+    pub async fn purge_messages(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+    ) -> Result<usize, MediatorError> {
+        self._delete_inbox(session_id, did_hash, None).await
+    }
+
+    /// Deletes the messages queued for a DID before the given time
+    /// - expired_before: time in milliseconds since epoch, messages queued earlier are deleted
+    ///
+    /// The time a message was queued is the TIMESTAMP of its metadata, imported messages keep their
+    /// original time while their stream IDs are assigned on import
+    ///
+    /// Returns the number of messages deleted
+    pub async fn expire_messages(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        expired_before: u64,
+    ) -> Result<usize, MediatorError> {
+        self._delete_inbox(session_id, did_hash, Some(expired_before))
+            .await
+    }
+
+    /// Deletes the inbox messages of a DID queued before `expired_before` (all messages if None)
+    /// Each message is removed with `expire_message`, which keeps counters and sender records consistent
+    /// without recording the message as delivered to its sender
+    async fn _delete_inbox(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        expired_before: Option<u64>,
+    ) -> Result<usize, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+        let mut start = "-".to_string();
+        let mut deleted = 0;
+
+        loop {
+            let items: Vec<(String, Vec<(String, String)>)> = deadpool_redis::redis::cmd("XRANGE")
+                .arg(keys::receive_queue_key(did_hash))
+                .arg(&start)
+                .arg("+")
+                .arg("COUNT")
+                .arg(PURGE_PAGE_SIZE)
+                .query_async(&mut conn)
+                .await
+                .map_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        "Couldn't read inbox of did_hash({}): {}",
+                        did_hash,
+                        err
+                    );
+                    MediatorError::DatabaseError(
+                        session_id.into(),
+                        format!("Couldn't read inbox: {}", err),
+                    )
+                })?;
+
+            let exhausted = items.len() < PURGE_PAGE_SIZE;
+            if let Some((last_id, _)) = items.last() {
+                start = ["(", last_id].concat();
+            }
+
+            let msg_ids: Vec<(String, String)> = items
+                .into_iter()
+                .filter_map(|(stream_id, fields)| {
+                    fields
+                        .into_iter()
+                        .find(|(k, _)| k == "MSG_ID")
+                        .map(|(_, msg_id)| (stream_id, msg_id))
+                })
+                .collect();
+
+            let timestamps: Vec<Option<u64>> = match expired_before {
+                Some(_) if !msg_ids.is_empty() => {
+                    let mut pipe = deadpool_redis::redis::pipe();
+                    for (_, msg_id) in &msg_ids {
+                        pipe.cmd("HGET")
+                            .arg(keys::message_meta_key(did_hash, msg_id))
+                            .arg("TIMESTAMP");
+                    }
+                    pipe.query_async(&mut conn).await.map_err(|err| {
+                        event!(
+                            Level::ERROR,
+                            "Couldn't read message timestamps of did_hash({}): {}",
+                            did_hash,
+                            err
+                        );
+                        MediatorError::DatabaseError(
+                            session_id.into(),
+                            format!("Couldn't read message timestamps: {}", err),
+                        )
+                    })?
+                }
+                _ => vec![None; msg_ids.len()],
+            };
+
+            for ((stream_id, msg_id), timestamp) in msg_ids.into_iter().zip(timestamps) {
+                if let Some(expired_before) = expired_before {
+                    if !_queued_before(&stream_id, timestamp, expired_before) {
+                        continue;
+                    }
+                }
+                match self.expire_message(session_id, did_hash, &msg_id).await {
+                    Ok(_) => deleted += 1,
+                    Err(err) => warn!("Couldn't delete msg_id({}): {}", msg_id, err),
+                }
+            }
+
+            if exhausted {
+                debug!("did_hash({}) deleted ({}) messages", did_hash, deleted);
+                return Ok(deleted);
+            }
+        }
+    }
+}
+
+/// Was a message queued before `expired_before` (milliseconds since epoch)?
+/// Uses the TIMESTAMP of the message metadata, or the time of the stream ID if it is missing
+fn _queued_before(stream_id: &str, timestamp: Option<u64>, expired_before: u64) -> bool {
+    let queued = timestamp.or_else(|| {
+        stream_id
+            .split('-')
+            .next()
+            .and_then(|time| time.parse().ok())
+    });
+    matches!(queued, Some(queued) if queued < expired_before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::_queued_before;
+
+    #[test]
+    fn queued_before_uses_the_message_timestamp() {
+        // An imported message has a recent stream ID, but was queued long before
+        assert!(_queued_before("2000-0", Some(500), 1000));
+        assert!(!_queued_before("500-0", Some(2000), 1000));
+        assert!(!_queued_before("500-0", Some(1000), 1000));
+    }
+
+    #[test]
+    fn queued_before_falls_back_to_the_stream_id() {
+        assert!(_queued_before("500-0", None, 1000));
+        assert!(!_queued_before("2000-0", None, 1000));
+        assert!(!_queued_before("invalid", None, 1000));
+    }
+}
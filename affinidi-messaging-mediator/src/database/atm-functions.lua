@@ -1,20 +1,49 @@
 #!lua name=atm
 
+-- Key layout (see database/keys.rs)
+-- All keys are declared by the caller, and every key passed to a function must share the same hash tag
+-- so that the function can run on Redis Cluster. A message is stored in the recipient's slot, the sender
+-- records (SEND_Q, SEND_INDEX) live in the sender's slot. store_message writes both when it is given the
+-- sender keys (single node, or sender and recipient in the same slot), otherwise the sender records are
+-- written by store_message_sender first.
+
 -- store_message
--- keys = message_hash
+-- keys = [1] MSG:{tag}:<msg_id>
+--        [2] MSG:META:{tag}:<msg_id>
+--        [3] RECEIVE_Q:{tag}:<to_did_hash>
+--        [4] DID:{tag}:<to_did_hash>
+--        [5] GLOBAL:{tag}
+--        [6] MSG_EXPIRY:{tag}
+--        [7] CONTENT_INDEX:{tag}:<to_did_hash>
+--        [8] MAILBOX_RATE:{tag}:<to_did_hash>
+--        [9] SEND_Q:{tag}:<from_did_hash> (optional, sender records are written by this call)
+--        [10] DID:{tag}:<from_did_hash> (optional)
+--        [11] SEND_INDEX:{tag}:<from_did_hash> (optional)
 -- args = [1] message
 --        [2] message length in bytes
---        [3] to_did
---        [4] to_did_hash
---        [5] from_did
---        [6] from_did_hash
+--        [3] to_did_hash
+--        [4] msg_id (unique per recipient copy)
+--        [5] content_hash (sha256 of the message)
+--        [6] max queued messages of the recipient (0 = unlimited)
+--        [7] max queued bytes of the recipient (0 = unlimited)
+--        [8] max messages per minute to the recipient (0 = unlimited)
+--        [9] retention of the recipient tier (milliseconds), the message expires this long after it is queued
+--        [10] from_did, or ANONYMOUS
+--        [11] from_did_hash
+--        [12] send_id (stream ID of the senders SEND_Q record), or with the sender keys: to_did
+--        [13] max queued messages of the sender (0 = unlimited), only with the sender keys
+-- returns [msg_id, RECEIVE_Q stream ID], or a QUOTA_EXCEEDED error if the recipient (or sender) tier doesn't allow the message
+-- If the same content is already queued for the recipient, nothing is stored and [existing msg_id, ''] is returned
 local function store_message(keys, args)
+    -- Correct number of keys?
+    if #keys ~= 8 and #keys ~= 11 then
+        return redis.error_reply('store_message: requires eight keys, or eleven with the sender keys')
+    end
+
     -- Do we have the correct number of arguments?
-    -- from_did_hash can be optional!!!
-    if #args < 5 then
-        return redis.error_reply('store_message: not enough arguments')
-    elseif #args > 6 then
-        return redis.error_reply('store_message: too many arguments')
+    -- from_did_hash and send_id are optional!!!
+    if (#keys == 8 and #args ~= 10 and #args ~= 12) or (#keys == 11 and #args ~= 13) then
+        return redis.error_reply('store_message: wrong number of arguments')
     end
 
     -- set response type to Version 3
@@ -22,69 +51,164 @@ local function store_message(keys, args)
 
     -- Get current time on server
     local time = redis.call('TIME')
-    redis.log(redis.LOG_WARNING, 'TIME:   ' .. time[1] .. ':' .. time[2])
-    local time = string.format("%d%d", time[1], time[2] / 1000)
-    redis.log(redis.LOG_WARNING, 'TIME-2: ' .. time)
+    time = string.format("%d%03d", time[1], time[2] / 1000)
     local bytes = tonumber(args[2])
     if bytes == nil then
         return redis.error_reply('store_message: invalid bytes')
     end
 
+    -- Deduplicate on the content hash, re-sending the same message to a recipient is a no-op
+    local existing = redis.call('HGET', keys[7], args[5])
+    if existing then
+        return { existing, '' }
+    end
+
+    -- Check the quota of the sender tier
+    if #keys == 11 then
+        local max_sent = tonumber(args[13]) or 0
+        if max_sent > 0 and (tonumber(redis.call('HGET', keys[10], 'SEND_QUEUE_COUNT')) or 0) >= max_sent then
+            return redis.error_reply('QUOTA_EXCEEDED: sender queue is full (' .. max_sent .. ' messages)')
+        end
+    end
+
+    -- Check the quotas of the recipient tier
+    local max_count = tonumber(args[6]) or 0
+    local max_bytes = tonumber(args[7]) or 0
+    local rate_limit = tonumber(args[8]) or 0
+    local queue = redis.call('HMGET', keys[4], 'RECEIVE_QUEUE_COUNT', 'RECEIVE_QUEUE_BYTES')
+    if max_count > 0 and (tonumber(queue[1]) or 0) >= max_count then
+        return redis.error_reply('QUOTA_EXCEEDED: recipient queue is full (' .. max_count .. ' messages)')
+    end
+    if max_bytes > 0 and (tonumber(queue[2]) or 0) + bytes > max_bytes then
+        return redis.error_reply('QUOTA_EXCEEDED: recipient queue is full (' .. max_bytes .. ' bytes)')
+    end
+    -- Rejected messages don't count towards the rate limit, the counter is increased once the message is stored
+    if rate_limit > 0 and (tonumber(redis.call('GET', keys[8])) or 0) >= rate_limit then
+        return redis.error_reply('QUOTA_EXCEEDED: recipient rate limit (' .. rate_limit .. ' messages per minute)')
+    end
+
+    -- Update the sender records
+    local send_id = args[12]
+    if #keys == 11 then
+        redis.call('HINCRBY', keys[10], 'SEND_QUEUE_BYTES', bytes)
+        redis.call('HINCRBY', keys[10], 'SEND_QUEUE_COUNT', 1)
+        send_id = redis.call('XADD', keys[9], time .. '-*', 'MSG_ID', args[4], 'BYTES', bytes, 'TO', args[12])
+        redis.call('HSET', keys[11], args[4], args[3])
+    end
+
     -- Store message
-    redis.call('SET', 'MSG:' .. keys[1], args[1])
+    redis.call('SET', keys[1], args[1])
 
     -- Set Global Metrics
-    redis.call('HINCRBY', 'GLOBAL', 'RECEIVED_BYTES', bytes)
-    redis.call('HINCRBY', 'GLOBAL', 'RECEIVED_COUNT', 1)
+    redis.call('HINCRBY', keys[5], 'RECEIVED_BYTES', bytes)
+    redis.call('HINCRBY', keys[5], 'RECEIVED_COUNT', 1)
 
     -- Create Message Expiry Record
-    redis.call('RPUSH', 'MSG_EXPIRY', keys[1] .. ':' .. time)
+    redis.call('ZADD', keys[6], tonumber(time) + (tonumber(args[9]) or 0), args[3] .. ':' .. args[4])
 
     -- Update the receiver records
-    redis.call('HINCRBY', 'DID:' .. args[4], 'RECEIVE_QUEUE_BYTES', bytes)
-    redis.call('HINCRBY', 'DID:' .. args[4], 'RECEIVE_QUEUE_COUNT', 1)
-    -- If changing the fields in the future, update the fetch_messages function
-    local RQ = redis.call('XADD', 'RECEIVE_Q:' .. args[4], time .. '-*', 'MSG_ID', keys[1], 'BYTES', bytes, 'FROM',
+    redis.call('HINCRBY', keys[4], 'RECEIVE_QUEUE_BYTES', bytes)
+    redis.call('HINCRBY', keys[4], 'RECEIVE_QUEUE_COUNT', 1)
+    -- If changing the fields in the future, update fetch_messages() in fetch.rs
+    local RQ = redis.call('XADD', keys[3], time .. '-*', 'MSG_ID', args[4], 'BYTES', bytes, 'FROM', args[10])
+
+    -- Content hash is only used to deduplicate messages to the same recipient
+    redis.call('HSET', keys[7], args[5], args[4])
+
+    -- Update message MetaData
+    redis.call('HSET', keys[2], 'BYTES', bytes, 'TO', args[3], 'TIMESTAMP', time, 'RECEIVE_ID', RQ, 'CONTENT_HASH',
         args[5])
+    if #args >= 12 then
+        redis.call('HSET', keys[2], 'FROM', args[11], 'SEND_ID', send_id)
+    end
 
-    -- Update the sender records
-    local SQ = nil
-    if table.getn(args) == 6 then
-        -- Update the sender records
-        redis.call('HINCRBY', 'DID:' .. args[6], 'SEND_QUEUE_BYTES', bytes)
-        redis.call('HINCRBY', 'DID:' .. args[6], 'SEND_QUEUE_COUNT', 1)
-        SQ = redis.call('XADD', 'SEND_Q:' .. args[6], time .. '-*', 'MSG_ID', keys[1], 'BYTES', bytes, 'TO', args[3])
+    if rate_limit > 0 and redis.call('INCR', keys[8]) == 1 then
+        redis.call('EXPIRE', keys[8], 60)
     end
 
-    -- Update message MetaData
-    redis.call('HMSET', 'MSG:META:' .. keys[1], 'BYTES', bytes, 'TO', args[4], 'TIMESTAMP', time, 'RECEIVE_ID', RQ)
-    if SQ ~= nil then
-        redis.call('HMSET', 'MSG:META:' .. keys[1], 'FROM', args[6], 'SEND_ID', SQ)
+    return { args[4], RQ }
+end
+
+-- store_message_sender
+-- keys = [1] SEND_Q:{tag}:<from_did_hash>
+--        [2] DID:{tag}:<from_did_hash>
+--        [3] SEND_INDEX:{tag}:<from_did_hash>
+-- args = [1] msg_id
+--        [2] message length in bytes
+--        [3] to_did
+--        [4] to_did_hash
+--        [5] max queued messages of the sender (0 = unlimited)
+-- returns the SEND_Q stream ID, or a QUOTA_EXCEEDED error if the sender tier doesn't allow the message
+local function store_message_sender(keys, args)
+    -- Correct number of keys?
+    if #keys ~= 3 then
+        return redis.error_reply('store_message_sender: requires three keys')
     end
 
-    return redis.status_reply('OK')
+    -- Correct number of args?
+    if #args ~= 5 then
+        return redis.error_reply('store_message_sender: wrong number of arguments')
+    end
+
+    -- set response type to Version 3
+    redis.setresp(3)
+
+    -- Get current time on server
+    local time = redis.call('TIME')
+    time = string.format("%d%03d", time[1], time[2] / 1000)
+    local bytes = tonumber(args[2])
+    if bytes == nil then
+        return redis.error_reply('store_message_sender: invalid bytes')
+    end
+
+    -- Check the quota of the sender tier
+    local max_count = tonumber(args[5]) or 0
+    if max_count > 0 and (tonumber(redis.call('HGET', keys[2], 'SEND_QUEUE_COUNT')) or 0) >= max_count then
+        return redis.error_reply('QUOTA_EXCEEDED: sender queue is full (' .. max_count .. ' messages)')
+    end
+
+    -- Update the sender records
+    redis.call('HINCRBY', keys[2], 'SEND_QUEUE_BYTES', bytes)
+    redis.call('HINCRBY', keys[2], 'SEND_QUEUE_COUNT', 1)
+    local SQ = redis.call('XADD', keys[1], time .. '-*', 'MSG_ID', args[1], 'BYTES', bytes, 'TO', args[3])
+
+    -- Index so that the sender can find the recipient slot of the message
+    redis.call('HSET', keys[3], args[1], args[4])
+
+    return SQ
 end
 
 -- delete_message
--- keys = message_hash
--- args = [1] did_hash
+-- keys = [1] MSG:{tag}:<msg_id>
+--        [2] MSG:META:{tag}:<msg_id>
+--        [3] RECEIVE_Q:{tag}:<to_did_hash>
+--        [4] DID:{tag}:<to_did_hash>
+--        [5] GLOBAL:{tag}
+--        [6] CONTENT_INDEX:{tag}:<to_did_hash>
+--        [7] MSG_EXPIRY:{tag}
+-- args = [1] did_hash of the requestor
+--        [2] to_did_hash
+--        [3] msg_id
+--        [4] 'RECALL' (optional), the sender is recalling the message before it is fetched
+-- returns [from_did_hash, send_id, bytes] so that the caller can remove the sender records
+-- from_did_hash and send_id are empty strings if the message was anonymous
 local function delete_message(keys, args)
     -- Correct number of keys?
-    if #keys ~= 1 then
-        return redis.error_reply('delete_message: only accepts one key')
+    if #keys ~= 7 then
+        return redis.error_reply('delete_message: requires seven keys')
     end
 
     -- Correct number of args?
-    if #args ~= 1 then
-        return redis.error_reply('delete_message: Requires DID hash argument')
+    if #args ~= 3 and #args ~= 4 then
+        return redis.error_reply('delete_message: Requires DID hash, recipient DID hash and msg_id arguments')
     end
 
     -- set response type to Version 3
     redis.setresp(3)
 
     -- Retrieve message metadata
-    local meta = redis.call('HGETALL', 'MSG:META:' .. keys[1])
-    if meta.map == nil then
+    local meta = redis.call('HGETALL', keys[2])
+    if meta.map == nil or next(meta.map) == nil then
         return redis.error_reply('Couldn\'t retrieve metadata')
     end
 
@@ -93,6 +217,21 @@ local function delete_message(keys, args)
         return redis.error_reply('Requesting DID does not have ownership of this message')
     end
 
+    -- Check that the declared recipient keys belong to this message
+    if meta.map.TO ~= args[2] then
+        return redis.error_reply('Recipient DID hash does not match message metadata')
+    end
+
+    -- A recall only succeeds if the sender asks for it before the recipient has fetched the message
+    if args[4] == 'RECALL' then
+        if meta.map.FROM ~= args[1] then
+            return redis.error_reply('Only the sender can recall a message')
+        end
+        if meta.map.FETCHED ~= nil then
+            return redis.error_reply('RECALL_TOO_LATE: message was fetched by the recipient')
+        end
+    end
+
     local bytes = meta.map.BYTES
     if bytes == nil then
         redis.log(redis.LOG_WARNING, 'message (' .. keys[1] .. ') metadata did not contain BYTES field.')
@@ -100,149 +239,123 @@ local function delete_message(keys, args)
     end
 
     -- Delete message
-    redis.call('DEL', 'MSG:' .. keys[1])
+    redis.call('DEL', keys[1])
 
     -- Set Global Metrics
-    redis.call('HINCRBY', 'GLOBAL', 'DELETED_BYTES', bytes)
-    redis.call('HINCRBY', 'GLOBAL', 'DELETED_COUNT', 1)
+    redis.call('HINCRBY', keys[5], 'DELETED_BYTES', bytes)
+    redis.call('HINCRBY', keys[5], 'DELETED_COUNT', 1)
 
     -- Remove the receiver records
-    redis.call('HINCRBY', 'DID:' .. meta.map.TO, 'RECEIVE_QUEUE_BYTES', -bytes)
-    redis.call('HINCRBY', 'DID:' .. meta.map.TO, 'RECEIVE_QUEUE_COUNT', -1)
-    redis.call('XDEL', 'RECEIVE_Q:' .. meta.map.TO, meta.map.RECEIVE_ID)
+    redis.call('HINCRBY', keys[4], 'RECEIVE_QUEUE_BYTES', -bytes)
+    redis.call('HINCRBY', keys[4], 'RECEIVE_QUEUE_COUNT', -1)
+    redis.call('XDEL', keys[3], meta.map.RECEIVE_ID)
 
-    -- Remove the sender records
-    local SQ = nil
-    if meta.map.SEND_ID ~= nil then
-        -- Remove the sender records
-        redis.call('HINCRBY', 'DID:' .. meta.map.FROM, 'SEND_QUEUE_BYTES', -bytes)
-        redis.call('HINCRBY', 'DID:' .. meta.map.FROM, 'SEND_QUEUE_COUNT', -1)
-        SQ = redis.call('XDEL', 'SEND_Q:' .. meta.map.FROM, meta.map.SEND_ID)
+    -- Remove the deduplication record if it points to this copy of the message
+    if meta.map.CONTENT_HASH ~= nil and redis.call('HGET', keys[6], meta.map.CONTENT_HASH) == args[3] then
+        redis.call('HDEL', keys[6], meta.map.CONTENT_HASH)
     end
 
     -- Remove the message metadata
-    redis.call('DEL', 'MSG:META:' .. keys[1])
+    redis.call('DEL', keys[2])
+    redis.call('ZREM', keys[7], args[2] .. ':' .. args[3])
 
-    return redis.status_reply('OK')
+    return { meta.map.FROM or '', meta.map.SEND_ID or '', bytes }
 end
 
--- fetch_messages
--- keys = did_hash
--- args = [1] start_id
---        [2] limit
-local function fetch_messages(keys, args)
-    -- Do we have the correct number of arguments?
-    if #args ~= 2 then
-        return redis.error_reply('fetch_messages: wrong arguments')
+-- delete_message_sender
+-- keys = [1] SEND_Q:{tag}:<from_did_hash>
+--        [2] DID:{tag}:<from_did_hash>
+--        [3] SEND_INDEX:{tag}:<from_did_hash>
+--        [4] SEND_DELIVERED:{tag}:<from_did_hash> (optional, only if the message was delivered)
+-- args = [1] msg_id
+--        [2] send_id
+--        [3] message length in bytes
+--        [4] delivered time in milliseconds (optional, only if the message was delivered)
+--        [5] number of delivery records to keep (optional, only if the message was delivered)
+local function delete_message_sender(keys, args)
+    -- Correct number of keys?
+    if #keys ~= 3 and #keys ~= 4 then
+        return redis.error_reply('delete_message_sender: requires three or four keys')
+    end
+
+    -- Correct number of args?
+    if (#keys == 3 and #args ~= 3) or (#keys == 4 and #args ~= 5) then
+        return redis.error_reply('delete_message_sender: wrong number of arguments')
     end
 
     -- set response type to Version 3
     redis.setresp(3)
 
-    -- Prepend an exclusive start_id if it exists
-    local start_id = '-'
-    if args[1] ~= "-" then
-        start_id = '(' .. args[1]
-    end
-
-    -- Get list of messages from stream
-    local list = redis.call('XRANGE', 'RECEIVE_Q:' .. keys[1], start_id, '+', 'COUNT', args[2])
-
-    local fetched_messages = {}
-    -- unpack the XRANGE list
-    for x, element in ipairs(list) do
-        -- element[1] = stream_id
-        -- element[2] = array of Stream Fields
-        for i, sub_element in ipairs(element) do
-            if i == 1 then
-                -- This is the stream ID
-                fetched_messages[x] = { 'STREAM_ID', sub_element }
-            else
-                -- [1] = MSG_ID
-                -- [2] = message_id
-                -- [3] = BYTES
-                -- [4] = bytes
-                -- [5] = FROM
-                -- [6] = from_did
-                table.insert(fetched_messages[x], sub_element[1])
-                table.insert(fetched_messages[x], sub_element[2])
-                table.insert(fetched_messages[x], 'FROM_DID')
-                table.insert(fetched_messages[x], sub_element[6])
-
-                -- fetch the message
-                table.insert(fetched_messages[x], 'MSG')
-                local msg = redis.call('GET', 'MSG:' .. sub_element[2])
-                table.insert(fetched_messages[x], msg)
-
-                -- fetch the message metadata
-                local meta = redis.call('HGETALL', 'MSG:META:' .. sub_element[2])
-                for k, v in pairs(meta.map) do
-                    table.insert(fetched_messages[x], 'META_' .. k)
-                    table.insert(fetched_messages[x], v)
-                end
-            end
-        end
-    end -- end of XRANGE list
+    local bytes = tonumber(args[3])
+    if bytes == nil then
+        return redis.error_reply('delete_message_sender: invalid bytes')
+    end
 
-    return fetched_messages
-end
+    -- Only adjust the counters if the record still exists
+    if redis.call('XDEL', keys[1], args[2]) == 1 then
+        redis.call('HINCRBY', keys[2], 'SEND_QUEUE_BYTES', -bytes)
+        redis.call('HINCRBY', keys[2], 'SEND_QUEUE_COUNT', -1)
+    end
+    redis.call('HDEL', keys[3], args[1])
 
--- clean_start_streaming
--- keys = uuid
--- returns number of sessions cleaned up
-local function clean_start_streaming(keys, args)
-    -- Correct number of keys?
-    if #keys ~= 1 then
-        return redis.error_reply('clean_start_streaming: only accepts one key')
+    -- Keep a record of recent deliveries, so that a late recall can be told the message was delivered
+    -- Skipped if the sender records are gone (e.g. the sender account was deleted)
+    if #keys == 4 and redis.call('EXISTS', keys[2]) == 1 then
+        redis.call('ZADD', keys[4], args[4], args[1])
+        redis.call('ZREMRANGEBYRANK', keys[4], 0, -(tonumber(args[5]) + 1))
     end
 
+    return redis.status_reply('OK')
+end
+
+-- mark_fetched
+-- keys = [1..n] MSG:META:{tag}:<msg_id> of messages that have been fetched by the recipient
+-- args = [1..n] msg_id of each key
+-- returns [msg_id, receipt_request, ...] of messages marked as fetched for the first time that requested a receipt
+-- A fetched message can no longer be recalled by the sender
+local function mark_fetched(keys, args)
     -- Correct number of args?
-    if #args ~= 0 then
-        return redis.error_reply('clean_start_streaming: No arguments required')
+    if #keys ~= #args then
+        return redis.error_reply('mark_fetched: requires a msg_id for each key')
     end
 
     -- set response type to Version 3
     redis.setresp(3)
 
-    -- Prepend an exclusive start_id if it exists
-    local key = 'STREAMING_SESSIONS:' .. keys[1]
-
-    -- Clean up sessions
-    local counter = 0
-    while (true) do
-        local response = redis.call('SPOP', key, 1)
-
-        -- No more items in the set
-        if next(response.set) == nil then
-            break
-        end
-
-        local session = nil
-        for k, v in pairs(response.set) do
-            session = k
-            counter = counter + 1
+    -- Get current time on server
+    local time = redis.call('TIME')
+    time = string.format("%d%03d", time[1], time[2] / 1000)
+
+    local receipts = {}
+    for i, key in ipairs(keys) do
+        -- Don't recreate the metadata of a message that has since been deleted
+        if redis.call('EXISTS', key) == 1 and redis.call('HSETNX', key, 'FETCHED', time) == 1 then
+            local receipt = redis.call('HGET', key, 'RECEIPT')
+            if receipt then
+                table.insert(receipts, args[i])
+                table.insert(receipts, receipt)
+            end
         end
-
-
-        -- remove from global session list
-        redis.call('HDEL', 'GLOBAL_STREAMING', session)
     end
 
-    return counter
+    return receipts
 end
 
 -- get_status_reply
--- keys = did_hash that we are getting status for
+-- keys = [1] DID:{tag}:<did_hash>
+--        [2] RECEIVE_Q:{tag}:<did_hash>
+-- args = [1] did_hash that we are getting status for
 -- returns Message Pickup 3.0 Status details
+-- NOTE: live_delivery status is held in a different slot and is retrieved separately
 local function get_status_reply(keys, args)
     -- Correct number of keys?
-    if #keys ~= 1 then
-        return redis.error_reply('get_status_reply: only accepts one key (recipient_did_hash)')
+    if #keys ~= 2 then
+        return redis.error_reply('get_status_reply: requires two keys (DID, RECEIVE_Q)')
     end
 
     -- Correct number of args?
-    if #args ~= 0 then
-        return redis.error_reply('get_status_reply: No arguments required')
+    if #args ~= 1 then
+        return redis.error_reply('get_status_reply: requires one argument (recipient_did_hash)')
     end
 
     -- set response type to Version 3
@@ -250,35 +363,350 @@ local function get_status_reply(keys, args)
 
     local response = {}
     response.map = {}
-    response.map.recipient_did = keys[1]
+    response.map.recipient_did = args[1]
 
     -- Set the message count and total bytes
-    local r = redis.call('HMGET', 'DID:' .. keys[1], 'RECEIVE_QUEUE_COUNT', 'RECEIVE_QUEUE_BYTES')
+    local r = redis.call('HMGET', keys[1], 'RECEIVE_QUEUE_COUNT', 'RECEIVE_QUEUE_BYTES')
     response.map.message_count = tonumber(r[1])
     response.map.total_bytes = tonumber(r[2])
 
     -- Get the oldest and newest message information
-    local r = redis.pcall('XINFO', 'STREAM', 'RECEIVE_Q:' .. keys[1])
+    local r = redis.pcall('XINFO', 'STREAM', keys[2])
     if r['err'] == nil and r.map then
         response.map.oldest_received = r.map['first-entry'] and r.map['first-entry'][1] or 0
         response.map.newest_received = r.map['last-entry'] and r.map['last-entry'][1] or 0
         response.map.queue_count = r.map['length']
     end
 
-    -- Get live streaming status
-    local r = redis.call("HEXISTS", "GLOBAL_STREAMING", keys[1])
-    if r == 0 then
-        response.map.live_delivery = false
-    else
-        response.map.live_delivery = true
+    return response
+end
+
+-- import_message
+-- Queues a message from a mailbox archive, skips messages that are already queued for the recipient
+-- keys = [1] MSG:{tag}:<msg_id>
+--        [2] MSG:META:{tag}:<msg_id>
+--        [3] RECEIVE_Q:{tag}:<to_did_hash>
+--        [4] DID:{tag}:<to_did_hash>
+--        [5] GLOBAL:{tag}
+--        [6] MSG_EXPIRY:{tag}
+--        [7] CONTENT_INDEX:{tag}:<to_did_hash>
+-- args = [1] message
+--        [2] message length in bytes
+--        [3] to_did_hash
+--        [4] msg_id
+--        [5] content_hash (sha256 of the message)
+--        [6] timestamp of the original message (milliseconds)
+--        [7] from_did
+--        [8] max queued messages of the recipient (0 = unlimited)
+--        [9] max queued bytes of the recipient (0 = unlimited)
+--        [10] retention of the recipient tier (milliseconds), the message expires this long after the original timestamp
+-- returns 1 if the message was queued, 0 if it already existed
+local function import_message(keys, args)
+    -- Correct number of keys?
+    if #keys ~= 7 then
+        return redis.error_reply('import_message: requires seven keys')
     end
 
-    return response
+    -- Correct number of args?
+    if #args ~= 10 then
+        return redis.error_reply('import_message: wrong number of arguments')
+    end
+
+    -- set response type to Version 3
+    redis.setresp(3)
+
+    local bytes = tonumber(args[2])
+    if bytes == nil then
+        return redis.error_reply('import_message: invalid bytes')
+    end
+
+    -- Duplicate?
+    if redis.call('HEXISTS', keys[7], args[5]) == 1 or redis.call('EXISTS', keys[1]) == 1 then
+        return 0
+    end
+
+    -- Check the quotas of the recipient tier
+    local max_count = tonumber(args[8]) or 0
+    local max_bytes = tonumber(args[9]) or 0
+    local queue = redis.call('HMGET', keys[4], 'RECEIVE_QUEUE_COUNT', 'RECEIVE_QUEUE_BYTES')
+    if max_count > 0 and (tonumber(queue[1]) or 0) >= max_count then
+        return redis.error_reply('QUOTA_EXCEEDED: recipient queue is full (' .. max_count .. ' messages)')
+    end
+    if max_bytes > 0 and (tonumber(queue[2]) or 0) + bytes > max_bytes then
+        return redis.error_reply('QUOTA_EXCEEDED: recipient queue is full (' .. max_bytes .. ' bytes)')
+    end
+
+    -- Store message
+    redis.call('SET', keys[1], args[1])
+
+    -- Set Global Metrics
+    redis.call('HINCRBY', keys[5], 'RECEIVED_BYTES', bytes)
+    redis.call('HINCRBY', keys[5], 'RECEIVED_COUNT', 1)
+
+    -- Create Message Expiry Record
+    redis.call('ZADD', keys[6], (tonumber(args[6]) or 0) + (tonumber(args[10]) or 0), args[3] .. ':' .. args[4])
+
+    -- Update the receiver records, stream IDs must increase so the entry gets a new ID
+    redis.call('HINCRBY', keys[4], 'RECEIVE_QUEUE_BYTES', bytes)
+    redis.call('HINCRBY', keys[4], 'RECEIVE_QUEUE_COUNT', 1)
+    local RQ = redis.call('XADD', keys[3], '*', 'MSG_ID', args[4], 'BYTES', bytes, 'FROM', args[7])
+
+    redis.call('HSET', keys[7], args[5], args[4])
+
+    -- The original timestamp is kept, so retention is unchanged by the import
+    redis.call('HSET', keys[2], 'BYTES', bytes, 'TO', args[3], 'TIMESTAMP', args[6], 'RECEIVE_ID', RQ, 'CONTENT_HASH',
+        args[5])
+
+    return 1
+end
+
+-- import_message_sender
+-- Recreates an outbox record from a mailbox archive, skips records that already exist
+-- keys = [1] SEND_Q:{tag}:<from_did_hash>
+--        [2] DID:{tag}:<from_did_hash>
+--        [3] SEND_INDEX:{tag}:<from_did_hash>
+-- args = [1] msg_id
+--        [2] message length in bytes
+--        [3] to_did
+--        [4] to_did_hash
+-- returns 1 if the record was created, 0 if it already existed
+local function import_message_sender(keys, args)
+    -- Correct number of keys?
+    if #keys ~= 3 then
+        return redis.error_reply('import_message_sender: requires three keys')
+    end
+
+    -- Correct number of args?
+    if #args ~= 4 then
+        return redis.error_reply('import_message_sender: wrong number of arguments')
+    end
+
+    -- set response type to Version 3
+    redis.setresp(3)
+
+    local bytes = tonumber(args[2])
+    if bytes == nil then
+        return redis.error_reply('import_message_sender: invalid bytes')
+    end
+
+    -- Duplicate?
+    if redis.call('HEXISTS', keys[3], args[1]) == 1 then
+        return 0
+    end
+
+    redis.call('HINCRBY', keys[2], 'SEND_QUEUE_BYTES', bytes)
+    redis.call('HINCRBY', keys[2], 'SEND_QUEUE_COUNT', 1)
+    redis.call('XADD', keys[1], '*', 'MSG_ID', args[1], 'BYTES', bytes, 'TO', args[3])
+    redis.call('HSET', keys[3], args[1], args[4])
+
+    return 1
+end
+
+-- push_register
+-- Adds (or replaces) a push endpoint of a DID, unless the DID already has the maximum number of endpoints
+-- keys = [1] PUSH:{tag}:<did_hash>
+-- args = [1] registration id
+--        [2] registration (JSON)
+--        [3] maximum number of registrations
+-- returns 1, or a QUOTA_EXCEEDED error if the maximum is reached
+local function push_register(keys, args)
+    -- Correct number of keys?
+    if #keys ~= 1 then
+        return redis.error_reply('push_register: requires one key')
+    end
+
+    -- Correct number of args?
+    if #args ~= 3 then
+        return redis.error_reply('push_register: wrong number of arguments')
+    end
+
+    local max = tonumber(args[3])
+    if max == nil then
+        return redis.error_reply('push_register: invalid maximum number of registrations')
+    end
+
+    -- Replacing an existing registration doesn't count towards the maximum
+    if redis.call('HEXISTS', keys[1], args[1]) == 0 and redis.call('HLEN', keys[1]) >= max then
+        return redis.error_reply('QUOTA_EXCEEDED: maximum number of push endpoints (' .. max .. ') already registered')
+    end
+
+    redis.call('HSET', keys[1], args[1], args[2])
+
+    return 1
+end
+
+-- oob_register
+-- Adds a hosted invitation to the invitations of a DID, unless the DID already hosts the maximum number of invitations
+-- Expired invitations are removed from the index first
+-- keys = [1] OOB_INDEX:{tag}:<did_hash>
+-- args = [1] oobid
+--        [2] expiry in seconds
+--        [3] maximum number of invitations
+-- returns 1, or a QUOTA_EXCEEDED error if the maximum is reached
+local function oob_register(keys, args)
+    -- Correct number of keys?
+    if #keys ~= 1 then
+        return redis.error_reply('oob_register: requires one key')
+    end
+
+    -- Correct number of args?
+    if #args ~= 3 then
+        return redis.error_reply('oob_register: wrong number of arguments')
+    end
+
+    local expiry = tonumber(args[2])
+    local max = tonumber(args[3])
+    if expiry == nil or max == nil then
+        return redis.error_reply('oob_register: invalid expiry or maximum number of invitations')
+    end
+
+    local now = tonumber(redis.call('TIME')[1])
+    redis.call('ZREMRANGEBYSCORE', keys[1], '-inf', now)
+
+    if redis.call('ZCARD', keys[1]) >= max then
+        return redis.error_reply('QUOTA_EXCEEDED: maximum number of hosted invitations (' .. max .. ') reached')
+    end
+
+    local expires_at = now + expiry
+    redis.call('ZADD', keys[1], expires_at, args[1])
+    -- The index lives as long as its longest invitation
+    if redis.call('EXPIRETIME', keys[1]) < expires_at then
+        redis.call('EXPIREAT', keys[1], expires_at)
+    end
+
+    return 1
 end
 
+-- delete_account_messages
+-- Deletes a batch of the queued messages of a DID that is being deleted, the caller removes the
+-- sender records of the deleted messages (these live in other slots)
+-- Each message is removed the same way as delete_message does, so the mailbox stays consistent
+-- if the deletion stops between batches
+-- keys = [1] RECEIVE_Q:{tag}:<did_hash>
+--        [2] DID:{tag}:<did_hash>
+--        [3] GLOBAL:{tag}
+--        [4] CONTENT_INDEX:{tag}:<did_hash>
+--        [5] MSG_EXPIRY:{tag}
+--        [6..] MSG:{tag}:<msg_id>, MSG:META:{tag}:<msg_id> of each message in the batch
+-- args = [1] did_hash
+--        [2..] stream_id, msg_id of each MSG, MSG:META key pair
+-- returns [messages, bytes, {msg_id, from_did_hash, send_id, bytes, ...}]
+-- messages that were deleted in the meantime are skipped
+local function delete_account_messages(keys, args)
+    -- Correct number of keys?
+    if #keys < 5 or (#keys - 5) % 2 ~= 0 then
+        return redis.error_reply('delete_account_messages: requires five keys and a key pair per message')
+    end
+
+    -- Correct number of args?
+    if #args ~= 1 + (#keys - 5) then
+        return redis.error_reply('delete_account_messages: requires the did_hash and a stream_id, msg_id per message')
+    end
+
+    -- set response type to Version 3
+    redis.setresp(3)
+
+    local count = 0
+    local total_bytes = 0
+    local senders = {}
+    for i = 2, #args, 2 do
+        local msg_id = args[i + 1]
+        local msg_key = keys[4 + i]
+        local meta_key = keys[5 + i]
+        local meta = redis.call('HGETALL', meta_key)
+        if meta.map ~= nil and next(meta.map) ~= nil and meta.map.TO == args[1] then
+            local bytes = tonumber(meta.map.BYTES) or 0
+            -- The sender records of messages from other DIDs are removed by the caller
+            if meta.map.FROM ~= nil and meta.map.SEND_ID ~= nil and meta.map.FROM ~= args[1] then
+                table.insert(senders, msg_id)
+                table.insert(senders, meta.map.FROM)
+                table.insert(senders, meta.map.SEND_ID)
+                table.insert(senders, bytes)
+            end
+            redis.call('DEL', msg_key, meta_key)
+            redis.call('ZREM', keys[5], args[1] .. ':' .. msg_id)
+
+            -- Remove the receiver records
+            redis.call('HINCRBY', keys[2], 'RECEIVE_QUEUE_BYTES', -bytes)
+            redis.call('HINCRBY', keys[2], 'RECEIVE_QUEUE_COUNT', -1)
+
+            -- Remove the deduplication record if it points to this copy of the message
+            if meta.map.CONTENT_HASH ~= nil and redis.call('HGET', keys[4], meta.map.CONTENT_HASH) == msg_id then
+                redis.call('HDEL', keys[4], meta.map.CONTENT_HASH)
+            end
+            count = count + 1
+            total_bytes = total_bytes + bytes
+        end
+        redis.call('XDEL', keys[1], args[i])
+    end
+
+    -- Set Global Metrics
+    redis.call('HINCRBY', keys[3], 'DELETED_BYTES', total_bytes)
+    redis.call('HINCRBY', keys[3], 'DELETED_COUNT', count)
+
+    return { count, total_bytes, senders }
+end
+-- delete_account
+-- Deletes the remaining records held in the slot of a DID once its queued messages have been deleted
+-- by delete_account_messages, the caller removes sessions and streaming registrations
+-- keys = [1] RECEIVE_Q:{tag}:<did_hash>
+--        [2] SEND_Q:{tag}:<did_hash>
+--        [3] SEND_INDEX:{tag}:<did_hash>
+--        [4] CONTENT_INDEX:{tag}:<did_hash>
+--        [5] SEND_DELIVERED:{tag}:<did_hash>
+--        [6] DID:{tag}:<did_hash>
+--        [7] MAILBOX_RATE:{tag}:<did_hash>
+--        [8] PUSH:{tag}:<did_hash>
+--        [9] PUSH_RATE:{tag}:<did_hash>
+--        [10] FORWARD_ACL:{tag}:<did_hash>
+--        [11] SESSIONS:{tag}:<did_hash>
+-- args = [1] did_hash
+-- returns [sent, push_registrations, forward_acl, {session_id, ...}]
+-- returns a MAILBOX_NOT_EMPTY error if messages arrived since the last batch, the caller deletes them first
+local function delete_account(keys, args)
+    -- Correct number of keys?
+    if #keys ~= 11 then
+        return redis.error_reply('delete_account: requires eleven keys')
+    end
+
+    -- Correct number of args?
+    if #args ~= 1 then
+        return redis.error_reply('delete_account: requires the did_hash')
+    end
+
+    -- set response type to Version 3
+    redis.setresp(3)
+
+    local queued = redis.call('XLEN', keys[1])
+    if queued > 0 then
+        return redis.error_reply('MAILBOX_NOT_EMPTY: queue has ' .. queued .. ' messages')
+    end
+
+    local sent = redis.call('XLEN', keys[2])
+    local push = redis.call('HLEN', keys[8])
+    local acl = redis.call('EXISTS', keys[10])
+    local sessions = {}
+    local members = redis.call('SMEMBERS', keys[11])
+    for session_id, _ in pairs(members.set) do
+        table.insert(sessions, session_id)
+    end
+
+    -- Remove the records of the DID
+    redis.call('DEL', keys[1], keys[2], keys[3], keys[4], keys[5], keys[6], keys[7], keys[8], keys[9], keys[10],
+        keys[11])
+
+    return { sent, push, acl, sessions }
+end
 
 redis.register_function('store_message', store_message)
+redis.register_function('store_message_sender', store_message_sender)
 redis.register_function('delete_message', delete_message)
-redis.register_function('fetch_messages', fetch_messages)
-redis.register_function('clean_start_streaming', clean_start_streaming)
+redis.register_function('delete_message_sender', delete_message_sender)
+redis.register_function('mark_fetched', mark_fetched)
 redis.register_function('get_status_reply', get_status_reply)
+redis.register_function('import_message', import_message)
+redis.register_function('import_message_sender', import_message_sender)
+redis.register_function('delete_account_messages', delete_account_messages)
+redis.register_function('delete_account', delete_account)
+redis.register_function('push_register', push_register)
+redis.register_function('oob_register', oob_register)
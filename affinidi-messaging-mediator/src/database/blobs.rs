@@ -0,0 +1,582 @@
+use super::{keys, DatabaseHandler};
+use crate::common::errors::MediatorError;
+use affinidi_messaging_sdk::messages::BlobStatus;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{debug, event, warn, Level};
+
+/// Out-of-band blob record (stored in `BLOB:{xx}:<blob_id>`)
+/// - owner: DID hash of the uploader
+/// - upload_id: Multipart upload ID of the storage backend (S3), empty for local disk
+/// - received: Chunks that have been received (index -> ETag)
+/// - expires_at: When the blob is removed (seconds since epoch)
+#[derive(Debug, Default, Clone)]
+pub struct BlobRecord {
+    pub blob_id: String,
+    pub owner: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub upload_id: String,
+    pub complete: bool,
+    pub expires_at: u64,
+    pub received: BTreeMap<u64, String>,
+}
+
+impl BlobRecord {
+    /// Number of chunks the blob is uploaded in
+    pub fn chunks(&self) -> u64 {
+        self.size.div_ceil(self.chunk_size)
+    }
+
+    /// Expected size of a chunk, the last chunk holds the remainder
+    pub fn chunk_len(&self, index: u64) -> u64 {
+        if index + 1 == self.chunks() {
+            self.size - index * self.chunk_size
+        } else {
+            self.chunk_size
+        }
+    }
+
+    pub fn status(&self) -> BlobStatus {
+        BlobStatus {
+            blob_id: self.blob_id.clone(),
+            size: self.size,
+            chunk_size: self.chunk_size,
+            chunks: self.chunks(),
+            received: self.received.keys().copied().collect(),
+            complete: self.complete,
+            expires_at: self.expires_at,
+        }
+    }
+
+    fn from_hash(blob_id: &str, hash: HashMap<String, String>) -> Self {
+        let mut record = BlobRecord {
+            blob_id: blob_id.into(),
+            ..Default::default()
+        };
+        for (field, value) in hash {
+            match field.as_str() {
+                "owner" => record.owner = value,
+                "size" => record.size = value.parse().unwrap_or(0),
+                "chunk_size" => record.chunk_size = value.parse().unwrap_or(1),
+                "upload_id" => record.upload_id = value,
+                "complete" => record.complete = value == "true",
+                "expires_at" => record.expires_at = value.parse().unwrap_or(0),
+                _ => {
+                    if let Some(index) = field.strip_prefix("chunk:") {
+                        if let Ok(index) = index.parse() {
+                            record.received.insert(index, value);
+                        }
+                    }
+                }
+            }
+        }
+        record
+    }
+}
+
+impl DatabaseHandler {
+    /// Creates the record of a new blob upload
+    /// - access: DID hashes (besides the owner) that can download the blob
+    pub async fn blob_create(
+        &self,
+        session_id: &str,
+        record: &BlobRecord,
+        access: &[String],
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let mut pipe = deadpool_redis::redis::pipe();
+        pipe.atomic()
+            .cmd("HSET")
+            .arg(keys::blob_key(&record.blob_id))
+            .arg("owner")
+            .arg(&record.owner)
+            .arg("size")
+            .arg(record.size)
+            .arg("chunk_size")
+            .arg(record.chunk_size)
+            .arg("upload_id")
+            .arg(&record.upload_id)
+            .arg("complete")
+            .arg("false")
+            .arg("expires_at")
+            .arg(record.expires_at)
+            .cmd("ZADD")
+            .arg(keys::blob_expiry_key(&record.blob_id))
+            .arg(record.expires_at)
+            .arg(&record.blob_id);
+        if !access.is_empty() {
+            pipe.cmd("SADD")
+                .arg(keys::blob_access_key(&record.blob_id))
+                .arg(access);
+        }
+
+        pipe.query_async::<()>(&mut con).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't create blob({}): {}",
+                record.blob_id,
+                err
+            );
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!("Couldn't create blob({}): {}", record.blob_id, err),
+            )
+        })?;
+
+        // The owner index lives in the slot of the owner, it is used to erase the blobs of a DID
+        deadpool_redis::redis::cmd("SADD")
+            .arg(keys::blob_owner_key(&record.owner))
+            .arg(&record.blob_id)
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't index blob({}) of did_hash({}): {}",
+                    record.blob_id,
+                    record.owner,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't index blob({}): {}", record.blob_id, err),
+                )
+            })?;
+
+        debug!("blob({}) created", record.blob_id);
+        Ok(())
+    }
+
+    /// Retrieves a blob record, None if the blob doesn't exist
+    pub async fn blob_get(
+        &self,
+        session_id: &str,
+        blob_id: &str,
+    ) -> Result<Option<BlobRecord>, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let hash: HashMap<String, String> = deadpool_redis::redis::cmd("HGETALL")
+            .arg(keys::blob_key(blob_id))
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(Level::ERROR, "Couldn't get blob({}): {}", blob_id, err);
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't get blob({}): {}", blob_id, err),
+                )
+            })?;
+
+        if hash.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(BlobRecord::from_hash(blob_id, hash)))
+        }
+    }
+
+    /// Can this DID download the blob? (the owner is checked separately)
+    pub async fn blob_has_access(
+        &self,
+        session_id: &str,
+        blob_id: &str,
+        did_hash: &str,
+    ) -> Result<bool, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("SISMEMBER")
+            .arg(keys::blob_access_key(blob_id))
+            .arg(did_hash)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't check access to blob({}): {}",
+                    blob_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't check access to blob({}): {}", blob_id, err),
+                )
+            })
+    }
+
+    /// Records that a chunk of the blob has been stored
+    /// - etag: ETag of the chunk from the storage backend (empty for local disk)
+    pub async fn blob_chunk_received(
+        &self,
+        session_id: &str,
+        blob_id: &str,
+        index: u64,
+        etag: &str,
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("HSET")
+            .arg(keys::blob_key(blob_id))
+            .arg(format!("chunk:{}", index))
+            .arg(etag)
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't record chunk({}) of blob({}): {}",
+                    index,
+                    blob_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!(
+                        "Couldn't record chunk({}) of blob({}): {}",
+                        index, blob_id, err
+                    ),
+                )
+            })
+    }
+
+    /// Marks the upload of a blob as complete, it can now be downloaded
+    pub async fn blob_completed(
+        &self,
+        session_id: &str,
+        blob_id: &str,
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("HSET")
+            .arg(keys::blob_key(blob_id))
+            .arg("complete")
+            .arg("true")
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                event!(Level::ERROR, "Couldn't complete blob({}): {}", blob_id, err);
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't complete blob({}): {}", blob_id, err),
+                )
+            })
+    }
+
+    /// Binds a blob to a message that references it
+    /// The recipient of the message can download the blob, and the blob lives as long as the message
+    /// would (it isn't removed when the message is deleted)
+    /// - recipient_hash: DID hash of the message recipient
+    /// - expires_at: New expiry of the blob (seconds since epoch)
+    pub async fn blob_bind(
+        &self,
+        session_id: &str,
+        blob_id: &str,
+        msg_id: &str,
+        recipient_hash: &str,
+        expires_at: u64,
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        // The message lookup is in a different slot to the blob, so this is not atomic
+        let mut message_lookup = deadpool_redis::redis::cmd("SADD");
+        message_lookup
+            .arg(keys::blob_message_key(msg_id))
+            .arg(blob_id);
+        let mut blob = deadpool_redis::redis::pipe();
+        blob.atomic()
+            .cmd("SADD")
+            .arg(keys::blob_refs_key(blob_id))
+            .arg(msg_id)
+            .cmd("SADD")
+            .arg(keys::blob_access_key(blob_id))
+            .arg(recipient_hash)
+            .cmd("HSET")
+            .arg(keys::blob_key(blob_id))
+            .arg("expires_at")
+            .arg(expires_at)
+            .cmd("ZADD")
+            .arg(keys::blob_expiry_key(blob_id))
+            .arg(expires_at)
+            .arg(blob_id);
+
+        async {
+            message_lookup.query_async::<()>(&mut con).await?;
+            blob.query_async::<()>(&mut con).await
+        }
+        .await
+        .map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't bind blob({}) to message({}): {}",
+                blob_id,
+                msg_id,
+                err
+            );
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!(
+                    "Couldn't bind blob({}) to message({}): {}",
+                    blob_id, msg_id, err
+                ),
+            )
+        })
+    }
+
+    /// A message has been deleted, releases the blobs it references
+    /// The blobs are kept until they expire, as the recipient may still download them
+    pub async fn blob_release_message(&self, msg_id: &str) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let (blob_ids, _): (Vec<String>, i64) = deadpool_redis::redis::pipe()
+            .atomic()
+            .cmd("SMEMBERS")
+            .arg(keys::blob_message_key(msg_id))
+            .cmd("DEL")
+            .arg(keys::blob_message_key(msg_id))
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get blobs of message({}): {}",
+                    msg_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!("Couldn't get blobs of message({}): {}", msg_id, err),
+                )
+            })?;
+
+        for blob_id in blob_ids {
+            deadpool_redis::redis::cmd("SREM")
+                .arg(keys::blob_refs_key(&blob_id))
+                .arg(msg_id)
+                .query_async::<()>(&mut con)
+                .await
+                .map_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        "Couldn't release blob({}) from message({}): {}",
+                        blob_id,
+                        msg_id,
+                        err
+                    );
+                    MediatorError::DatabaseError(
+                        "NA".into(),
+                        format!(
+                            "Couldn't release blob({}) from message({}): {}",
+                            blob_id, msg_id, err
+                        ),
+                    )
+                })?;
+            debug!("blob({}) released from message({})", blob_id, msg_id);
+        }
+
+        Ok(())
+    }
+
+    /// Returns blobs of a shard bucket that have expired
+    /// - now: Current time (seconds since epoch)
+    /// - limit: Maximum number of blob_ids to return
+    pub async fn blob_expired(
+        &self,
+        shard: &str,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<String>, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("ZRANGEBYSCORE")
+            .arg(keys::blob_expiry_shard_key(shard))
+            .arg("-inf")
+            .arg(now)
+            .arg("LIMIT")
+            .arg(0)
+            .arg(limit)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(Level::ERROR, "Couldn't get expired blobs: {}", err);
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!("Couldn't get expired blobs: {}", err),
+                )
+            })
+    }
+
+    /// Removes all records of a blob (the blob itself is removed from storage separately)
+    pub async fn blob_delete(&self, session_id: &str, blob_id: &str) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let (owner, refs, _): (Option<String>, Vec<String>, ()) = deadpool_redis::redis::pipe()
+            .atomic()
+            .cmd("HGET")
+            .arg(keys::blob_key(blob_id))
+            .arg("owner")
+            .cmd("SMEMBERS")
+            .arg(keys::blob_refs_key(blob_id))
+            .cmd("DEL")
+            .arg(keys::blob_key(blob_id))
+            .arg(keys::blob_access_key(blob_id))
+            .arg(keys::blob_refs_key(blob_id))
+            .ignore()
+            .cmd("ZREM")
+            .arg(keys::blob_expiry_key(blob_id))
+            .arg(blob_id)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(Level::ERROR, "Couldn't delete blob({}): {}", blob_id, err);
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't delete blob({}): {}", blob_id, err),
+                )
+            })?;
+
+        // Remove the lookups from the messages that referenced the blob
+        for msg_id in refs {
+            if let Err(err) = deadpool_redis::redis::cmd("SREM")
+                .arg(keys::blob_message_key(&msg_id))
+                .arg(blob_id)
+                .query_async::<()>(&mut con)
+                .await
+            {
+                warn!(
+                    "Couldn't remove blob({}) from message({}): {}",
+                    blob_id, msg_id, err
+                );
+            }
+        }
+
+        if let Some(owner) = owner {
+            if let Err(err) = deadpool_redis::redis::cmd("SREM")
+                .arg(keys::blob_owner_key(&owner))
+                .arg(blob_id)
+                .query_async::<()>(&mut con)
+                .await
+            {
+                warn!(
+                    "Couldn't remove blob({}) from the index of did_hash({}): {}",
+                    blob_id, owner, err
+                );
+            }
+        }
+
+        debug!("blob({}) records deleted", blob_id);
+        Ok(())
+    }
+
+    /// Expires all blobs uploaded by a DID, they are removed from storage by the blob expiry task
+    /// and can't be downloaded in the meantime
+    /// Returns the number of blobs that were expired
+    pub async fn blob_expire_owner(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+    ) -> Result<usize, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let blob_ids: Vec<String> = deadpool_redis::redis::cmd("SMEMBERS")
+            .arg(keys::blob_owner_key(did_hash))
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get blobs of did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't get blobs: {}", err),
+                )
+            })?;
+
+        let mut expired = 0;
+        for blob_id in blob_ids {
+            // The blob may have been removed since it was indexed
+            if self.blob_get(session_id, &blob_id).await?.is_none() {
+                continue;
+            }
+
+            deadpool_redis::redis::pipe()
+                .atomic()
+                .cmd("HSET")
+                .arg(keys::blob_key(&blob_id))
+                .arg("expires_at")
+                .arg(0)
+                .cmd("ZADD")
+                .arg(keys::blob_expiry_key(&blob_id))
+                .arg(0)
+                .arg(&blob_id)
+                .query_async::<()>(&mut con)
+                .await
+                .map_err(|err| {
+                    event!(Level::ERROR, "Couldn't expire blob({}): {}", blob_id, err);
+                    MediatorError::DatabaseError(
+                        session_id.into(),
+                        format!("Couldn't expire blob({}): {}", blob_id, err),
+                    )
+                })?;
+            expired += 1;
+        }
+
+        deadpool_redis::redis::cmd("DEL")
+            .arg(keys::blob_owner_key(did_hash))
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't delete blob index of did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't delete blob index: {}", err),
+                )
+            })?;
+
+        debug!("expired ({}) blobs of did_hash({})", expired, did_hash);
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlobRecord;
+
+    fn record(size: u64, chunk_size: u64) -> BlobRecord {
+        BlobRecord {
+            size,
+            chunk_size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn chunks() {
+        assert_eq!(record(1, 5).chunks(), 1);
+        assert_eq!(record(5, 5).chunks(), 1);
+        assert_eq!(record(6, 5).chunks(), 2);
+        assert_eq!(record(10, 5).chunks(), 2);
+        assert_eq!(record(11, 5).chunks(), 3);
+    }
+
+    #[test]
+    fn chunk_len() {
+        let blob = record(12, 5);
+        assert_eq!(blob.chunk_len(0), 5);
+        assert_eq!(blob.chunk_len(1), 5);
+        assert_eq!(blob.chunk_len(2), 2);
+
+        // An exact multiple has a full last chunk
+        let blob = record(10, 5);
+        assert_eq!(blob.chunk_len(1), 5);
+
+        let blob = record(3, 5);
+        assert_eq!(blob.chunk_len(0), 3);
+    }
+}
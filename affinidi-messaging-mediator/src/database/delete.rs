@@ -1,12 +1,17 @@
-use super::DatabaseHandler;
+use super::{keys, DatabaseHandler};
 use crate::common::errors::MediatorError;
-use tracing::{debug, event, span, Instrument, Level};
+use redis::{from_redis_value, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, event, span, warn, Instrument, Level};
+
+/// Number of delivered message records kept per sender, used to answer a recall that arrives too late
+const SEND_DELIVERED_KEPT: usize = 1000;
 
 impl DatabaseHandler {
-    /// Deletes a message in the database
+    /// Deletes a message in the database, a message removed by its recipient is recorded as delivered
     /// - session_id: authentication session ID
     /// - did: DID of the delete requestor
-    /// - message_hash: sha257 hash of the message to delete
+    /// - message_hash: ID of the message to delete
     pub async fn delete_message(
         &self,
         session_id: &str,
@@ -19,41 +24,324 @@ impl DatabaseHandler {
             message_hash = message_hash,
             did_hash = did_hash
         );
+        self._delete_message(session_id, did_hash, message_hash, false, true)
+            .instrument(_span)
+            .await
+    }
+
+    /// Removes a message that wasn't delivered (it expired, or was purged by an operator)
+    /// The sender can still tell that the message wasn't delivered, and recall fails as not found
+    /// - session_id: authentication session ID
+    /// - did_hash: DID hash of the recipient
+    /// - message_hash: ID of the message to remove
+    pub async fn expire_message(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        message_hash: &str,
+    ) -> Result<(), MediatorError> {
+        let _span = span!(
+            Level::DEBUG,
+            "expire_message",
+            message_hash = message_hash,
+            did_hash = did_hash
+        );
+        self._delete_message(session_id, did_hash, message_hash, false, false)
+            .instrument(_span)
+            .await
+    }
+
+    /// Recalls a message that the sender has sent, as long as the recipient hasn't fetched it yet
+    /// The message is removed from the recipient queue as well as the sender outbox
+    /// - session_id: authentication session ID
+    /// - did_hash: DID hash of the sender
+    /// - message_hash: ID of the message to recall
+    ///
+    /// Returns MessageRecallTooLate if the message has already been fetched or delivered
+    pub async fn recall_message(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        message_hash: &str,
+    ) -> Result<(), MediatorError> {
+        let _span = span!(
+            Level::DEBUG,
+            "recall_message",
+            message_hash = message_hash,
+            did_hash = did_hash
+        );
         async move {
-            let mut conn = self.get_async_connection().await?;
-            let response: String = deadpool_redis::redis::cmd("FCALL")
-                .arg("delete_message")
-                .arg(1)
-                .arg(message_hash)
-                .arg(did_hash)
-                .query_async(&mut conn)
-                .await
-                .map_err(|err| {
-                    event!(
-                        Level::ERROR,
-                        "Couldn't delete message_id({}) from database for DID {}: {}",
-                        message_hash,
-                        did_hash,
-                        err
-                    );
-                    MediatorError::DatabaseError(
-                        did_hash.into(),
-                        format!(
-                            "Couldn't delete message_id({}) from database for DID {}: {}",
-                            message_hash, did_hash, err
-                        ),
-                    )
-                })?;
-
-            debug!("database response: ({})", response);
-
-            if response != "OK" {
-                Err(MediatorError::DatabaseError(session_id.into(), response))
-            } else {
-                Ok(())
+            if self
+                .get_message_recipient(did_hash, message_hash)
+                .await?
+                .is_none()
+            {
+                // The message may have been delivered (and removed) already
+                let mut conn = self.get_async_connection().await?;
+                let delivered: Option<u64> = deadpool_redis::redis::cmd("ZSCORE")
+                    .arg(keys::send_delivered_key(did_hash))
+                    .arg(message_hash)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(|err| {
+                        event!(
+                            Level::ERROR,
+                            "Couldn't check delivery of message_id({}): {}",
+                            message_hash,
+                            err
+                        );
+                        MediatorError::DatabaseError(
+                            session_id.into(),
+                            format!(
+                                "Couldn't check delivery of message_id({}): {}",
+                                message_hash, err
+                            ),
+                        )
+                    })?;
+
+                return Err(match delivered {
+                    Some(timestamp) => MediatorError::MessageRecallTooLate(
+                        session_id.into(),
+                        format!("message was delivered to the recipient at ({})", timestamp),
+                    ),
+                    None => MediatorError::DatabaseError(
+                        session_id.into(),
+                        format!("Message not found for ID: {}", message_hash),
+                    ),
+                });
             }
+
+            self._delete_message(session_id, did_hash, message_hash, true, false)
+                .await
         }
         .instrument(_span)
         .await
     }
+
+    /// Removes a message from the recipient slot, followed by the sender records
+    /// When recalling, the recipient must not have fetched the message yet (checked atomically by the delete)
+    /// - delivered: record the removal as a delivery to the sender, if the requestor is the recipient
+    async fn _delete_message(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        message_hash: &str,
+        recall: bool,
+        delivered: bool,
+    ) -> Result<(), MediatorError> {
+        let to_hash = match self.get_message_recipient(did_hash, message_hash).await? {
+            Some(to_hash) => to_hash,
+            None => {
+                return Err(MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Message not found for ID: {}", message_hash),
+                ))
+            }
+        };
+
+        let mut conn = self.get_async_connection().await?;
+        let mut cmd = deadpool_redis::redis::cmd("FCALL");
+        cmd.arg("delete_message")
+            .arg(7)
+            .arg(keys::message_key(&to_hash, message_hash))
+            .arg(keys::message_meta_key(&to_hash, message_hash))
+            .arg(keys::receive_queue_key(&to_hash))
+            .arg(keys::did_key(&to_hash))
+            .arg(keys::global_key(&to_hash))
+            .arg(keys::content_index_key(&to_hash))
+            .arg(keys::message_expiry_key(&to_hash))
+            .arg(did_hash)
+            .arg(&to_hash)
+            .arg(message_hash);
+        if recall {
+            cmd.arg("RECALL");
+        }
+        let response: Vec<Value> = cmd.query_async(&mut conn).await.map_err(|err| {
+            if recall && err.to_string().contains("RECALL_TOO_LATE") {
+                return MediatorError::MessageRecallTooLate(
+                    session_id.into(),
+                    "message was fetched by the recipient".into(),
+                );
+            }
+            event!(
+                Level::ERROR,
+                "Couldn't delete message_id({}) from database for DID {}: {}",
+                message_hash,
+                did_hash,
+                err
+            );
+            MediatorError::DatabaseError(
+                did_hash.into(),
+                format!(
+                    "Couldn't delete message_id({}) from database for DID {}: {}",
+                    message_hash, did_hash, err
+                ),
+            )
+        })?;
+
+        debug!("database response: ({:?})", response);
+
+        // response = [from_did_hash, send_id, bytes]
+        let (from_hash, send_id, bytes) = match response.as_slice() {
+            [from_hash, send_id, bytes] => (
+                from_redis_value::<String>(from_hash).unwrap_or_default(),
+                from_redis_value::<String>(send_id).unwrap_or_default(),
+                from_redis_value::<usize>(bytes).unwrap_or(0),
+            ),
+            _ => {
+                return Err(MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Unexpected response from delete_message: {:?}", response),
+                ))
+            }
+        };
+
+        // Blobs that are no longer referenced by any message can be removed
+        if let Err(err) = self.blob_release_message(message_hash).await {
+            warn!(
+                "Couldn't release blobs of message_id({}): {}",
+                message_hash, err
+            );
+        }
+
+        // Anonymous messages have no sender records
+        if !from_hash.is_empty() && !send_id.is_empty() {
+            // The recipient removing a message means it was delivered, the sender keeps a record of this
+            let delivered = if delivered && to_hash == did_hash {
+                Some(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_millis() as u64)
+                        .unwrap_or(0),
+                )
+            } else {
+                None
+            };
+            self.delete_message_sender(
+                session_id,
+                &from_hash,
+                message_hash,
+                &send_id,
+                bytes,
+                delivered,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the sender records (SEND_Q, SEND_INDEX and counters) of a message
+    /// These live in the sender's slot, and are removed separately from the message itself
+    /// - delivered: time (ms) the recipient removed the message, None if it wasn't delivered
+    pub(crate) async fn delete_message_sender(
+        &self,
+        session_id: &str,
+        from_hash: &str,
+        message_hash: &str,
+        send_id: &str,
+        bytes: usize,
+        delivered: Option<u64>,
+    ) -> Result<(), MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+        let mut cmd = deadpool_redis::redis::cmd("FCALL");
+        cmd.arg("delete_message_sender")
+            .arg(if delivered.is_some() { 4 } else { 3 })
+            .arg(keys::send_queue_key(from_hash))
+            .arg(keys::did_key(from_hash))
+            .arg(keys::send_index_key(from_hash));
+        if delivered.is_some() {
+            cmd.arg(keys::send_delivered_key(from_hash));
+        }
+        cmd.arg(message_hash).arg(send_id).arg(bytes);
+        if let Some(delivered) = delivered {
+            cmd.arg(delivered).arg(SEND_DELIVERED_KEPT);
+        }
+        let response: String = cmd.query_async(&mut conn).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't delete sender records for message_id({}) from database: {}",
+                message_hash,
+                err
+            );
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!(
+                    "Couldn't delete sender records for message_id({}) from database: {}",
+                    message_hash, err
+                ),
+            )
+        })?;
+
+        if response != "OK" {
+            Err(MediatorError::DatabaseError(session_id.into(), response))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the messages of a shard bucket that have expired, as `(did_hash, msg_id)`
+    /// - now: Current time (milliseconds since epoch)
+    /// - limit: Maximum number of messages to return
+    pub async fn messages_expired(
+        &self,
+        shard: &str,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let expired: Vec<String> = deadpool_redis::redis::cmd("ZRANGEBYSCORE")
+            .arg(keys::message_expiry_shard_key(shard))
+            .arg("-inf")
+            .arg(now)
+            .arg("LIMIT")
+            .arg(0)
+            .arg(limit)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(Level::ERROR, "Couldn't get expired messages: {}", err);
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!("Couldn't get expired messages: {}", err),
+                )
+            })?;
+
+        Ok(expired
+            .iter()
+            .filter_map(|record| record.split_once(':'))
+            .map(|(did_hash, msg_id)| (did_hash.to_string(), msg_id.to_string()))
+            .collect())
+    }
+
+    /// Removes the expiry record of a message that no longer exists
+    pub async fn message_expiry_remove(
+        &self,
+        did_hash: &str,
+        msg_id: &str,
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("ZREM")
+            .arg(keys::message_expiry_key(did_hash))
+            .arg([did_hash, ":", msg_id].concat())
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't remove expiry record of msg_id({}): {}",
+                    msg_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!(
+                        "Couldn't remove expiry record of msg_id({}): {}",
+                        msg_id, err
+                    ),
+                )
+            })
+    }
 }
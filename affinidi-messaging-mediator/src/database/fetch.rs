@@ -3,14 +3,16 @@ use affinidi_messaging_sdk::messages::{
 };
 use itertools::Itertools;
 use redis::{from_redis_value, Value};
+use std::time::SystemTime;
 use tracing::{debug, event, span, warn, Instrument, Level};
 
 use crate::common::errors::MediatorError;
 
-use super::DatabaseHandler;
+use super::{keys, receipts::StoredReceiptRequest, DatabaseHandler};
 
 impl DatabaseHandler {
     /// Fetch as many messages as possible from the database
+    /// Messages older than the retention of the DID's mailbox tier are removed instead of returned
     /// - did_hash: DID we are checking
     pub async fn fetch_messages(
         &self,
@@ -20,15 +22,28 @@ impl DatabaseHandler {
     ) -> Result<GetMessagesResponse, MediatorError> {
         let _span = span!(Level::DEBUG, "fetch_messages");
         async move {
+            // Messages queued before this time (milliseconds) have expired
+            let tier = self.did_tier(session_id, did_hash).await?;
+            let expired_before = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|now| now.as_millis() as u64)
+                .unwrap_or(0)
+                .saturating_sub(tier.message_expiry_minutes as u64 * 60_000);
+
             let mut conn = self.get_async_connection().await?;
 
-            let start_id = options.start_id.as_deref().unwrap_or("-");
+            // Prepend an exclusive start_id if it exists
+            let start_id = match options.start_id.as_deref() {
+                Some(start_id) if start_id != "-" => ["(", start_id].concat(),
+                _ => "-".to_string(),
+            };
 
-            let results: Vec<Value> = deadpool_redis::redis::cmd("FCALL")
-                .arg("fetch_messages")
-                .arg(1)
-                .arg(did_hash)
-                .arg(start_id)
+            // Get list of messages from stream
+            let list: Vec<(String, Vec<(String, String)>)> = deadpool_redis::redis::cmd("XRANGE")
+                .arg(keys::receive_queue_key(did_hash))
+                .arg(&start_id)
+                .arg("+")
+                .arg("COUNT")
                 .arg(options.limit)
                 .query_async(&mut conn)
                 .await
@@ -44,32 +59,83 @@ impl DatabaseHandler {
                     )
                 })?;
 
+            // Fetch the messages and metadata, these are all in the same slot as the RECEIVE_Q
+            let mut pipe = deadpool_redis::redis::pipe();
+            let mut elements: Vec<MessageListElement> = Vec::new();
+            for (stream_id, fields) in list {
+                let mut message = MessageListElement {
+                    receive_id: Some(stream_id),
+                    ..Default::default()
+                };
+                for (k, v) in fields {
+                    match k.as_str() {
+                        "MSG_ID" => message.msg_id = v,
+                        "FROM" => message.from_address = Some(v),
+                        _ => {}
+                    }
+                }
+                pipe.cmd("GET")
+                    .arg(keys::message_key(did_hash, &message.msg_id))
+                    .cmd("HGETALL")
+                    .arg(keys::message_meta_key(did_hash, &message.msg_id));
+                elements.push(message);
+            }
+
+            let results: Vec<Value> = if elements.is_empty() {
+                Vec::new()
+            } else {
+                pipe.query_async(&mut conn).await.map_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        "Couldn't fetch_messages() from database: {}",
+                        err
+                    );
+                    MediatorError::DatabaseError(
+                        "NA".into(),
+                        format!("Couldn't fetch_messages() from database: {}", err),
+                    )
+                })?
+            };
+
             let mut messages = GetMessagesResponse::default();
-            for item in &results {
-                let sub_item: Vec<String> = match from_redis_value(item) {
-                    Ok(v) => v,
+            for (mut message, (msg, meta)) in elements.into_iter().zip(results.into_iter().tuples())
+            {
+                match from_redis_value::<Option<String>>(&msg) {
+                    Ok(msg) => message.msg = msg,
                     Err(e) => {
-                        warn!("Error parsing redis value: ({:?}). Reason: {:?}", item, e);
+                        warn!("Error parsing redis value: ({:?}). Reason: {:?}", msg, e);
                         messages
                             .get_errors
-                            .push((format!("{:?}", item), e.to_string()));
+                            .push((message.msg_id.clone(), e.to_string()));
                         continue;
                     }
-                };
-                let mut message = MessageListElement::default();
-                for (k, v) in sub_item.iter().tuples() {
+                }
+                let meta: Vec<String> = from_redis_value(&meta).unwrap_or_default();
+                for (k, v) in meta.iter().tuples() {
                     match k.as_str() {
-                        "MSG_ID" => message.msg_id.clone_from(v),
-                        "META_SEND_ID" => message.send_id = Some(v.clone()),
-                        "META_RECEIVE_ID" => message.receive_id = Some(v.clone()),
-                        "META_BYTES" => message.size = v.parse().unwrap_or(0),
-                        "META_TIMESTAMP" => message.timestamp = v.parse().unwrap_or(0),
-                        "META_TO" => message.to_address = Some(v.clone()),
-                        "FROM_DID" => message.from_address = Some(v.clone()),
-                        "MSG" => message.msg = Some(v.clone()),
+                        "SEND_ID" => message.send_id = Some(v.clone()),
+                        "RECEIVE_ID" => message.receive_id = Some(v.clone()),
+                        "BYTES" => message.size = v.parse().unwrap_or(0),
+                        "TIMESTAMP" => message.timestamp = v.parse().unwrap_or(0),
+                        "TO" => message.to_address = Some(v.clone()),
+                        "CONTENT_HASH" => message.content_hash = Some(v.clone()),
                         _ => {}
                     }
                 }
+
+                if message.timestamp > 0 && message.timestamp < expired_before {
+                    debug!(
+                        "Message id({}) expired (tier {}), removing",
+                        &message.msg_id, tier.name
+                    );
+                    if let Err(e) = self
+                        .expire_message(session_id, did_hash, &message.msg_id)
+                        .await
+                    {
+                        warn!("Error deleting expired message: ({})", e);
+                    }
+                    continue;
+                }
                 debug!("Message id({}) fetched", &message.msg_id);
 
                 // Delete message if requested
@@ -97,4 +163,53 @@ impl DatabaseHandler {
         .instrument(_span)
         .await
     }
+
+    /// Marks messages as fetched by the recipient, once fetched a message can't be recalled
+    /// - did_hash: DID hash of the recipient
+    /// - msg_ids: messages that have been handed to the recipient
+    ///
+    /// Returns the receipt requests of messages that were fetched for the first time
+    pub async fn mark_fetched(
+        &self,
+        did_hash: &str,
+        msg_ids: &[&str],
+    ) -> Result<Vec<(String, StoredReceiptRequest)>, MediatorError> {
+        if msg_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut conn = self.get_async_connection().await?;
+        let mut cmd = deadpool_redis::redis::cmd("FCALL");
+        cmd.arg("mark_fetched").arg(msg_ids.len());
+        for msg_id in msg_ids {
+            cmd.arg(keys::message_meta_key(did_hash, msg_id));
+        }
+        cmd.arg(msg_ids);
+
+        let receipts: Vec<String> = cmd.query_async(&mut conn).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't mark messages as fetched for DID_hash {}: {}",
+                did_hash,
+                err
+            );
+            MediatorError::DatabaseError(
+                did_hash.into(),
+                format!(
+                    "Couldn't mark messages as fetched for DID_hash {}: {}",
+                    did_hash, err
+                ),
+            )
+        })?;
+
+        Ok(receipts
+            .into_iter()
+            .tuples()
+            .filter_map(|(msg_id, request)| {
+                serde_json::from_str(&request)
+                    .ok()
+                    .map(|request| (msg_id, request))
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,125 @@
+use super::{keys, DatabaseHandler};
+use crate::common::errors::MediatorError;
+use affinidi_messaging_sdk::messages::{ForwardAcl, ForwardConsent};
+use tracing::{debug, event, Level};
+
+/// Window in seconds that the anonymous forward rate limit applies to
+const FORWARD_RATE_WINDOW: u64 = 60;
+
+impl DatabaseHandler {
+    /// Retrieves the consent of a DID to receive anonymous forward messages
+    /// Returns the default (no consent) if the DID hasn't set an ACL
+    pub async fn forward_acl_get(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+    ) -> Result<ForwardAcl, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let acl: Option<String> = deadpool_redis::redis::cmd("GET")
+            .arg(keys::forward_acl_key(did_hash))
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get forward ACL for did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't get forward ACL: {}", err),
+                )
+            })?;
+
+        Ok(acl
+            .and_then(|acl| serde_json::from_str(&acl).ok())
+            .unwrap_or_default())
+    }
+
+    /// Sets the consent of a DID to receive anonymous forward messages
+    /// The ACL is removed when consent is `None`
+    pub async fn forward_acl_set(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        acl: &ForwardAcl,
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let key = keys::forward_acl_key(did_hash);
+        let cmd = if acl.consent == ForwardConsent::None {
+            let mut cmd = deadpool_redis::redis::cmd("DEL");
+            cmd.arg(&key);
+            cmd
+        } else {
+            let serialized = serde_json::to_string(acl).map_err(|err| {
+                MediatorError::InternalError(
+                    session_id.into(),
+                    format!("Couldn't serialize forward ACL: {}", err),
+                )
+            })?;
+            let mut cmd = deadpool_redis::redis::cmd("SET");
+            cmd.arg(&key).arg(serialized);
+            cmd
+        };
+
+        cmd.query_async::<()>(&mut con).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't set forward ACL for did_hash({}): {}",
+                did_hash,
+                err
+            );
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!("Couldn't set forward ACL: {}", err),
+            )
+        })?;
+
+        debug!(
+            "forward ACL for did_hash({}) set to ({:?})",
+            did_hash, acl.consent
+        );
+        Ok(())
+    }
+
+    /// Counts an anonymous forward message from a client (IP address)
+    /// Returns true if the client has sent no more than `limit` messages within the rate window (across all mediators)
+    pub async fn forward_rate_limit(
+        &self,
+        client: &str,
+        limit: u64,
+    ) -> Result<bool, MediatorError> {
+        if limit == 0 {
+            return Ok(true);
+        }
+
+        let mut con = self.get_async_connection().await?;
+
+        let key = keys::forward_rate_key(client);
+        let (count,): (u64,) = deadpool_redis::redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(&key)
+            .arg(0)
+            .arg("NX")
+            .arg("EX")
+            .arg(FORWARD_RATE_WINDOW)
+            .ignore()
+            .cmd("INCR")
+            .arg(&key)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(Level::ERROR, "Couldn't update forward rate limit: {}", err);
+                MediatorError::DatabaseError(
+                    "ANONYMOUS".into(),
+                    format!("Couldn't update forward rate limit: {}", err),
+                )
+            })?;
+
+        Ok(count <= limit)
+    }
+}
@@ -5,7 +5,7 @@ use tracing::{debug, event, span, Instrument, Level};
 
 use crate::common::errors::MediatorError;
 
-use super::DatabaseHandler;
+use super::{keys, DatabaseHandler};
 
 impl DatabaseHandler {
     /// Get a message from the database
@@ -17,14 +17,24 @@ impl DatabaseHandler {
     ) -> Result<MessageListElement, MediatorError> {
         let _span = span!(Level::DEBUG, "get_message", msg_id = msg_id,);
         async move {
+            let to_hash = match self.get_message_recipient(did_hash, msg_id).await? {
+                Some(to_hash) => to_hash,
+                None => {
+                    return Err(MediatorError::DatabaseError(
+                        did_hash.into(),
+                        format!("Message not found for ID: {}", msg_id),
+                    ));
+                }
+            };
+
             let mut conn = self.get_async_connection().await?;
 
             let (didcomm_message, meta_data): (Value, Vec<String>) = deadpool_redis::redis::pipe()
                 .atomic()
                 .cmd("GET")
-                .arg(["MSG:", msg_id].concat())
+                .arg(keys::message_key(&to_hash, msg_id))
                 .cmd("HGETALL")
-                .arg(["MSG:META:", msg_id].concat())
+                .arg(keys::message_meta_key(&to_hash, msg_id))
                 .query_async(&mut conn)
                 .await
                 .map_err(|err| {
@@ -73,6 +83,7 @@ impl DatabaseHandler {
                     "TIMESTAMP" => message.timestamp = v.parse().unwrap_or(0),
                     "SEND_ID" => message.send_id = Some(v.clone()),
                     "RECEIVE_ID" => message.receive_id = Some(v.clone()),
+                    "CONTENT_HASH" => message.content_hash = Some(v.clone()),
                     _ => {}
                 }
             }
@@ -82,7 +93,7 @@ impl DatabaseHandler {
             if did_hash == message.from_address.as_ref().unwrap_or(&"".to_string())
                 || did_hash == message.to_address.as_ref().unwrap_or(&"".to_string())
             {
-                let _ = self.update_send_stats(message.size as i64).await;
+                let _ = self.update_send_stats(did_hash, message.size as i64).await;
                 Ok(message)
             } else {
                 Err(MediatorError::DatabaseError(
@@ -94,4 +105,64 @@ impl DatabaseHandler {
         .instrument(_span)
         .await
     }
+
+    /// Finds the recipient DID hash of a message, which determines where the message is stored
+    /// - did_hash: DID hash of the requestor, can be either the recipient or the sender
+    /// - msg_id: The unique identifier of the message
+    ///
+    /// Returns None if the message doesn't exist for this DID
+    pub async fn get_message_recipient(
+        &self,
+        did_hash: &str,
+        msg_id: &str,
+    ) -> Result<Option<String>, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        // Is the requestor the recipient?
+        let exists: bool = deadpool_redis::redis::cmd("EXISTS")
+            .arg(keys::message_meta_key(did_hash, msg_id))
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't check message_id({}) exists in database: {}",
+                    msg_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    did_hash.into(),
+                    format!(
+                        "Couldn't check message_id({}) exists in database: {}",
+                        msg_id, err
+                    ),
+                )
+            })?;
+
+        if exists {
+            return Ok(Some(did_hash.to_string()));
+        }
+
+        // Is the requestor the sender?
+        deadpool_redis::redis::cmd("HGET")
+            .arg(keys::send_index_key(did_hash))
+            .arg(msg_id)
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't lookup SEND_INDEX for message_id({}): {}",
+                    msg_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    did_hash.into(),
+                    format!(
+                        "Couldn't lookup SEND_INDEX for message_id({}): {}",
+                        msg_id, err
+                    ),
+                )
+            })
+    }
 }
@@ -1,7 +1,10 @@
-use std::{thread::sleep, time::Duration};
+use std::{collections::BTreeMap, fmt::Display, thread::sleep, time::Duration};
 
-use deadpool_redis::Connection;
-use redis::aio::PubSub;
+use redis::{
+    aio::{ConnectionLike, PubSub},
+    cluster_routing::{get_slot, Route, RoutingInfo, SingleNodeRoutingInfo, SlotAddr},
+    Arg, Cmd, Pipeline, RedisFuture, RedisResult, Value,
+};
 use tracing::{event, Level};
 
 use crate::common::{config::Config, errors::MediatorError};
@@ -10,41 +13,143 @@ use super::DatabaseHandler;
 
 static LUA_SCRIPTS: &[u8] = include_bytes!("atm-functions.lua");
 
+/// Connection pool to a single database node, or to a Redis Cluster
+#[derive(Clone)]
+pub enum DatabasePool {
+    Single(deadpool_redis::Pool),
+    Cluster(deadpool_redis::cluster::Pool),
+}
+
+/// Connection taken from the database pool
+/// On a cluster each command is sent to the node that holds the slot of its keys, so pipelines and
+/// functions must only use keys of a single slot (see `keys.rs`)
+pub enum DatabaseConnection {
+    Single(deadpool_redis::Connection),
+    Cluster(deadpool_redis::cluster::Connection),
+}
+
+impl ConnectionLike for DatabaseConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Single(conn) => conn.req_packed_command(cmd),
+            Self::Cluster(conn) => match _fcall_routing(cmd) {
+                Some(routing) => Box::pin(conn.route_command(cmd, routing)),
+                None => conn.req_packed_command(cmd),
+            },
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            Self::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Single(conn) => conn.get_db(),
+            Self::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+/// State of the `atm` functions library in the database
+/// - loaded  : True if the library is loaded
+/// - current : True if the loaded library is the library of this mediator version
+/// - missing : Functions of this mediator version that aren't registered in the database
+#[derive(Debug, Default)]
+pub struct FunctionsStatus {
+    pub loaded: bool,
+    pub current: bool,
+    pub missing: Vec<String>,
+}
+
 impl DatabaseHandler {
     pub async fn new(config: &Config) -> Result<Self, MediatorError> {
-        // Creates initial pool Configuration from the redis database URL
-        let pool = deadpool_redis::Config::from_url(&config.database_url)
-            .builder()
-            .map_err(|err| {
-                event!(Level::ERROR, "Database URL is invalid. Reason: {}", err);
-                MediatorError::DatabaseError(
-                    "NA".into(),
-                    format!("Database URL is invalid. Reason: {}", err),
-                )
-            })?;
+        let database = Self::connect(config).await?;
 
-        // Now that we have a base config, we customise the redis pool config
-        // and create the async pool of redis connections
-        let pool = pool
-            .runtime(deadpool_redis::Runtime::Tokio1)
-            .max_size(config.database_pool_size)
-            .timeouts(deadpool_redis::Timeouts {
-                wait: Some(Duration::from_secs(config.database_timeout.into())),
-                create: Some(Duration::from_secs(config.database_timeout.into())),
-                recycle: Some(Duration::from_secs(config.database_timeout.into())),
-            })
-            .build()
-            .map_err(|err| {
-                event!(Level::ERROR, "Database config is invalid. Reason: {}", err);
-                MediatorError::DatabaseError(
-                    "NA".into(),
-                    format!("Database config is invalid. Reason: {}", err),
-                )
-            })?;
+        // Check and load LUA scripts as required
+        {
+            let mut conn = database.get_async_connection().await?;
+            let function_load: Result<String, deadpool_redis::redis::RedisError> =
+                deadpool_redis::redis::cmd("FUNCTION")
+                    .arg("LOAD")
+                    .arg("REPLACE")
+                    .arg(LUA_SCRIPTS)
+                    .query_async(&mut conn)
+                    .await;
+            match function_load {
+                Ok(function_load) => {
+                    event!(
+                        Level::INFO,
+                        "database response for FUNCTION LOAD: ({})",
+                        function_load
+                    );
+                }
+                Err(err) => {
+                    event!(
+                        Level::WARN,
+                        "database response for FUNCTION LOAD: ({})",
+                        err
+                    );
+                }
+            }
+        }
+
+        database.get_db_metadata().await?;
+        Ok(database)
+    }
+
+    /// Connects to the database without loading the `atm` functions library
+    /// Used by tools that inspect the database, such as `mediator-admin`
+    pub async fn connect(config: &Config) -> Result<Self, MediatorError> {
+        let timeouts = deadpool_redis::Timeouts {
+            wait: Some(Duration::from_secs(config.database_timeout.into())),
+            create: Some(Duration::from_secs(config.database_timeout.into())),
+            recycle: Some(Duration::from_secs(config.database_timeout.into())),
+        };
+
+        // Creates the async pool of redis connections from the database URL(s)
+        // A cluster is discovered from a list of node URLs
+        let urls: Vec<String> = config
+            .database_url
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        let pool = if config.database_cluster {
+            deadpool_redis::cluster::Config::from_urls(urls.clone())
+                .builder()
+                .map_err(_url_error)?
+                .runtime(deadpool_redis::Runtime::Tokio1)
+                .max_size(config.database_pool_size)
+                .timeouts(timeouts)
+                .build()
+                .map(DatabasePool::Cluster)
+                .map_err(_config_error)?
+        } else {
+            deadpool_redis::Config::from_url(&config.database_url)
+                .builder()
+                .map_err(_url_error)?
+                .runtime(deadpool_redis::Runtime::Tokio1)
+                .max_size(config.database_pool_size)
+                .timeouts(timeouts)
+                .build()
+                .map(DatabasePool::Single)
+                .map_err(_config_error)?
+        };
 
         let database = Self {
             pool,
-            redis_url: config.database_url.clone(),
+            // Pub/Sub messages are broadcast to every node of a cluster, so any node can be used
+            redis_url: urls.first().cloned().unwrap_or_default(),
+            tiers: config.tiers.clone(),
         };
         loop {
             let mut conn = match database.get_async_connection().await {
@@ -82,42 +187,65 @@ impl DatabaseHandler {
             }
         }
 
-        // Check and load LUA scripts as required
-        {
-            let mut conn = database.get_async_connection().await?;
-            let function_load: Result<String, deadpool_redis::redis::RedisError> =
-                deadpool_redis::redis::cmd("FUNCTION")
-                    .arg("LOAD")
-                    .arg("REPLACE")
-                    .arg(LUA_SCRIPTS)
-                    .query_async(&mut conn)
-                    .await;
-            match function_load {
-                Ok(function_load) => {
-                    event!(
-                        Level::INFO,
-                        "database response for FUNCTION LOAD: ({})",
-                        function_load
-                    );
-                }
-                Err(err) => {
-                    event!(
-                        Level::WARN,
-                        "database response for FUNCTION LOAD: ({})",
-                        err
-                    );
-                }
+        Ok(database)
+    }
+
+    /// Checks that the `atm` functions library of this mediator version is loaded in the database
+    /// On Redis Cluster this only checks the node that the connection is made to
+    pub async fn functions_status(&self) -> Result<FunctionsStatus, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let response: Value = deadpool_redis::redis::cmd("FUNCTION")
+            .arg("LIST")
+            .arg("LIBRARYNAME")
+            .arg("atm")
+            .arg("WITHCODE")
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(Level::ERROR, "Couldn't list database functions: {}", err);
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!("Couldn't list database functions: {}", err),
+                )
+            })?;
+
+        // The reply is a list of libraries, each a list of (field, value) pairs
+        // Function names follow a `name` field, the library code follows `library_code`
+        let mut strings = Vec::new();
+        _flatten_strings(&response, &mut strings);
+        let mut loaded = Vec::new();
+        let mut code = None;
+        for (field, value) in strings.iter().zip(strings.iter().skip(1)) {
+            match field.as_str() {
+                "name" => loaded.push(value.clone()),
+                "library_code" => code = Some(value.clone()),
+                _ => {}
             }
         }
 
-        database.get_db_metadata().await?;
-        Ok(database)
+        let scripts = String::from_utf8_lossy(LUA_SCRIPTS);
+        Ok(FunctionsStatus {
+            loaded: code.is_some(),
+            current: code.as_deref() == Some(scripts.as_ref()),
+            missing: scripts
+                .lines()
+                .filter_map(|line| line.strip_prefix("redis.register_function('"))
+                .filter_map(|line| line.split('\'').next())
+                .filter(|name| !loaded.iter().any(|l| l == name))
+                .map(|name| name.to_string())
+                .collect(),
+        })
     }
 
     /// Returns a redis async database connector or returns an Error
     /// This is the main method to get a connection to the database
-    pub async fn get_async_connection(&self) -> Result<Connection, MediatorError> {
-        self.pool.get().await.map_err(|err| {
+    pub async fn get_async_connection(&self) -> Result<DatabaseConnection, MediatorError> {
+        let conn = match &self.pool {
+            DatabasePool::Single(pool) => pool.get().await.map(DatabaseConnection::Single),
+            DatabasePool::Cluster(pool) => pool.get().await.map(DatabaseConnection::Cluster),
+        };
+        conn.map_err(|err| {
             event!(Level::ERROR, "Couldn't get database connection: {}", err);
             MediatorError::DatabaseError(
                 "NA".into(),
@@ -126,6 +254,51 @@ impl DatabaseHandler {
         })
     }
 
+    /// True if the database is a Redis Cluster, keys of different slots can't be used in a single
+    /// function call or pipeline
+    pub fn is_cluster(&self) -> bool {
+        matches!(self.pool, DatabasePool::Cluster(_))
+    }
+
+    /// Runs a batch of commands whose (first) keys can be in different slots
+    /// A single node runs the batch as one pipeline, on a cluster it is split into a pipeline per slot
+    /// The batch isn't atomic, results are returned in the order of the commands
+    pub async fn query_batch(
+        &self,
+        conn: &mut DatabaseConnection,
+        cmds: Vec<Cmd>,
+    ) -> RedisResult<Vec<Value>> {
+        if !self.is_cluster() {
+            let mut pipe = redis::pipe();
+            for cmd in cmds {
+                pipe.add_command(cmd);
+            }
+            return pipe.query_async(conn).await;
+        }
+
+        let mut slots: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+        for (i, cmd) in cmds.iter().enumerate() {
+            let slot = match cmd.args_iter().nth(1) {
+                Some(Arg::Simple(key)) => get_slot(key),
+                _ => 0,
+            };
+            slots.entry(slot).or_default().push(i);
+        }
+
+        let mut results = vec![Value::Nil; cmds.len()];
+        for indexes in slots.values() {
+            let mut pipe = redis::pipe();
+            for i in indexes {
+                pipe.add_command(cmds[*i].clone());
+            }
+            let values: Vec<Value> = pipe.query_async(conn).await?;
+            for (i, value) in indexes.iter().zip(values) {
+                results[*i] = value;
+            }
+        }
+        Ok(results)
+    }
+
     /// Returns a redis database connector or returns an Error
     /// This should only be used for pubsub operations
     pub async fn get_pubsub_connection(&self) -> Result<PubSub, MediatorError> {
@@ -144,3 +317,56 @@ impl DatabaseHandler {
         })
     }
 }
+
+/// Collects the string values of a (nested) redis reply in order
+fn _flatten_strings(value: &Value, strings: &mut Vec<String>) {
+    match value {
+        Value::BulkString(bytes) => strings.push(String::from_utf8_lossy(bytes).to_string()),
+        Value::SimpleString(s) => strings.push(s.clone()),
+        Value::Array(values) | Value::Set(values) => {
+            values.iter().for_each(|v| _flatten_strings(v, strings))
+        }
+        Value::Map(pairs) => pairs.iter().for_each(|(k, v)| {
+            _flatten_strings(k, strings);
+            _flatten_strings(v, strings);
+        }),
+        // Keeps the (field, value) pairs aligned
+        _ => strings.push(String::new()),
+    }
+}
+
+/// FCALL isn't routed by its keys by the cluster client, so it is sent to the slot of its first key
+fn _fcall_routing(cmd: &Cmd) -> Option<RoutingInfo> {
+    let mut args = cmd.args_iter().filter_map(|arg| match arg {
+        Arg::Simple(arg) => Some(arg),
+        Arg::Cursor => None,
+    });
+    if !args.next()?.eq_ignore_ascii_case(b"FCALL") {
+        return None;
+    }
+    let _function = args.next()?;
+    let numkeys: usize = std::str::from_utf8(args.next()?).ok()?.parse().ok()?;
+    if numkeys == 0 {
+        return None;
+    }
+
+    Some(RoutingInfo::SingleNode(
+        SingleNodeRoutingInfo::SpecificNode(Route::new(get_slot(args.next()?), SlotAddr::Master)),
+    ))
+}
+
+fn _url_error(err: impl Display) -> MediatorError {
+    event!(Level::ERROR, "Database URL is invalid. Reason: {}", err);
+    MediatorError::DatabaseError(
+        "NA".into(),
+        format!("Database URL is invalid. Reason: {}", err),
+    )
+}
+
+fn _config_error(err: impl Display) -> MediatorError {
+    event!(Level::ERROR, "Database config is invalid. Reason: {}", err);
+    MediatorError::DatabaseError(
+        "NA".into(),
+        format!("Database config is invalid. Reason: {}", err),
+    )
+}
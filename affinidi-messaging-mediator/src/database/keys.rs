@@ -0,0 +1,339 @@
+//! Redis key layout for the mediator
+//!
+//! Every key that is touched together inside a single Lua function call carries the same
+//! hash tag (the `{..}` part of the key), so that Redis Cluster (and sharded MemoryDB)
+//! will place them into the same slot.
+//!
+//! The hash tag is a shard bucket derived from a DID hash (first two hex characters, 256 buckets).
+//! Records of a DID live in the bucket of that DID, messages live in the bucket of the recipient:
+//! - `MSG:{xx}:<msg_id>`              : The packed message itself
+//! - `MSG:META:{xx}:<msg_id>`         : Message metadata (hash)
+//! - `RECEIVE_Q:{xx}:<did_hash>`      : Inbox stream for the DID
+//! - `SEND_Q:{xx}:<did_hash>`         : Outbox stream for the DID
+//! - `SEND_INDEX:{xx}:<did_hash>`     : Outbox lookup (msg_id -> recipient did_hash)
+//! - `CONTENT_INDEX:{xx}:<did_hash>`  : Inbox deduplication (content hash -> msg_id)
+//! - `SEND_DELIVERED:{xx}:<did_hash>` : Recently delivered outbox messages (msg_id scored by delivery time)
+//! - `DID:{xx}:<did_hash>`            : Per DID queue counters and mailbox tier (`TIER`)
+//! - `MAILBOX_RATE:{xx}:<did_hash>`   : Messages queued for a DID in the current minute (expires)
+//! - `BLOCKED:{xx}:<did_hash>`        : Time a DID was blocked by an operator, kept when the account is deleted
+//! - `ACCOUNTS:{xx}`                  : Per shard registry of DIDs that have authenticated (did_hash -> DID)
+//! - `GLOBAL:{xx}`                    : Per shard global counters (aggregated on read)
+//! - `MSG_EXPIRY:{xx}`                : Per shard message expiry records (`<did_hash>:<msg_id>` scored by expiry time, ms)
+//! - `PUSH:{xx}:<did_hash>`          : Push endpoints of a DID (registration id -> registration)
+//! - `PUSH_RATE:{xx}:<did_hash>`     : Push notification rate limit of a DID (expires)
+//! - `BLOB:{xx}:<blob_id>`            : Out-of-band blob record, and received chunks (bucket of the blob_id)
+//! - `BLOB_ACCESS:{xx}:<blob_id>`     : DID hashes that can download the blob (besides the owner)
+//! - `BLOB_REFS:{xx}:<blob_id>`       : Messages (msg_id) that reference the blob
+//! - `BLOB_EXPIRY:{xx}`               : Per shard blob expiry records (blob_id scored by expiry time)
+//! - `BLOB_MSG:{xx}:<msg_id>`         : Blobs referenced by a message (bucket of the msg_id)
+//! - `BLOB_OWNER:{xx}:<did_hash>`     : Blobs (blob_id) uploaded by a DID, used to erase them
+//! - `OOB_INVITE:{xx}:<oobid>`        : Hosted Out-of-Band invitation and its owner (bucket of the oobid, expires)
+//! - `OOB_INDEX:{xx}:<did_hash>`      : Hosted invitations of a DID (oobid scored by expiry time)
+//! - `FORWARD_ACL:{xx}:<did_hash>`   : Consent of a DID to receive anonymous forward messages
+//! - `FORWARD_RATE:{xx}:<hash>`       : Anonymous forward rate limit, sha256 of the client address (expires)
+//! - `SESSION:{xx}:<session_id>`      : Authentication sessions (bucket of the session_id)
+//! - `SESSIONS:{xx}:<did_hash>`       : Authenticated sessions (session_id) of a DID, used to erase them (expires)
+//! - `WS_TICKET:{xx}:<ticket>`        : Single use websocket tickets (bucket of the ticket, expires)
+//! - `REPLAY:{xx}:MSG:<hash>`         : Replay protection, sha256 of sender and message id
+//! - `REPLAY:{xx}:ENV:<hash>`         : Replay protection, sha256 of the message envelope
+//!
+//! DID hashes are namespaced by tenant (see `did_hash()`), which isolates the records of each tenant.
+//! The default tenant has an empty namespace, so its DID hashes are the plain sha256 hash of the DID.
+//!
+//! Streaming state is shared between all mediators and uses a fixed hash tag
+//! - `STREAMING_DID:{STREAMING}:<did_hash>`   : Connections of a DID (conn_id -> `<uuid>:<TRUE|FALSE>`)
+//! - `STREAMING_SESSIONS:{STREAMING}:<uuid>`  : Connections (`<did_hash>:<conn_id>`) of a streaming service
+//! - `STREAMING_NODE:{STREAMING}:<uuid>`      : Heartbeat of a streaming service (expires if the service dies)
+//! - `STREAMING_NODES:{STREAMING}`            : All streaming services that have registered
+use sha256::digest;
+
+/// Number of shard buckets that per shard keys are spread across
+pub const SHARD_BUCKETS: usize = 256;
+
+/// Hash tag used by all streaming related keys
+pub const STREAMING_TAG: &str = "{STREAMING}";
+
+/// Returns the hash of a DID within a tenant namespace
+/// All records of a DID are keyed by this hash, so the same DID has separate records in each tenant
+pub fn did_hash(namespace: &str, did: &str) -> String {
+    if namespace.is_empty() {
+        digest(did)
+    } else {
+        digest([namespace, ":", did].concat())
+    }
+}
+
+/// Returns the shard bucket for a sha256 hash (DID hash or session hash)
+/// Short or non-hex input is hashed first so that a bucket is always returned
+pub fn shard(hash: &str) -> String {
+    match hash.get(..2) {
+        Some(prefix) if prefix.chars().all(|c| c.is_ascii_hexdigit()) => {
+            prefix.to_ascii_lowercase()
+        }
+        _ => digest(hash)[..2].to_string(),
+    }
+}
+
+/// Returns all shard buckets, used when aggregating per shard records
+pub fn all_shards() -> impl Iterator<Item = String> {
+    (0..SHARD_BUCKETS).map(|i| format!("{:02x}", i))
+}
+
+/// Hash tag for a given DID hash
+pub fn tag(did_hash: &str) -> String {
+    ["{", &shard(did_hash), "}"].concat()
+}
+
+pub fn message_key(to_did_hash: &str, msg_id: &str) -> String {
+    ["MSG:", &tag(to_did_hash), ":", msg_id].concat()
+}
+
+pub fn message_meta_key(to_did_hash: &str, msg_id: &str) -> String {
+    ["MSG:META:", &tag(to_did_hash), ":", msg_id].concat()
+}
+
+pub fn receive_queue_key(did_hash: &str) -> String {
+    ["RECEIVE_Q:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn send_queue_key(did_hash: &str) -> String {
+    ["SEND_Q:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn send_index_key(did_hash: &str) -> String {
+    ["SEND_INDEX:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn content_index_key(did_hash: &str) -> String {
+    ["CONTENT_INDEX:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn send_delivered_key(did_hash: &str) -> String {
+    ["SEND_DELIVERED:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn did_key(did_hash: &str) -> String {
+    ["DID:", &tag(did_hash), ":", did_hash].concat()
+}
+
+/// Messages queued for a DID in the current minute, used for the rate limit of the mailbox tier
+pub fn mailbox_rate_key(did_hash: &str) -> String {
+    ["MAILBOX_RATE:", &tag(did_hash), ":", did_hash].concat()
+}
+
+/// Blocked DIDs can't authenticate or use existing tokens
+pub fn blocked_key(did_hash: &str) -> String {
+    ["BLOCKED:", &tag(did_hash), ":", did_hash].concat()
+}
+
+/// Registry of the DIDs that have authenticated, for the shard that `did_hash` belongs to
+pub fn accounts_key(did_hash: &str) -> String {
+    ["ACCOUNTS:", &tag(did_hash)].concat()
+}
+
+/// Registry of the DIDs that have authenticated, for a specific shard bucket
+pub fn accounts_shard_key(shard: &str) -> String {
+    ["ACCOUNTS:{", shard, "}"].concat()
+}
+
+/// Global counters for the shard that `did_hash` belongs to
+pub fn global_key(did_hash: &str) -> String {
+    ["GLOBAL:", &tag(did_hash)].concat()
+}
+
+/// Global counters for a specific shard bucket
+pub fn global_shard_key(shard: &str) -> String {
+    ["GLOBAL:{", shard, "}"].concat()
+}
+
+pub fn message_expiry_key(did_hash: &str) -> String {
+    ["MSG_EXPIRY:", &tag(did_hash)].concat()
+}
+
+/// Message expiry records of a specific shard bucket
+pub fn message_expiry_shard_key(shard: &str) -> String {
+    ["MSG_EXPIRY:{", shard, "}"].concat()
+}
+
+pub fn push_key(did_hash: &str) -> String {
+    ["PUSH:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn push_rate_key(did_hash: &str) -> String {
+    ["PUSH_RATE:", &tag(did_hash), ":", did_hash].concat()
+}
+
+/// Hash tag for a blob, derived from the blob_id
+pub fn blob_tag(blob_id: &str) -> String {
+    tag(&digest(blob_id))
+}
+
+pub fn blob_key(blob_id: &str) -> String {
+    ["BLOB:", &blob_tag(blob_id), ":", blob_id].concat()
+}
+
+pub fn blob_access_key(blob_id: &str) -> String {
+    ["BLOB_ACCESS:", &blob_tag(blob_id), ":", blob_id].concat()
+}
+
+pub fn blob_refs_key(blob_id: &str) -> String {
+    ["BLOB_REFS:", &blob_tag(blob_id), ":", blob_id].concat()
+}
+
+/// Expiry records of the shard that the blob belongs to
+pub fn blob_expiry_key(blob_id: &str) -> String {
+    ["BLOB_EXPIRY:", &blob_tag(blob_id)].concat()
+}
+
+/// Expiry records of a specific shard bucket
+pub fn blob_expiry_shard_key(shard: &str) -> String {
+    ["BLOB_EXPIRY:{", shard, "}"].concat()
+}
+
+pub fn blob_message_key(msg_id: &str) -> String {
+    ["BLOB_MSG:", &tag(msg_id), ":", msg_id].concat()
+}
+
+pub fn blob_owner_key(did_hash: &str) -> String {
+    ["BLOB_OWNER:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn oob_invite_key(oobid: &str) -> String {
+    let oobid_hash = digest(oobid);
+    ["OOB_INVITE:", &tag(&oobid_hash), ":", oobid].concat()
+}
+
+pub fn oob_index_key(did_hash: &str) -> String {
+    ["OOB_INDEX:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn forward_acl_key(did_hash: &str) -> String {
+    ["FORWARD_ACL:", &tag(did_hash), ":", did_hash].concat()
+}
+
+pub fn forward_rate_key(client: &str) -> String {
+    let client_hash = digest(client);
+    ["FORWARD_RATE:", &tag(&client_hash), ":", &client_hash].concat()
+}
+
+pub fn session_key(session_id: &str) -> String {
+    let session_hash = digest(session_id);
+    ["SESSION:", &tag(&session_hash), ":", session_id].concat()
+}
+
+pub fn ws_ticket_key(ticket: &str) -> String {
+    let ticket_hash = digest(ticket);
+    ["WS_TICKET:", &tag(&ticket_hash), ":", ticket].concat()
+}
+
+/// Authenticated sessions of a DID, lives in the bucket of the DID
+pub fn did_sessions_key(did_hash: &str) -> String {
+    ["SESSIONS:", &tag(did_hash), ":", did_hash].concat()
+}
+
+/// Global counters that live in the same slot as the session
+pub fn session_global_key(session_id: &str) -> String {
+    global_key(&digest(session_id))
+}
+
+/// Replay record for a (sender, message id) pair
+pub fn replay_message_key(sender: &str, msg_id: &str) -> String {
+    let hash = digest([sender, ":", msg_id].concat());
+    ["REPLAY:", &tag(&hash), ":MSG:", &hash].concat()
+}
+
+/// Replay record for a message envelope
+pub fn replay_envelope_key(envelope_hash: &str) -> String {
+    ["REPLAY:", &tag(envelope_hash), ":ENV:", envelope_hash].concat()
+}
+
+/// Live streaming connections of a DID, a DID can be connected from multiple devices
+pub fn streaming_did_key(did_hash: &str) -> String {
+    ["STREAMING_DID:", STREAMING_TAG, ":", did_hash].concat()
+}
+
+pub fn streaming_sessions_key(uuid: &str) -> String {
+    ["STREAMING_SESSIONS:", STREAMING_TAG, ":", uuid].concat()
+}
+
+/// Heartbeat of a streaming service, set with a TTL
+pub fn streaming_node_key(uuid: &str) -> String {
+    ["STREAMING_NODE:", STREAMING_TAG, ":", uuid].concat()
+}
+
+pub fn streaming_nodes_key() -> String {
+    ["STREAMING_NODES:", STREAMING_TAG].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE_HASH: &str = "9c5fa0e6a3ba3fd6b1b3e2e9c43ce7d2ecfcdd43f5e63f3a8d87e8cb9bb64e1b";
+
+    #[test]
+    fn shard_uses_hash_prefix() {
+        assert_eq!(shard(ALICE_HASH), "9c");
+        assert_eq!(shard("AB12"), "ab");
+    }
+
+    #[test]
+    fn shard_hashes_non_hex_input() {
+        let bucket = shard("zz-not-a-hash");
+        assert_eq!(bucket, digest("zz-not-a-hash")[..2]);
+        assert_eq!(shard("a"), digest("a")[..2]);
+    }
+
+    #[test]
+    fn all_shards_cover_every_bucket() {
+        let shards: Vec<String> = all_shards().collect();
+        assert_eq!(shards.len(), SHARD_BUCKETS);
+        assert_eq!(shards.first().map(String::as_str), Some("00"));
+        assert_eq!(shards.last().map(String::as_str), Some("ff"));
+    }
+
+    #[test]
+    fn keys_of_a_did_share_the_same_tag() {
+        assert_eq!(tag(ALICE_HASH), "{9c}");
+        for key in [
+            receive_queue_key(ALICE_HASH),
+            send_queue_key(ALICE_HASH),
+            did_key(ALICE_HASH),
+            message_key(ALICE_HASH, "msg_id"),
+            message_meta_key(ALICE_HASH, "msg_id"),
+            global_key(ALICE_HASH),
+            did_sessions_key(ALICE_HASH),
+            accounts_key(ALICE_HASH),
+            blob_owner_key(ALICE_HASH),
+            oob_index_key(ALICE_HASH),
+        ] {
+            assert!(key.contains("{9c}"), "{} isn't in the DID's slot", key);
+        }
+    }
+
+    #[test]
+    fn session_counters_share_the_session_tag() {
+        let session = session_key("session-1");
+        let global = session_global_key("session-1");
+        assert_eq!(
+            &global["GLOBAL:".len()..],
+            &session["SESSION:".len()..session.find('}').unwrap() + 1]
+        );
+    }
+
+    #[test]
+    fn did_hash_is_namespaced_by_tenant() {
+        assert_eq!(
+            did_hash("", "did:example:alice"),
+            digest("did:example:alice")
+        );
+        assert_eq!(
+            did_hash("tenant", "did:example:alice"),
+            digest("tenant:did:example:alice")
+        );
+        assert_ne!(
+            did_hash("tenant", "did:example:alice"),
+            did_hash("", "did:example:alice")
+        );
+    }
+}
@@ -1,143 +1,356 @@
-use affinidi_messaging_sdk::messages::{Folder, MessageList, MessageListElement};
-use itertools::Itertools;
-use redis::{from_redis_value, Value};
+use affinidi_messaging_sdk::messages::{
+    list::ListOptions, Folder, ListMessagesResponse, MessageListElement,
+};
+use redis::from_redis_value;
 use tracing::{event, span, Instrument, Level};
 
 use crate::common::errors::MediatorError;
 
-use super::DatabaseHandler;
+use super::{keys, DatabaseHandler};
+
+/// Maximum number of stream entries scanned per page, as a multiple of the page limit
+/// Stops a restrictive filter from scanning an entire folder in a single request
+const SCAN_FACTOR: u32 = 10;
 
 impl DatabaseHandler {
-    /// Retrieves list of messages for the specified DID and folder
+    /// Retrieves a page of messages for the specified DID and folder
     /// The folder can be either Inbox or Outbox
     /// - did_hash: The DID sha256 hash to retrieve messages for
-    /// - range: stream ID range to retrieve (defaults to '-' and '+' which gets all messages)
+    /// - namespace: tenant namespace of the DID
+    /// - options: cursor, time range and filters to apply (the options limit is ignored)
+    /// - limit: maximum number of messages to return
+    ///
+    /// The returned next_cursor is set if there may be more messages to retrieve
     pub async fn list_messages(
         &self,
         did_hash: &str,
+        namespace: &str,
         folder: Folder,
-        range: Option<(&str, &str)>,
+        options: &ListOptions,
         limit: u32,
-    ) -> Result<MessageList, MediatorError> {
+    ) -> Result<ListMessagesResponse, MediatorError> {
         let _span = span!(
             Level::DEBUG,
             "list_messages",
             did_hash = did_hash,
             folder = format!("{:?}", folder),
-            range = format!("{:?}", range)
+            options = format!("{:?}", options)
         );
         async move {
             let mut conn = self.get_async_connection().await?;
 
             let key = match folder {
-                Folder::Inbox => format!("RECEIVE_Q:{}", did_hash),
-                Folder::Outbox => format!("SEND_Q:{}", did_hash),
+                Folder::Inbox => keys::receive_queue_key(did_hash),
+                Folder::Outbox => keys::send_queue_key(did_hash),
             };
 
-            let (start, end) = if let Some((start, end)) = range {
-                (start, end)
-            } else {
-                ("-", "+")
-            };
+            let (mut start, end) = _stream_range(options)
+                .map_err(|err| MediatorError::RequestDataError(did_hash.into(), err))?;
 
-            let db_response: Value = deadpool_redis::redis::cmd("XRANGE")
-                .arg(&key)
-                .arg(start)
-                .arg(end)
-                .arg("COUNT")
-                .arg(limit)
-                .query_async(&mut conn)
-                .await
-                .map_err(|err| {
-                    event!(
-                        Level::ERROR,
-                        "Couldn't get message_list({}) from database for DID_hash {}: {}",
-                        key,
-                        did_hash,
-                        err
-                    );
-                    MediatorError::DatabaseError(
-                        did_hash.into(),
-                        format!(
-                            "Couldn't get message_list({}) from database for DID_hash {}: {}",
-                            key, did_hash, err
-                        ),
-                    )
-                })?;
-
-            // The following should really be a Impl FromRedisValue for MessageList
-            // But I don't want to poison the affinidi-messaging-sdk crate with Redis and internal details
-            // So I'm going to manually parse the response here
-            // We could have an internal/external SDK - but that's a lot of work
-            let mut messages: MessageList = Vec::new();
-
-            // Redis response is
-            // Bulk([bulk(string(id), bulk(string(field), string(field))])
-
-            fn _error<T>(e: T, did: &str, key: &str) -> MediatorError
-            where
-                T: std::fmt::Display,
-            {
-                event!(
-                    Level::ERROR,
-                    "Couldn't parse message_list did({}) folder({}): {}",
-                    did,
-                    key,
-                    e
-                );
-                MediatorError::DatabaseError(
-                    "NA".into(),
-                    format!(
-                        "Couldn't parse message_list did({}) folder({}): {}",
-                        did, key, e
-                    ),
-                )
-            }
+            let mut response = ListMessagesResponse::default();
+            let mut scanned: u32 = 0;
+
+            loop {
+                // Entries are [stream_id, [field, value, ...]]
+                let items: Vec<(String, Vec<(String, String)>)> =
+                    deadpool_redis::redis::cmd("XRANGE")
+                        .arg(&key)
+                        .arg(&start)
+                        .arg(&end)
+                        .arg("COUNT")
+                        .arg(limit)
+                        .query_async(&mut conn)
+                        .await
+                        .map_err(|err| {
+                            let message = format!(
+                                "Couldn't get message_list({}) from database for DID_hash {}: {}",
+                                key, did_hash, err
+                            );
+                            event!(Level::ERROR, "{}", message);
+                            MediatorError::DatabaseError(did_hash.into(), message)
+                        })?;
 
-            let items: Vec<Value> =
-                from_redis_value(&db_response).map_err(|e| _error(e, did_hash, &key))?;
+                let exhausted = items.len() < limit as usize;
+                scanned += items.len() as u32;
+                if let Some((last_id, _)) = items.last() {
+                    start = ["(", last_id].concat();
+                }
 
-            for item in items {
-                // item = Bulk(string(id), Bulk(fields...))
-                let item: Vec<Value> = from_redis_value(&item).unwrap();
-                let mut msg_element = MessageListElement::default();
+                let mut candidates: Vec<MessageListElement> = items
+                    .into_iter()
+                    .map(|(stream_id, fields)| _list_element(&folder, &stream_id, fields))
+                    .filter(|element| _matches(&folder, element, options))
+                    .collect();
 
-                let stream_id: String =
-                    from_redis_value(&item[0]).map_err(|e| _error(e, did_hash, &key))?;
-                match folder {
-                    Folder::Inbox => {
-                        msg_element.receive_id = Some(stream_id.clone());
-                    }
-                    Folder::Outbox => {
-                        msg_element.send_id = Some(stream_id.clone());
-                    }
+                if let (Folder::Outbox, Some(true)) = (&folder, options.unfetched) {
+                    candidates = self._unfetched(did_hash, namespace, candidates).await?;
                 }
 
-                msg_element.timestamp = stream_id
-                    .split('-')
-                    .next()
-                    .unwrap_or("")
-                    .parse()
-                    .unwrap_or(0);
-
-                let fields: Vec<String> =
-                    from_redis_value(&item[1]).map_err(|e| _error(e, did_hash, &key))?;
-
-                for (k, v) in fields.iter().tuples() {
-                    match k.as_str() {
-                        "MSG_ID" => msg_element.msg_id.clone_from(v),
-                        "BYTES" => msg_element.size = v.parse().unwrap_or(0),
-                        "FROM" => msg_element.from_address = Some(v.clone()),
-                        "TO" => msg_element.to_address = Some(v.clone()),
-                        _ => {}
+                for element in candidates {
+                    let stream_id = element.send_id.clone().or(element.receive_id.clone());
+                    response.messages.push(element);
+
+                    if response.messages.len() >= limit as usize {
+                        // Page is full, continue from this message next time
+                        response.next_cursor = stream_id;
+                        return Ok(response);
                     }
                 }
-                messages.push(msg_element);
-            }
 
-            Ok(messages)
+                if exhausted {
+                    return Ok(response);
+                }
+
+                if scanned >= limit.saturating_mul(SCAN_FACTOR) {
+                    // Scan limit reached, the caller continues from the last scanned message
+                    response.next_cursor = start.strip_prefix('(').map(|s| s.to_string());
+                    return Ok(response);
+                }
+            }
         }
         .instrument(_span)
         .await
     }
+
+    /// Keeps only the outbox messages that the recipient hasn't fetched yet
+    /// Messages live in the recipient slot, so each message is checked individually
+    async fn _unfetched(
+        &self,
+        did_hash: &str,
+        namespace: &str,
+        elements: Vec<MessageListElement>,
+    ) -> Result<Vec<MessageListElement>, MediatorError> {
+        if elements.is_empty() {
+            return Ok(elements);
+        }
+
+        let mut conn = self.get_async_connection().await?;
+        let cmds = elements
+            .iter()
+            .map(|element| {
+                let to_hash =
+                    keys::did_hash(namespace, element.to_address.as_deref().unwrap_or_default());
+                let mut cmd = deadpool_redis::redis::cmd("HEXISTS");
+                cmd.arg(keys::message_meta_key(&to_hash, &element.msg_id))
+                    .arg("FETCHED");
+                cmd
+            })
+            .collect();
+
+        let fetched = self.query_batch(&mut conn, cmds).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't check fetched messages for DID_hash {}: {}",
+                did_hash,
+                err
+            );
+            MediatorError::DatabaseError(
+                did_hash.into(),
+                format!(
+                    "Couldn't check fetched messages for DID_hash {}: {}",
+                    did_hash, err
+                ),
+            )
+        })?;
+
+        Ok(elements
+            .into_iter()
+            .zip(fetched)
+            .filter_map(|(element, fetched)| {
+                (!from_redis_value::<bool>(&fetched).unwrap_or(false)).then_some(element)
+            })
+            .collect())
+    }
+}
+
+/// Maps the cursor and time range onto a stream range (start, end)
+/// Stream ID's start with the time in milliseconds, so the time range maps onto the stream range
+/// A cursor is exclusive, as it is the last stream ID of the previous page
+fn _stream_range(options: &ListOptions) -> Result<(String, String), String> {
+    let start = if let Some(cursor) = &options.cursor {
+        if !valid_stream_id(cursor) {
+            return Err(format!("Invalid cursor ({})", cursor));
+        }
+        ["(", cursor].concat()
+    } else if let Some(start_time) = options.start_time {
+        start_time.to_string()
+    } else {
+        "-".to_string()
+    };
+    let end = if let Some(end_time) = options.end_time {
+        end_time.to_string()
+    } else {
+        "+".to_string()
+    };
+
+    Ok((start, end))
+}
+
+/// Stream ID's are `<milliseconds>-<sequence>`, the sequence is optional
+pub(super) fn valid_stream_id(id: &str) -> bool {
+    let mut parts = id.splitn(2, '-');
+    let valid = |part: &str| !part.is_empty() && part.parse::<u64>().is_ok();
+    parts.next().is_some_and(valid) && parts.next().is_none_or(valid)
+}
+
+/// Converts a stream entry into a MessageListElement
+fn _list_element(
+    folder: &Folder,
+    stream_id: &str,
+    fields: Vec<(String, String)>,
+) -> MessageListElement {
+    let mut msg_element = MessageListElement::default();
+
+    match folder {
+        Folder::Inbox => {
+            msg_element.receive_id = Some(stream_id.to_string());
+        }
+        Folder::Outbox => {
+            msg_element.send_id = Some(stream_id.to_string());
+        }
+    }
+
+    msg_element.timestamp = stream_id
+        .split('-')
+        .next()
+        .unwrap_or("")
+        .parse()
+        .unwrap_or(0);
+
+    for (k, v) in fields {
+        match k.as_str() {
+            "MSG_ID" => msg_element.msg_id = v,
+            "BYTES" => msg_element.size = v.parse().unwrap_or(0),
+            "FROM" => msg_element.from_address = Some(v),
+            "TO" => msg_element.to_address = Some(v),
+            _ => {}
+        }
+    }
+
+    msg_element
+}
+
+/// Does the message match the list filters?
+fn _matches(folder: &Folder, element: &MessageListElement, options: &ListOptions) -> bool {
+    if let (Folder::Inbox, Some(from_did)) = (folder, &options.from_did) {
+        if element.from_address.as_ref() != Some(from_did) {
+            return false;
+        }
+    }
+    if options.min_size.is_some_and(|min| element.size < min) {
+        return false;
+    }
+    if options.max_size.is_some_and(|max| element.size > max) {
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_range_defaults_to_whole_stream() {
+        let range = _stream_range(&ListOptions::default()).unwrap();
+        assert_eq!(range, ("-".to_string(), "+".to_string()));
+    }
+
+    #[test]
+    fn stream_range_uses_time_range() {
+        let options = ListOptions {
+            start_time: Some(1000),
+            end_time: Some(2000),
+            ..Default::default()
+        };
+        let range = _stream_range(&options).unwrap();
+        assert_eq!(range, ("1000".to_string(), "2000".to_string()));
+    }
+
+    #[test]
+    fn stream_range_cursor_is_exclusive_and_wins_over_start_time() {
+        let options = ListOptions {
+            cursor: Some("1700000000000-3".into()),
+            start_time: Some(1000),
+            ..Default::default()
+        };
+        let (start, end) = _stream_range(&options).unwrap();
+        assert_eq!(start, "(1700000000000-3");
+        assert_eq!(end, "+");
+    }
+
+    #[test]
+    fn stream_range_rejects_invalid_cursor() {
+        for cursor in ["", "abc", "-1", "1-", "1-2-3", "1-x", "(1-0"] {
+            let options = ListOptions {
+                cursor: Some(cursor.into()),
+                ..Default::default()
+            };
+            assert!(_stream_range(&options).is_err(), "cursor({})", cursor);
+        }
+        assert!(valid_stream_id("1700000000000"));
+        assert!(valid_stream_id("1700000000000-0"));
+    }
+
+    #[test]
+    fn list_element_reads_stream_entry() {
+        let element = _list_element(
+            &Folder::Inbox,
+            "1700000000000-1",
+            vec![
+                ("MSG_ID".into(), "abc".into()),
+                ("BYTES".into(), "42".into()),
+                ("FROM".into(), "did:example:alice".into()),
+            ],
+        );
+        assert_eq!(element.receive_id.as_deref(), Some("1700000000000-1"));
+        assert_eq!(element.send_id, None);
+        assert_eq!(element.timestamp, 1700000000000);
+        assert_eq!(element.msg_id, "abc");
+        assert_eq!(element.size, 42);
+        assert_eq!(element.from_address.as_deref(), Some("did:example:alice"));
+    }
+
+    #[test]
+    fn matches_filters() {
+        let element = MessageListElement {
+            from_address: Some("did:example:alice".into()),
+            size: 100,
+            ..Default::default()
+        };
+        let options = |from_did: Option<&str>, min_size, max_size| ListOptions {
+            from_did: from_did.map(|did| did.to_string()),
+            min_size,
+            max_size,
+            ..Default::default()
+        };
+
+        assert!(_matches(&Folder::Inbox, &element, &ListOptions::default()));
+        assert!(_matches(
+            &Folder::Inbox,
+            &element,
+            &options(Some("did:example:alice"), Some(100), Some(100))
+        ));
+        assert!(!_matches(
+            &Folder::Inbox,
+            &element,
+            &options(Some("did:example:bob"), None, None)
+        ));
+        // from_did only applies to the Inbox
+        assert!(_matches(
+            &Folder::Outbox,
+            &element,
+            &options(Some("did:example:bob"), None, None)
+        ));
+        assert!(!_matches(
+            &Folder::Inbox,
+            &element,
+            &options(None, Some(101), None)
+        ));
+        assert!(!_matches(
+            &Folder::Inbox,
+            &element,
+            &options(None, None, Some(99))
+        ));
+    }
 }
@@ -0,0 +1,299 @@
+use super::{keys, list::valid_stream_id, DatabaseHandler};
+use crate::common::{errors::MediatorError, tier::MailboxTier};
+use affinidi_messaging_sdk::messages::{ArchivedMessage, ArchivedSentMessage};
+use redis::{from_redis_value, Value};
+use sha256::digest;
+use tracing::{debug, event, Level};
+
+/// Number of stream entries read per page when exporting a mailbox
+const EXPORT_PAGE_SIZE: usize = 100;
+
+impl DatabaseHandler {
+    /// Reads a page of the inbox of a DID for a mailbox archive
+    /// - cursor: Stream ID of the last message of the previous page, None for the first page
+    /// - max_bytes: Size of the messages in a page, a page holds at least one message
+    ///
+    /// Returns the messages and the cursor of the next page (None if this is the last page)
+    pub async fn mailbox_export_inbox(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        cursor: Option<&str>,
+        max_bytes: usize,
+    ) -> Result<(Vec<ArchivedMessage>, Option<String>), MediatorError> {
+        let mut start = match cursor {
+            Some(cursor) if valid_stream_id(cursor) => ["(", cursor].concat(),
+            Some(cursor) => {
+                return Err(MediatorError::RequestDataError(
+                    session_id.into(),
+                    format!("Invalid cursor ({})", cursor),
+                ))
+            }
+            None => "-".to_string(),
+        };
+
+        let mut conn = self.get_async_connection().await?;
+        let mut inbox = Vec::new();
+        let mut bytes = 0;
+
+        loop {
+            let items = self
+                ._export_page(session_id, &keys::receive_queue_key(did_hash), &start)
+                .await?;
+            let exhausted = items.len() < EXPORT_PAGE_SIZE;
+
+            // Messages and their timestamps are in the same slot as the RECEIVE_Q
+            let mut pipe = deadpool_redis::redis::pipe();
+            let mut elements = Vec::new();
+            for (stream_id, fields) in items {
+                let mut element = ArchivedMessage::default();
+                for (k, v) in fields {
+                    match k.as_str() {
+                        "MSG_ID" => element.msg_id = v,
+                        "FROM" if v != "ANONYMOUS" => element.from_address = Some(v),
+                        _ => {}
+                    }
+                }
+                // Stream IDs start with the time in milliseconds
+                element.timestamp = stream_id
+                    .split('-')
+                    .next()
+                    .and_then(|t| t.parse().ok())
+                    .unwrap_or(0);
+                pipe.cmd("GET")
+                    .arg(keys::message_key(did_hash, &element.msg_id))
+                    .cmd("HGET")
+                    .arg(keys::message_meta_key(did_hash, &element.msg_id))
+                    .arg("TIMESTAMP");
+                elements.push((stream_id, element));
+            }
+
+            if elements.is_empty() {
+                return Ok((inbox, None));
+            }
+
+            let results: Vec<Value> = pipe.query_async(&mut conn).await.map_err(|err| {
+                event!(Level::ERROR, "Couldn't export inbox messages: {}", err);
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't export inbox messages: {}", err),
+                )
+            })?;
+
+            for ((stream_id, mut element), result) in elements.into_iter().zip(results.chunks(2)) {
+                match from_redis_value::<Option<String>>(&result[0]) {
+                    Ok(Some(msg)) => element.msg = msg,
+                    _ => {
+                        debug!("msg_id({}) is missing, not exported", element.msg_id);
+                        start = ["(", &stream_id].concat();
+                        continue;
+                    }
+                }
+                if !inbox.is_empty() && bytes + element.msg.len() > max_bytes {
+                    // The page is full, the next page starts after the last exported message
+                    return Ok((inbox, Some(start[1..].to_string())));
+                }
+                if let Ok(Some(timestamp)) = from_redis_value::<Option<u64>>(&result[1]) {
+                    element.timestamp = timestamp;
+                }
+                bytes += element.msg.len();
+                inbox.push(element);
+                start = ["(", &stream_id].concat();
+            }
+
+            if exhausted {
+                return Ok((inbox, None));
+            }
+        }
+    }
+
+    /// Reads a page of the outbox of a DID for a mailbox archive
+    /// - cursor: Stream ID of the last record of the previous page, None to start at the first record
+    /// - max_bytes: Size of the records in a page, a page holds at least one record
+    ///
+    /// Returns the records and the cursor of the next page (None if this is the last page)
+    pub async fn mailbox_export_outbox(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        cursor: Option<&str>,
+        max_bytes: usize,
+    ) -> Result<(Vec<ArchivedSentMessage>, Option<String>), MediatorError> {
+        let mut start = match cursor {
+            Some(cursor) if valid_stream_id(cursor) => ["(", cursor].concat(),
+            Some(cursor) => {
+                return Err(MediatorError::RequestDataError(
+                    session_id.into(),
+                    format!("Invalid cursor ({})", cursor),
+                ))
+            }
+            None => "-".to_string(),
+        };
+
+        let mut outbox = Vec::new();
+        let mut bytes = 0;
+
+        loop {
+            let items = self
+                ._export_page(session_id, &keys::send_queue_key(did_hash), &start)
+                .await?;
+            let exhausted = items.len() < EXPORT_PAGE_SIZE;
+
+            for (stream_id, fields) in items {
+                let mut element = ArchivedSentMessage {
+                    // Stream IDs start with the time in milliseconds
+                    timestamp: stream_id
+                        .split('-')
+                        .next()
+                        .and_then(|t| t.parse().ok())
+                        .unwrap_or(0),
+                    ..Default::default()
+                };
+                for (k, v) in fields {
+                    match k.as_str() {
+                        "MSG_ID" => element.msg_id = v,
+                        "TO" => element.to_address = v,
+                        "BYTES" => element.size = v.parse().unwrap_or(0),
+                        _ => {}
+                    }
+                }
+
+                // Records only hold IDs, their size in the archive is estimated
+                let len = element.msg_id.len() + element.to_address.len() + 64;
+                if !outbox.is_empty() && bytes + len > max_bytes {
+                    // The page is full, the next page starts after the last exported record
+                    return Ok((outbox, Some(start[1..].to_string())));
+                }
+                bytes += len;
+                outbox.push(element);
+                start = ["(", &stream_id].concat();
+            }
+
+            if exhausted {
+                return Ok((outbox, None));
+            }
+        }
+    }
+
+    /// Queues an inbox message from a mailbox archive
+    /// Returns false if the message was already queued for the DID
+    pub async fn mailbox_import_message(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        message: &ArchivedMessage,
+        tier: &MailboxTier,
+    ) -> Result<bool, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let imported: i64 = deadpool_redis::redis::cmd("FCALL")
+            .arg("import_message")
+            .arg(7)
+            .arg(keys::message_key(did_hash, &message.msg_id))
+            .arg(keys::message_meta_key(did_hash, &message.msg_id))
+            .arg(keys::receive_queue_key(did_hash))
+            .arg(keys::did_key(did_hash))
+            .arg(keys::global_key(did_hash))
+            .arg(keys::message_expiry_key(did_hash))
+            .arg(keys::content_index_key(did_hash))
+            .arg(&message.msg)
+            .arg(message.msg.len())
+            .arg(did_hash)
+            .arg(&message.msg_id)
+            .arg(digest(message.msg.as_bytes()))
+            .arg(message.timestamp)
+            .arg(message.from_address.as_deref().unwrap_or("ANONYMOUS"))
+            .arg(tier.max_queued_messages)
+            .arg(tier.max_queued_bytes)
+            .arg(tier.message_expiry_minutes as u64 * 60_000)
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                if err.to_string().contains("QUOTA_EXCEEDED") {
+                    return MediatorError::ServiceLimitError(
+                        session_id.into(),
+                        format!("Mailbox quota exceeded ({})", tier.name),
+                    );
+                }
+                event!(
+                    Level::ERROR,
+                    "Couldn't import msg_id({}): {}",
+                    message.msg_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't import message: {}", err),
+                )
+            })?;
+
+        Ok(imported == 1)
+    }
+
+    /// Recreates an outbox record from a mailbox archive
+    /// - to_hash: DID hash of the recipient on this mediator
+    ///
+    /// Returns false if the record already existed (same msg_id)
+    pub async fn mailbox_import_sent(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        message: &ArchivedSentMessage,
+        to_hash: &str,
+    ) -> Result<bool, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let imported: i64 = deadpool_redis::redis::cmd("FCALL")
+            .arg("import_message_sender")
+            .arg(3)
+            .arg(keys::send_queue_key(did_hash))
+            .arg(keys::did_key(did_hash))
+            .arg(keys::send_index_key(did_hash))
+            .arg(&message.msg_id)
+            .arg(message.size)
+            .arg(&message.to_address)
+            .arg(to_hash)
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't import outbox msg_id({}): {}",
+                    message.msg_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't import outbox record: {}", err),
+                )
+            })?;
+
+        Ok(imported == 1)
+    }
+
+    /// Reads a page of stream entries after `start` (exclusive cursor or `-`)
+    async fn _export_page(
+        &self,
+        session_id: &str,
+        key: &str,
+        start: &str,
+    ) -> Result<Vec<(String, Vec<(String, String)>)>, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("XRANGE")
+            .arg(key)
+            .arg(start)
+            .arg("+")
+            .arg("COUNT")
+            .arg(EXPORT_PAGE_SIZE)
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(Level::ERROR, "Couldn't read stream({}): {}", key, err);
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't read stream({}): {}", key, err),
+                )
+            })
+    }
+}
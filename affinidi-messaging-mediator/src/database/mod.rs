@@ -1,14 +1,28 @@
+use crate::common::tier::Tiers;
+
+pub mod account;
+pub mod admin;
+pub mod blobs;
 pub mod delete;
 pub mod fetch;
+pub mod forward;
 pub mod get;
 pub mod handlers;
+pub mod keys;
 pub mod list;
+pub mod mailbox;
+pub mod oob;
+pub mod push;
+pub mod receipts;
+pub mod replay;
 pub mod session;
 pub mod stats;
 pub mod store;
 pub mod streaming;
+pub mod tiers;
 #[derive(Clone)]
 pub struct DatabaseHandler {
-    pub pool: deadpool_redis::Pool,
+    pub pool: handlers::DatabasePool,
     redis_url: String,
+    tiers: Tiers,
 }
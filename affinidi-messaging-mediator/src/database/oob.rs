@@ -0,0 +1,247 @@
+use super::{keys, DatabaseHandler};
+use crate::common::errors::MediatorError;
+use std::collections::HashMap;
+use tracing::{debug, event, warn, Level};
+
+impl DatabaseHandler {
+    /// Stores a hosted Out-of-Band invitation, Redis removes it once it expires
+    /// - did_hash: DID hash of the inviter, only the inviter can delete the invitation
+    /// - invitation: The invitation message (JSON)
+    /// - expiry: Time in seconds before the invitation expires
+    /// - max_invitations: Maximum number of invitations that the DID can host at the same time
+    pub async fn oob_invite_store(
+        &self,
+        session_id: &str,
+        oobid: &str,
+        did_hash: &str,
+        invitation: &str,
+        expiry: u64,
+        max_invitations: usize,
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        // The maximum is checked and the invitation added to the DID's index in a single function call
+        // The invitation itself lives in the slot of the oobid, and is stored afterwards
+        deadpool_redis::redis::cmd("FCALL")
+            .arg("oob_register")
+            .arg(1)
+            .arg(keys::oob_index_key(did_hash))
+            .arg(oobid)
+            .arg(expiry)
+            .arg(max_invitations)
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                if err.to_string().contains("QUOTA_EXCEEDED") {
+                    return MediatorError::ServiceLimitError(
+                        session_id.into(),
+                        format!(
+                            "Maximum number of hosted invitations ({}) reached",
+                            max_invitations
+                        ),
+                    );
+                }
+                event!(
+                    Level::ERROR,
+                    "Couldn't register OOB invitation({}): {}",
+                    oobid,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't register OOB invitation({}): {}", oobid, err),
+                )
+            })?;
+
+        let key = keys::oob_invite_key(oobid);
+
+        if let Err(err) = deadpool_redis::redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("did_hash")
+            .arg(did_hash)
+            .arg("invitation")
+            .arg(invitation)
+            .expire(&key, expiry as i64)
+            .query_async::<()>(&mut con)
+            .await
+        {
+            event!(
+                Level::ERROR,
+                "Couldn't store OOB invitation({}): {}",
+                oobid,
+                err
+            );
+            self._oob_index_remove(did_hash, oobid).await;
+            return Err(MediatorError::DatabaseError(
+                session_id.into(),
+                format!("Couldn't store OOB invitation({}): {}", oobid, err),
+            ));
+        }
+
+        debug!(
+            "OOB invitation({}) stored, expires in ({}) seconds",
+            oobid, expiry
+        );
+        Ok(())
+    }
+
+    /// Retrieves a hosted Out-of-Band invitation
+    /// Returns (did_hash, invitation) of the invitation, None if it doesn't exist or has expired
+    pub async fn oob_invite_get(
+        &self,
+        session_id: &str,
+        oobid: &str,
+    ) -> Result<Option<(String, String)>, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let mut fields: HashMap<String, String> = deadpool_redis::redis::cmd("HGETALL")
+            .arg(keys::oob_invite_key(oobid))
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get OOB invitation({}): {}",
+                    oobid,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't get OOB invitation({}): {}", oobid, err),
+                )
+            })?;
+
+        match (fields.remove("did_hash"), fields.remove("invitation")) {
+            (Some(did_hash), Some(invitation)) => Ok(Some((did_hash, invitation))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Removes a hosted Out-of-Band invitation
+    /// - did_hash: DID hash of the inviter
+    pub async fn oob_invite_delete(
+        &self,
+        session_id: &str,
+        oobid: &str,
+        did_hash: &str,
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        deadpool_redis::redis::cmd("DEL")
+            .arg(keys::oob_invite_key(oobid))
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't delete OOB invitation({}): {}",
+                    oobid,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't delete OOB invitation({}): {}", oobid, err),
+                )
+            })?;
+
+        self._oob_index_remove(did_hash, oobid).await;
+
+        debug!("OOB invitation({}) deleted", oobid);
+        Ok(())
+    }
+
+    /// Removes all hosted Out-of-Band invitations of a DID
+    /// Returns the number of invitations that still existed
+    pub async fn oob_invite_delete_all(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+    ) -> Result<usize, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let oobids: Vec<String> = deadpool_redis::redis::cmd("ZRANGE")
+            .arg(keys::oob_index_key(did_hash))
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get OOB invitations of did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't get OOB invitations: {}", err),
+                )
+            })?;
+
+        // Each invitation lives in the slot of its oobid
+        let mut deleted = 0;
+        for oobid in &oobids {
+            let removed: usize = deadpool_redis::redis::cmd("DEL")
+                .arg(keys::oob_invite_key(oobid))
+                .query_async(&mut con)
+                .await
+                .map_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        "Couldn't delete OOB invitation({}): {}",
+                        oobid,
+                        err
+                    );
+                    MediatorError::DatabaseError(
+                        session_id.into(),
+                        format!("Couldn't delete OOB invitation({}): {}", oobid, err),
+                    )
+                })?;
+            deleted += removed;
+        }
+
+        deadpool_redis::redis::cmd("DEL")
+            .arg(keys::oob_index_key(did_hash))
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't delete OOB index of did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't delete OOB index: {}", err),
+                )
+            })?;
+
+        debug!(
+            "deleted ({}) OOB invitations of did_hash({})",
+            deleted, did_hash
+        );
+        Ok(deleted)
+    }
+
+    /// Removes an invitation from the index of the inviter, the index counts invitations and is used to
+    /// erase them, a failure is logged and otherwise ignored (the entry is removed once it expires)
+    async fn _oob_index_remove(&self, did_hash: &str, oobid: &str) {
+        let Ok(mut con) = self.get_async_connection().await else {
+            return;
+        };
+        if let Err(err) = deadpool_redis::redis::cmd("ZREM")
+            .arg(keys::oob_index_key(did_hash))
+            .arg(oobid)
+            .query_async::<()>(&mut con)
+            .await
+        {
+            warn!(
+                "Couldn't remove OOB invitation({}) from the index of did_hash({}): {}",
+                oobid, did_hash, err
+            );
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use super::{keys, DatabaseHandler};
+use crate::common::errors::MediatorError;
+use affinidi_messaging_sdk::protocols::push::{PushEndpoint, PushFormat};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+/// Push endpoint registered by a DID (stored in `PUSH:{xx}:<did_hash>`)
+/// - id: SHA256 hash of the endpoint and token, registering the same endpoint again replaces it
+/// - authorization: Value of the `Authorization` header sent to the endpoint, sealed with the push `SecretBox`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredPushRegistration {
+    pub id: String,
+    pub endpoint: String,
+    pub format: PushFormat,
+    pub token: Option<String>,
+    pub authorization: Option<String>,
+}
+
+impl From<&StoredPushRegistration> for PushEndpoint {
+    fn from(registration: &StoredPushRegistration) -> Self {
+        PushEndpoint {
+            id: registration.id.clone(),
+            endpoint: registration.endpoint.clone(),
+            format: registration.format,
+        }
+    }
+}
+
+impl DatabaseHandler {
+    /// Adds (or replaces) a push endpoint for a DID
+    /// - max_registrations: Maximum number of push endpoints a DID can have
+    pub async fn push_register(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        registration: &StoredPushRegistration,
+        max_registrations: usize,
+    ) -> Result<(), MediatorError> {
+        let value = serde_json::to_string(registration).map_err(|err| {
+            MediatorError::InternalError(
+                session_id.into(),
+                format!("Couldn't serialize push registration: {}", err),
+            )
+        })?;
+
+        // The maximum is checked and the registration stored in a single function call
+        let mut conn = self.get_async_connection().await?;
+        deadpool_redis::redis::cmd("FCALL")
+            .arg("push_register")
+            .arg(1)
+            .arg(keys::push_key(did_hash))
+            .arg(&registration.id)
+            .arg(value)
+            .arg(max_registrations)
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|err| {
+                if err.to_string().contains("QUOTA_EXCEEDED") {
+                    return MediatorError::RequestDataError(
+                        session_id.into(),
+                        format!(
+                            "Maximum number of push endpoints ({}) already registered",
+                            max_registrations
+                        ),
+                    );
+                }
+                event!(
+                    Level::ERROR,
+                    "Couldn't store push registration for did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't store push registration: {}", err),
+                )
+            })
+    }
+
+    /// Removes a push endpoint of a DID
+    /// - id: ID of the registration, if None then all push endpoints of the DID are removed
+    pub async fn push_unregister(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        id: Option<&str>,
+    ) -> Result<(), MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+        let cmd = match id {
+            Some(id) => {
+                let mut cmd = deadpool_redis::redis::cmd("HDEL");
+                cmd.arg(keys::push_key(did_hash)).arg(id);
+                cmd
+            }
+            None => {
+                let mut cmd = deadpool_redis::redis::cmd("DEL");
+                cmd.arg(keys::push_key(did_hash));
+                cmd
+            }
+        };
+
+        cmd.query_async::<()>(&mut conn).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't remove push registration for did_hash({}): {}",
+                did_hash,
+                err
+            );
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!("Couldn't remove push registration: {}", err),
+            )
+        })
+    }
+
+    /// Returns the push endpoints of a DID
+    pub async fn push_registrations(
+        &self,
+        did_hash: &str,
+    ) -> Result<Vec<StoredPushRegistration>, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+        let registrations: Vec<String> = deadpool_redis::redis::cmd("HVALS")
+            .arg(keys::push_key(did_hash))
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get push registrations for did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    did_hash.into(),
+                    format!("Couldn't get push registrations: {}", err),
+                )
+            })?;
+
+        Ok(registrations
+            .iter()
+            .filter_map(|registration| serde_json::from_str(registration).ok())
+            .collect())
+    }
+
+    /// Claims the next push notification for a DID
+    /// Returns true if no notification was sent to the DID within the last `seconds` (across all mediators)
+    pub async fn push_rate_limit(
+        &self,
+        did_hash: &str,
+        seconds: u64,
+    ) -> Result<bool, MediatorError> {
+        if seconds == 0 {
+            return Ok(true);
+        }
+
+        let mut conn = self.get_async_connection().await?;
+        let claimed: Option<String> = deadpool_redis::redis::cmd("SET")
+            .arg(keys::push_rate_key(did_hash))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(seconds)
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't set push rate limit for did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    did_hash.into(),
+                    format!("Couldn't set push rate limit: {}", err),
+                )
+            })?;
+
+        Ok(claimed.is_some())
+    }
+}
@@ -0,0 +1,95 @@
+use super::{keys, DatabaseHandler};
+use crate::common::errors::MediatorError;
+use affinidi_messaging_sdk::protocols::receipts::ReceiptType;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+/// Receipt request that is stored with a message (RECEIPT field of the message metadata)
+/// - id: ID of the original message, set by the sender
+/// - types: Receipts that the sender asked for (already filtered by the receipts policy)
+/// - sender: DID that the receipts are sent to
+/// - recipient: DID that the message was sent to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredReceiptRequest {
+    pub id: String,
+    pub types: Vec<ReceiptType>,
+    pub sender: String,
+    pub recipient: String,
+}
+
+impl DatabaseHandler {
+    /// Stores a receipt request with a message
+    /// - to_hash: DID hash of the recipient (messages are stored in the recipient slot)
+    /// - msg_id: ATM message ID of the recipient copy
+    pub async fn set_receipt_request(
+        &self,
+        session_id: &str,
+        to_hash: &str,
+        msg_id: &str,
+        request: &StoredReceiptRequest,
+    ) -> Result<(), MediatorError> {
+        let request = serde_json::to_string(request).map_err(|err| {
+            MediatorError::InternalError(
+                session_id.into(),
+                format!("Couldn't serialize receipt request: {}", err),
+            )
+        })?;
+
+        let mut conn = self.get_async_connection().await?;
+        // Only set if the message still exists, so that the metadata isn't recreated
+        deadpool_redis::redis::cmd("HSETNX")
+            .arg(keys::message_meta_key(to_hash, msg_id))
+            .arg("RECEIPT")
+            .arg(request)
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't store receipt request for message_id({}): {}",
+                    msg_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!(
+                        "Couldn't store receipt request for message_id({}): {}",
+                        msg_id, err
+                    ),
+                )
+            })
+    }
+
+    /// Retrieves the receipt request of a message, if there is one
+    /// - to_hash: DID hash of the recipient
+    /// - msg_id: ATM message ID of the recipient copy
+    pub async fn get_receipt_request(
+        &self,
+        to_hash: &str,
+        msg_id: &str,
+    ) -> Result<Option<StoredReceiptRequest>, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+        let request: Option<String> = deadpool_redis::redis::cmd("HGET")
+            .arg(keys::message_meta_key(to_hash, msg_id))
+            .arg("RECEIPT")
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get receipt request for message_id({}): {}",
+                    msg_id,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    to_hash.into(),
+                    format!(
+                        "Couldn't get receipt request for message_id({}): {}",
+                        msg_id, err
+                    ),
+                )
+            })?;
+
+        Ok(request.and_then(|request| serde_json::from_str(&request).ok()))
+    }
+}
@@ -0,0 +1,88 @@
+use super::{keys, DatabaseHandler};
+use crate::common::errors::MediatorError;
+use redis::Value;
+use tracing::{debug, event, Level};
+
+impl DatabaseHandler {
+    /// Records an inbound message in the replay cache
+    /// - envelope_hash: sha256 hash of the message envelope as received
+    /// - sender: DID of the sender (or ANONYMOUS)
+    /// - msg_id: DIDComm message `id`
+    /// - ttl: How long in seconds to remember this message for
+    ///
+    /// Returns true if either the envelope or the (sender, msg_id) pair has already been seen
+    pub async fn replay_check(
+        &self,
+        session_id: &str,
+        envelope_hash: &str,
+        sender: &str,
+        msg_id: &str,
+        ttl: u64,
+    ) -> Result<bool, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        // Keys are in different slots, so this is not atomic
+        let cmds = [
+            keys::replay_envelope_key(envelope_hash),
+            keys::replay_message_key(sender, msg_id),
+        ]
+        .into_iter()
+        .map(|key| {
+            let mut cmd = deadpool_redis::redis::cmd("SET");
+            cmd.arg(key).arg(1).arg("NX").arg("EX").arg(ttl);
+            cmd
+        })
+        .collect();
+        let results = self.query_batch(&mut conn, cmds).await.map_err(|err| {
+            event!(Level::ERROR, "Couldn't check replay cache: {}", err);
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!("Couldn't check replay cache: {}", err),
+            )
+        })?;
+
+        // SET NX returns Nil if the key already exists
+        let replayed = results.contains(&Value::Nil);
+        if replayed {
+            debug!(
+                "replay detected: envelope({}) sender({}) msg_id({})",
+                envelope_hash, sender, msg_id
+            );
+        }
+
+        Ok(replayed)
+    }
+
+    /// Removes the replay records of an inbound message, so that a message that couldn't be
+    /// processed can be sent again
+    pub async fn replay_release(
+        &self,
+        session_id: &str,
+        envelope_hash: &str,
+        sender: &str,
+        msg_id: &str,
+    ) -> Result<(), MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let cmds = [
+            keys::replay_envelope_key(envelope_hash),
+            keys::replay_message_key(sender, msg_id),
+        ]
+        .into_iter()
+        .map(|key| {
+            let mut cmd = deadpool_redis::redis::cmd("DEL");
+            cmd.arg(key);
+            cmd
+        })
+        .collect();
+        self.query_batch(&mut conn, cmds).await.map_err(|err| {
+            event!(Level::ERROR, "Couldn't release replay records: {}", err);
+            MediatorError::DatabaseError(
+                session_id.into(),
+                format!("Couldn't release replay records: {}", err),
+            )
+        })?;
+
+        Ok(())
+    }
+}
@@ -8,7 +8,7 @@ use tracing::{debug, warn};
 
 use crate::common::errors::MediatorError;
 
-use super::DatabaseHandler;
+use super::{keys, DatabaseHandler};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SessionClaims {
@@ -16,6 +16,8 @@ pub struct SessionClaims {
     pub sub: String, // subject (DID)
     pub session_id: String,
     pub exp: u64,
+    #[serde(default)]
+    pub tenant: String, // tenant namespace
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -58,6 +60,7 @@ pub struct Session {
     pub challenge: String,
     pub state: SessionState,
     pub did: String,
+    pub tenant: String,
 }
 
 impl TryFrom<(&str, HashMap<String, String>)> for Session {
@@ -101,6 +104,11 @@ impl TryFrom<(&str, HashMap<String, String>)> for Session {
             ));
         }
 
+        // Sessions created before tenants were introduced belong to the default tenant
+        if let Some(tenant) = hash.get("tenant") {
+            session.tenant = tenant.into();
+        }
+
         Ok(session)
     }
 }
@@ -111,7 +119,7 @@ impl DatabaseHandler {
     pub async fn create_session(&self, session: &Session) -> Result<(), MediatorError> {
         let mut con = self.get_async_connection().await?;
 
-        let sid = format!("SESSION:{}", session.session_id);
+        let sid = keys::session_key(&session.session_id);
 
         deadpool_redis::redis::pipe()
             .atomic()
@@ -123,12 +131,14 @@ impl DatabaseHandler {
             .arg(session.state.to_string())
             .arg("did")
             .arg(&session.did)
+            .arg("tenant")
+            .arg(&session.tenant)
             .cmd("HINCRBY")
-            .arg("GLOBAL")
+            .arg(keys::session_global_key(&session.session_id))
             .arg("SESSIONS_CREATED")
             .arg(1)
             .expire(&sid, 900)
-            .query_async(&mut con)
+            .query_async::<()>(&mut con)
             .await
             .map_err(|err| {
                 MediatorError::SessionError(
@@ -147,7 +157,7 @@ impl DatabaseHandler {
         let mut con = self.get_async_connection().await?;
 
         let result: HashMap<String, String> = deadpool_redis::redis::cmd("HGETALL")
-            .arg(format!("SESSION:{}", session_id))
+            .arg(keys::session_key(session_id))
             .query_async(&mut con)
             .await
             .map_err(|err| {
@@ -169,24 +179,51 @@ impl DatabaseHandler {
     ) -> Result<(), MediatorError> {
         let mut con = self.get_async_connection().await?;
 
-        let old_sid = format!("SESSION:{}", old_session_id);
-        let new_sid = format!("SESSION:{}", new_session_id);
+        let old_sid = keys::session_key(old_session_id);
+        let new_sid = keys::session_key(new_session_id);
+
+        // The old and new session keys are in different slots, so the session is copied
+        // to the new key rather than using RENAME
+        let session: HashMap<String, String> = deadpool_redis::redis::cmd("HGETALL")
+            .arg(&old_sid)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                MediatorError::SessionError(
+                    old_session_id.into(),
+                    format!(
+                        "tried to retrieve session({}). Error: {}",
+                        old_session_id, err
+                    ),
+                )
+            })?;
+
+        if session.is_empty() {
+            return Err(MediatorError::SessionError(
+                old_session_id.into(),
+                format!("session({}) doesn't exist", old_session_id),
+            ));
+        }
+
+        let mut fields: Vec<(&str, &str)> = session
+            .iter()
+            .filter(|(k, _)| k.as_str() != "state")
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let state = SessionState::Authenticated.to_string();
+        fields.push(("state", &state));
 
         deadpool_redis::redis::pipe()
             .atomic()
-            .cmd("RENAME")
-            .arg(&old_sid)
-            .arg(&new_sid)
             .cmd("HSET")
             .arg(&new_sid)
-            .arg("state")
-            .arg(SessionState::Authenticated.to_string())
+            .arg(&fields)
             .cmd("HINCRBY")
-            .arg("GLOBAL")
+            .arg(keys::session_global_key(new_session_id))
             .arg("SESSIONS_SUCCESS")
             .arg(1)
             .expire(&new_sid, 86400)
-            .query_async(&mut con)
+            .query_async::<()>(&mut con)
             .await
             .map_err(|err| {
                 MediatorError::SessionError(
@@ -198,6 +235,120 @@ impl DatabaseHandler {
                 )
             })?;
 
+        // Index the session under the DID (in the DID's slot), so that an account deletion can find it
+        // The DID is also registered so that admins can list the accounts of the mediator
+        if let Some(did) = session.get("did") {
+            let tenant = session.get("tenant").map(|t| t.as_str()).unwrap_or("");
+            let did_hash = keys::did_hash(tenant, did);
+            let index = keys::did_sessions_key(&did_hash);
+            deadpool_redis::redis::pipe()
+                .atomic()
+                .cmd("SADD")
+                .arg(&index)
+                .arg(new_session_id)
+                .expire(&index, 86400)
+                .cmd("HSET")
+                .arg(keys::accounts_key(&did_hash))
+                .arg(&did_hash)
+                .arg(did)
+                .query_async::<()>(&mut con)
+                .await
+                .map_err(|err| {
+                    MediatorError::SessionError(
+                        new_session_id.into(),
+                        format!("tried to index session({}). Error: {}", new_session_id, err),
+                    )
+                })?;
+        }
+
+        deadpool_redis::redis::cmd("DEL")
+            .arg(&old_sid)
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                MediatorError::SessionError(
+                    old_session_id.into(),
+                    format!(
+                        "tried to remove old session({}). Error: {}",
+                        old_session_id, err
+                    ),
+                )
+            })?;
+
+        Ok(())
+    }
+
+    /// Stores a single use websocket ticket for an authenticated session
+    /// - expiry: Time in seconds before the ticket expires
+    pub async fn create_ws_ticket(
+        &self,
+        ticket: &str,
+        session_id: &str,
+        did: &str,
+        tenant: &str,
+        expiry: u64,
+    ) -> Result<(), MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let key = keys::ws_ticket_key(ticket);
+
+        deadpool_redis::redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("session_id")
+            .arg(session_id)
+            .arg("did")
+            .arg(did)
+            .arg("tenant")
+            .arg(tenant)
+            .expire(&key, expiry as i64)
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| {
+                MediatorError::SessionError(
+                    session_id.into(),
+                    format!("tried to create websocket ticket! Error: {}", err),
+                )
+            })?;
+
+        debug!("{}: websocket ticket created", session_id);
+
         Ok(())
     }
+
+    /// Redeems a websocket ticket, the ticket is removed so that it can only be used once
+    /// Returns (session_id, did, tenant) of the session the ticket was issued to, None if the ticket is unknown or expired
+    pub async fn redeem_ws_ticket(
+        &self,
+        ticket: &str,
+    ) -> Result<Option<(String, String, String)>, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let key = keys::ws_ticket_key(ticket);
+
+        let (mut fields, _): (HashMap<String, String>, i64) = deadpool_redis::redis::pipe()
+            .atomic()
+            .cmd("HGETALL")
+            .arg(&key)
+            .cmd("DEL")
+            .arg(&key)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                MediatorError::SessionError(
+                    "UNKNOWN".into(),
+                    format!("tried to redeem websocket ticket! Error: {}", err),
+                )
+            })?;
+
+        match (fields.remove("session_id"), fields.remove("did")) {
+            (Some(session_id), Some(did)) => Ok(Some((
+                session_id,
+                did,
+                fields.remove("tenant").unwrap_or_default(),
+            ))),
+            _ => Ok(None),
+        }
+    }
 }
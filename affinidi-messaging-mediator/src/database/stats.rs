@@ -1,7 +1,7 @@
-use super::DatabaseHandler;
+use super::{keys, DatabaseHandler};
 use crate::common::errors::MediatorError;
 use itertools::Itertools;
-use redis::{from_redis_value, Value};
+use redis::from_redis_value;
 use std::fmt::{self, Display, Formatter};
 use tracing::{debug, event, Level};
 
@@ -67,68 +67,83 @@ impl MetadataStats {
 impl DatabaseHandler {
     /// Retrieves metadata statistics that are global to the mediator database
     /// This means it may include more than this mediator's messages
+    /// Global counters are held per shard (GLOBAL:{xx}) and are aggregated here
     pub async fn get_db_metadata(&self) -> Result<MetadataStats, MediatorError> {
         let mut conn = self.get_async_connection().await?;
 
         let mut stats = MetadataStats::default();
 
-        let result: Value = deadpool_redis::redis::cmd("HGETALL")
-            .arg("GLOBAL")
-            .query_async(&mut conn)
-            .await
-            .map_err(|err| {
-                event!(
-                    Level::ERROR,
-                    "Couldn't get shared METADATA from database: {}",
-                    err
-                );
+        let cmds = keys::all_shards()
+            .map(|shard| {
+                let mut cmd = deadpool_redis::redis::cmd("HGETALL");
+                cmd.arg(keys::global_shard_key(&shard));
+                cmd
+            })
+            .collect();
+
+        let results = self.query_batch(&mut conn, cmds).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't get shared METADATA from database: {}",
+                err
+            );
+            MediatorError::DatabaseError(
+                "NA".into(),
+                format!("Couldn't get shared METADATA from database: {}", err),
+            )
+        })?;
+
+        for result in &results {
+            let result: Vec<String> = from_redis_value(result).map_err(|e| {
                 MediatorError::DatabaseError(
                     "NA".into(),
-                    format!("Couldn't get shared METADATA from database: {}", err),
+                    format!("Couldn't parse GLOBAL metadata from database: {}", e),
                 )
             })?;
 
-        let result: Vec<String> = from_redis_value(&result).map_err(|e| {
-            MediatorError::DatabaseError(
-                "NA".into(),
-                format!("Couldn't parse GLOBAL metadata from database: {}", e),
-            )
-        })?;
-        debug!("Stats: {:?}", result);
-
-        for (k, v) in result.iter().tuples() {
-            match k.as_str() {
-                "RECEIVED_BYTES" => stats.received_bytes = v.parse().unwrap_or(0),
-                "SENT_BYTES" => stats.sent_bytes = v.parse().unwrap_or(0),
-                "DELETED_BYTES" => stats.deleted_bytes = v.parse().unwrap_or(0),
-                "RECEIVED_COUNT" => stats.received_count = v.parse().unwrap_or(0),
-                "SENT_COUNT" => stats.sent_count = v.parse().unwrap_or(0),
-                "DELETED_COUNT" => stats.deleted_count = v.parse().unwrap_or(0),
-                "WEBSOCKET_OPEN" => stats.websocket_open = v.parse().unwrap_or(0),
-                "WEBSOCKET_CLOSE" => stats.websocket_close = v.parse().unwrap_or(0),
-                "SESSIONS_CREATED" => stats.sessions_created = v.parse().unwrap_or(0),
-                "SESSIONS_SUCCESS" => stats.sessions_success = v.parse().unwrap_or(0),
-                _ => {}
+            for (k, v) in result.iter().tuples() {
+                let v: i64 = v.parse().unwrap_or(0);
+                match k.as_str() {
+                    "RECEIVED_BYTES" => stats.received_bytes += v,
+                    "SENT_BYTES" => stats.sent_bytes += v,
+                    "DELETED_BYTES" => stats.deleted_bytes += v,
+                    "RECEIVED_COUNT" => stats.received_count += v,
+                    "SENT_COUNT" => stats.sent_count += v,
+                    "DELETED_COUNT" => stats.deleted_count += v,
+                    "WEBSOCKET_OPEN" => stats.websocket_open += v,
+                    "WEBSOCKET_CLOSE" => stats.websocket_close += v,
+                    "SESSIONS_CREATED" => stats.sessions_created += v,
+                    "SESSIONS_SUCCESS" => stats.sessions_success += v,
+                    _ => {}
+                }
             }
         }
+        debug!("Stats: {:?}", stats);
 
         Ok(stats)
     }
 
     /// Updates GLOBAL send metrics
-    pub async fn update_send_stats(&self, sent_bytes: i64) -> Result<(), MediatorError> {
+    /// - did_hash: DID hash of the requestor, selects the shard the counters are held in
+    pub async fn update_send_stats(
+        &self,
+        did_hash: &str,
+        sent_bytes: i64,
+    ) -> Result<(), MediatorError> {
         let mut con = self.get_async_connection().await?;
+        let global = keys::global_key(did_hash);
 
         deadpool_redis::redis::pipe()
             .atomic()
             .cmd("HINCRBY")
-            .arg("GLOBAL")
+            .arg(&global)
             .arg("SENT_BYTES")
             .arg(sent_bytes)
             .cmd("HINCRBY")
+            .arg(&global)
             .arg("SENT_COUNT")
             .arg(1)
-            .query_async(&mut con)
+            .query_async::<()>(&mut con)
             .await
             .map_err(|err| {
                 MediatorError::DatabaseError(
@@ -141,14 +156,18 @@ impl DatabaseHandler {
     }
 
     /// Increment WebSocket open count
-    pub async fn global_stats_increment_websocket_open(&self) -> Result<(), MediatorError> {
+    /// - did_hash: DID hash of the client, selects the shard the counters are held in
+    pub async fn global_stats_increment_websocket_open(
+        &self,
+        did_hash: &str,
+    ) -> Result<(), MediatorError> {
         let mut con = self.get_async_connection().await?;
 
         deadpool_redis::redis::cmd("HINCRBY")
-            .arg("GLOBAL")
+            .arg(keys::global_key(did_hash))
             .arg("WEBSOCKET_OPEN")
             .arg(1)
-            .query_async(&mut con)
+            .query_async::<()>(&mut con)
             .await
             .map_err(|err| {
                 MediatorError::DatabaseError(
@@ -164,14 +183,18 @@ impl DatabaseHandler {
     }
 
     /// Increment WebSocket close count
-    pub async fn global_stats_increment_websocket_close(&self) -> Result<(), MediatorError> {
+    /// - did_hash: DID hash of the client, selects the shard the counters are held in
+    pub async fn global_stats_increment_websocket_close(
+        &self,
+        did_hash: &str,
+    ) -> Result<(), MediatorError> {
         let mut con = self.get_async_connection().await?;
 
         deadpool_redis::redis::cmd("HINCRBY")
-            .arg("GLOBAL")
+            .arg(keys::global_key(did_hash))
             .arg("WEBSOCKET_CLOSE")
             .arg(1)
-            .query_async(&mut con)
+            .query_async::<()>(&mut con)
             .await
             .map_err(|err| {
                 MediatorError::DatabaseError(
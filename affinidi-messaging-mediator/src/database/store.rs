@@ -1,8 +1,9 @@
-use super::DatabaseHandler;
+use super::{keys, DatabaseHandler};
 use crate::common::errors::MediatorError;
 use serde::{Deserialize, Serialize};
 use sha256::digest;
 use tracing::{debug, event, span, Instrument, Level};
+use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageMetaData {
@@ -14,65 +15,222 @@ pub struct MessageMetaData {
 
 impl DatabaseHandler {
     /// Stores a message in the database
-    /// Returns the message_id (hash of the message)
+    /// Returns the message_id, which is unique for each recipient copy of the message
+    /// If the same message is already queued for the recipient, the existing message_id is returned
+    /// The message must fit the mailbox tier of the recipient (and the queue quota of the sender's tier)
+    /// - namespace: tenant namespace of the sender and recipient
     pub async fn store_message(
         &self,
         session_id: &str,
+        namespace: &str,
         message: &str,
         to_did: &str,
         from_did: Option<&str>,
     ) -> Result<String, MediatorError> {
         let _span = span!(Level::DEBUG, "store_message", session_id = session_id);
         async move {
-            let message_hash = digest(message.as_bytes());
-            let to_hash = digest(to_did.as_bytes());
+            let content_hash = digest(message.as_bytes());
+            let to_hash = keys::did_hash(namespace, to_did);
+            let from_hash = from_did.map(|from_did| keys::did_hash(namespace, from_did));
 
-            let mut conn = self.get_async_connection().await?;
-            let mut tx = deadpool_redis::redis::cmd("FCALL");
+            // Mailbox tier of the recipient, and of the sender for the sender queue quota
+            let to_tier = self.did_tier(session_id, &to_hash).await?;
+            if to_tier.max_message_size > 0 && message.len() > to_tier.max_message_size as usize {
+                return Err(MediatorError::ServiceLimitError(
+                    session_id.into(),
+                    format!(
+                        "Message size ({}) exceeds the limit of {} bytes for the recipient",
+                        message.len(),
+                        to_tier.max_message_size
+                    ),
+                ));
+            }
+            let from_max_queued = match &from_hash {
+                Some(from_hash) => self.did_tier(session_id, from_hash).await?.max_queued_messages,
+                None => 0,
+            };
 
-            tx.arg("store_message")
-                .arg(1)
-                .arg(&message_hash)
-                .arg(message)
-                .arg(message.len())
-                .arg(to_did)
-                .arg(&to_hash);
+            let mut conn = self.get_async_connection().await?;
 
-            if let Some(from_did) = from_did {
-                let from_hash = digest(from_did.as_bytes());
-                tx.arg(from_did).arg(from_hash);
-            } else {
-                tx.arg("ANONYMOUS");
-            }
+            // The message ID is unique per recipient copy
+            let msg_id = digest(
+                [
+                    content_hash.as_str(),
+                    &to_hash,
+                    &Uuid::new_v4().to_string(),
+                ]
+                .concat(),
+            );
 
             debug!(
-                "trying to store msg_id({}), from({:?}) from_hash({:?}) to({}) to_hash({}), bytes({})",
-                message_hash,
+                "trying to store msg_id({}), content_hash({}), from({:?}) from_hash({:?}) to({}) to_hash({}), bytes({})",
+                msg_id,
+                content_hash,
                 from_did,
-                from_did.map(|h| digest(h.as_bytes())),
+                from_hash,
                 to_did,
                 &to_hash,
                 message.len()
             );
 
-            let result: String = tx.query_async(&mut conn).await.map_err(|err| {
-                event!(Level::ERROR, "Couldn't store message in database: {}", err);
-                MediatorError::DatabaseError(
-                    session_id.into(),
-                    format!("Couldn't store message in database: {}", err),
-                )
-            })?;
+            // Sender and recipient records are written atomically by store_message, unless they live in
+            // different slots of a cluster. The sender records are then written first, and the resulting
+            // SEND_ID is stored in the message metadata (recipient slot)
+            let combined = match &from_hash {
+                Some(from_hash) => !self.is_cluster() || keys::tag(from_hash) == keys::tag(&to_hash),
+                None => false,
+            };
+            let send_id: Option<String> = if let (Some(from_hash), false) = (&from_hash, combined) {
+                let send_id: String = deadpool_redis::redis::cmd("FCALL")
+                    .arg("store_message_sender")
+                    .arg(3)
+                    .arg(keys::send_queue_key(from_hash))
+                    .arg(keys::did_key(from_hash))
+                    .arg(keys::send_index_key(from_hash))
+                    .arg(&msg_id)
+                    .arg(message.len())
+                    .arg(to_did)
+                    .arg(&to_hash)
+                    .arg(from_max_queued)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(|err| {
+                        if err.to_string().contains("QUOTA_EXCEEDED") {
+                            return MediatorError::ServiceLimitError(
+                                session_id.into(),
+                                format!("Sender has too many queued messages ({})", from_max_queued),
+                            );
+                        }
+                        event!(
+                            Level::ERROR,
+                            "Couldn't store sender records in database: {}",
+                            err
+                        );
+                        MediatorError::DatabaseError(
+                            session_id.into(),
+                            format!("Couldn't store sender records in database: {}", err),
+                        )
+                    })?;
+                Some(send_id)
+            } else {
+                None
+            };
+
+            let mut tx = deadpool_redis::redis::cmd("FCALL");
+            tx.arg("store_message")
+                .arg(if combined { 11 } else { 8 })
+                .arg(keys::message_key(&to_hash, &msg_id))
+                .arg(keys::message_meta_key(&to_hash, &msg_id))
+                .arg(keys::receive_queue_key(&to_hash))
+                .arg(keys::did_key(&to_hash))
+                .arg(keys::global_key(&to_hash))
+                .arg(keys::message_expiry_key(&to_hash))
+                .arg(keys::content_index_key(&to_hash))
+                .arg(keys::mailbox_rate_key(&to_hash));
+            if let (Some(from_hash), true) = (&from_hash, combined) {
+                tx.arg(keys::send_queue_key(from_hash))
+                    .arg(keys::did_key(from_hash))
+                    .arg(keys::send_index_key(from_hash));
+            }
+            tx.arg(message)
+                .arg(message.len())
+                .arg(&to_hash)
+                .arg(&msg_id)
+                .arg(&content_hash)
+                .arg(to_tier.max_queued_messages)
+                .arg(to_tier.max_queued_bytes)
+                .arg(to_tier.message_rate_limit)
+                .arg(to_tier.message_expiry_minutes as u64 * 60_000);
+
+            match (from_did, &from_hash, &send_id) {
+                (Some(from_did), Some(from_hash), _) if combined => {
+                    tx.arg(from_did).arg(from_hash).arg(to_did).arg(from_max_queued);
+                }
+                (Some(from_did), Some(from_hash), Some(send_id)) => {
+                    tx.arg(from_did).arg(from_hash).arg(send_id);
+                }
+                _ => {
+                    tx.arg("ANONYMOUS");
+                }
+            }
+
+            // store_message deduplicates on the content hash, re-sending the same message to a recipient
+            // returns the msg_id of the queued message
+            let result: Result<(String, String), _> = tx.query_async(&mut conn).await;
+            let msg_id = match result {
+                Ok((stored_id, receive_id)) if stored_id == msg_id => {
+                    debug!("result = {:?}", receive_id);
+                    msg_id
+                }
+                Ok((existing, _)) => {
+                    debug!(
+                        "message content_hash({}) already queued for to_hash({}) as msg_id({})",
+                        content_hash, to_hash, existing
+                    );
+                    if let (Some(from_hash), Some(send_id)) = (&from_hash, &send_id) {
+                        self._rollback_sender(session_id, from_hash, &msg_id, send_id, message.len())
+                            .await;
+                    }
+                    return Ok(existing);
+                }
+                Err(err) => {
+                    let quota_exceeded = err.to_string().contains("QUOTA_EXCEEDED");
+                    if !quota_exceeded {
+                        event!(Level::ERROR, "Couldn't store message in database: {}", err);
+                    }
 
-            debug!("result = {:?}", result);
+                    // Roll back the sender records so that the SEND_Q doesn't point at a missing message
+                    if let (Some(from_hash), Some(send_id)) = (&from_hash, &send_id) {
+                        self._rollback_sender(session_id, from_hash, &msg_id, send_id, message.len())
+                            .await;
+                    }
 
-            println!("Message hash({}) stored in database", message_hash);
+                    if quota_exceeded {
+                        debug!("message not stored for to_hash({}): {}", to_hash, err);
+                        let reason = if err.to_string().contains("sender queue") {
+                            format!("Sender has too many queued messages ({})", from_max_queued)
+                        } else {
+                            format!("Recipient mailbox quota exceeded ({})", to_tier.name)
+                        };
+                        return Err(MediatorError::ServiceLimitError(session_id.into(), reason));
+                    }
+                    return Err(MediatorError::DatabaseError(
+                        session_id.into(),
+                        format!("Couldn't store message in database: {}", err),
+                    ));
+                }
+            };
+
+            debug!("Message id({}) stored in database", msg_id);
 
-            Ok(message_hash)
+            Ok(msg_id)
         }
         .instrument(_span)
         .await
     }
 
+    /// Removes the sender records of a message that wasn't stored for the recipient
+    async fn _rollback_sender(
+        &self,
+        session_id: &str,
+        from_hash: &str,
+        msg_id: &str,
+        send_id: &str,
+        bytes: usize,
+    ) {
+        if let Err(err) = self
+            .delete_message_sender(session_id, from_hash, msg_id, send_id, bytes, None)
+            .await
+        {
+            event!(
+                Level::ERROR,
+                "Couldn't roll back sender records of msg_id({}): {}",
+                msg_id,
+                err
+            );
+        }
+    }
+
     /// Retrieves the message MetaData for a given message hash
     /// - session_id: The session_id for the request
     /// - message_hash: The hash of the message to retrieve
@@ -1,104 +1,330 @@
-use super::DatabaseHandler;
+use super::{handlers::DatabaseConnection, keys, DatabaseHandler};
 use crate::{common::errors::MediatorError, tasks::websocket_streaming::PubSubRecord};
-use redis::{from_redis_value, Value};
-use tracing::{debug, error, event, Level};
+use redis::Value;
+use std::time::SystemTime;
+use tracing::{debug, error, event, warn, Level};
+
+/// A streaming service registered in the database
+/// - uuid        : ID of the streaming service
+/// - alive       : True if the heartbeat of the service is current
+/// - connections : Connections of the service (`<did_hash>:<conn_id>`)
+#[derive(Debug)]
+pub struct StreamingNode {
+    pub uuid: String,
+    pub alive: bool,
+    pub connections: Vec<String>,
+}
 
 impl DatabaseHandler {
-    pub async fn streaming_clean_start(&self, uuid: &str) -> Result<(), MediatorError> {
+    /// Removes any connections left over from a previous run of this streaming service
+    /// and starts the heartbeat for this streaming service
+    pub async fn streaming_clean_start(&self, uuid: &str, ttl: u64) -> Result<(), MediatorError> {
+        let count = self.streaming_remove_node(uuid).await?;
+        event!(
+            Level::INFO,
+            "streaming_clean_start() cleaned {} sessions",
+            count
+        );
+
+        self.streaming_heartbeat(uuid, ttl).await
+    }
+
+    /// Removes a streaming service and all of its connections
+    /// Returns the number of connections removed
+    pub async fn streaming_remove_node(&self, uuid: &str) -> Result<usize, MediatorError> {
         let mut conn = self.get_async_connection().await?;
 
-        let response: Vec<Value> = deadpool_redis::redis::pipe()
-            .atomic()
-            .cmd("FCALL")
-            .arg("clean_start_streaming")
-            .arg(1)
-            .arg(uuid)
+        let sessions: Vec<String> = deadpool_redis::redis::cmd("SMEMBERS")
+            .arg(keys::streaming_sessions_key(uuid))
             .query_async(&mut conn)
             .await
             .map_err(|err| {
                 event!(
                     Level::ERROR,
-                    "redis function clean_start_streaming() failed. Reason: {}",
+                    "streaming_remove_node() failed to get sessions. Reason: {}",
                     err
                 );
                 MediatorError::DatabaseError(
                     "NA".into(),
                     format!(
-                        "redis function clean_start_streaming() failed. Reason: {}",
+                        "streaming_remove_node() failed to get sessions. Reason: {}",
                         err
                     ),
                 )
             })?;
 
-        if let Ok(count) = from_redis_value::<i64>(&response[0]) {
+        // Members are stored as <did_hash>:<conn_id>
+        let mut pipe = deadpool_redis::redis::pipe();
+        pipe.atomic();
+        for session in &sessions {
+            if let Some((did_hash, conn_id)) = session.split_once(':') {
+                pipe.cmd("HDEL")
+                    .arg(keys::streaming_did_key(did_hash))
+                    .arg(conn_id);
+            }
+        }
+        pipe.cmd("DEL")
+            .arg(keys::streaming_sessions_key(uuid))
+            .cmd("SREM")
+            .arg(keys::streaming_nodes_key())
+            .arg(uuid);
+
+        pipe.query_async::<()>(&mut conn).await.map_err(|err| {
             event!(
-                Level::INFO,
-                "clean_start_streaming() cleaned {} sessions",
-                count
+                Level::ERROR,
+                "streaming_remove_node() failed. Reason: {}",
+                err
             );
-            Ok(())
-        } else {
+            MediatorError::DatabaseError(
+                "NA".into(),
+                format!("streaming_remove_node() failed. Reason: {}", err),
+            )
+        })?;
+
+        Ok(sessions.len())
+    }
+
+    /// Removes all streaming connections of a DID from every streaming service
+    /// Returns the number of connections removed
+    pub async fn streaming_remove_did(&self, did_hash: &str) -> Result<usize, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let connections: Vec<(String, String)> = deadpool_redis::redis::cmd("HGETALL")
+            .arg(keys::streaming_did_key(did_hash))
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "streaming_remove_did() failed to get connections. Reason: {}",
+                    err
+                );
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!(
+                        "streaming_remove_did() failed to get connections. Reason: {}",
+                        err
+                    ),
+                )
+            })?;
+
+        // Values are stored as <uuid>:<TRUE|FALSE>
+        let mut pipe = deadpool_redis::redis::pipe();
+        pipe.atomic();
+        for (conn_id, value) in &connections {
+            if let Some((uuid, _)) = value.split_once(':') {
+                pipe.cmd("SREM")
+                    .arg(keys::streaming_sessions_key(uuid))
+                    .arg([did_hash, ":", conn_id].concat());
+            }
+        }
+        pipe.cmd("DEL").arg(keys::streaming_did_key(did_hash));
+
+        pipe.query_async::<()>(&mut conn).await.map_err(|err| {
             event!(
                 Level::ERROR,
-                "clean_start_streaming() failed to parse response: {:?}",
-                response
+                "streaming_remove_did() failed. Reason: {}",
+                err
             );
-            Err(MediatorError::DatabaseError(
+            MediatorError::DatabaseError(
                 "NA".into(),
-                format!(
-                    "redis fn clean_start_streaming() failed. Response ({:?})",
-                    response
-                ),
-            ))
+                format!("streaming_remove_did() failed. Reason: {}", err),
+            )
+        })?;
+
+        Ok(connections.len())
+    }
+
+    /// Refreshes the heartbeat of this streaming service, it expires after ttl seconds
+    pub async fn streaming_heartbeat(&self, uuid: &str, ttl: u64) -> Result<(), MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        deadpool_redis::redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(keys::streaming_node_key(uuid))
+            .arg(now)
+            .arg("EX")
+            .arg(ttl)
+            .cmd("SADD")
+            .arg(keys::streaming_nodes_key())
+            .arg(uuid)
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "streaming_heartbeat() for ({}) failed. Reason: {}",
+                    uuid,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!(
+                        "streaming_heartbeat() for ({}) failed. Reason: {}",
+                        uuid, err
+                    ),
+                )
+            })
+    }
+
+    /// Returns which of the given streaming services have a current heartbeat
+    async fn _streaming_alive_nodes(
+        &self,
+        conn: &mut DatabaseConnection,
+        nodes: Vec<String>,
+    ) -> Result<Vec<String>, MediatorError> {
+        if nodes.is_empty() {
+            return Ok(nodes);
+        }
+
+        let mut pipe = deadpool_redis::redis::pipe();
+        for node in &nodes {
+            pipe.cmd("EXISTS").arg(keys::streaming_node_key(node));
+        }
+        let alive: Vec<bool> = pipe.query_async(conn).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "streaming node heartbeat check failed. Reason: {}",
+                err
+            );
+            MediatorError::DatabaseError(
+                "NA".into(),
+                format!("streaming node heartbeat check failed. Reason: {}", err),
+            )
+        })?;
+
+        Ok(nodes
+            .into_iter()
+            .zip(alive)
+            .filter_map(|(node, alive)| alive.then_some(node))
+            .collect())
+    }
+
+    /// Returns all registered streaming services, if their heartbeat is current and their connections
+    /// Connections are returned as `<did_hash>:<conn_id>`
+    pub async fn streaming_nodes(&self) -> Result<Vec<StreamingNode>, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let nodes: Vec<String> = deadpool_redis::redis::cmd("SMEMBERS")
+            .arg(keys::streaming_nodes_key())
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "streaming_nodes() failed to get nodes. Reason: {}",
+                    err
+                );
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!("streaming_nodes() failed to get nodes. Reason: {}", err),
+                )
+            })?;
+        let alive = self
+            ._streaming_alive_nodes(&mut conn, nodes.clone())
+            .await?;
+
+        let mut result = Vec::new();
+        for uuid in nodes {
+            let connections: Vec<String> = deadpool_redis::redis::cmd("SMEMBERS")
+                .arg(keys::streaming_sessions_key(&uuid))
+                .query_async(&mut conn)
+                .await
+                .map_err(|err| {
+                    event!(
+                        Level::ERROR,
+                        "streaming_nodes() failed to get sessions. Reason: {}",
+                        err
+                    );
+                    MediatorError::DatabaseError(
+                        "NA".into(),
+                        format!("streaming_nodes() failed to get sessions. Reason: {}", err),
+                    )
+                })?;
+            result.push(StreamingNode {
+                alive: alive.contains(&uuid),
+                uuid,
+                connections,
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// Removes streaming services (and their connections) whose heartbeat has expired
+    /// uuid: This streaming service, which is never reaped by itself
+    /// Returns the number of streaming services removed
+    pub async fn streaming_reap_nodes(&self, uuid: &str) -> Result<usize, MediatorError> {
+        let mut conn = self.get_async_connection().await?;
+
+        let nodes: Vec<String> = deadpool_redis::redis::cmd("SMEMBERS")
+            .arg(keys::streaming_nodes_key())
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "streaming_reap_nodes() failed to get nodes. Reason: {}",
+                    err
+                );
+                MediatorError::DatabaseError(
+                    "NA".into(),
+                    format!(
+                        "streaming_reap_nodes() failed to get nodes. Reason: {}",
+                        err
+                    ),
+                )
+            })?;
+
+        let nodes: Vec<String> = nodes.into_iter().filter(|node| node != uuid).collect();
+        let alive = self
+            ._streaming_alive_nodes(&mut conn, nodes.clone())
+            .await?;
+
+        let mut count = 0;
+        for node in nodes.iter().filter(|node| !alive.contains(node)) {
+            let sessions = self.streaming_remove_node(node).await?;
+            warn!(
+                "Reaped streaming service ({}) with expired heartbeat, removed {} connections",
+                node, sessions
+            );
+            count += 1;
         }
+
+        Ok(count)
     }
 
-    /// Checks if the given DID hash is live streaming
+    /// Returns the streaming services that the given DID hash is live streaming from
+    /// A DID may be connected to multiple streaming services (one per connection/device)
     /// did_hash: The DID hash to check
-    /// force_delivery: If true, the message will be delivered even if the client is not live streaming
-    /// Returns the streaming service ID that the client is connecting to if the DID hash is live streaming
-    pub async fn streaming_is_client_live(
+    /// force_delivery: If true, also returns services where the client is connected but not live streaming
+    /// stored: If true, also returns services where a request is waiting for stored messages (long-polling fetch)
+    /// Returns the unique streaming service ID's, empty if the DID hash is not live streaming
+    pub async fn streaming_live_nodes(
         &self,
         did_hash: &str,
         force_delivery: bool,
-    ) -> Option<String> {
+        stored: bool,
+    ) -> Vec<String> {
         let mut conn = if let Ok(conn) = self.get_async_connection().await {
             conn
         } else {
-            error!("is_live_streaming(): Failed to get connection to Redis");
-            return None;
+            error!("streaming_live_nodes(): Failed to get connection to Redis");
+            return Vec::new();
         };
 
-        match deadpool_redis::redis::cmd("HGET")
-            .arg("GLOBAL_STREAMING")
-            .arg(did_hash)
-            .query_async::<Option<String>>(&mut conn)
+        let connections = match deadpool_redis::redis::cmd("HVALS")
+            .arg(keys::streaming_did_key(did_hash))
+            .query_async::<Vec<String>>(&mut conn)
             .await
         {
-            Ok(response) => {
-                if let Some(row) = response {
-                    let parts: Vec<&str> = row.split(':').collect();
-
-                    if parts.len() == 2 {
-                        if parts[1] == "TRUE" {
-                            // Client is live streaming
-                            Some(parts[0].to_string())
-                        } else if force_delivery {
-                            // Client is not live streaming, but we are forcing delivery
-                            Some(parts[0].to_string())
-                        } else {
-                            // Client is not live streaming
-                            None
-                        }
-                    } else {
-                        // Invalid Redis data
-                        None
-                    }
-                } else {
-                    // No Redis data found.
-                    None
-                }
-            }
+            Ok(connections) => connections,
             Err(err) => {
                 event!(
                     Level::ERROR,
@@ -106,30 +332,56 @@ impl DatabaseHandler {
                     did_hash,
                     err
                 );
-                None
+                return Vec::new();
+            }
+        };
+
+        // Each connection is stored as <uuid>:<TRUE|FALSE|WAIT>
+        let mut nodes: Vec<String> = Vec::new();
+        for row in connections {
+            if let Some((uuid, live)) = row.split_once(':') {
+                let include = match live {
+                    "TRUE" => true,
+                    "WAIT" => stored,
+                    _ => force_delivery,
+                };
+                if include && !nodes.iter().any(|n| n == uuid) {
+                    nodes.push(uuid.to_string());
+                }
             }
         }
+
+        // Streaming services without a heartbeat are skipped, the message is only stored
+        self._streaming_alive_nodes(&mut conn, nodes)
+            .await
+            .unwrap_or_default()
     }
 
     /// Publishes a live message to the streaming service
-    /// This follows a call to is_live_streaming() to get the streaming service ID if valid
+    /// This follows a call to streaming_live_nodes() to get the streaming service ID if valid
     /// NOTE: There is a chance that a client could close live-streaming between the check and publishing
     ///        Not an issue, as the streaming component will handle this gracefully
-    /// NOTE: Why not combine is_live_streaming() and this function into an atomic action?
+    /// NOTE: Why not combine streaming_live_nodes() and this function into an atomic action?
     ///       While it would be more efficient in some ways, it would also mean pushing the full message to Redis
     ///       on every delivery, as messages get larger this would become less efficient.
     ///       So best compromise is to have the client check if live-streaming is active, then send the message
+    /// msg_id: ID of the stored message, the client must acknowledge stored messages. None for ephemeral messages
+    /// session_id: If set, only connections of this session receive the message
     pub async fn streaming_publish_message(
         &self,
         did_hash: &str,
         stream_uuid: &str,
         message: &str,
         force_delivery: bool,
+        msg_id: Option<&str>,
+        session_id: Option<&str>,
     ) -> Result<(), MediatorError> {
         let record = match serde_json::to_string(&PubSubRecord {
             did_hash: did_hash.to_string(),
             message: message.to_string(),
             force_delivery,
+            msg_id: msg_id.map(|id| id.to_string()),
+            session_id: session_id.map(|id| id.to_string()),
         }) {
             Ok(record) => record,
             Err(err) => {
@@ -186,28 +438,55 @@ impl DatabaseHandler {
         }
     }
 
-    /// Registers a client to the live streaming service
+    /// Registers a client connection to the live streaming service
     pub async fn streaming_register_client(
         &self,
         did_hash: &str,
+        conn_id: &str,
+        stream_uuid: &str,
+    ) -> Result<(), MediatorError> {
+        self._streaming_register(did_hash, conn_id, stream_uuid, ":FALSE")
+            .await
+    }
+
+    /// Registers a request that is waiting for stored messages (long-polling fetch)
+    /// A waiting request isn't live streaming, it is only told about stored messages
+    pub async fn streaming_register_waiter(
+        &self,
+        did_hash: &str,
+        conn_id: &str,
+        stream_uuid: &str,
+    ) -> Result<(), MediatorError> {
+        self._streaming_register(did_hash, conn_id, stream_uuid, ":WAIT")
+            .await
+    }
+
+    async fn _streaming_register(
+        &self,
+        did_hash: &str,
+        conn_id: &str,
         stream_uuid: &str,
+        status: &str,
     ) -> Result<(), MediatorError> {
         let mut conn = self.get_async_connection().await?;
 
         match deadpool_redis::redis::pipe()
             .atomic()
             .cmd("SADD")
-            .arg(["STREAMING_SESSIONS:", stream_uuid].concat())
-            .arg(did_hash)
+            .arg(keys::streaming_sessions_key(stream_uuid))
+            .arg([did_hash, ":", conn_id].concat())
             .cmd("HSET")
-            .arg("GLOBAL_STREAMING")
-            .arg(did_hash)
-            .arg([stream_uuid, ":FALSE"].concat())
+            .arg(keys::streaming_did_key(did_hash))
+            .arg(conn_id)
+            .arg([stream_uuid, status].concat())
             .query_async::<Value>(&mut conn)
             .await
         {
             Ok(_) => {
-                debug!("did_hash({}) registered to ({})", did_hash, stream_uuid);
+                debug!(
+                    "did_hash({}) conn_id({}) registered to ({})",
+                    did_hash, conn_id, stream_uuid
+                );
 
                 Ok(())
             }
@@ -232,25 +511,28 @@ impl DatabaseHandler {
         }
     }
 
-    /// Enables live streaming for the client
-    pub async fn streaming_start_live(
+    /// Enables or disables live streaming for a client connection
+    pub async fn streaming_set_live(
         &self,
         did_hash: &str,
+        conn_id: &str,
         stream_uuid: &str,
+        live: bool,
     ) -> Result<(), MediatorError> {
         let mut conn = self.get_async_connection().await?;
 
+        let status = if live { ":TRUE" } else { ":FALSE" };
         match deadpool_redis::redis::cmd("HSET")
-            .arg("GLOBAL_STREAMING")
-            .arg(did_hash)
-            .arg([stream_uuid, ":", "TRUE"].concat())
+            .arg(keys::streaming_did_key(did_hash))
+            .arg(conn_id)
+            .arg([stream_uuid, status].concat())
             .query_async::<Value>(&mut conn)
             .await
         {
             Ok(_) => {
                 debug!(
-                    "did_hash({}) started live streaming from ({})",
-                    did_hash, stream_uuid
+                    "did_hash({}) conn_id({}) live streaming({}) from ({})",
+                    did_hash, conn_id, live, stream_uuid
                 );
 
                 Ok(())
@@ -258,7 +540,7 @@ impl DatabaseHandler {
             Err(err) => {
                 event!(
                     Level::ERROR,
-                    "streaming_start_live() for did_hash({}) stream_uuid(STREAMING_SESSIONS:{}) failed. Reason: {}",
+                    "streaming_set_live() for did_hash({}) stream_uuid(STREAMING_SESSIONS:{}) failed. Reason: {}",
                     did_hash,
                     stream_uuid,
                     err
@@ -266,7 +548,7 @@ impl DatabaseHandler {
                 Err(MediatorError::DatabaseError(
                     "NA".into(),
                     format!(
-                        "streaming_start_live() for did_hash({}) stream_uuid(STREAMING_SESSIONS:{}) failed. Reason: {}",
+                        "streaming_set_live() for did_hash({}) stream_uuid(STREAMING_SESSIONS:{}) failed. Reason: {}",
                         did_hash,
                         stream_uuid,
                         err
@@ -276,54 +558,12 @@ impl DatabaseHandler {
         }
     }
 
-    /// Disables live streaming for a client
-    pub async fn streaming_stop_live(
-        &self,
-        did_hash: &str,
-        stream_uuid: &str,
-    ) -> Result<(), MediatorError> {
-        let mut conn = self.get_async_connection().await?;
-
-        match deadpool_redis::redis::cmd("HSET")
-            .arg("GLOBAL_STREAMING")
-            .arg(did_hash)
-            .arg([stream_uuid, ":", "FALSE"].concat())
-            .query_async::<Value>(&mut conn)
-            .await
-        {
-            Ok(_) => {
-                debug!(
-                    "did_hash({}) stopped live streaming from ({})",
-                    did_hash, stream_uuid
-                );
-
-                Ok(())
-            }
-            Err(err) => {
-                event!(
-                Level::ERROR,
-                "streaming_stop_live() for did_hash({}) stream_uuid(STREAMING_SESSIONS:{}) failed. Reason: {}",
-                did_hash,
-                stream_uuid,
-                err
-            );
-                Err(MediatorError::DatabaseError(
-                "NA".into(),
-                format!(
-                    "streaming_stop_live() for did_hash({}) stream_uuid(STREAMING_SESSIONS:{}) failed. Reason: {}",
-                    did_hash,
-                    stream_uuid,
-                    err
-                ),
-            ))
-            }
-        }
-    }
-
-    /// Removes client from live streaming service
+    /// Removes a client connection from live streaming service
+    /// Other connections for the same DID are not affected
     pub async fn streaming_deregister_client(
         &self,
         did_hash: &str,
+        conn_id: &str,
         stream_uuid: &str,
     ) -> Result<(), MediatorError> {
         let mut conn = self.get_async_connection().await?;
@@ -331,16 +571,19 @@ impl DatabaseHandler {
         match deadpool_redis::redis::pipe()
             .atomic()
             .cmd("SREM")
-            .arg(["STREAMING_SESSIONS:", stream_uuid].concat())
-            .arg(did_hash)
+            .arg(keys::streaming_sessions_key(stream_uuid))
+            .arg([did_hash, ":", conn_id].concat())
             .cmd("HDEL")
-            .arg("GLOBAL_STREAMING")
-            .arg(did_hash)
+            .arg(keys::streaming_did_key(did_hash))
+            .arg(conn_id)
             .query_async::<Value>(&mut conn)
             .await
         {
             Ok(_) => {
-                debug!("did_hash({}) deregistered from ({})", did_hash, stream_uuid);
+                debug!(
+                    "did_hash({}) conn_id({}) deregistered from ({})",
+                    did_hash, conn_id, stream_uuid
+                );
 
                 Ok(())
             }
@@ -0,0 +1,79 @@
+use super::{keys, DatabaseHandler};
+use crate::common::{errors::MediatorError, tier::MailboxTier};
+use tracing::{debug, event, Level};
+
+impl DatabaseHandler {
+    /// Returns the mailbox tier of a DID (stored in the `TIER` field of `DID:<hash>`)
+    /// DIDs without a tier, or with a tier that is no longer configured, get the default tier
+    pub async fn did_tier(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+    ) -> Result<MailboxTier, MediatorError> {
+        let mut con = self.get_async_connection().await?;
+
+        let tier: Option<String> = deadpool_redis::redis::cmd("HGET")
+            .arg(keys::did_key(did_hash))
+            .arg("TIER")
+            .query_async(&mut con)
+            .await
+            .map_err(|err| {
+                event!(
+                    Level::ERROR,
+                    "Couldn't get tier for did_hash({}): {}",
+                    did_hash,
+                    err
+                );
+                MediatorError::DatabaseError(
+                    session_id.into(),
+                    format!("Couldn't get tier: {}", err),
+                )
+            })?;
+
+        Ok(self.tiers.get(tier.as_deref()).clone())
+    }
+
+    /// Assigns a mailbox tier to a DID
+    /// - tier: Name of the tier, None (or the default tier) removes the tier of the DID
+    pub async fn set_did_tier(
+        &self,
+        session_id: &str,
+        did_hash: &str,
+        tier: Option<&str>,
+    ) -> Result<MailboxTier, MediatorError> {
+        let tier = tier.filter(|tier| *tier != self.tiers.default.name);
+        if let Some(tier) = tier {
+            if !self.tiers.contains(tier) {
+                return Err(MediatorError::RequestDataError(
+                    session_id.into(),
+                    format!("Tier ({}) isn't configured on this mediator", tier),
+                ));
+            }
+        }
+
+        let mut con = self.get_async_connection().await?;
+
+        let cmd = if let Some(tier) = tier {
+            let mut cmd = deadpool_redis::redis::cmd("HSET");
+            cmd.arg(keys::did_key(did_hash)).arg("TIER").arg(tier);
+            cmd
+        } else {
+            let mut cmd = deadpool_redis::redis::cmd("HDEL");
+            cmd.arg(keys::did_key(did_hash)).arg("TIER");
+            cmd
+        };
+
+        cmd.query_async::<()>(&mut con).await.map_err(|err| {
+            event!(
+                Level::ERROR,
+                "Couldn't set tier for did_hash({}): {}",
+                did_hash,
+                err
+            );
+            MediatorError::DatabaseError(session_id.into(), format!("Couldn't set tier: {}", err))
+        })?;
+
+        debug!("did_hash({}) tier set to ({:?})", did_hash, tier);
+        Ok(self.tiers.get(tier).clone())
+    }
+}
@@ -0,0 +1,49 @@
+//! Deletion of all data the mediator holds for the authenticated DID
+//!
+//! `DELETE /account` removes queued messages, outbox records, counters, the mailbox tier, sessions,
+//! streaming registrations, push endpoints, the forward consent, uploaded blobs and hosted invitations
+//! of the authenticated DID.
+//! Admin DIDs can do the same for any DID of their tenant with the admin account-delete message.
+use affinidi_messaging_sdk::messages::AccountDeletionReport;
+use axum::{extract::State, Json};
+use http::StatusCode;
+use tracing::{span, Instrument, Level};
+
+use crate::{
+    common::errors::{AppError, Session, SuccessResponse},
+    SharedData,
+};
+
+/// Deletes everything the mediator holds for the authenticated DID
+/// Returns a report of what was deleted
+pub async fn account_delete_handler(
+    session: Session,
+    State(state): State<SharedData>,
+) -> Result<(StatusCode, Json<SuccessResponse<AccountDeletionReport>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "account_delete_handler",
+        session = session.session_id,
+        did = session.did,
+    );
+    async move {
+        let report = state
+            .database
+            .delete_account(&session.session_id, &session.did, &session.did_hash)
+            .await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(report),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
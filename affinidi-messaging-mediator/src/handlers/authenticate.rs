@@ -9,9 +9,12 @@ use std::time::SystemTime;
 use tracing::{debug, info, warn};
 
 use crate::{
-    common::errors::{AppError, MediatorError, SuccessResponse},
+    common::{
+        errors::{AppError, MediatorError, SuccessResponse},
+        tenant::RequestTenant,
+    },
     database::session::{Session, SessionClaims, SessionState},
-    messages::MessageType,
+    messages::{inbound::check_replay, MessageType},
     SharedData,
 };
 
@@ -45,6 +48,7 @@ pub struct ChallengeBody {
 pub async fn authentication_challenge(
     // ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
     State(state): State<SharedData>,
+    RequestTenant(tenant): RequestTenant,
     Json(body): Json<ChallengeBody>,
 ) -> Result<(StatusCode, Json<SuccessResponse<AuthenticationChallenge>>), AppError> {
     println!("GOT authenticate/challenge");
@@ -53,6 +57,7 @@ pub async fn authentication_challenge(
         challenge: create_random_string(32),
         state: SessionState::ChallengeSent,
         did: body.did.clone(),
+        tenant,
     };
 
     state.database.create_session(&session).await?;
@@ -92,30 +97,123 @@ impl GenericDataStruct for AuthorizationResponse {}
 /// Check that the DID matches from the message to the session DID recorded
 pub async fn authentication_response(
     State(state): State<SharedData>,
+    RequestTenant(tenant): RequestTenant,
     Json(body): Json<InboundMessage>,
 ) -> Result<(StatusCode, Json<SuccessResponse<AuthorizationResponse>>), AppError> {
     println!("on authenticate_response");
     let s = serde_json::to_string(&body).unwrap();
+    let mut session = verify_challenge_response(&state, &s, &tenant).await?;
 
-    let mut envelope =
-        match MetaEnvelope::new(&s, &state.did_resolver, &state.config.mediator_secrets).await {
-            Ok(envelope) => envelope,
-            Err(e) => {
-                return Err(MediatorError::ParseError(
-                    "UNKNOWN".to_string(),
-                    "Raw inbound DIDComm message".into(),
-                    e.to_string(),
+    let old_sid = session.session_id;
+    session.session_id = create_random_string(12);
+
+    // Passed all the checks, now create the JWT tokens
+    let access_claims = SessionClaims {
+        aud: "ATM".to_string(),
+        sub: session.did.clone(),
+        session_id: session.session_id.clone(),
+        exp: (SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 900),
+        tenant: session.tenant.clone(),
+    };
+    // refresh token expires in 24 hours (86,400 seconds - 900 (15 minutes) = 85,500 seconds)
+    let mut refresh_claims = access_claims.clone();
+    refresh_claims.exp += 85500;
+
+    let response = if let Some(encoding_key) = state.config.jwt_encoding_key.as_ref() {
+        AuthorizationResponse {
+            access_token: encode(
+                &Header::new(jsonwebtoken::Algorithm::EdDSA),
+                &access_claims,
+                encoding_key,
+            )
+            .map_err(|err| {
+                MediatorError::InternalError(
+                    "UNKNOWN".into(),
+                    format!("Couldn't encode access token. Reason: {}", err),
                 )
-                .into());
-            }
-        };
+            })?,
+            refresh_token: encode(
+                &Header::new(jsonwebtoken::Algorithm::EdDSA),
+                &refresh_claims,
+                encoding_key,
+            )
+            .map_err(|err| {
+                MediatorError::InternalError(
+                    "UNKNOWN".into(),
+                    format!("Couldn't encode refresh token. Reason: {}", err),
+                )
+            })?,
+        }
+    } else {
+        return Err(
+            MediatorError::InternalError("NA".into(), "JWT Encoding Key not found".into()).into(),
+        );
+    };
+
+    // Set the session state to Authorized
+    state
+        .database
+        .update_session_authenticated(&old_sid, &session.session_id)
+        .await?;
+
+    info!(
+        "{}: Authentication successful for DID({})",
+        session.session_id, session.did
+    );
+
+    Ok((
+        StatusCode::OK,
+        Json(SuccessResponse {
+            sessionId: session.session_id.clone(),
+            httpCode: StatusCode::OK.as_u16(),
+            errorCode: 0,
+            errorCodeStr: "NA".to_string(),
+            message: "Success".to_string(),
+            data: Some(response),
+        }),
+    ))
+}
+
+/// Verifies a packed Affinidi Authenticate message (response to the challenge)
+/// Unpack the message (only accepts Affinidi Authenticate Protocol)
+/// Retrieve Session data from database
+/// Check that the DID matches from the message to the session DID recorded
+/// - request_tenant: namespace of the tenant the request was made to, used if the message doesn't select a tenant
+///
+/// Returns the session, which is still in the ChallengeSent state
+pub(crate) async fn verify_challenge_response(
+    state: &SharedData,
+    raw: &str,
+    request_tenant: &str,
+) -> Result<Session, MediatorError> {
+    let Some(tenant) = state.config.message_tenant(raw, request_tenant) else {
+        return Err(MediatorError::SessionError(
+            "UNKNOWN".into(),
+            format!("Unknown tenant ({})", request_tenant),
+        ));
+    };
+
+    let mut envelope = match MetaEnvelope::new(raw, &state.did_resolver, &tenant.secrets).await {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            return Err(MediatorError::ParseError(
+                "UNKNOWN".to_string(),
+                "Raw inbound DIDComm message".into(),
+                e.to_string(),
+            ));
+        }
+    };
 
     println!("abut to unpack Message");
     // Unpack the message
     let (msg, _) = match Message::unpack(
         &mut envelope,
         &state.did_resolver,
-        &state.config.mediator_secrets,
+        &tenant.secrets,
         &UnpackOptions::default(),
     )
     .await
@@ -125,13 +223,23 @@ pub async fn authentication_response(
             return Err(MediatorError::MessageUnpackError(
                 "UNKNOWN".to_string(),
                 format!("Couldn't unpack incoming message. Reason: {}", e),
-            )
-            .into());
+            ));
         }
     };
 
     println!("received message: {msg:?}");
 
+    // Reject replayed authentication messages
+    check_replay(
+        state,
+        "UNKNOWN",
+        raw,
+        msg.from.as_deref().unwrap_or("ANONYMOUS"),
+        &msg,
+    )
+    .await?
+    .accept();
+
     // Only accepts AffinidiAuthenticate messages
     match msg.type_.as_str().parse::<MessageType>()? {
         MessageType::AffinidiAuthenticate => (),
@@ -139,8 +247,7 @@ pub async fn authentication_response(
             return Err(MediatorError::SessionError(
                 "UNKNOWN".to_string(),
                 "Only accepts Affinidi Authentication protocol messages".to_string(),
-            )
-            .into())
+            ))
         }
     }
 
@@ -155,8 +262,7 @@ pub async fn authentication_response(
                 "-1".into(),
                 expires.to_string(),
                 now.to_string(),
-            )
-            .into());
+            ));
         }
     }
 
@@ -170,7 +276,7 @@ pub async fn authentication_response(
     })?;
 
     // Retrieve the session info from the database
-    let mut session = state.database.get_session(&challenge.session_id).await?;
+    let session = state.database.get_session(&challenge.session_id).await?;
 
     // check that the DID matches from what was given for the initial challenge request to what was used for the message response
     if let Some(from_did) = msg.from {
@@ -185,11 +291,36 @@ pub async fn authentication_response(
                     "DID ({}) from authorization message does not match DID from session",
                     from_did
                 ),
-            )
-            .into());
+            ));
         }
     }
 
+    // The challenge must be answered to the same tenant that issued it
+    if session.tenant != tenant.namespace {
+        warn!(
+            "{}: Challenge issued by tenant ({}) was answered to tenant ({})",
+            session.session_id, session.tenant, tenant.namespace
+        );
+        return Err(MediatorError::SessionError(
+            session.session_id.clone(),
+            "Authentication message is addressed to a different mediator".into(),
+        ));
+    }
+
+    // Blocked DIDs can't authenticate
+    if state
+        .database
+        .did_blocked(&tenant.did_hash(&session.did))
+        .await?
+        .is_some()
+    {
+        warn!("{}: DID ({}) is blocked", session.session_id, session.did);
+        return Err(MediatorError::PermissionError(
+            session.session_id.clone(),
+            "DID is blocked".into(),
+        ));
+    }
+
     // Check that this isn't a replay attack
     if let SessionState::ChallengeSent = session.state {
         debug!("Database session state is ChallengeSent - Good to go!");
@@ -201,84 +332,13 @@ pub async fn authentication_response(
         return Err(MediatorError::SessionError(
             session.session_id.clone(),
             "Session is in an invalid state for authentication".into(),
-        )
-        .into());
+        ));
     }
-    let old_sid = session.session_id;
-    session.session_id = create_random_string(12);
-
-    // Passed all the checks, now create the JWT tokens
-    let access_claims = SessionClaims {
-        aud: "ATM".to_string(),
-        sub: session.did.clone(),
-        session_id: session.session_id.clone(),
-        exp: (SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            + 900),
-    };
-    // refresh token expires in 24 hours (86,400 seconds - 900 (15 minutes) = 85,500 seconds)
-    let mut refresh_claims = access_claims.clone();
-    refresh_claims.exp += 85500;
-
-    let response = if let Some(encoding_key) = state.config.jwt_encoding_key.as_ref() {
-        AuthorizationResponse {
-            access_token: encode(
-                &Header::new(jsonwebtoken::Algorithm::EdDSA),
-                &access_claims,
-                encoding_key,
-            )
-            .map_err(|err| {
-                MediatorError::InternalError(
-                    "UNKNOWN".into(),
-                    format!("Couldn't encode access token. Reason: {}", err),
-                )
-            })?,
-            refresh_token: encode(
-                &Header::new(jsonwebtoken::Algorithm::EdDSA),
-                &refresh_claims,
-                encoding_key,
-            )
-            .map_err(|err| {
-                MediatorError::InternalError(
-                    "UNKNOWN".into(),
-                    format!("Couldn't encode refresh token. Reason: {}", err),
-                )
-            })?,
-        }
-    } else {
-        return Err(
-            MediatorError::InternalError("NA".into(), "JWT Encoding Key not found".into()).into(),
-        );
-    };
-
-    // Set the session state to Authorized
-    state
-        .database
-        .update_session_authenticated(&old_sid, &session.session_id)
-        .await?;
-
-    info!(
-        "{}: Authentication successful for DID({})",
-        session.session_id, session.did
-    );
-
-    Ok((
-        StatusCode::OK,
-        Json(SuccessResponse {
-            sessionId: session.session_id.clone(),
-            httpCode: StatusCode::OK.as_u16(),
-            errorCode: 0,
-            errorCodeStr: "NA".to_string(),
-            message: "Success".to_string(),
-            data: Some(response),
-        }),
-    ))
+    Ok(session)
 }
 
 /// creates a random string of up to length characters
-fn create_random_string(length: usize) -> String {
+pub(crate) fn create_random_string(length: usize) -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(length)
@@ -0,0 +1,493 @@
+//! Out-of-band blob store for attachments that are too large to send as a message
+//!
+//! 1. `POST /blobs` creates an upload, the blob is split into `chunk_size` chunks
+//! 2. `PUT /blobs/:blob_id/chunks/:index` stores a chunk, chunks can be sent in any order and again
+//! 3. `GET /blobs/:blob_id/status` lists the chunks received, so an interrupted upload can be resumed
+//! 4. `POST /blobs/:blob_id/complete` assembles the blob
+//! 5. `POST /blobs/:blob_id/bind` ties the blob to the messages that reference it
+//!
+//! The uploader and the recipients can download the blob (`GET /blobs/:blob_id`) until it expires.
+//! The mediator never sees the content, clients encrypt blobs before uploading them.
+use affinidi_messaging_sdk::messages::{BlobBindRequest, BlobStatus, BlobUploadRequest};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header, StatusCode};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, span, Instrument, Level};
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        blob_storage::BlobStorage,
+        errors::{AppError, MediatorError, Session, SuccessResponse},
+    },
+    database::blobs::BlobRecord,
+    SharedData,
+};
+
+/// Creates a blob upload
+/// Returns the status of the new blob, including the chunk size to upload with
+pub async fn blob_create_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Json(body): Json<BlobUploadRequest>,
+) -> Result<(StatusCode, Json<SuccessResponse<BlobStatus>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "blob_create_handler",
+        session = session.session_id,
+        did = session.did,
+        size = body.size,
+    );
+    async move {
+        let storage = _storage(&state, &session)?;
+
+        if body.size == 0 || body.size > state.config.blob_max_size {
+            return Err(MediatorError::ServiceLimitError(
+                session.session_id.clone(),
+                format!(
+                    "Blob size ({}) must be between 1 and {} bytes",
+                    body.size, state.config.blob_max_size
+                ),
+            )
+            .into());
+        }
+        if body.recipients.len() > state.config.to_recipients_limit {
+            return Err(MediatorError::ServiceLimitError(
+                session.session_id.clone(),
+                format!(
+                    "Blob recipients ({}) exceeds the limit of {}",
+                    body.recipients.len(),
+                    state.config.to_recipients_limit
+                ),
+            )
+            .into());
+        }
+
+        let tenant = state.config.tenant(&session.tenant);
+        let access: Vec<String> = body
+            .recipients
+            .iter()
+            .map(|recipient| tenant.did_hash(recipient))
+            .collect();
+
+        // Until it is bound, the blob is kept as long as a message to its recipients (or the uploader) would be
+        let expires_at = if access.is_empty() {
+            _expires_at(&state, &session, std::slice::from_ref(&session.did_hash)).await?
+        } else {
+            _expires_at(&state, &session, &access).await?
+        };
+
+        let blob_id = Uuid::new_v4().to_string();
+        let upload_id = storage.create_upload(&blob_id).await?;
+
+        let record = BlobRecord {
+            blob_id: blob_id.clone(),
+            owner: session.did_hash.clone(),
+            size: body.size,
+            chunk_size: state.config.blob_chunk_size,
+            upload_id,
+            complete: false,
+            expires_at,
+            received: Default::default(),
+        };
+
+        if let Err(err) = state
+            .database
+            .blob_create(&session.session_id, &record, &access)
+            .await
+        {
+            let _ = storage.delete(&blob_id, &record.upload_id).await;
+            return Err(err.into());
+        }
+
+        debug!(
+            "blob({}) created with ({}) chunks",
+            blob_id,
+            record.chunks()
+        );
+        Ok(_response(session.session_id, record.status()))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Stores a chunk of a blob upload, only the uploader can send chunks
+/// Each chunk must be exactly `chunk_size` bytes, except the last chunk
+pub async fn blob_chunk_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Path((blob_id, index)): Path<(String, u64)>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<SuccessResponse<BlobStatus>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "blob_chunk_handler",
+        session = session.session_id,
+        did = session.did,
+        blob_id = blob_id,
+        index = index,
+    );
+    async move {
+        let storage = _storage(&state, &session)?;
+        let mut record = _get_blob(&state, &session, &blob_id, true).await?;
+
+        if record.complete {
+            return Err(MediatorError::RequestDataError(
+                session.session_id.clone(),
+                format!("blob({}) upload has already been completed", blob_id),
+            )
+            .into());
+        }
+        if index >= record.chunks() {
+            return Err(MediatorError::RequestDataError(
+                session.session_id.clone(),
+                format!(
+                    "chunk index ({}) is out of range, blob({}) has ({}) chunks",
+                    index,
+                    blob_id,
+                    record.chunks()
+                ),
+            )
+            .into());
+        }
+        if body.len() as u64 != record.chunk_len(index) {
+            return Err(MediatorError::RequestDataError(
+                session.session_id.clone(),
+                format!(
+                    "chunk ({}) must be ({}) bytes, received ({}) bytes",
+                    index,
+                    record.chunk_len(index),
+                    body.len()
+                ),
+            )
+            .into());
+        }
+
+        let etag = storage
+            .put_chunk(&blob_id, &record.upload_id, index, body)
+            .await?;
+        state
+            .database
+            .blob_chunk_received(&session.session_id, &blob_id, index, &etag)
+            .await?;
+        record.received.insert(index, etag);
+
+        Ok(_response(session.session_id, record.status()))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Returns the status of a blob, only the uploader can see the status
+pub async fn blob_status_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Path(blob_id): Path<String>,
+) -> Result<(StatusCode, Json<SuccessResponse<BlobStatus>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "blob_status_handler",
+        session = session.session_id,
+        did = session.did,
+        blob_id = blob_id,
+    );
+    async move {
+        _storage(&state, &session)?;
+        let record = _get_blob(&state, &session, &blob_id, true).await?;
+
+        Ok(_response(session.session_id, record.status()))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Completes a blob upload once all chunks have been received
+pub async fn blob_complete_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Path(blob_id): Path<String>,
+) -> Result<(StatusCode, Json<SuccessResponse<BlobStatus>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "blob_complete_handler",
+        session = session.session_id,
+        did = session.did,
+        blob_id = blob_id,
+    );
+    async move {
+        let storage = _storage(&state, &session)?;
+        let mut record = _get_blob(&state, &session, &blob_id, true).await?;
+
+        if !record.complete {
+            if (record.received.len() as u64) < record.chunks() {
+                return Err(MediatorError::RequestDataError(
+                    session.session_id.clone(),
+                    format!(
+                        "blob({}) has received ({}) of ({}) chunks",
+                        blob_id,
+                        record.received.len(),
+                        record.chunks()
+                    ),
+                )
+                .into());
+            }
+
+            // received is ordered by chunk index
+            let etags = record.received.values().cloned().collect();
+            storage
+                .complete_upload(&blob_id, &record.upload_id, etags)
+                .await?;
+            state
+                .database
+                .blob_completed(&session.session_id, &blob_id)
+                .await?;
+            record.complete = true;
+            debug!("blob({}) upload completed", blob_id);
+        }
+
+        Ok(_response(session.session_id, record.status()))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Binds a blob to messages sent by the uploader
+/// The recipients of the messages can download the blob, and the blob expires with the messages
+pub async fn blob_bind_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Path(blob_id): Path<String>,
+    Json(body): Json<BlobBindRequest>,
+) -> Result<(StatusCode, Json<SuccessResponse<BlobStatus>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "blob_bind_handler",
+        session = session.session_id,
+        did = session.did,
+        blob_id = blob_id,
+    );
+    async move {
+        _storage(&state, &session)?;
+        let mut record = _get_blob(&state, &session, &blob_id, true).await?;
+
+        if body.message_ids.len() > state.config.to_recipients_limit {
+            return Err(MediatorError::ServiceLimitError(
+                session.session_id.clone(),
+                format!(
+                    "Blob can be bound to a maximum of {} messages per request",
+                    state.config.to_recipients_limit
+                ),
+            )
+            .into());
+        }
+
+        let mut recipients = Vec::with_capacity(body.message_ids.len());
+        for msg_id in &body.message_ids {
+            let recipient = match state
+                .database
+                .get_message_recipient(&session.did_hash, msg_id)
+                .await?
+            {
+                Some(recipient) => recipient,
+                None => {
+                    return Err(MediatorError::RequestDataError(
+                        session.session_id.clone(),
+                        format!("Message not found for ID: {}", msg_id),
+                    )
+                    .into())
+                }
+            };
+            recipients.push(recipient);
+        }
+
+        // The blob lives as long as the longest retained message, binding never shortens the life of a blob
+        let expires_at = _expires_at(&state, &session, &recipients)
+            .await?
+            .max(record.expires_at);
+        for (msg_id, recipient) in body.message_ids.iter().zip(&recipients) {
+            state
+                .database
+                .blob_bind(&session.session_id, &blob_id, msg_id, recipient, expires_at)
+                .await?;
+            debug!("blob({}) bound to message({})", blob_id, msg_id);
+        }
+        if !body.message_ids.is_empty() {
+            record.expires_at = expires_at;
+        }
+
+        Ok(_response(session.session_id, record.status()))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Downloads a completed blob, available to the uploader and the recipients
+pub async fn blob_download_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Path(blob_id): Path<String>,
+) -> Result<Response, AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "blob_download_handler",
+        session = session.session_id,
+        did = session.did,
+        blob_id = blob_id,
+    );
+    async move {
+        let storage = _storage(&state, &session)?;
+        let record = _get_blob(&state, &session, &blob_id, false).await?;
+
+        if !record.complete || record.expires_at <= _now() {
+            return Err(MediatorError::PermissionError(
+                session.session_id.clone(),
+                format!("blob({}) is not available", blob_id),
+            )
+            .into());
+        }
+
+        let body = storage.download(&blob_id).await?;
+        Ok((
+            [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (header::CONTENT_LENGTH, record.size.to_string()),
+            ],
+            body,
+        )
+            .into_response())
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Deletes a blob, only the uploader can delete a blob
+pub async fn blob_delete_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Path(blob_id): Path<String>,
+) -> Result<(StatusCode, Json<SuccessResponse<BlobStatus>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "blob_delete_handler",
+        session = session.session_id,
+        did = session.did,
+        blob_id = blob_id,
+    );
+    async move {
+        let storage = _storage(&state, &session)?;
+        let record = _get_blob(&state, &session, &blob_id, true).await?;
+
+        storage.delete(&blob_id, &record.upload_id).await?;
+        state
+            .database
+            .blob_delete(&session.session_id, &blob_id)
+            .await?;
+
+        debug!("blob({}) deleted", blob_id);
+        Ok(_response(session.session_id, record.status()))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Blob storage, if blobs are enabled
+fn _storage<'a>(
+    state: &'a SharedData,
+    session: &Session,
+) -> Result<&'a BlobStorage, MediatorError> {
+    match &state.config.blob_storage {
+        Some(storage) if state.config.blobs_enabled => Ok(storage),
+        _ => Err(MediatorError::NotImplemented(
+            session.session_id.clone(),
+            "Blob storage is not enabled on this mediator".into(),
+        )),
+    }
+}
+
+/// Retrieves a blob the session has access to
+/// A blob that doesn't exist is a permission error, so that blob IDs can't be probed
+/// - owner_only: Only the uploader has access, otherwise recipients have access as well
+async fn _get_blob(
+    state: &SharedData,
+    session: &Session,
+    blob_id: &str,
+    owner_only: bool,
+) -> Result<BlobRecord, MediatorError> {
+    let denied = || {
+        MediatorError::PermissionError(
+            session.session_id.clone(),
+            format!("blob({}) not found or access denied", blob_id),
+        )
+    };
+
+    // blob_id is used in storage paths, only accept the UUIDs the mediator issues
+    if Uuid::parse_str(blob_id).is_err() {
+        return Err(denied());
+    }
+
+    let record = state
+        .database
+        .blob_get(&session.session_id, blob_id)
+        .await?
+        .ok_or_else(denied)?;
+
+    if record.owner == session.did_hash
+        || (!owner_only
+            && state
+                .database
+                .blob_has_access(&session.session_id, blob_id, &session.did_hash)
+                .await?)
+    {
+        Ok(record)
+    } else {
+        Err(denied())
+    }
+}
+
+/// Blobs are kept as long as the messages that reference them, messages are retained by the mailbox tier
+/// of their recipient
+/// Returns when a blob referenced by messages to the recipients (DID hashes) expires, the longest retention wins
+async fn _expires_at(
+    state: &SharedData,
+    session: &Session,
+    recipients: &[String],
+) -> Result<u64, MediatorError> {
+    let mut retention = 0;
+    for recipient in recipients {
+        let tier = state
+            .database
+            .did_tier(&session.session_id, recipient)
+            .await?;
+        retention = retention.max(tier.message_expiry_minutes as u64 * 60);
+    }
+    Ok(_now() + retention)
+}
+
+fn _now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn _response(
+    session_id: String,
+    status: BlobStatus,
+) -> (StatusCode, Json<SuccessResponse<BlobStatus>>) {
+    (
+        StatusCode::OK,
+        Json(SuccessResponse {
+            sessionId: session_id,
+            httpCode: StatusCode::OK.as_u16(),
+            errorCode: 0,
+            errorCodeStr: "NA".to_string(),
+            message: "Success".to_string(),
+            data: Some(status),
+        }),
+    )
+}
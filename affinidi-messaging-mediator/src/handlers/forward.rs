@@ -0,0 +1,144 @@
+//! Anonymous forward messages and the consent of a DID to receive them
+//!
+//! `POST /forward` accepts a DIDComm routing 2.0 `forward` message without authentication.
+//! Authenticated DIDs manage who can deliver these messages to them with `GET/PUT /forward/acl`.
+use affinidi_messaging_sdk::messages::{ForwardAcl, ForwardConsent};
+use axum::{
+    extract::{ConnectInfo, State},
+    Json,
+};
+use http::{HeaderMap, StatusCode};
+use std::net::SocketAddr;
+use tracing::{span, Instrument, Level};
+
+use crate::{
+    common::{
+        errors::{AppError, MediatorError, Session, SuccessResponse},
+        tenant::RequestTenant,
+    },
+    messages::forward::{handle_forward, ANONYMOUS_SESSION},
+    SharedData,
+};
+
+/// Header that carries the proof-of-work nonce of a forward message
+pub const POW_HEADER: &str = "x-atm-pow";
+
+/// Accepts an anonymous forward message for a DID that is mediated by this mediator
+/// Returns the msg_id of the queued message
+pub async fn forward_handler(
+    State(state): State<SharedData>,
+    RequestTenant(tenant): RequestTenant,
+    ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, Json<SuccessResponse<String>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "forward_handler",
+        client = connect_info.to_string()
+    );
+    async move {
+        let pow = headers.get(POW_HEADER).and_then(|pow| pow.to_str().ok());
+
+        let msg_id =
+            handle_forward(&state, &tenant, &connect_info.ip().to_string(), &body, pow).await?;
+
+        Ok((
+            StatusCode::ACCEPTED,
+            Json(SuccessResponse {
+                sessionId: ANONYMOUS_SESSION.to_string(),
+                httpCode: StatusCode::ACCEPTED.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(msg_id),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Returns who can deliver anonymous forward messages to the authenticated DID
+pub async fn forward_acl_handler(
+    session: Session,
+    State(state): State<SharedData>,
+) -> Result<(StatusCode, Json<SuccessResponse<ForwardAcl>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "forward_acl_handler",
+        session = session.session_id,
+        did = session.did,
+    );
+    async move {
+        let acl = state
+            .database
+            .forward_acl_get(&session.session_id, &session.did_hash)
+            .await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(acl),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Sets who can deliver anonymous forward messages to the authenticated DID
+pub async fn forward_acl_update_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Json(body): Json<ForwardAcl>,
+) -> Result<(StatusCode, Json<SuccessResponse<ForwardAcl>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "forward_acl_update_handler",
+        session = session.session_id,
+        did = session.did,
+        consent = format!("{:?}", body.consent),
+    );
+    async move {
+        let acl = match body.consent {
+            ForwardConsent::Senders if body.senders.is_empty() => {
+                return Err(MediatorError::RequestDataError(
+                    session.session_id.clone(),
+                    "Consent for senders requires at least one sender DID".into(),
+                )
+                .into());
+            }
+            ForwardConsent::Senders => body,
+            // Senders only apply to consent for senders
+            consent => ForwardAcl {
+                consent,
+                senders: vec![],
+            },
+        };
+
+        state
+            .database
+            .forward_acl_set(&session.session_id, &session.did_hash, &acl)
+            .await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(acl),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
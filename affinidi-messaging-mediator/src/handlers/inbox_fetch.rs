@@ -1,12 +1,23 @@
 use crate::{
     common::errors::{AppError, MediatorError, Session, SuccessResponse},
+    messages::receipts,
+    tasks::websocket_streaming::{
+        StreamingClient, StreamingTask, StreamingUpdate, StreamingUpdateState,
+    },
     SharedData,
 };
 use affinidi_messaging_sdk::messages::{fetch::FetchOptions, GetMessagesResponse};
 use axum::{extract::State, Json};
 use http::StatusCode;
 use regex::Regex;
-use tracing::{span, Instrument, Level};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    select,
+    sync::{mpsc, Notify},
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, span, warn, Instrument, Level};
+use uuid::Uuid;
 
 /// Fetches available messages from the inbox
 ///
@@ -14,6 +25,9 @@ use tracing::{span, Instrument, Level};
 /// - `session`: Session information
 /// - `folder`: Folder to retrieve messages from
 /// - `did_hash`: sha256 hash of the DID we are checking
+///
+/// If `wait_seconds` is set and there are no messages, the request waits (long-polling) until
+/// messages arrive or the wait expires (capped at `fetch_wait_max`)
 pub async fn inbox_fetch_handler(
     session: Session,
     State(state): State<SharedData>,
@@ -26,7 +40,8 @@ pub async fn inbox_fetch_handler(
         session_did = session.did,
         fetch.limit = body.limit,
         fetch.start_id = body.start_id,
-        fetch.delete_policy = body.delete_policy.to_string()
+        fetch.delete_policy = body.delete_policy.to_string(),
+        fetch.wait_seconds = body.wait_seconds
     );
     async move {
 
@@ -45,7 +60,19 @@ pub async fn inbox_fetch_handler(
         }
 
         // Fetch messages if possible
-        let results = state.database.fetch_messages(&session.session_id, &session.did_hash, &body).await?;
+        let mut results = state.database.fetch_messages(&session.session_id, &session.did_hash, &body).await?;
+
+        // Long-polling requires streaming, which wakes up the request when messages are stored
+        let wait = body.wait_seconds.unwrap_or(0).min(state.config.fetch_wait_max);
+        if let Some(streaming) = &state.streaming_task {
+            if results.success.is_empty() && wait > 0 {
+                results = _wait_for_messages(&state, streaming, &session, &body, wait).await?;
+            }
+        }
+
+        // Fetched messages can no longer be recalled, and may need a delivery receipt
+        let fetched: Vec<&str> = results.success.iter().map(|m| m.msg_id.as_str()).collect();
+        receipts::fetched(&state, &session, &fetched).await;
 
         Ok((
             StatusCode::OK,
@@ -62,3 +89,87 @@ pub async fn inbox_fetch_handler(
     .instrument(_span)
     .await
 }
+
+/// Waits up to `wait` seconds for messages to arrive for the DID, then fetches them
+/// Registers with the streaming task as a waiting request, which is only woken up by stored messages
+/// and doesn't count as a live streaming connection
+async fn _wait_for_messages(
+    state: &SharedData,
+    streaming: &StreamingTask,
+    session: &Session,
+    options: &FetchOptions,
+    wait: u64,
+) -> Result<GetMessagesResponse, MediatorError> {
+    let deadline = Instant::now() + Duration::from_secs(wait);
+
+    let (tx, mut rx) = mpsc::channel(10);
+    let resync = Arc::new(Notify::new());
+    let conn_id = Uuid::new_v4().to_string();
+
+    if let Err(err) = streaming
+        .channel
+        .send(StreamingUpdate {
+            did_hash: session.did_hash.clone(),
+            state: StreamingUpdateState::Wait(StreamingClient {
+                conn_id: conn_id.clone(),
+                session_id: session.session_id.clone(),
+                tx,
+                resync: resync.clone(),
+            }),
+        })
+        .await
+    {
+        warn!("Error sending wait message to streaming task: {:?}", err);
+        return state
+            .database
+            .fetch_messages(&session.session_id, &session.did_hash, options)
+            .await;
+    }
+    debug!("waiting up to {} seconds for messages", wait);
+
+    let results = async {
+        // Messages stored before the request is registered are found by the first fetch
+        select! {
+            _ = resync.notified() => {}
+            _ = sleep_until(deadline) => {}
+        }
+
+        loop {
+            let results = state
+                .database
+                .fetch_messages(&session.session_id, &session.did_hash, options)
+                .await?;
+            if !results.success.is_empty() || Instant::now() >= deadline {
+                return Ok(results);
+            }
+
+            // Only stored messages are sent to a waiting request
+            select! {
+                value = rx.recv() => {
+                    if value.is_none() {
+                        sleep_until(deadline).await;
+                    }
+                }
+                _ = resync.notified() => {}
+                _ = sleep_until(deadline) => {}
+            }
+        }
+    }
+    .await;
+
+    if let Err(err) = streaming
+        .channel
+        .send(StreamingUpdate {
+            did_hash: session.did_hash.clone(),
+            state: StreamingUpdateState::Deregister(conn_id),
+        })
+        .await
+    {
+        warn!(
+            "Error sending deregister message to streaming task: {:?}",
+            err
+        );
+    }
+
+    results
+}
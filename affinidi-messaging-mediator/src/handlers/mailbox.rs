@@ -0,0 +1,432 @@
+//! Export and import of the mailbox of a DID
+//!
+//! `GET /mailbox/export` returns a page of the inbox and outbox of the authenticated DID as a DIDComm message
+//! that is signed by the mediator (tenant), so the archive can be verified by the mediator that imports it.
+//! The inbox is exported first, followed by the outbox.
+//! `POST /mailbox/import` verifies a page of the authenticated DID and recreates its `RECEIVE_Q`/`SEND_Q`
+//! entries and counters. Messages that are already queued (same content or msg_id) and outbox records that
+//! already exist (same msg_id) are skipped.
+//! Archives are only imported if they are signed by a tenant of this mediator, or by a trusted mediator.
+use affinidi_messaging_didcomm::{envelope::MetaEnvelope, Message, UnpackOptions};
+use affinidi_messaging_sdk::messages::{
+    ArchivedSentMessage, MailboxArchive, MailboxImportReport, SignedMailboxArchive,
+    MAILBOX_ARCHIVE_MESSAGE_TYPE,
+};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::SystemTime;
+use tracing::{debug, info, span, warn, Instrument, Level};
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        config::Config,
+        errors::{AppError, MediatorError, Session, SuccessResponse},
+    },
+    SharedData,
+};
+
+/// Cursor of an export page that continues with the outbox, followed by the stream ID of the last record
+const OUTBOX_CURSOR: &str = "outbox";
+
+/// Position of a paged export, the inbox is exported before the outbox
+/// Each holds the stream ID of the last entry of the previous page, None to start at the first entry
+#[derive(Debug, PartialEq)]
+enum ExportCursor<'a> {
+    Inbox(Option<&'a str>),
+    Outbox(Option<&'a str>),
+}
+
+/// Query parameters of a mailbox export
+/// - cursor: The `cursor` of the previous page, omit for the first page
+#[derive(Deserialize, Debug, Default)]
+pub struct MailboxExportQuery {
+    pub cursor: Option<String>,
+}
+
+/// Exports a page of the mailbox of the authenticated DID as a signed archive
+/// Pages are sized so that they can be imported within the http_size_limit of a mediator
+pub async fn mailbox_export_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Query(query): Query<MailboxExportQuery>,
+) -> Result<(StatusCode, Json<SuccessResponse<SignedMailboxArchive>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "mailbox_export_handler",
+        session = session.session_id,
+        did = session.did,
+    );
+    async move {
+        let tenant = state.config.tenant(&session.tenant);
+
+        // A signed archive is base64 encoded JSON, which roughly doubles the size of the messages
+        let max_bytes = state.config.http_size_limit as usize / 2;
+        let mut inbox = Vec::new();
+        let mut outbox = Vec::new();
+        let cursor = match _parse_cursor(query.cursor.as_deref()) {
+            ExportCursor::Inbox(start) => {
+                let next;
+                (inbox, next) = state
+                    .database
+                    .mailbox_export_inbox(&session.session_id, &session.did_hash, start, max_bytes)
+                    .await?;
+                let used: usize = inbox.iter().map(|message| message.msg.len()).sum();
+                match next {
+                    Some(next) => Some(next),
+                    // The rest of the page is filled with the outbox
+                    None if used < max_bytes => {
+                        let next;
+                        (outbox, next) =
+                            _export_outbox(&state, &session, None, max_bytes - used).await?;
+                        next
+                    }
+                    None => Some(OUTBOX_CURSOR.to_string()),
+                }
+            }
+            ExportCursor::Outbox(start) => {
+                let next;
+                (outbox, next) = _export_outbox(&state, &session, start, max_bytes).await?;
+                next
+            }
+        };
+
+        let archive = MailboxArchive {
+            did: session.did.clone(),
+            mediator_did: tenant.did.clone(),
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            inbox,
+            outbox,
+        };
+
+        info!(
+            "mailbox exported: inbox({}) outbox({}) more({})",
+            archive.inbox.len(),
+            archive.outbox.len(),
+            cursor.is_some()
+        );
+
+        let message = Message::build(
+            Uuid::new_v4().into(),
+            MAILBOX_ARCHIVE_MESSAGE_TYPE.to_owned(),
+            json!(archive),
+        )
+        .from(tenant.did.clone())
+        .to(session.did.clone())
+        .created_time(archive.created)
+        .finalize();
+
+        let (signed, _) = message
+            .pack_signed(&tenant.did, &state.did_resolver, &tenant.secrets)
+            .await
+            .map_err(|err| {
+                MediatorError::InternalError(
+                    session.session_id.clone(),
+                    format!("Couldn't sign mailbox archive: {}", err),
+                )
+            })?;
+
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(SignedMailboxArchive {
+                    archive: signed,
+                    cursor,
+                }),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Imports a signed mailbox archive of the authenticated DID
+/// Returns a report of the messages imported, skipped and failed
+pub async fn mailbox_import_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Json(body): Json<SignedMailboxArchive>,
+) -> Result<(StatusCode, Json<SuccessResponse<MailboxImportReport>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "mailbox_import_handler",
+        session = session.session_id,
+        did = session.did,
+    );
+    async move {
+        let archive = _verify_archive(&state, &session, &body.archive).await?;
+        let tenant = state.config.tenant(&session.tenant);
+        let tier = state
+            .database
+            .did_tier(&session.session_id, &session.did_hash)
+            .await?;
+
+        let mut report = MailboxImportReport::default();
+        for message in &archive.inbox {
+            if tier.max_message_size > 0 && message.msg.len() > tier.max_message_size as usize {
+                report.errors.push((
+                    message.msg_id.clone(),
+                    format!(
+                        "Message exceeds the limit of {} bytes",
+                        tier.max_message_size
+                    ),
+                ));
+                continue;
+            }
+
+            match state
+                .database
+                .mailbox_import_message(&session.session_id, &session.did_hash, message, &tier)
+                .await
+            {
+                Ok(true) => report.imported += 1,
+                Ok(false) => report.duplicates += 1,
+                Err(err) => {
+                    warn!("msg_id({}) not imported: {}", message.msg_id, err);
+                    report
+                        .errors
+                        .push((message.msg_id.clone(), err.to_string()));
+                }
+            }
+        }
+
+        for message in &archive.outbox {
+            match state
+                .database
+                .mailbox_import_sent(
+                    &session.session_id,
+                    &session.did_hash,
+                    message,
+                    &tenant.did_hash(&message.to_address),
+                )
+                .await
+            {
+                Ok(true) => report.sent += 1,
+                Ok(false) => report.duplicates += 1,
+                Err(err) => {
+                    warn!("outbox msg_id({}) not imported: {}", message.msg_id, err);
+                    report
+                        .errors
+                        .push((message.msg_id.clone(), err.to_string()));
+                }
+            }
+        }
+
+        info!(
+            "mailbox imported from mediator({}): imported({}) sent({}) duplicates({}) errors({})",
+            archive.mediator_did,
+            report.imported,
+            report.sent,
+            report.duplicates,
+            report.errors.len()
+        );
+
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(report),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Verifies that the archive is signed by the mediator that exported it, and belongs to the session DID
+/// The exporting mediator must be a tenant of this mediator, or one of the `mailbox_trusted_mediators`
+async fn _verify_archive(
+    state: &SharedData,
+    session: &Session,
+    archive: &str,
+) -> Result<MailboxArchive, MediatorError> {
+    let tenant = state.config.tenant(&session.tenant);
+
+    let mut envelope = MetaEnvelope::new(archive, &state.did_resolver, &tenant.secrets)
+        .await
+        .map_err(|err| {
+            MediatorError::ParseError(
+                session.session_id.clone(),
+                "Mailbox archive".into(),
+                err.to_string(),
+            )
+        })?;
+
+    let (msg, metadata) = Message::unpack(
+        &mut envelope,
+        &state.did_resolver,
+        &tenant.secrets,
+        &UnpackOptions {
+            crypto_operations_limit_per_message: state.config.crypto_operations_per_message_limit,
+            ..UnpackOptions::default()
+        },
+    )
+    .await
+    .map_err(|err| {
+        MediatorError::MessageUnpackError(
+            session.session_id.clone(),
+            format!("Couldn't verify mailbox archive. Reason: {}", err),
+        )
+    })?;
+
+    if msg.type_ != MAILBOX_ARCHIVE_MESSAGE_TYPE {
+        return Err(MediatorError::RequestDataError(
+            session.session_id.clone(),
+            format!("Expected a mailbox archive, received ({})", msg.type_),
+        ));
+    }
+
+    let signer = metadata
+        .sign_from
+        .as_deref()
+        .map(|kid| kid.split('#').next().unwrap_or(kid));
+    if signer.is_none() || signer != msg.from.as_deref() {
+        return Err(MediatorError::PermissionError(
+            session.session_id.clone(),
+            "Mailbox archive isn't signed by the mediator that exported it".into(),
+        ));
+    }
+
+    let archive: MailboxArchive = serde_json::from_value(msg.body).map_err(|err| {
+        MediatorError::RequestDataError(
+            session.session_id.clone(),
+            format!("Mailbox archive isn't valid. Reason: {}", err),
+        )
+    })?;
+
+    if archive.did != session.did || signer != Some(archive.mediator_did.as_str()) {
+        return Err(MediatorError::PermissionError(
+            session.session_id.clone(),
+            "Mailbox archive belongs to a different DID".into(),
+        ));
+    }
+
+    if !_trusted_mediator(&state.config, &archive.mediator_did) {
+        return Err(MediatorError::PermissionError(
+            session.session_id.clone(),
+            format!(
+                "Mailbox archive is signed by a mediator ({}) that isn't trusted",
+                archive.mediator_did
+            ),
+        ));
+    }
+
+    debug!(
+        "mailbox archive verified: mediator({}) inbox({}) outbox({})",
+        archive.mediator_did,
+        archive.inbox.len(),
+        archive.outbox.len()
+    );
+    Ok(archive)
+}
+
+/// Exports a page of the outbox, the cursor of the next page is prefixed with `outbox:`
+async fn _export_outbox(
+    state: &SharedData,
+    session: &Session,
+    start: Option<&str>,
+    max_bytes: usize,
+) -> Result<(Vec<ArchivedSentMessage>, Option<String>), MediatorError> {
+    let (outbox, next) = state
+        .database
+        .mailbox_export_outbox(&session.session_id, &session.did_hash, start, max_bytes)
+        .await?;
+    Ok((
+        outbox,
+        next.map(|next| [OUTBOX_CURSOR, ":", &next].concat()),
+    ))
+}
+
+/// Parses the cursor of an export, a stream ID continues the inbox and `outbox[:<stream ID>]` the outbox
+fn _parse_cursor(cursor: Option<&str>) -> ExportCursor<'_> {
+    match cursor {
+        None => ExportCursor::Inbox(None),
+        Some(OUTBOX_CURSOR) => ExportCursor::Outbox(None),
+        Some(cursor) => match cursor
+            .strip_prefix(OUTBOX_CURSOR)
+            .and_then(|rest| rest.strip_prefix(':'))
+        {
+            Some(start) => ExportCursor::Outbox(Some(start)),
+            None => ExportCursor::Inbox(Some(cursor)),
+        },
+    }
+}
+
+/// Archives can be imported from the tenants of this mediator and from trusted mediators
+fn _trusted_mediator(config: &Config, mediator_did: &str) -> bool {
+    config
+        .tenants
+        .iter()
+        .any(|tenant| tenant.did == mediator_did)
+        || config
+            .mailbox_trusted_mediators
+            .iter()
+            .any(|trusted| trusted == mediator_did)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::tenant::Tenant;
+
+    #[test]
+    fn trusts_tenants_and_configured_mediators() {
+        let config = Config {
+            tenants: vec![
+                Tenant {
+                    did: "did:example:mediator".into(),
+                    ..Tenant::default()
+                },
+                Tenant {
+                    namespace: "acme".into(),
+                    did: "did:example:acme".into(),
+                    ..Tenant::default()
+                },
+            ],
+            mailbox_trusted_mediators: vec!["did:example:old-mediator".into()],
+            ..Config::default()
+        };
+
+        assert!(_trusted_mediator(&config, "did:example:mediator"));
+        assert!(_trusted_mediator(&config, "did:example:acme"));
+        assert!(_trusted_mediator(&config, "did:example:old-mediator"));
+        assert!(!_trusted_mediator(&config, "did:example:alice"));
+        assert!(!_trusted_mediator(&config, ""));
+    }
+
+    #[test]
+    fn export_cursor_continues_inbox_then_outbox() {
+        assert_eq!(_parse_cursor(None), ExportCursor::Inbox(None));
+        assert_eq!(
+            _parse_cursor(Some("1700000000000-0")),
+            ExportCursor::Inbox(Some("1700000000000-0"))
+        );
+        assert_eq!(_parse_cursor(Some("outbox")), ExportCursor::Outbox(None));
+        assert_eq!(
+            _parse_cursor(Some("outbox:1700000000000-1")),
+            ExportCursor::Outbox(Some("1700000000000-1"))
+        );
+        // Anything else is validated as an inbox stream ID
+        assert_eq!(
+            _parse_cursor(Some("outboxes")),
+            ExportCursor::Inbox(Some("outboxes"))
+        );
+    }
+}
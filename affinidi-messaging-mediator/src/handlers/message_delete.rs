@@ -9,6 +9,7 @@ use tracing::{debug, span, warn, Instrument, Level};
 
 use crate::{
     common::errors::{AppError, MediatorError, Session, SuccessResponse},
+    tasks::websocket_streaming::{StreamingUpdate, StreamingUpdateState},
     SharedData,
 };
 
@@ -62,6 +63,19 @@ pub async fn message_delete_handler(
             }
         }
 
+        // Live streamed messages no longer need to be tracked for redelivery (e.g. SSE connections)
+        if !deleted.success.is_empty() {
+            if let Some(stream_task) = &state.streaming_task {
+                let _ = stream_task
+                    .channel
+                    .send(StreamingUpdate {
+                        did_hash: session.did_hash.clone(),
+                        state: StreamingUpdateState::Acknowledged(deleted.success.clone()),
+                    })
+                    .await;
+            }
+        }
+
         Ok((
             StatusCode::OK,
             Json(SuccessResponse {
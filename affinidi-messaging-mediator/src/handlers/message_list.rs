@@ -3,13 +3,16 @@ use crate::{
     SharedData,
 };
 use affinidi_messaging_didcomm::UnpackMetadata;
-use affinidi_messaging_sdk::messages::{Folder, GenericDataStruct, MessageList};
+use affinidi_messaging_sdk::messages::{
+    list::ListOptions, Folder, GenericDataStruct, ListMessagesResponse, MessageList,
+};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     Json,
 };
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
+use sha256::digest;
 use tracing::{debug, span, Instrument, Level};
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -20,13 +23,16 @@ pub struct ResponseData {
 impl GenericDataStruct for ResponseData {}
 
 /// Retrieves lists of messages either from the send or receive queue
+/// Returns the first page of messages, use `/list/:did_hash/:folder/page` to page through all messages
 /// # Parameters
 /// - `session`: Session information
 /// - `folder`: Folder to retrieve messages from
 /// - `did_hash`: sha256 hash of the DID we are checking
+/// - `options`: Query parameters to filter the list (see ListOptions)
 pub async fn message_list_handler(
     session: Session,
     Path((did_hash, folder)): Path<(String, Folder)>,
+    Query(options): Query<ListOptions>,
     State(state): State<SharedData>,
 ) -> Result<(StatusCode, Json<SuccessResponse<MessageList>>), AppError> {
     let _span = span!(
@@ -38,26 +44,53 @@ pub async fn message_list_handler(
         folder = folder.to_string()
     );
     async move {
-        // Check that the DID hash matches the session DID
-        // TODO: In the future, add support for lists of DID's owned by the session owner
-        if session.did_hash != did_hash {
-            return Err(MediatorError::PermissionError(
-                session.session_id,
-                "You don't have permission to access this resource.".into(),
-            )
-            .into());
-        }
+        let session_id = session.session_id.clone();
+        let messages = _list(&state, &session, &did_hash, folder, &options).await?;
 
-        let messages = state
-            .database
-            .list_messages(&did_hash, folder, None, state.config.max_listed_messages)
-            .await?;
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(messages.messages),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Retrieves a page of messages either from the send or receive queue
+/// # Parameters
+/// - `session`: Session information
+/// - `folder`: Folder to retrieve messages from
+/// - `did_hash`: sha256 hash of the DID we are checking
+/// - `options`: Query parameters to page through and filter the list (see ListOptions)
+pub async fn message_list_page_handler(
+    session: Session,
+    Path((did_hash, folder)): Path<(String, Folder)>,
+    Query(options): Query<ListOptions>,
+    State(state): State<SharedData>,
+) -> Result<(StatusCode, Json<SuccessResponse<ListMessagesResponse>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "message_list_page_handler",
+        session = session.session_id,
+        session_did = session.did,
+        did_hash = did_hash,
+        folder = folder.to_string()
+    );
+    async move {
+        let session_id = session.session_id.clone();
+        let messages = _list(&state, &session, &did_hash, folder, &options).await?;
 
-        debug!("List contains ({}) messages", messages.len());
         Ok((
             StatusCode::OK,
             Json(SuccessResponse {
-                sessionId: session.session_id,
+                sessionId: session_id,
                 httpCode: StatusCode::OK.as_u16(),
                 errorCode: 0,
                 errorCodeStr: "NA".to_string(),
@@ -69,3 +102,49 @@ pub async fn message_list_handler(
     .instrument(_span)
     .await
 }
+
+/// Checks access to the folder and retrieves a page of messages
+async fn _list(
+    state: &SharedData,
+    session: &Session,
+    did_hash: &str,
+    folder: Folder,
+    options: &ListOptions,
+) -> Result<ListMessagesResponse, MediatorError> {
+    // Check that the DID hash matches the session DID
+    // Clients use the plain sha256 hash of their DID, which differs from the session DID hash on tenants
+    // TODO: In the future, add support for lists of DID's owned by the session owner
+    if session.did_hash != did_hash && digest(&session.did) != did_hash {
+        return Err(MediatorError::PermissionError(
+            session.session_id.clone(),
+            "You don't have permission to access this resource.".into(),
+        ));
+    }
+
+    // Limit can't exceed the configured maximum
+    let limit = match options.limit {
+        Some(limit) if (1..=state.config.max_listed_messages).contains(&limit) => limit,
+        Some(limit) => {
+            return Err(MediatorError::RequestDataError(
+                session.session_id.clone(),
+                format!(
+                    "limit must be between 1 and {} inclusive. Got: {}",
+                    state.config.max_listed_messages, limit
+                ),
+            ));
+        }
+        None => state.config.max_listed_messages,
+    };
+
+    let messages = state
+        .database
+        .list_messages(&session.did_hash, &session.tenant, folder, options, limit)
+        .await?;
+
+    debug!(
+        "List contains ({}) messages, next_cursor({:?})",
+        messages.messages.len(),
+        messages.next_cursor
+    );
+    Ok(messages)
+}
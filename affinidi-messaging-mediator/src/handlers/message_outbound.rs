@@ -5,6 +5,7 @@ use tracing::{debug, span, Instrument, Level};
 
 use crate::{
     common::errors::{AppError, Session, SuccessResponse},
+    messages::receipts,
     SharedData,
 };
 
@@ -32,6 +33,10 @@ pub async fn message_outbound_handler(
             match state.database.get_message(&session.did_hash, msg_id).await {
                 Ok(msg) => {
                     debug!("Got message: {:?}", msg);
+                    // Only the recipient fetching the message counts as a delivery
+                    if msg.to_address.as_ref() == Some(&session.did_hash) {
+                        receipts::fetched(&state, &session, &[msg_id]).await;
+                    }
                     messages.success.push(msg);
 
                     if body.delete {
@@ -0,0 +1,73 @@
+use affinidi_messaging_sdk::messages::{RecallMessageRequest, RecallMessageResponse};
+use axum::{extract::State, Json};
+use http::StatusCode;
+use tracing::{debug, span, warn, Instrument, Level};
+
+use crate::{
+    common::errors::{AppError, MediatorError, Session, SuccessResponse},
+    SharedData,
+};
+
+/// Recalls messages sent by the caller that the recipient hasn't fetched yet
+/// Returns the messages that were recalled, and those that were too late to recall
+pub async fn message_recall_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Json(body): Json<RecallMessageRequest>,
+) -> Result<(StatusCode, Json<SuccessResponse<RecallMessageResponse>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "message_recall_handler",
+        session = session.session_id,
+        did = session.did,
+    );
+    async move {
+        debug!("Recalling ({}) messages", body.message_ids.len());
+        if body.message_ids.len() > state.config.max_deleted_messages.try_into().unwrap() {
+            return Err(MediatorError::RequestDataError(
+                session.session_id.clone(),
+                format!(
+                    "Operation exceeds the allowed limit. You may recall a maximum of {} messages per request. Received {} ids.",
+                    state.config.max_deleted_messages,
+                    body.message_ids.len()
+                ),
+            )
+            .into());
+        }
+        let mut recalled = RecallMessageResponse::default();
+
+        for message in &body.message_ids {
+            debug!("Recalling message: message_id({})", message);
+            let result = state
+                .database
+                .recall_message(&session.session_id, &session.did_hash, message)
+                .await;
+
+            match result {
+                Ok(_) => recalled.recalled.push(message.into()),
+                Err(MediatorError::MessageRecallTooLate(_, reason)) => {
+                    debug!("too late to recall msg({}). Reason: {}", message, reason);
+                    recalled.too_late.push((message.into(), reason));
+                }
+                Err(err) => {
+                    warn!("failed to recall msg({}). Reason: {}", message, err);
+                    recalled.errors.push((message.into(), err.to_string()));
+                }
+            }
+        }
+
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(recalled),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
@@ -1,25 +1,42 @@
 use crate::SharedData;
 use axum::{
-    extract::State,
+    extract::{DefaultBodyLimit, State},
     response::IntoResponse,
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 
+pub mod account;
 pub mod authenticate;
+pub mod blobs;
+pub mod forward;
 pub mod inbox_fetch;
+pub mod mailbox;
 pub mod message_delete;
 pub mod message_inbound;
 pub mod message_list;
 pub mod message_outbound;
+pub mod message_recall;
+pub mod oob;
+pub mod sse;
 pub mod websocket;
 pub mod well_known_did_fetch;
 
 pub fn application_routes(api_prefix: &String, shared_data: &SharedData) -> Router {
+    // Inbound messages (and imported archives) are limited by the tier max_message_size when stored,
+    // and by http_size_limit (not the 2MB default)
+    let inbound_limit = DefaultBodyLimit::max(shared_data.config.http_size_limit as usize);
+
     let app = Router::new()
         // Inbound message handling from ATM clients
-        .route("/inboud", post(message_inbound::message_inbound_handler))
-        .route("/inbound", post(message_inbound::message_inbound_handler))
+        .route(
+            "/inboud",
+            post(message_inbound::message_inbound_handler).layer(inbound_limit),
+        )
+        .route(
+            "/inbound",
+            post(message_inbound::message_inbound_handler).layer(inbound_limit),
+        )
         // Outbound message handling to ATM clients
         .route(
             "/outbound",
@@ -31,8 +48,17 @@ pub fn application_routes(api_prefix: &String, shared_data: &SharedData) -> Rout
             "/list/:did_hash/:folder",
             get(message_list::message_list_handler),
         )
+        .route(
+            "/list/:did_hash/:folder/page",
+            get(message_list::message_list_page_handler),
+        )
         // Delete/remove messages stored in ATM
         .route("/delete", delete(message_delete::message_delete_handler))
+        // Recall sent messages that haven't been fetched by the recipient yet
+        .route(
+            "/outbox/recall",
+            post(message_recall::message_recall_handler),
+        )
         // Authentication step 1/2 - Client requests challenge from server
         .route(
             "/authenticate/challenge",
@@ -42,6 +68,53 @@ pub fn application_routes(api_prefix: &String, shared_data: &SharedData) -> Rout
         .route("/authenticate", post(authenticate::authentication_response))
         // Websocket endpoint for ATM clients
         .route("/ws", get(websocket::websocket_handler))
+        // Single use ticket to authenticate a websocket without an Authorization header
+        .route("/ws/ticket", post(websocket::websocket_ticket_handler))
+        // Server-Sent Events endpoint for ATM clients that can't use websockets
+        .route("/sse", get(sse::sse_handler))
+        // Out-of-band storage for large attachments
+        .route("/blobs", post(blobs::blob_create_handler))
+        .route(
+            "/blobs/:blob_id",
+            get(blobs::blob_download_handler).delete(blobs::blob_delete_handler),
+        )
+        .route(
+            "/blobs/:blob_id/chunks/:index",
+            put(blobs::blob_chunk_handler).layer(DefaultBodyLimit::max(
+                shared_data.config.blob_chunk_size as usize,
+            )),
+        )
+        .route("/blobs/:blob_id/status", get(blobs::blob_status_handler))
+        .route(
+            "/blobs/:blob_id/complete",
+            post(blobs::blob_complete_handler),
+        )
+        .route("/blobs/:blob_id/bind", post(blobs::blob_bind_handler))
+        // Out-of-Band invitations hosted by the mediator, served at /oob?_oobid=<oobid>
+        .route(
+            "/oob",
+            get(oob::oob_invite_handler)
+                .post(oob::oob_invite_create_handler)
+                .delete(oob::oob_invite_delete_handler),
+        )
+        // Deletes all data of the authenticated DID
+        .route("/account", delete(account::account_delete_handler))
+        // Export and import of the mailbox of a DID (moving to another mediator or cluster)
+        .route("/mailbox/export", get(mailbox::mailbox_export_handler))
+        .route(
+            "/mailbox/import",
+            post(mailbox::mailbox_import_handler).layer(inbound_limit),
+        )
+        // Anonymous forward messages from other agents, and consent of a DID to receive them
+        .route(
+            "/forward",
+            post(forward::forward_handler)
+                .layer(DefaultBodyLimit::max(shared_data.config.forward_max_size)),
+        )
+        .route(
+            "/forward/acl",
+            get(forward::forward_acl_handler).put(forward::forward_acl_update_handler),
+        )
         .route(
             "/.well-known/did",
             get(well_known_did_fetch::well_known_did_fetch_handler),
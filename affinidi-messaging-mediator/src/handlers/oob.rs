@@ -0,0 +1,246 @@
+//! Out-of-Band 2.0 invitations hosted by the mediator
+//!
+//! An authenticated DID creates an invitation (`POST /oob`), which is served to anyone at the short URL
+//! `/oob?_oobid=<oobid>` until it expires. Clients that request JSON receive the invitation itself, other
+//! clients are redirected to `<redirect_url>?_oob=<invitation>` if a redirect URL is configured.
+use affinidi_messaging_didcomm::Message;
+use affinidi_messaging_sdk::messages::{OOBInvitationRequest, OOBInvitationResponse};
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use base64::prelude::*;
+use http::{header, HeaderMap, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, span, Instrument, Level};
+use uuid::Uuid;
+
+use crate::{
+    common::errors::{AppError, MediatorError, Session, SuccessResponse},
+    handlers::authenticate::create_random_string,
+    SharedData,
+};
+
+/// DIDComm message type of an Out-of-Band 2.0 invitation
+pub const OOB_INVITATION_TYPE: &str = "https://didcomm.org/out-of-band/2.0/invitation";
+
+/// Length of the short invitation ID
+const OOBID_LENGTH: usize = 20;
+
+#[derive(Deserialize)]
+pub struct OOBParams {
+    #[serde(rename = "_oobid")]
+    pub oobid: String,
+}
+
+/// Creates an Out-of-Band invitation from the authenticated DID, and hosts it until it expires
+pub async fn oob_invite_create_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Json(body): Json<OOBInvitationRequest>,
+) -> Result<(StatusCode, Json<SuccessResponse<OOBInvitationResponse>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "oob_invite_create_handler",
+        session = session.session_id,
+        did = session.did,
+    );
+    async move {
+        let expiry = body.expiry.unwrap_or(state.config.oob_max_expiry);
+        if expiry == 0 || expiry > state.config.oob_max_expiry {
+            return Err(MediatorError::RequestDataError(
+                session.session_id.clone(),
+                format!(
+                    "Invitation expiry ({}) must be between 1 and {} seconds",
+                    expiry, state.config.oob_max_expiry
+                ),
+            )
+            .into());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut invitation_body = Map::new();
+        if let Some(goal_code) = body.goal_code {
+            invitation_body.insert("goal_code".into(), json!(goal_code));
+        }
+        if let Some(goal) = body.goal {
+            invitation_body.insert("goal".into(), json!(goal));
+        }
+        if !body.accept.is_empty() {
+            invitation_body.insert("accept".into(), json!(body.accept));
+        }
+
+        let mut invitation = Message::build(
+            Uuid::new_v4().to_string(),
+            OOB_INVITATION_TYPE.to_string(),
+            Value::Object(invitation_body),
+        )
+        .from(session.did.clone())
+        .created_time(now)
+        .expires_time(now + expiry);
+        if !body.attachments.is_empty() {
+            invitation = invitation.attachments(body.attachments);
+        }
+        let invitation = invitation.finalize();
+
+        let serialized = serde_json::to_string(&invitation).map_err(|err| {
+            MediatorError::InternalError(
+                session.session_id.clone(),
+                format!("Couldn't serialize OOB invitation: {}", err),
+            )
+        })?;
+        if serialized.len() > state.config.oob_max_size {
+            return Err(MediatorError::ServiceLimitError(
+                session.session_id.clone(),
+                format!(
+                    "Invitation size ({}) exceeds the limit of {} bytes",
+                    serialized.len(),
+                    state.config.oob_max_size
+                ),
+            )
+            .into());
+        }
+
+        let oobid = create_random_string(OOBID_LENGTH);
+        state
+            .database
+            .oob_invite_store(
+                &session.session_id,
+                &oobid,
+                &session.did_hash,
+                &serialized,
+                expiry,
+                state.config.oob_max_invitations,
+            )
+            .await?;
+
+        debug!(
+            "OOB invitation({}) created as oobid({})",
+            invitation.id, oobid
+        );
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(OOBInvitationResponse {
+                    oobid,
+                    invitation,
+                    expires_at: now + expiry,
+                }),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Serves a hosted Out-of-Band invitation, no authentication is required
+/// Returns the invitation JSON, or a redirect to the configured `redirect_url` for clients that don't accept JSON
+pub async fn oob_invite_handler(
+    State(state): State<SharedData>,
+    Query(params): Query<OOBParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let _span = span!(Level::DEBUG, "oob_invite_handler", oobid = params.oobid);
+    async move {
+        let (_, invitation) = match state.database.oob_invite_get("NA", &params.oobid).await? {
+            Some(invite) => invite,
+            None => {
+                return Err(MediatorError::NotFound(
+                    "NA".into(),
+                    "OOB invitation not found or expired".into(),
+                )
+                .into())
+            }
+        };
+
+        let accepts_json = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json"));
+
+        match &state.config.oob_redirect_url {
+            Some(redirect_url) if !accepts_json => {
+                let separator = if redirect_url.contains('?') { '&' } else { '?' };
+                Ok(Redirect::to(&format!(
+                    "{}{}_oob={}",
+                    redirect_url,
+                    separator,
+                    BASE64_URL_SAFE_NO_PAD.encode(&invitation)
+                ))
+                .into_response())
+            }
+            _ => Ok(([(header::CONTENT_TYPE, "application/json")], invitation).into_response()),
+        }
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Removes a hosted Out-of-Band invitation, only the inviter can remove it
+pub async fn oob_invite_delete_handler(
+    session: Session,
+    State(state): State<SharedData>,
+    Query(params): Query<OOBParams>,
+) -> Result<(StatusCode, Json<SuccessResponse<String>>), AppError> {
+    let _span = span!(
+        Level::DEBUG,
+        "oob_invite_delete_handler",
+        session = session.session_id,
+        did = session.did,
+        oobid = params.oobid,
+    );
+    async move {
+        match state
+            .database
+            .oob_invite_get(&session.session_id, &params.oobid)
+            .await?
+        {
+            Some((did_hash, _)) if did_hash == session.did_hash => {
+                state
+                    .database
+                    .oob_invite_delete(&session.session_id, &params.oobid, &did_hash)
+                    .await?;
+            }
+            Some(_) => {
+                return Err(MediatorError::PermissionError(
+                    session.session_id.clone(),
+                    "OOB invitation not owned by this DID".into(),
+                )
+                .into())
+            }
+            None => {
+                return Err(MediatorError::NotFound(
+                    session.session_id.clone(),
+                    "OOB invitation not found or expired".into(),
+                )
+                .into())
+            }
+        }
+
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(params.oobid),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
@@ -0,0 +1,213 @@
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::Stream;
+use std::{convert::Infallible, sync::Arc};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Notify,
+    },
+    time::{interval, MissedTickBehavior},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info, span, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    common::errors::{AppError, MediatorError, Session},
+    handlers::websocket::{
+        is_blocked, LiveDeliveries, BLOCK_CHECK_INTERVAL, STREAMING_CHANNEL_SIZE,
+    },
+    messages::receipts,
+    tasks::websocket_streaming::{
+        StreamingClient, StreamingMessage, StreamingUpdate, StreamingUpdateState,
+    },
+    SharedData,
+};
+
+/// Name of the SSE event that carries a packed DIDComm message
+const MESSAGE_EVENT: &str = "message";
+
+/// Server-Sent Events alternative to the websocket, for clients that can't hold a websocket open
+/// The connection is live as soon as it opens, stored messages are delivered first
+/// Each packed message is sent as a `message` event, with the `id` set to the msg_id if the message is stored
+///
+/// Messages are sent to the mediator via `/inbound`, and acknowledged (removed) via `/delete`
+/// or a Message Pickup `messages-received` message
+pub async fn sse_handler(
+    session: Session,
+    State(state): State<SharedData>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "sse_handler",
+        session = session.session_id
+    );
+    async move {
+        let Some(streaming) = &state.streaming_task else {
+            return Err(MediatorError::NotImplemented(
+                session.session_id,
+                "Live streaming is not enabled on this mediator".into(),
+            )
+            .into());
+        };
+
+        // Register the transmission channel between websocket_streaming task and this connection
+        let (tx, rx): (Sender<StreamingMessage>, Receiver<StreamingMessage>) =
+            mpsc::channel(STREAMING_CHANNEL_SIZE);
+        let resync = Arc::new(Notify::new());
+        let conn_id = Uuid::new_v4().to_string();
+
+        streaming
+            .channel
+            .send(StreamingUpdate {
+                did_hash: session.did_hash.clone(),
+                state: StreamingUpdateState::Listen(StreamingClient {
+                    conn_id: conn_id.clone(),
+                    session_id: session.session_id.clone(),
+                    tx,
+                    resync: resync.clone(),
+                }),
+            })
+            .await
+            .map_err(|err| {
+                MediatorError::InternalError(
+                    session.session_id.clone(),
+                    format!("Couldn't register with the streaming task: {:?}", err),
+                )
+            })?;
+
+        let (events_tx, events_rx) = mpsc::channel(STREAMING_CHANNEL_SIZE);
+        tokio::spawn(handle_events(
+            state.clone(),
+            session,
+            conn_id,
+            rx,
+            resync,
+            events_tx,
+        ));
+
+        Ok(Sse::new(ReceiverStream::new(events_rx)).keep_alive(KeepAlive::default()))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// SSE state machine. This is spawned per connection and runs until the client disconnects
+async fn handle_events(
+    state: SharedData,
+    session: Session,
+    conn_id: String,
+    mut rx: Receiver<StreamingMessage>,
+    resync: Arc<Notify>,
+    events: Sender<Result<Event, Infallible>>,
+) {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "handle_events",
+        session = session.session_id
+    );
+    async move {
+        info!("SSE connection established");
+
+        let mut deliveries = LiveDeliveries::default();
+
+        // Closes the connection once the DID is blocked
+        let mut block_check = interval(BLOCK_CHECK_INTERVAL);
+        block_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        block_check.reset();
+
+        loop {
+            select! {
+                value = rx.recv() => {
+                    match value {
+                        Some(StreamingMessage::Deliver { msg_id, message }) => {
+                            if let Some(msg_id) = &msg_id {
+                                if deliveries.contains(msg_id) {
+                                    debug!("msg_id({}) already delivered, skipping", msg_id);
+                                    continue;
+                                }
+                                deliveries.insert(msg_id, &message);
+                            }
+                            if !_send_event(&events, msg_id.as_deref(), message).await {
+                                break;
+                            }
+                            if let Some(msg_id) = &msg_id {
+                                // Delivered messages can no longer be recalled, and may need a delivery receipt
+                                receipts::fetched(&state, &session, &[msg_id]).await;
+                            }
+                        }
+                        Some(StreamingMessage::Acknowledged(msg_ids)) => {
+                            for msg_id in msg_ids {
+                                deliveries.remove(&msg_id);
+                            }
+                        }
+                        None => {
+                            debug!("Received None from streaming task, closing connection");
+                            break;
+                        }
+                    }
+                }
+                _ = resync.notified() => {
+                    let mut sent: Vec<String> = Vec::new();
+                    let mut open = true;
+                    for (msg_id, msg) in deliveries.resync_batch(&state, &session, &resync).await {
+                        if !_send_event(&events, Some(&msg_id), msg).await {
+                            open = false;
+                            break;
+                        }
+                        sent.push(msg_id);
+                    }
+                    let sent: Vec<&str> = sent.iter().map(|id| id.as_str()).collect();
+                    receipts::fetched(&state, &session, &sent).await;
+                    if !open {
+                        break;
+                    }
+                }
+                _ = block_check.tick() => {
+                    if is_blocked(&state, &session).await {
+                        break;
+                    }
+                }
+                _ = events.closed() => {
+                    break;
+                }
+            }
+        }
+
+        // Remove this connection from the streaming task
+        if let Some(streaming) = &state.streaming_task {
+            let stop = StreamingUpdate {
+                did_hash: session.did_hash.clone(),
+                state: StreamingUpdateState::Deregister(conn_id),
+            };
+            let _ = streaming.channel.send(stop).await;
+        }
+
+        info!("SSE connection closed");
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Sends a packed message as an SSE event
+/// Returns false if the client has disconnected
+async fn _send_event(
+    events: &Sender<Result<Event, Infallible>>,
+    msg_id: Option<&str>,
+    message: String,
+) -> bool {
+    let mut event = Event::default().event(MESSAGE_EVENT).data(message);
+    if let Some(msg_id) = msg_id {
+        event = event.id(msg_id);
+    }
+    if events.send(Ok(event)).await.is_err() {
+        debug!("Couldn't send event to client, closing connection");
+        false
+    } else {
+        true
+    }
+}
@@ -1,37 +1,402 @@
+use affinidi_messaging_sdk::{
+    messages::{fetch::FetchOptions, FetchDeletePolicy, GenericDataStruct},
+    transports::websockets::frames::{decode_binary, encode_binary},
+};
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Query, State, WebSocketUpgrade,
     },
-    response::IntoResponse,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha256::digest;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
 };
 use tokio::{
     select,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Notify,
+    },
+    time::{interval, timeout, Instant, MissedTickBehavior},
 };
 use tracing::{debug, info, span, warn, Instrument};
+use uuid::Uuid;
 
 use crate::{
-    common::errors::Session,
-    messages::inbound::handle_inbound,
-    tasks::websocket_streaming::{StreamingUpdate, StreamingUpdateState},
+    common::{
+        errors::{AppError, MediatorError, Session, SuccessResponse},
+        jwt_auth::AuthError,
+        tenant::RequestTenant,
+    },
+    handlers::authenticate::{create_random_string, verify_challenge_response},
+    messages::{inbound::handle_inbound, receipts},
+    tasks::websocket_streaming::{
+        StreamingClient, StreamingMessage, StreamingUpdate, StreamingUpdateState,
+    },
     SharedData,
 };
 
-// Handles the switching of the protocol to a websocket connection
-pub async fn websocket_handler(
+/// Number of messages that can be queued from the streaming task before backpressure is applied
+pub(crate) const STREAMING_CHANNEL_SIZE: usize = 32;
+
+/// Number of stored messages redelivered per resync pass
+const RESYNC_BATCH_SIZE: usize = 100;
+
+/// Maximum number of unacknowledged messages tracked per connection, the oldest are forgotten first
+/// Forgotten messages can still be acknowledged by msg_id, and may be redelivered on resync
+const MAX_UNACKNOWLEDGED: usize = 1000;
+
+/// How often a streaming connection checks if its DID has been blocked, tokens are only checked on connect
+pub(crate) const BLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Prefix of a `Sec-WebSocket-Protocol` value that carries a websocket ticket, e.g. `ticket.<ticket>`
+const TICKET_PROTOCOL_PREFIX: &str = "ticket.";
+
+/// Query parameters of the websocket endpoint
+/// ticket: Single use ticket from `/ws/ticket`, for clients that can't set an Authorization header
+#[derive(Deserialize)]
+pub struct WebSocketParams {
+    ticket: Option<String>,
+}
+
+/// Single use ticket that authenticates a websocket connection
+/// expires_in: Time in seconds that the ticket is valid for
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct WebSocketTicket {
+    pub ticket: String,
+    pub expires_in: u64,
+}
+impl GenericDataStruct for WebSocketTicket {}
+
+/// Lightweight acknowledgement frame sent by the client over the websocket
+/// e.g. `{"ack": ["<msg_id or sha256 of the received message>", ...]}`
+/// Acknowledged messages are removed from the mediator, same as a `messages-received` message
+#[derive(Deserialize)]
+struct AckFrame {
+    ack: Vec<String>,
+}
+
+/// Tracks stored messages that have been live streamed on this connection and not yet acknowledged
+/// delivered: msg_id -> sha256 hash of the message, so that the client can acknowledge by either
+/// order: msg_id's in the order they were delivered, acknowledged msg_id's are removed lazily
+/// cursor: receive_id of the last message delivered via resync from the database
+#[derive(Default)]
+pub(crate) struct LiveDeliveries {
+    delivered: HashMap<String, String>,
+    order: VecDeque<String>,
+    cursor: Option<String>,
+}
+
+impl LiveDeliveries {
+    pub(crate) fn contains(&self, msg_id: &str) -> bool {
+        self.delivered.contains_key(msg_id)
+    }
+
+    /// Tracks a delivered message, forgetting the oldest once MAX_UNACKNOWLEDGED is reached
+    pub(crate) fn insert(&mut self, msg_id: &str, message: &str) {
+        if self
+            .delivered
+            .insert(msg_id.to_string(), digest(message))
+            .is_none()
+        {
+            self.order.push_back(msg_id.to_string());
+        }
+        while self.delivered.len() > MAX_UNACKNOWLEDGED {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.delivered.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        if self.order.len() > MAX_UNACKNOWLEDGED * 2 {
+            self.order.retain(|id| self.delivered.contains_key(id));
+        }
+    }
+
+    pub(crate) fn remove(&mut self, msg_id: &str) {
+        self.delivered.remove(msg_id);
+    }
+
+    /// Resolves an acknowledgement, which is either the msg_id or the sha256 hash of the message
+    fn msg_id(&self, id: String) -> String {
+        if self.delivered.contains_key(&id) {
+            return id;
+        }
+        match self.delivered.iter().find(|(_, hash)| **hash == id) {
+            Some((msg_id, _)) => msg_id.to_owned(),
+            None => id,
+        }
+    }
+
+    /// Fetches the next batch of stored messages that have not yet been delivered on this connection
+    /// Returned messages (msg_id, message) are marked as delivered, resync is notified again if more messages remain
+    pub(crate) async fn resync_batch(
+        &mut self,
+        state: &SharedData,
+        session: &Session,
+        resync: &Notify,
+    ) -> Vec<(String, String)> {
+        let messages = match state
+            .database
+            .fetch_messages(
+                &session.session_id,
+                &session.did_hash,
+                &FetchOptions {
+                    limit: RESYNC_BATCH_SIZE,
+                    start_id: self.cursor.clone(),
+                    delete_policy: FetchDeletePolicy::DoNotDelete,
+                    wait_seconds: None,
+                },
+            )
+            .await
+        {
+            Ok(messages) => messages,
+            Err(err) => {
+                warn!("Couldn't fetch messages to resync: {}", err);
+                return Vec::new();
+            }
+        };
+
+        debug!("resyncing {} messages", messages.success.len());
+        if messages.success.len() >= RESYNC_BATCH_SIZE {
+            resync.notify_one();
+        }
+
+        let mut batch = Vec::new();
+        for element in messages.success {
+            if element.receive_id.is_some() {
+                self.cursor = element.receive_id;
+            }
+            if self.contains(&element.msg_id) {
+                continue;
+            }
+            if let Some(msg) = element.msg {
+                self.insert(&element.msg_id, &msg);
+                batch.push((element.msg_id, msg));
+            }
+        }
+        batch
+    }
+}
+
+/// POST /ws/ticket
+/// Issues a single use ticket that authenticates a websocket connection without an Authorization header
+/// The ticket is passed as the `ticket` query parameter, or as a `ticket.<ticket>` `Sec-WebSocket-Protocol`
+pub async fn websocket_ticket_handler(
     session: Session,
-    ws: WebSocketUpgrade,
     State(state): State<SharedData>,
-) -> impl IntoResponse {
+) -> Result<(StatusCode, Json<SuccessResponse<WebSocketTicket>>), AppError> {
     let _span = span!(
         tracing::Level::DEBUG,
-        "websocket_handler",
+        "websocket_ticket_handler",
         session = session.session_id
     );
-    async move { ws.on_upgrade(move |socket| handle_socket(socket, state, session)) }
-        .instrument(_span)
-        .await
+    async move {
+        let ticket = create_random_string(32);
+        state
+            .database
+            .create_ws_ticket(
+                &ticket,
+                &session.session_id,
+                &session.did,
+                &session.tenant,
+                state.config.ws_ticket_expiry,
+            )
+            .await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(SuccessResponse {
+                sessionId: session.session_id,
+                httpCode: StatusCode::OK.as_u16(),
+                errorCode: 0,
+                errorCodeStr: "NA".to_string(),
+                message: "Success".to_string(),
+                data: Some(WebSocketTicket {
+                    ticket,
+                    expires_in: state.config.ws_ticket_expiry,
+                }),
+            }),
+        ))
+    }
+    .instrument(_span)
+    .await
+}
+
+// Handles the switching of the protocol to a websocket connection
+// Clients that can't set an Authorization header (e.g. browsers) authenticate with either:
+// - A ticket from `/ws/ticket`, as a query parameter or `Sec-WebSocket-Protocol`
+// - An Affinidi Authenticate message (response to `/authenticate/challenge`) as the first frame
+pub async fn websocket_handler(
+    session: Result<Session, AuthError>,
+    Query(params): Query<WebSocketParams>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+    State(state): State<SharedData>,
+    RequestTenant(tenant): RequestTenant,
+) -> Response {
+    let _span = span!(tracing::Level::DEBUG, "websocket_handler");
+    async move {
+        let session = match session {
+            Ok(session) => session,
+            Err(AuthError::MissingCredentials) => {
+                // Ticket in the Sec-WebSocket-Protocol header, this protocol is selected in the response
+                let protocol = headers
+                    .get(SEC_WEBSOCKET_PROTOCOL)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| {
+                        value
+                            .split(',')
+                            .map(|protocol| protocol.trim())
+                            .find(|protocol| protocol.starts_with(TICKET_PROTOCOL_PREFIX))
+                            .map(|protocol| protocol.to_string())
+                    });
+                let ticket = params.ticket.or_else(|| {
+                    protocol
+                        .as_ref()
+                        .map(|protocol| protocol[TICKET_PROTOCOL_PREFIX.len()..].to_string())
+                });
+
+                let Some(ticket) = ticket else {
+                    // No credentials, the first frame must authenticate the connection
+                    debug!("No credentials, expecting authentication message as the first frame");
+                    return ws
+                        .on_upgrade(move |socket| handle_unauthenticated(socket, state, tenant));
+                };
+
+                let session = match _redeem_ticket(&state, &ticket, &tenant).await {
+                    Ok(session) => session,
+                    Err(err) => return err.into_response(),
+                };
+
+                let ws = match protocol {
+                    Some(protocol) => ws.protocols([protocol]),
+                    None => ws,
+                };
+                return ws.on_upgrade(move |socket| handle_socket(socket, state, session));
+            }
+            Err(err) => return err.into_response(),
+        };
+
+        ws.on_upgrade(move |socket| handle_socket(socket, state, session))
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Converts a websocket ticket into the session it was issued to
+/// Tickets are only valid for the tenant (request_tenant) they were issued by
+async fn _redeem_ticket(
+    state: &SharedData,
+    ticket: &str,
+    request_tenant: &str,
+) -> Result<Session, AuthError> {
+    match state.database.redeem_ws_ticket(ticket).await {
+        Ok(Some((session_id, did, tenant))) => {
+            if tenant != request_tenant {
+                warn!(
+                    "{}: websocket ticket of tenant ({}) used on tenant ({})",
+                    session_id, tenant, request_tenant
+                );
+                return Err(AuthError::WrongCredentials);
+            }
+            info!("{}: websocket ticket accepted", session_id);
+            Ok(Session {
+                session_id,
+                authenticated: true,
+                challenge_sent: None,
+                did_hash: state.config.tenant(&tenant).did_hash(&did),
+                did,
+                tenant,
+            })
+        }
+        Ok(None) => {
+            warn!("Unknown or expired websocket ticket");
+            Err(AuthError::InvalidToken)
+        }
+        Err(err) => Err(AuthError::InternalServerError(err.to_string())),
+    }
+}
+
+/// Websocket without credentials, the first frame must be an Affinidi Authenticate message
+/// The connection is closed (policy violation) if authentication fails or doesn't happen in time
+async fn handle_unauthenticated(mut socket: WebSocket, state: SharedData, tenant: String) {
+    let session = match timeout(
+        Duration::from_secs(state.config.ws_auth_timeout),
+        socket.recv(),
+    )
+    .await
+    {
+        Ok(Some(Ok(Message::Text(msg)))) if msg.len() <= state.config.ws_size_limit as usize => {
+            _authenticate_frame(&state, &msg, &tenant).await
+        }
+        Ok(Some(Ok(_))) => Err(MediatorError::SessionError(
+            "UNKNOWN".into(),
+            "Expected an authentication message as the first frame".into(),
+        )),
+        Ok(_) => {
+            debug!("Websocket closed before authentication");
+            return;
+        }
+        Err(_) => Err(MediatorError::SessionError(
+            "UNKNOWN".into(),
+            "Timed out waiting for the authentication message".into(),
+        )),
+    };
+
+    match session {
+        Ok(session) => handle_socket(socket, state, session).await,
+        Err(err) => {
+            warn!("Websocket authentication failed: {}", err);
+            let _ = socket
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "Authentication failed".into(),
+                })))
+                .await;
+        }
+    }
+}
+
+/// Authenticates a websocket connection with an Affinidi Authenticate message
+/// Same checks as `/authenticate`, but no tokens are issued as the session only lives as long as the connection
+async fn _authenticate_frame(
+    state: &SharedData,
+    msg: &str,
+    request_tenant: &str,
+) -> Result<Session, MediatorError> {
+    let challenge = verify_challenge_response(state, msg, request_tenant).await?;
+
+    let session_id = create_random_string(12);
+    state
+        .database
+        .update_session_authenticated(&challenge.session_id, &session_id)
+        .await?;
+
+    info!(
+        "{}: Websocket authentication successful for DID({})",
+        session_id, challenge.did
+    );
+
+    Ok(Session {
+        session_id,
+        authenticated: true,
+        challenge_sent: None,
+        did_hash: state
+            .config
+            .tenant(&challenge.tenant)
+            .did_hash(&challenge.did),
+        did: challenge.did,
+        tenant: challenge.tenant,
+    })
 }
 
 /// WebSocket state machine. This is spawned per connection.
@@ -43,12 +408,21 @@ async fn handle_socket(mut socket: WebSocket, state: SharedData, session: Sessio
     );
     async move {
         // Register the transmission channel between websocket_streaming task and this websocket.
-        let (tx, mut rx): (Sender<String>, Receiver<String>) = mpsc::channel(5);
+        let (tx, mut rx): (Sender<StreamingMessage>, Receiver<StreamingMessage>) =
+            mpsc::channel(STREAMING_CHANNEL_SIZE);
+        let resync = Arc::new(Notify::new());
+        // A DID can have multiple connections at the same time, each has its own ID
+        let conn_id = Uuid::new_v4().to_string();
         if let Some(streaming) = &state.streaming_task {
 
             let start = StreamingUpdate {
                 did_hash: session.did_hash.clone(),
-                state: StreamingUpdateState::Register(tx),
+                state: StreamingUpdateState::Register(StreamingClient {
+                    conn_id: conn_id.clone(),
+                    session_id: session.session_id.clone(),
+                    tx,
+                    resync: resync.clone(),
+                }),
             };
             match streaming.channel.send(start).await {
                 Ok(_) => {
@@ -61,49 +435,142 @@ async fn handle_socket(mut socket: WebSocket, state: SharedData, session: Sessio
             }
         }
 
-        let _ = state.database.global_stats_increment_websocket_open().await;
+        let _ = state.database.global_stats_increment_websocket_open(&session.did_hash).await;
         info!("Websocket connection established");
 
+        let mut deliveries = LiveDeliveries::default();
+
+        // Heartbeat, pings the client and closes the connection if nothing has been received for ws_idle_timeout
+        // Ticks every ws_ping_interval, or every ws_idle_timeout if pings are disabled
+        let heartbeat_period = match state.config.ws_ping_interval {
+            0 => state.config.ws_idle_timeout,
+            ping_interval => ping_interval,
+        };
+        let mut heartbeat = interval(Duration::from_secs(heartbeat_period.max(1)));
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        heartbeat.reset();
+        let mut last_seen = Instant::now();
+
+        // Closes the connection once the DID is blocked
+        let mut block_check = interval(BLOCK_CHECK_INTERVAL);
+        block_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        block_check.reset();
+
+        // Switches to binary frames once the client has sent a binary frame
+        let mut binary = false;
+
         loop {
             select! {
                 value = socket.recv() => {
-                    if let Some(msg) = value {
-                        info!("ws: Received message: {:?}", msg);
-                        if let Ok(msg) = msg {
-                            if let Message::Text(msg) = msg {
-                                debug!("ws: Received text message: {:?}", msg);
-                                if msg.len() > state.config.ws_size_limit as usize {
-                                    warn!("Error processing message, the size is too big. limit is {}, message size is {}", state.config.ws_size_limit, msg.len());
-                                    break;
-                                }
+                    let msg = match value {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(err)) => {
+                            debug!("ws: Error receiving frame ({}), closing connection", err);
+                            break;
+                        }
+                        None => {
+                            debug!("Received None, closing connection");
+                            break;
+                        }
+                    };
+                    last_seen = Instant::now();
 
-                                // Process the message, which also takes care of any storing and live-streaming of the message
-                                match handle_inbound(&state, &session, &msg).await {
-                                    Ok(response) => {
-                                        debug!("Successful handling of message - finished processing");
-                                        response
-                                    }
-                                    Err(e) => {
-                                        warn!("Error processing message: {:?}", e);
-                                        continue;
-                                    }
-                                };
-                            } else {
-                                warn!("Received non-text message, ignoring");
-                                continue;
+                    // DIDComm envelopes can be sent as text or binary (compressed envelope) frames
+                    let msg = match msg {
+                        Message::Text(msg) => msg,
+                        Message::Binary(bytes) => match decode_binary(&bytes, state.config.ws_size_limit as usize) {
+                            Ok(msg) => {
+                                binary = true;
+                                msg
+                            }
+                            Err(err) => {
+                                warn!("Received binary frame that can't be decoded ({}), closing connection", err);
+                                break;
                             }
+                        },
+                        Message::Ping(_) | Message::Pong(_) => continue,
+                        Message::Close(_) => {
+                            debug!("Received close frame, closing connection");
+                            break;
+                        }
+                    };
+                    debug!("ws: Received message: {:?}", msg);
+                    if msg.len() > state.config.ws_size_limit as usize {
+                        warn!("Error processing message, the size is too big. limit is {}, message size is {}", state.config.ws_size_limit, msg.len());
+                        break;
+                    }
+
+                    if let Ok(frame) = serde_json::from_str::<AckFrame>(&msg) {
+                        _acknowledge(&state, &session, &mut deliveries, frame.ack).await;
+                        continue;
+                    }
+
+                    // Process the message, which also takes care of any storing and live-streaming of the message
+                    match handle_inbound(&state, &session, &msg).await {
+                        Ok(response) => {
+                            debug!("Successful handling of message - finished processing");
+                            response
+                        }
+                        Err(e) => {
+                            warn!("Error processing message: {:?}", e);
+                            continue;
                         }
-                    } else {
-                        debug!("Received None, closing connection");
+                    };
+                }
+                _ = heartbeat.tick(), if heartbeat_period > 0 => {
+                    if state.config.ws_idle_timeout > 0 && last_seen.elapsed() > Duration::from_secs(state.config.ws_idle_timeout) {
+                        info!("No frames received for {} seconds, closing idle connection", state.config.ws_idle_timeout);
+                        let _ = socket.send(Message::Close(Some(CloseFrame {
+                            code: close_code::AWAY,
+                            reason: "Idle timeout".into(),
+                        }))).await;
+                        break;
+                    }
+                    if state.config.ws_ping_interval > 0 && socket.send(Message::Ping(Vec::new())).await.is_err() {
+                        debug!("Couldn't ping client, closing connection");
+                        break;
+                    }
+                }
+                _ = block_check.tick() => {
+                    if is_blocked(&state, &session).await {
+                        let _ = socket.send(_blocked_frame()).await;
                         break;
                     }
                 }
                 value = rx.recv() => {
-                    if let Some(msg) = value {
-                        debug!("ws: Received message from streaming task: {:?}", msg);
-                        let _ = socket.send(Message::Text(msg)).await;
-                    } else {
-                        debug!("Received None from streaming task, closing connection");
+                    match value {
+                        Some(StreamingMessage::Deliver { msg_id, message }) => {
+                            debug!("ws: Received message from streaming task: {:?}", message);
+                            if let Some(msg_id) = &msg_id {
+                                if deliveries.contains(msg_id) {
+                                    debug!("msg_id({}) already delivered, skipping", msg_id);
+                                    continue;
+                                }
+                                deliveries.insert(msg_id, &message);
+                            }
+                            if socket.send(_frame(message, binary)).await.is_err() {
+                                debug!("Couldn't send message to client, closing connection");
+                                break;
+                            }
+                            if let Some(msg_id) = &msg_id {
+                                // Delivered messages can no longer be recalled, and may need a delivery receipt
+                                receipts::fetched(&state, &session, &[msg_id]).await;
+                            }
+                        }
+                        Some(StreamingMessage::Acknowledged(msg_ids)) => {
+                            for msg_id in msg_ids {
+                                deliveries.remove(&msg_id);
+                            }
+                        }
+                        None => {
+                            debug!("Received None from streaming task, closing connection");
+                            break;
+                        }
+                    }
+                }
+                _ = resync.notified() => {
+                    if !_resync(&state, &session, &mut deliveries, &mut socket, &resync, binary).await {
+                        debug!("Couldn't resync messages to client, closing connection");
                         break;
                     }
                 }
@@ -114,7 +581,7 @@ async fn handle_socket(mut socket: WebSocket, state: SharedData, session: Sessio
         if let Some(streaming) = &state.streaming_task {
             let stop = StreamingUpdate {
                 did_hash: session.did_hash.clone(),
-                state: StreamingUpdateState::Deregister,
+                state: StreamingUpdateState::Deregister(conn_id),
             };
             let _ = streaming.channel.send(stop).await;
         }
@@ -122,7 +589,7 @@ async fn handle_socket(mut socket: WebSocket, state: SharedData, session: Sessio
         // We're done, close the connection
         let _ = state
             .database
-            .global_stats_increment_websocket_close()
+            .global_stats_increment_websocket_close(&session.did_hash)
             .await;
 
         info!("Websocket connection closed");
@@ -130,3 +597,99 @@ async fn handle_socket(mut socket: WebSocket, state: SharedData, session: Sessio
     .instrument(_span)
     .await
 }
+
+/// Removes acknowledged messages from the mediator
+/// ids can be either the msg_id or the sha256 hash of the message as received by the client
+async fn _acknowledge(
+    state: &SharedData,
+    session: &Session,
+    deliveries: &mut LiveDeliveries,
+    ids: Vec<String>,
+) {
+    for id in ids {
+        let msg_id = deliveries.msg_id(id);
+
+        let read_receipt = receipts::read_requested(state, session, &msg_id).await;
+        match state
+            .database
+            .delete_message(&session.session_id, &session.did_hash, &msg_id)
+            .await
+        {
+            Ok(_) => {
+                debug!("msg_id({}) acknowledged", msg_id);
+                if let Some(request) = read_receipt {
+                    receipts::read(state, session, &msg_id, &request).await;
+                }
+            }
+            Err(err) => {
+                warn!("Couldn't acknowledge msg_id({}): {}", msg_id, err);
+            }
+        }
+        deliveries.remove(&msg_id);
+    }
+}
+
+/// (Re)delivers stored messages that have not yet been delivered on this connection
+/// Works through the inbox in batches, notifying itself again if more messages remain.
+/// Returns false if the websocket is no longer usable
+async fn _resync(
+    state: &SharedData,
+    session: &Session,
+    deliveries: &mut LiveDeliveries,
+    socket: &mut WebSocket,
+    resync: &Notify,
+    binary: bool,
+) -> bool {
+    let mut sent: Vec<String> = Vec::new();
+    let mut open = true;
+    for (msg_id, msg) in deliveries.resync_batch(state, session, resync).await {
+        if socket.send(_frame(msg, binary)).await.is_err() {
+            open = false;
+            break;
+        }
+        sent.push(msg_id);
+    }
+
+    let sent: Vec<&str> = sent.iter().map(|id| id.as_str()).collect();
+    receipts::fetched(state, session, &sent).await;
+    open
+}
+
+/// Has the DID of the session been blocked since the connection was opened?
+/// Database errors are logged and keep the connection open
+pub(crate) async fn is_blocked(state: &SharedData, session: &Session) -> bool {
+    match state.database.did_blocked(&session.did_hash).await {
+        Ok(blocked) => {
+            if blocked.is_some() {
+                info!(
+                    "did_hash({}) is blocked, closing connection",
+                    session.did_hash
+                );
+            }
+            blocked.is_some()
+        }
+        Err(err) => {
+            warn!("Couldn't check if the DID is blocked: {}", err);
+            false
+        }
+    }
+}
+
+/// Close frame sent when the DID of the connection has been blocked
+fn _blocked_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: close_code::POLICY,
+        reason: "DID is blocked".into(),
+    }))
+}
+
+/// Wraps a packed message in a websocket frame
+/// binary: Send as a binary frame (compressed envelope) instead of a text frame
+fn _frame(message: String, binary: bool) -> Message {
+    if binary {
+        if let Ok(frame) = encode_binary(&message) {
+            return Message::Binary(frame);
+        }
+    }
+    Message::Text(message)
+}
@@ -3,14 +3,19 @@ use axum::{extract::State, Json};
 use http::StatusCode;
 use tracing::{span, Instrument, Level};
 
-use crate::{common::errors::AppError, SharedData};
+use crate::{
+    common::{errors::AppError, tenant::RequestTenant},
+    SharedData,
+};
 
+/// Returns the DID of the tenant that the request was made to
 pub async fn well_known_did_fetch_handler(
     State(state): State<SharedData>,
+    RequestTenant(tenant): RequestTenant,
 ) -> Result<(StatusCode, Json<SuccessResponse<String>>), AppError> {
     let _span = span!(Level::DEBUG, "well_known_jwks_fetch_handler");
     async move {
-        let did = state.config.clone().mediator_did;
+        let did = state.config.tenant(&tenant).did.clone();
 
         Ok((
             StatusCode::OK,
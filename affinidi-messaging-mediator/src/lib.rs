@@ -15,7 +15,7 @@ use common::{
 };
 use database::DatabaseHandler;
 use http::request::Parts;
-use tasks::websocket_streaming::StreamingTask;
+use tasks::{push_notifications::PushTask, websocket_streaming::StreamingTask};
 use tracing::{event, Level};
 use tracing_subscriber::{reload::Handle, EnvFilter, Registry};
 
@@ -34,6 +34,7 @@ pub struct SharedData {
     pub did_resolver: DIDCacheClient,
     pub database: DatabaseHandler,
     pub streaming_task: Option<StreamingTask>,
+    pub push_task: Option<PushTask>,
 }
 
 impl Debug for SharedData {
@@ -60,9 +61,17 @@ where
 
 pub async fn init(
     reload_handle: Option<Handle<EnvFilter, Registry>>,
+) -> Result<Config, MediatorError> {
+    init_from_file("conf/mediator.toml", reload_handle).await
+}
+
+/// Same as `init()`, with the configuration read from `file_name`
+pub async fn init_from_file(
+    file_name: &str,
+    reload_handle: Option<Handle<EnvFilter, Registry>>,
 ) -> Result<Config, MediatorError> {
     // Read configuration file parameters
-    let config = read_config_file("conf/mediator.toml")?;
+    let config = read_config_file(file_name)?;
 
     // Setup logging
     if reload_handle.is_some() {
@@ -0,0 +1,373 @@
+//! Anonymous forward messages
+//!
+//! Other agents deliver DIDComm routing 2.0 `forward` messages to `/forward` without a session.
+//! The forward message must be encrypted to the keys of the mediator (tenant), and is only delivered
+//! if the recipient (`next`) has given consent (see `ForwardAcl`).
+//! Consent for listed senders requires the forward message itself to be authcrypted or signed by the sender,
+//! as the mediator can't verify the sender of the forwarded message.
+//!
+//! Abuse is limited by a size cap, a rate limit per client address and an optional proof-of-work.
+use crate::{
+    common::{errors::MediatorError, tenant::Tenant},
+    messages::inbound::{_try_live_stream, check_replay},
+    SharedData,
+};
+use affinidi_messaging_didcomm::{
+    envelope::MetaEnvelope,
+    protocols::routing::{try_parse_forward, ParsedForward},
+    Message, UnpackMetadata, UnpackOptions,
+};
+use affinidi_messaging_sdk::messages::{ForwardAcl, ForwardConsent};
+use sha256::digest;
+use std::time::SystemTime;
+use tracing::{debug, info, span, Instrument};
+
+/// Session ID used for anonymous forward messages in logs and errors
+pub(crate) const ANONYMOUS_SESSION: &str = "ANONYMOUS";
+
+/// Accepts an anonymous forward message and queues the forwarded message for the recipient
+/// - tenant: Tenant of the request, the message may select another tenant by its recipient key IDs
+/// - client: Address of the sender, used for the rate limit
+/// - pow: Proof-of-work nonce sent by the client (`x-atm-pow` header)
+///
+/// Returns the msg_id of the queued message
+pub(crate) async fn handle_forward(
+    state: &SharedData,
+    tenant: &str,
+    client: &str,
+    message: &str,
+    pow: Option<&str>,
+) -> Result<String, MediatorError> {
+    let _span = span!(tracing::Level::DEBUG, "handle_forward", client = client);
+
+    async move {
+        if !state.config.forward_enabled {
+            return Err(MediatorError::NotImplemented(
+                ANONYMOUS_SESSION.into(),
+                "Anonymous forward messages are not enabled on this mediator".into(),
+            ));
+        }
+
+        if message.len() > state.config.forward_max_size {
+            return Err(MediatorError::ServiceLimitError(
+                ANONYMOUS_SESSION.into(),
+                format!(
+                    "Message size ({}) exceeds the limit of {} bytes",
+                    message.len(),
+                    state.config.forward_max_size
+                ),
+            ));
+        }
+
+        if !state
+            .database
+            .forward_rate_limit(client, state.config.forward_rate_limit)
+            .await?
+        {
+            return Err(MediatorError::ServiceLimitError(
+                ANONYMOUS_SESSION.into(),
+                "Too many forward messages, try again later".into(),
+            ));
+        }
+
+        _check_pow(state.config.forward_pow_difficulty, message, pow)?;
+
+        let Some(tenant) = state.config.message_tenant(message, tenant) else {
+            return Err(MediatorError::RequestDataError(
+                ANONYMOUS_SESSION.into(),
+                format!("Unknown tenant ({})", tenant),
+            ));
+        };
+
+        let (msg, metadata) = _unpack(state, tenant, message).await?;
+
+        // The replay records are released if the message isn't stored
+        let replay =
+            check_replay(state, ANONYMOUS_SESSION, message, ANONYMOUS_SESSION, &msg).await?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if let Some(expires) = msg.expires_time {
+            if expires <= now {
+                return Err(MediatorError::MessageExpired(
+                    ANONYMOUS_SESSION.into(),
+                    expires.to_string(),
+                    now.to_string(),
+                ));
+            }
+        }
+
+        let Some(ParsedForward {
+            next,
+            forwarded_msg,
+            ..
+        }) = try_parse_forward(&msg)
+        else {
+            return Err(MediatorError::RequestDataError(
+                ANONYMOUS_SESSION.into(),
+                "Only routing 2.0 forward messages are accepted".into(),
+            ));
+        };
+
+        // next can be a DID or a key ID of the recipient
+        let next = next.split('#').next().unwrap_or(&next).to_string();
+        let next_hash = tenant.did_hash(&next);
+
+        // The recipient must have consented to anonymous forward messages
+        let acl = state
+            .database
+            .forward_acl_get(ANONYMOUS_SESSION, &next_hash)
+            .await?;
+        if !_allowed(&acl, _sender(&metadata)) {
+            return Err(MediatorError::PermissionError(
+                ANONYMOUS_SESSION.into(),
+                "Recipient doesn't accept forward messages from this sender".into(),
+            ));
+        }
+
+        let packed = serde_json::to_string(&forwarded_msg).map_err(|err| {
+            MediatorError::InternalError(
+                ANONYMOUS_SESSION.into(),
+                format!("Couldn't serialize forwarded message: {}", err),
+            )
+        })?;
+
+        let msg_id = state
+            .database
+            .store_message(ANONYMOUS_SESSION, &tenant.namespace, &packed, &next, None)
+            .await?;
+        replay.accept();
+        info!(
+            "anonymous forward message stored as msg_id({}) for next({})",
+            msg_id, next
+        );
+
+        let streamed =
+            _try_live_stream(state, &next_hash, &packed, false, Some(&msg_id), None).await;
+        if !streamed {
+            if let Some(push_task) = &state.push_task {
+                push_task.notify(&next_hash);
+            }
+        }
+
+        Ok(msg_id)
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Unpacks the forward message, which must be encrypted to the keys of the tenant
+async fn _unpack(
+    state: &SharedData,
+    tenant: &Tenant,
+    message: &str,
+) -> Result<(Message, UnpackMetadata), MediatorError> {
+    let mut envelope = MetaEnvelope::new(message, &state.did_resolver, &tenant.secrets)
+        .await
+        .map_err(|err| {
+            MediatorError::ParseError(
+                ANONYMOUS_SESSION.into(),
+                "Raw inbound DIDComm message".into(),
+                err.to_string(),
+            )
+        })?;
+
+    let (msg, metadata) = Message::unpack(
+        &mut envelope,
+        &state.did_resolver,
+        &tenant.secrets,
+        &UnpackOptions {
+            crypto_operations_limit_per_message: state.config.crypto_operations_per_message_limit,
+            ..UnpackOptions::default()
+        },
+    )
+    .await
+    .map_err(|err| {
+        MediatorError::MessageUnpackError(
+            ANONYMOUS_SESSION.into(),
+            format!("Couldn't unpack incoming message. Reason: {}", err),
+        )
+    })?;
+
+    let addressed_to_tenant = metadata.encrypted
+        && metadata.encrypted_to_kids.as_ref().is_some_and(|kids| {
+            kids.iter()
+                .all(|kid| kid.split('#').next() == Some(tenant.did.as_str()))
+        });
+    if !addressed_to_tenant {
+        return Err(MediatorError::PermissionError(
+            ANONYMOUS_SESSION.into(),
+            "Forward messages must be encrypted to the mediator".into(),
+        ));
+    }
+
+    debug!("forward message unpacked: id({})", msg.id);
+    Ok((msg, metadata))
+}
+
+/// Checks the proof-of-work of a message
+/// The nonce is valid if sha256(<sha256 hex of the message> + <nonce>) has at least `difficulty` leading zero bits
+fn _check_pow(difficulty: u32, message: &str, nonce: Option<&str>) -> Result<(), MediatorError> {
+    if difficulty == 0 {
+        return Ok(());
+    }
+
+    let Some(nonce) = nonce else {
+        return Err(MediatorError::PermissionError(
+            ANONYMOUS_SESSION.into(),
+            format!(
+                "Proof-of-work (x-atm-pow header) with difficulty ({}) is required",
+                difficulty
+            ),
+        ));
+    };
+
+    let hash = digest([digest(message), nonce.to_string()].concat());
+    let mut zeros = 0;
+    for nibble in hash.chars().filter_map(|c| c.to_digit(16)) {
+        if nibble == 0 {
+            zeros += 4;
+        } else {
+            zeros += nibble.leading_zeros() - 28;
+            break;
+        }
+    }
+
+    if zeros < difficulty {
+        return Err(MediatorError::PermissionError(
+            ANONYMOUS_SESSION.into(),
+            format!("Proof-of-work doesn't meet the difficulty ({})", difficulty),
+        ));
+    }
+    Ok(())
+}
+
+/// DID of the verified sender of the forward message, it must be authcrypted or signed
+/// The `skid` of the forwarded message isn't used, it can only be verified by the recipient
+fn _sender(metadata: &UnpackMetadata) -> Option<&str> {
+    metadata
+        .encrypted_from_kid
+        .as_deref()
+        .filter(|_| metadata.authenticated)
+        .or(metadata.sign_from.as_deref())
+        .and_then(|kid| kid.split('#').next())
+}
+
+/// Checks the consent of the recipient for a forward message from the sender
+fn _allowed(acl: &ForwardAcl, sender: Option<&str>) -> bool {
+    match acl.consent {
+        ForwardConsent::None => false,
+        ForwardConsent::All => true,
+        ForwardConsent::Senders => {
+            sender.is_some_and(|sender| acl.senders.iter().any(|allowed| allowed == sender))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pow_nonce(difficulty: u32, message: &str) -> String {
+        (0..)
+            .map(|nonce: u64| nonce.to_string())
+            .find(|nonce| _check_pow(difficulty, message, Some(nonce)).is_ok())
+            .unwrap()
+    }
+
+    #[test]
+    fn pow_disabled() {
+        assert!(_check_pow(0, "message", None).is_ok());
+        assert!(_check_pow(0, "message", Some("x")).is_ok());
+    }
+
+    #[test]
+    fn pow_requires_nonce() {
+        assert!(matches!(
+            _check_pow(8, "message", None),
+            Err(MediatorError::PermissionError(..))
+        ));
+    }
+
+    #[test]
+    fn pow_checks_leading_zero_bits() {
+        let nonce = pow_nonce(10, "message");
+        let hash = digest([digest("message"), nonce.clone()].concat());
+        // 10 bits are two zero nibbles and a nibble below 4
+        assert!(hash.starts_with("00"));
+        assert!(u32::from_str_radix(&hash[2..3], 16).unwrap() < 4);
+        assert!(_check_pow(10, "message", Some(&nonce)).is_ok());
+
+        // A hash starting with a nibble of 8 or more has no leading zero bits
+        let nonce = (0..)
+            .map(|nonce: u64| nonce.to_string())
+            .find(|nonce| {
+                let hash = digest([digest("message"), nonce.clone()].concat());
+                u32::from_str_radix(&hash[..1], 16).unwrap() >= 8
+            })
+            .unwrap();
+        assert!(matches!(
+            _check_pow(1, "message", Some(&nonce)),
+            Err(MediatorError::PermissionError(..))
+        ));
+    }
+
+    fn metadata(
+        authenticated: bool,
+        encrypted_from_kid: Option<&str>,
+        sign_from: Option<&str>,
+    ) -> UnpackMetadata {
+        UnpackMetadata {
+            encrypted: true,
+            authenticated,
+            encrypted_from_kid: encrypted_from_kid.map(|kid| kid.to_string()),
+            sign_from: sign_from.map(|kid| kid.to_string()),
+            ..UnpackMetadata::default()
+        }
+    }
+
+    #[test]
+    fn sender_is_authenticated() {
+        assert_eq!(
+            _sender(&metadata(true, Some("did:example:alice#key-1"), None)),
+            Some("did:example:alice")
+        );
+        assert_eq!(
+            _sender(&metadata(false, None, Some("did:example:bob#key-1"))),
+            Some("did:example:bob")
+        );
+        // anoncrypted and unsigned
+        assert_eq!(_sender(&metadata(false, None, None)), None);
+        assert_eq!(
+            _sender(&metadata(false, Some("did:example:alice#key-1"), None)),
+            None
+        );
+    }
+
+    #[test]
+    fn acl_decision() {
+        let senders = vec!["did:example:alice".to_string()];
+        let acl = |consent| ForwardAcl {
+            consent,
+            senders: senders.clone(),
+        };
+
+        assert!(!_allowed(
+            &acl(ForwardConsent::None),
+            Some("did:example:alice")
+        ));
+        assert!(_allowed(&acl(ForwardConsent::All), None));
+        assert!(_allowed(&acl(ForwardConsent::All), Some("did:example:bob")));
+        assert!(_allowed(
+            &acl(ForwardConsent::Senders),
+            Some("did:example:alice")
+        ));
+        assert!(!_allowed(
+            &acl(ForwardConsent::Senders),
+            Some("did:example:bob")
+        ));
+        assert!(!_allowed(&acl(ForwardConsent::Senders), None));
+    }
+}
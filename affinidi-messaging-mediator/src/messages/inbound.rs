@@ -1,13 +1,14 @@
 use crate::{
     common::errors::{MediatorError, Session},
     database::DatabaseHandler,
-    messages::{MessageHandler, MessageResponse, PackOptions, ProcessMessageResponse},
+    messages::{receipts, MessageHandler, MessageResponse, PackOptions, ProcessMessageResponse},
     SharedData,
 };
 use affinidi_messaging_didcomm::{envelope::MetaEnvelope, Message, UnpackOptions};
 use affinidi_messaging_sdk::messages::sending::{InboundMessageList, InboundMessageResponse};
 use futures::future::try_join_all;
 use sha256::digest;
+use std::time::SystemTime;
 use tracing::{debug, error, span, trace, warn, Instrument};
 pub(crate) async fn handle_inbound(
     state: &SharedData,
@@ -17,8 +18,20 @@ pub(crate) async fn handle_inbound(
     let _span = span!(tracing::Level::DEBUG, "handle_inbound",);
 
     async move {
+        // Tenant is selected by the recipient key IDs, falling back to the tenant of the session (request host)
+        // Sessions can only send messages to the tenant they authenticated with
+        let tenant = match state.config.message_tenant(message, &session.tenant) {
+            Some(tenant) if tenant.namespace == session.tenant => tenant,
+            _ => {
+                return Err(MediatorError::PermissionError(
+                    session.session_id.clone(),
+                    "Message is addressed to a different mediator than this session".into(),
+                ));
+            }
+        };
+
         let mut envelope =
-            match MetaEnvelope::new(message, &state.did_resolver, &state.config.mediator_secrets)
+            match MetaEnvelope::new(message, &state.did_resolver, &tenant.secrets)
                 .await
             {
                 Ok(envelope) => envelope,
@@ -36,7 +49,7 @@ pub(crate) async fn handle_inbound(
         let (msg, metadata) = match Message::unpack(
             &mut envelope,
             &state.did_resolver,
-            &state.config.mediator_secrets,
+            &tenant.secrets,
             &UnpackOptions {
                 crypto_operations_limit_per_message: state
                     .config
@@ -57,13 +70,23 @@ pub(crate) async fn handle_inbound(
 
         debug!("message unpacked:\n{:#?}", msg);
 
+        // Reject replayed messages, the replay records are released if the message isn't stored
+        let replay = check_replay(
+            state,
+            &session.session_id,
+            message,
+            msg.from.as_deref().unwrap_or(&session.did),
+            &msg,
+        )
+        .await?;
+
         // Process the message
 
         if let Some(ProcessMessageResponse {
             message_response,
             store_message,
             force_live_delivery,
-        }) = msg.process(state, session).await? {
+        }) = msg.process(state, session, &metadata).await? {
             debug!("message processed:\n message: {message:?}\n store_message: {store_message:?}\n force_live_delivery: {force_live_delivery:?}\n");
 
 
@@ -92,9 +115,9 @@ pub(crate) async fn handle_inbound(
                         let (packed, _msg_metadata) = message
                             .pack(
                                 to_did,
-                                &state.config.mediator_did, // take `from` of message?
+                                &tenant.did, // take `from` of message?
                                 &metadata,
-                                &state.config.mediator_secrets,
+                                &tenant.secrets,
                                 &state.did_resolver,
                                 &PackOptions {
                                     to_keys_per_recipient_limit: state.config.to_keys_per_recipient_limit,
@@ -110,28 +133,20 @@ pub(crate) async fn handle_inbound(
                 },
             };
 
-            for (to_did, packed) in to_did_packed.iter() {
-                let to_did_hash = digest(*to_did);
-                _try_live_stream(
-                    state,
-                    &to_did_hash,
-                    packed,
-                    force_live_delivery,
-                )
-                    .await;
-            }
-
             if store_message {
+                // Messages are stored before being live streamed, so that the client can acknowledge them by msg_id
+                // Unacknowledged messages remain stored and are redelivered when the client resyncs
                 let mut stored_messages = InboundMessageList::default();
                 for (to_did, packed) in to_did_packed {
-                    match state
+                    let msg_id = match state
                         .database
                         .store_message(
-                    &session.session_id,
-                    &packed,
-                    to_did,
-                    Some(&state.config.mediator_did),
-                )
+                            &session.session_id,
+                            &tenant.namespace,
+                            &packed,
+                            to_did,
+                            Some(&tenant.did),
+                        )
                         .await
                     {
                         Ok(msg_id) => {
@@ -139,18 +154,70 @@ pub(crate) async fn handle_inbound(
                                 "message {} stored successfully, recipient({})",
                                 msg_id, to_did
                             );
-                            stored_messages.messages.push((to_did.to_owned(), msg_id));
+                            // Receipt request is stored before the message can be streamed to the recipient
+                            if let Some(request) = receipts::requested(state, session, &msg, to_did) {
+                                if let Err(e) = state
+                                    .database
+                                    .set_receipt_request(&session.session_id, &tenant.did_hash(to_did), &msg_id, &request)
+                                    .await
+                                {
+                                    warn!("error storing receipt request msg_id({}): {:?}", msg_id, e);
+                                }
+                            }
+                            stored_messages.messages.push((to_did.to_owned(), msg_id.clone()));
+                            Some(msg_id)
                         }
                         Err(e) => {
                             warn!("error storing message recipient({}): {:?}", to_did, e);
                             stored_messages
                                 .errors
                                 .push((to_did.to_owned(), e.to_string()));
+                            None
+                        }
+                    };
+
+                    let to_did_hash = tenant.did_hash(to_did);
+                    let streamed = _try_live_stream(
+                        state,
+                        &to_did_hash,
+                        &packed,
+                        force_live_delivery,
+                        msg_id.as_deref(),
+                        None,
+                    )
+                        .await;
+
+                    // Wake up the recipient if they aren't live streaming (no message content is sent)
+                    if !streamed && msg_id.is_some() {
+                        if let Some(push_task) = &state.push_task {
+                            push_task.notify(&to_did_hash);
                         }
                     }
                 };
+                if !stored_messages.messages.is_empty() || stored_messages.errors.is_empty() {
+                    replay.accept();
+                }
                 Ok(InboundMessageResponse::Stored(stored_messages))
             } else {
+                for (to_did, packed) in to_did_packed.iter() {
+                    // Replies to our own DID only go to the connections of this session
+                    let to_did_hash = tenant.did_hash(to_did);
+                    let session_id = if to_did_hash == session.did_hash {
+                        Some(session.session_id.as_str())
+                    } else {
+                        None
+                    };
+                    _try_live_stream(
+                        state,
+                        &to_did_hash,
+                        packed,
+                        force_live_delivery,
+                        None,
+                        session_id,
+                    )
+                        .await;
+                }
+                replay.accept();
                 Ok(InboundMessageResponse::Ephemeral(to_did_packed[0].1.to_owned()))
             }
         } else {
@@ -173,9 +240,18 @@ async fn _live_stream(
     stream_uuid: &str,
     packed: &str,
     force_live_delivery: bool,
+    msg_id: Option<&str>,
+    session_id: Option<&str>,
 ) {
     if database
-        .streaming_publish_message(did_hash, stream_uuid, packed, force_live_delivery)
+        .streaming_publish_message(
+            did_hash,
+            stream_uuid,
+            packed,
+            force_live_delivery,
+            msg_id,
+            session_id,
+        )
         .await
         .is_ok()
     {
@@ -183,26 +259,33 @@ async fn _live_stream(
     }
 }
 
-async fn _try_live_stream(
+/// Publishes the message to every streaming service that the DID is connected to
+/// Returns true if the DID was live streaming on at least one streaming service
+pub(crate) async fn _try_live_stream(
     state: &SharedData,
     did_hash: &str,
     packed: &str,
     force_live_delivery: bool,
-) {
-    if let Some(stream_uuid) = state
+    msg_id: Option<&str>,
+    session_id: Option<&str>,
+) -> bool {
+    let stream_uuids = state
         .database
-        .streaming_is_client_live(did_hash, force_live_delivery)
-        .await
-    {
+        .streaming_live_nodes(did_hash, force_live_delivery, msg_id.is_some())
+        .await;
+    for stream_uuid in stream_uuids.iter() {
         _live_stream(
             &state.database,
             did_hash,
-            &stream_uuid,
+            stream_uuid,
             packed,
             force_live_delivery,
+            msg_id,
+            session_id,
         )
         .await;
     }
+    !stream_uuids.is_empty()
 }
 
 async fn _try_store(
@@ -216,9 +299,10 @@ async fn _try_store(
         .database
         .store_message(
             &session.session_id,
+            &session.tenant,
             packed,
             to_did,
-            Some(&state.config.mediator_did),
+            Some(&state.config.tenant(&session.tenant).did),
         )
         .await
     {
@@ -238,3 +322,153 @@ async fn _try_store(
     }
     stored_messages
 }
+
+/// Replay records of an inbound message
+/// Call `accept()` once the message has been processed. If the record is dropped without being
+/// accepted, the replay records are removed so that the sender can send the message again.
+pub(crate) struct ReplayRecord {
+    database: DatabaseHandler,
+    session_id: String,
+    envelope_hash: String,
+    sender: String,
+    msg_id: String,
+    accepted: bool,
+}
+
+impl ReplayRecord {
+    /// Keeps the replay records until they expire
+    pub(crate) fn accept(mut self) {
+        self.accepted = true;
+    }
+}
+
+impl Drop for ReplayRecord {
+    fn drop(&mut self) {
+        if self.accepted {
+            return;
+        }
+
+        let database = self.database.clone();
+        let session_id = std::mem::take(&mut self.session_id);
+        let envelope_hash = std::mem::take(&mut self.envelope_hash);
+        let sender = std::mem::take(&mut self.sender);
+        let msg_id = std::mem::take(&mut self.msg_id);
+        tokio::spawn(async move {
+            if database
+                .replay_release(&session_id, &envelope_hash, &sender, &msg_id)
+                .await
+                .is_ok()
+            {
+                debug!("released replay records of message id({})", msg_id);
+            }
+        });
+    }
+}
+
+/// Rejects messages that have already been seen within the replay window
+/// - envelope: The message as received (before unpacking)
+/// - sender: DID of the sender, used with the message `id` to detect replays
+///
+/// Messages must have a `created_time` within the replay window, and are remembered until the
+/// window has passed. Messages created outside of the replay window are rejected as they can no
+/// longer be checked.
+pub(crate) async fn check_replay(
+    state: &SharedData,
+    session_id: &str,
+    envelope: &str,
+    sender: &str,
+    msg: &Message,
+) -> Result<ReplayRecord, MediatorError> {
+    let mut record = ReplayRecord {
+        database: state.database.clone(),
+        session_id: session_id.into(),
+        envelope_hash: digest(envelope),
+        sender: sender.into(),
+        msg_id: msg.id.clone(),
+        accepted: true,
+    };
+
+    let window = state.config.replay_window;
+    if window == 0 {
+        return Ok(record);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let ttl = replay_ttl(msg.created_time, now, window).map_err(|reason| {
+        MediatorError::MessageReplay(
+            session_id.into(),
+            format!("message id({}) {}", msg.id, reason),
+        )
+    })?;
+
+    if state
+        .database
+        .replay_check(session_id, &record.envelope_hash, sender, &msg.id, ttl)
+        .await?
+    {
+        warn!("replayed message from({}) id({}) rejected", sender, msg.id);
+        Err(MediatorError::MessageReplay(
+            session_id.into(),
+            format!(
+                "message from({}) id({}) has already been received",
+                sender, msg.id
+            ),
+        ))
+    } else {
+        record.accepted = false;
+        Ok(record)
+    }
+}
+
+/// How long in seconds a message must be remembered for, so that it can't be replayed
+/// The message is accepted until `created_time + window`, messages created more than the window
+/// in the future are rejected so that the records stay bounded.
+fn replay_ttl(created_time: Option<u64>, now: u64, window: u64) -> Result<u64, String> {
+    let Some(created) = created_time else {
+        return Err("has no created_time, which is required for replay protection".into());
+    };
+    if created.saturating_add(window) < now {
+        return Err(format!(
+            "was created({}) outside of the replay window({} seconds)",
+            created, window
+        ));
+    }
+    if created > now.saturating_add(window) {
+        return Err(format!(
+            "was created({}) in the future, outside of the replay window({} seconds)",
+            created, window
+        ));
+    }
+
+    Ok((created + window - now).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::replay_ttl;
+
+    #[test]
+    fn replay_ttl_requires_created_time() {
+        assert!(replay_ttl(None, 1000, 300).is_err());
+    }
+
+    #[test]
+    fn replay_ttl_covers_the_window() {
+        // Remembered until the message falls outside of the window
+        assert_eq!(replay_ttl(Some(1000), 1000, 300), Ok(300));
+        assert_eq!(replay_ttl(Some(900), 1000, 300), Ok(200));
+        assert_eq!(replay_ttl(Some(700), 1000, 300), Ok(1));
+        // Clock skew, created in the future
+        assert_eq!(replay_ttl(Some(1100), 1000, 300), Ok(400));
+    }
+
+    #[test]
+    fn replay_ttl_rejects_outside_window() {
+        assert!(replay_ttl(Some(699), 1000, 300).is_err());
+        assert!(replay_ttl(Some(1301), 1000, 300).is_err());
+    }
+}
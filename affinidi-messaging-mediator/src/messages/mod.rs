@@ -7,12 +7,16 @@ use affinidi_did_resolver_cache_sdk::DIDCacheClient;
 use affinidi_messaging_didcomm::{
     secrets::SecretsResolver, Message, PackEncryptedMetadata, PackEncryptedOptions, UnpackMetadata,
 };
+use protocols::admin;
 use protocols::message_pickup;
+use protocols::push;
 use protocols::routing;
 use std::{default, str::FromStr, time::SystemTime};
 
+pub mod forward;
 pub mod inbound;
 pub mod protocols;
+pub mod receipts;
 
 pub enum CoordinateMediationV3 {
     MediateRequest,
@@ -36,6 +40,14 @@ pub enum MessageType {
     MessagePickupMessagesReceived,   // Message Pickup 3.0 Messages Received (ok to delete)
     MessagePickupLiveDeliveryChange, // Message Pickup 3.0 Live-delivery-change (Streaming enabled)
     TrustPing,                       // Trust Ping Protocol
+    PushRegister,                    // Affinidi Push endpoint registration
+    PushUnregister,                  // Affinidi Push endpoint removal
+    AdminTier,                       // Affinidi Admin mailbox tier assignment
+    AdminAccountDelete,              // Affinidi Admin deletion of all data of a DID
+    AdminAccounts,                   // Affinidi Admin account listing
+    AdminStats,                      // Affinidi Admin mailbox statistics of a DID
+    AdminBlock,                      // Affinidi Admin block or unblock of a DID
+    AdminExpire,                     // Affinidi Admin forced expiry of queued messages
 }
 
 impl FromStr for MessageType {
@@ -58,6 +70,14 @@ impl FromStr for MessageType {
                 Ok(Self::MessagePickupMessagesReceived)
             }
             "https://didcomm.org/routing/2.0/forward" => Ok(Self::ForwardRequest),
+            "https://affinidi.com/atm/1.0/push/register" => Ok(Self::PushRegister),
+            "https://affinidi.com/atm/1.0/push/unregister" => Ok(Self::PushUnregister),
+            "https://affinidi.com/atm/1.0/admin/tier" => Ok(Self::AdminTier),
+            "https://affinidi.com/atm/1.0/admin/account-delete" => Ok(Self::AdminAccountDelete),
+            "https://affinidi.com/atm/1.0/admin/accounts" => Ok(Self::AdminAccounts),
+            "https://affinidi.com/atm/1.0/admin/stats" => Ok(Self::AdminStats),
+            "https://affinidi.com/atm/1.0/admin/block" => Ok(Self::AdminBlock),
+            "https://affinidi.com/atm/1.0/admin/expire" => Ok(Self::AdminExpire),
             _ => Err(MediatorError::ParseError(
                 "-1".into(),
                 s.into(),
@@ -73,6 +93,7 @@ impl MessageType {
         message: &Message,
         state: &SharedData,
         session: &Session,
+        metadata: &UnpackMetadata,
     ) -> Result<Option<ProcessMessageResponse>, MediatorError> {
         match self {
             Self::TrustPing => ping::process(message, session),
@@ -93,6 +114,16 @@ impl MessageType {
                 "Affinidi Authentication is only handled by the Authorization handler".into(),
             )),
             Self::ForwardRequest => routing::process(message, session),
+            Self::PushRegister => push::register(message, state, session).await,
+            Self::PushUnregister => push::unregister(message, state, session).await,
+            Self::AdminTier => admin::tier(message, state, session, metadata).await,
+            Self::AdminAccountDelete => {
+                admin::account_delete(message, state, session, metadata).await
+            }
+            Self::AdminAccounts => admin::accounts(message, state, session, metadata).await,
+            Self::AdminStats => admin::stats(message, state, session, metadata).await,
+            Self::AdminBlock => admin::block(message, state, session, metadata).await,
+            Self::AdminExpire => admin::expire(message, state, session, metadata).await,
         }
     }
 }
@@ -125,11 +156,14 @@ impl Default for PackOptions {
 
 pub(crate) trait MessageHandler {
     /// Processes an incoming message, determines any additional actions to take
+    /// - metadata: unpack metadata of the message, identifies the authenticated sender
+    ///
     /// Returns a message to store and deliver if necessary
     async fn process(
         &self,
         state: &SharedData,
         session: &Session,
+        metadata: &UnpackMetadata,
     ) -> Result<Option<ProcessMessageResponse>, MediatorError>;
 
     /// Uses the incoming unpack metadata to determine best way to pack the message
@@ -151,6 +185,7 @@ impl MessageHandler for Message {
         &self,
         state: &SharedData,
         session: &Session,
+        metadata: &UnpackMetadata,
     ) -> Result<Option<ProcessMessageResponse>, MediatorError> {
         let msg_type = self.type_.as_str().parse::<MessageType>()?;
 
@@ -169,7 +204,7 @@ impl MessageHandler for Message {
             }
         }
 
-        msg_type.process(self, state, session).await
+        msg_type.process(self, state, session, metadata).await
     }
 
     async fn pack<S>(
@@ -184,8 +219,8 @@ impl MessageHandler for Message {
     where
         S: SecretsResolver,
     {
-        if metadata.encrypted {
-            // Respond with an encrypted message
+        if metadata.encrypted || metadata.non_repudiation {
+            // Respond with an encrypted message, signed messages also get an encrypted response
             let a = match self
                 .pack_encrypted(
                     to_did,
@@ -0,0 +1,389 @@
+use affinidi_messaging_didcomm::{Message, UnpackMetadata};
+use affinidi_messaging_sdk::protocols::admin::{
+    AdminAccountDelete, AdminAccounts, AdminBlock, AdminBlockStatus, AdminExpire,
+    AdminExpireReport, AdminStats, AdminTier, AdminTierStatus,
+    ADMIN_ACCOUNT_DELETE_REPORT_MESSAGE_TYPE, ADMIN_ACCOUNT_LIST_MESSAGE_TYPE,
+    ADMIN_BLOCK_STATUS_MESSAGE_TYPE, ADMIN_EXPIRE_REPORT_MESSAGE_TYPE,
+    ADMIN_STATS_REPORT_MESSAGE_TYPE, ADMIN_TIER_STATUS_MESSAGE_TYPE,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use std::time::SystemTime;
+use tracing::{debug, info, span, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    common::errors::{MediatorError, Session},
+    messages::{MessageResponse, ProcessMessageResponse},
+    SharedData,
+};
+
+/// Number of accounts listed when the admin doesn't set a limit
+const ACCOUNT_LIST_DEFAULT_LIMIT: u32 = 100;
+
+/// Maximum number of accounts listed in a single reply
+const ACCOUNT_LIST_MAX_LIMIT: u32 = 1000;
+
+/// Assigns a mailbox tier to a DID of the same tenant
+/// Replies with the tier that now applies to the DID
+pub(crate) async fn tier(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+    metadata: &UnpackMetadata,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "admin_tier",
+        session_id = session.session_id.as_str()
+    );
+    async move {
+        let admin = _check_admin(state, session, metadata)?;
+        let body: AdminTier = _body(msg, session, "tier")?;
+
+        let tenant = state.config.tenant(&session.tenant);
+        let tier = state
+            .database
+            .set_did_tier(
+                &session.session_id,
+                &tenant.did_hash(&body.did),
+                body.tier.as_deref(),
+            )
+            .await?;
+
+        info!(
+            "admin did({}) assigned tier({}) to did({})",
+            admin, tier.name, body.did
+        );
+
+        _reply(
+            msg,
+            state,
+            session,
+            admin,
+            ADMIN_TIER_STATUS_MESSAGE_TYPE,
+            AdminTierStatus {
+                did: body.did,
+                tier,
+            },
+        )
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Deletes all data the mediator holds for a DID of the same tenant
+/// Replies with the deletion report
+pub(crate) async fn account_delete(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+    metadata: &UnpackMetadata,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "admin_account_delete",
+        session_id = session.session_id.as_str()
+    );
+    async move {
+        let admin = _check_admin(state, session, metadata)?;
+        let body: AdminAccountDelete = _body(msg, session, "account delete")?;
+
+        let tenant = state.config.tenant(&session.tenant);
+        let report = state
+            .database
+            .delete_account(&session.session_id, &body.did, &tenant.did_hash(&body.did))
+            .await?;
+
+        info!("admin did({}) deleted did({})", admin, body.did);
+
+        _reply(
+            msg,
+            state,
+            session,
+            admin,
+            ADMIN_ACCOUNT_DELETE_REPORT_MESSAGE_TYPE,
+            report,
+        )
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Lists the accounts (DIDs that have authenticated) of the same tenant
+/// Replies with a page of accounts and the cursor of the next page
+pub(crate) async fn accounts(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+    metadata: &UnpackMetadata,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "admin_accounts",
+        session_id = session.session_id.as_str()
+    );
+    async move {
+        let admin = _check_admin(state, session, metadata)?;
+        let body: AdminAccounts = _body(msg, session, "accounts")?;
+
+        let list = state
+            .database
+            .list_accounts(
+                &session.session_id,
+                &session.tenant,
+                body.cursor.as_deref(),
+                body.limit
+                    .unwrap_or(ACCOUNT_LIST_DEFAULT_LIMIT)
+                    .clamp(1, ACCOUNT_LIST_MAX_LIMIT),
+            )
+            .await?;
+
+        debug!(
+            "admin did({}) listed ({}) accounts, next cursor({:?})",
+            admin,
+            list.accounts.len(),
+            list.cursor
+        );
+
+        _reply(
+            msg,
+            state,
+            session,
+            admin,
+            ADMIN_ACCOUNT_LIST_MESSAGE_TYPE,
+            list,
+        )
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Replies with the mailbox statistics of a DID of the same tenant
+pub(crate) async fn stats(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+    metadata: &UnpackMetadata,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "admin_stats",
+        session_id = session.session_id.as_str()
+    );
+    async move {
+        let admin = _check_admin(state, session, metadata)?;
+        let body: AdminStats = _body(msg, session, "stats")?;
+
+        let tenant = state.config.tenant(&session.tenant);
+        let stats = state
+            .database
+            .did_stats(&session.session_id, &body.did, &tenant.did_hash(&body.did))
+            .await?;
+
+        _reply(
+            msg,
+            state,
+            session,
+            admin,
+            ADMIN_STATS_REPORT_MESSAGE_TYPE,
+            stats,
+        )
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Blocks or unblocks a DID of the same tenant, admin DIDs can't be blocked
+/// Replies with the block status of the DID
+pub(crate) async fn block(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+    metadata: &UnpackMetadata,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "admin_block",
+        session_id = session.session_id.as_str()
+    );
+    async move {
+        let admin = _check_admin(state, session, metadata)?;
+        let body: AdminBlock = _body(msg, session, "block")?;
+
+        // A blocked admin couldn't authenticate to unblock itself
+        if body.blocked && state.config.admin_dids.contains(&body.did) {
+            return Err(MediatorError::RequestDataError(
+                session.session_id.clone(),
+                "Admin DIDs can't be blocked".into(),
+            ));
+        }
+
+        let tenant = state.config.tenant(&session.tenant);
+        let changed = state
+            .database
+            .did_set_blocked(
+                &session.session_id,
+                &tenant.did_hash(&body.did),
+                body.blocked,
+            )
+            .await?;
+
+        info!(
+            "admin did({}) set blocked({}) for did({}) changed({})",
+            admin, body.blocked, body.did, changed
+        );
+
+        _reply(
+            msg,
+            state,
+            session,
+            admin,
+            ADMIN_BLOCK_STATUS_MESSAGE_TYPE,
+            AdminBlockStatus {
+                did: body.did,
+                blocked: body.blocked,
+                changed,
+            },
+        )
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Deletes the messages queued for a DID of the same tenant that are older than a retention
+/// The retention of the DID's mailbox tier is used if the admin doesn't set one
+/// Replies with the number of deleted messages
+pub(crate) async fn expire(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+    metadata: &UnpackMetadata,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "admin_expire",
+        session_id = session.session_id.as_str()
+    );
+    async move {
+        let admin = _check_admin(state, session, metadata)?;
+        let body: AdminExpire = _body(msg, session, "expire")?;
+
+        let tenant = state.config.tenant(&session.tenant);
+        let did_hash = tenant.did_hash(&body.did);
+        let older_than_minutes = match body.older_than_minutes {
+            Some(minutes) => minutes,
+            None => {
+                state
+                    .database
+                    .did_tier(&session.session_id, &did_hash)
+                    .await?
+                    .message_expiry_minutes as u64
+            }
+        };
+
+        let expired_before = (SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64)
+            .saturating_sub(older_than_minutes.saturating_mul(60_000));
+        let deleted = state
+            .database
+            .expire_messages(&session.session_id, &did_hash, expired_before)
+            .await?;
+
+        info!(
+            "admin did({}) expired ({}) messages of did({}) older than ({}) minutes",
+            admin, deleted, body.did, older_than_minutes
+        );
+
+        _reply(
+            msg,
+            state,
+            session,
+            admin,
+            ADMIN_EXPIRE_REPORT_MESSAGE_TYPE,
+            AdminExpireReport {
+                did: body.did,
+                older_than_minutes,
+                deleted,
+            },
+        )
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Admin messages are only accepted from admin DIDs, identified by the authenticated sender of the message
+/// The message must be authcrypted or signed, the session DID isn't used as it doesn't prove the sender
+/// Returns the DID of the admin
+fn _check_admin<'a>(
+    state: &SharedData,
+    session: &Session,
+    metadata: &'a UnpackMetadata,
+) -> Result<&'a str, MediatorError> {
+    let sender = metadata
+        .encrypted_from_kid
+        .as_deref()
+        .filter(|_| metadata.authenticated)
+        .or(metadata.sign_from.as_deref())
+        .and_then(|kid| kid.split('#').next());
+
+    match sender {
+        Some(did) if state.config.admin_dids.iter().any(|admin| admin == did) => Ok(did),
+        Some(_) => Err(MediatorError::PermissionError(
+            session.session_id.clone(),
+            "Admin messages can only be sent by admin DIDs".into(),
+        )),
+        None => Err(MediatorError::PermissionError(
+            session.session_id.clone(),
+            "Admin messages must be authcrypted or signed by an admin DID".into(),
+        )),
+    }
+}
+
+/// Parses the body of an admin message
+fn _body<T: DeserializeOwned>(
+    msg: &Message,
+    session: &Session,
+    name: &str,
+) -> Result<T, MediatorError> {
+    serde_json::from_value(msg.body.to_owned()).map_err(|err| {
+        MediatorError::RequestDataError(
+            session.session_id.clone(),
+            format!("Admin {} body isn't valid. Reason: {}", name, err),
+        )
+    })
+}
+
+/// Builds the reply to an admin message, it is sent from the mediator DID to the admin
+/// The reply is packed encrypted for the admin
+fn _reply<T: Serialize>(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+    admin: &str,
+    type_: &str,
+    body: T,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let reply = Message::build(Uuid::new_v4().into(), type_.to_owned(), json!(body))
+        .thid(msg.thid.clone().unwrap_or_else(|| msg.id.clone()))
+        .to(admin.to_owned())
+        .from(state.config.tenant(&session.tenant).did.clone())
+        .created_time(now)
+        .expires_time(now + 300)
+        .finalize();
+
+    debug!("admin reply message =\n{:?}", reply);
+
+    Ok(Some(ProcessMessageResponse {
+        store_message: false,
+        force_live_delivery: false,
+        message_response: MessageResponse::Message(reply),
+    }))
+}
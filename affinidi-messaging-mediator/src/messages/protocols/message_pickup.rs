@@ -10,14 +10,14 @@ use base64::prelude::*;
 use itertools::Itertools;
 use redis::{from_redis_value, Value};
 use serde_json::json;
-use sha256::digest;
 use std::time::SystemTime;
 use tracing::{debug, error, event, info, span, warn, Instrument, Level};
 use uuid::Uuid;
 
 use crate::{
     common::errors::{MediatorError, Session},
-    messages::{MessageResponse, ProcessMessageResponse},
+    database::keys,
+    messages::{receipts, MessageResponse, ProcessMessageResponse},
     tasks::websocket_streaming::{StreamingUpdate, StreamingUpdateState},
     SharedData,
 };
@@ -60,7 +60,7 @@ pub(crate) async fn status_request(
                         ),
                     ));
                 } else {
-                    digest(recipient_did)
+                    keys::did_hash(&session.tenant, &recipient_did)
                 }
             } else {
                 session.did_hash.clone()
@@ -102,7 +102,9 @@ async fn generate_status_reply(
 
         let response: Vec<Value> = deadpool_redis::redis::cmd("FCALL")
             .arg("get_status_reply")
-            .arg(1)
+            .arg(2)
+            .arg(keys::did_key(did_hash))
+            .arg(keys::receive_queue_key(did_hash))
             .arg(did_hash)
             .query_async(&mut conn)
             .await
@@ -158,11 +160,6 @@ async fn generate_status_reply(
                     }
                 }
                 "queue_count" => continue,
-                "live_delivery" => {
-                    if let Ok(v) = from_redis_value::<bool>(&v) {
-                        status.live_delivery = v;
-                    }
-                }
                 "total_bytes" => {
                     if let Ok(v) = from_redis_value::<u64>(&v) {
                         status.total_bytes = v;
@@ -175,9 +172,16 @@ async fn generate_status_reply(
             }
         }
 
-        if let Some(live_delivery) = override_live_delivery {
-            status.live_delivery = live_delivery;
-        }
+        // Live streaming status is held in a different slot to the DID records
+        status.live_delivery = if let Some(live_delivery) = override_live_delivery {
+            live_delivery
+        } else {
+            !state
+                .database
+                .streaming_live_nodes(did_hash, true, false)
+                .await
+                .is_empty()
+        };
 
         let now = _get_time_now();
 
@@ -194,7 +198,7 @@ async fn generate_status_reply(
         )
         .thid(thid.to_owned())
         .to(session.did.clone())
-        .from(state.config.mediator_did.clone())
+        .from(state.config.tenant(&session.tenant).did.clone())
         .created_time(now)
         .expires_time(now + 300)
         .finalize();
@@ -257,7 +261,7 @@ pub(crate) async fn toggle_live_delivery(
                     .channel
                     .send(StreamingUpdate {
                         did_hash: session.did_hash.clone(),
-                        state: StreamingUpdateState::Start,
+                        state: StreamingUpdateState::Start(session.session_id.clone()),
                     })
                     .await
                     .map_err(|e| {
@@ -275,7 +279,7 @@ pub(crate) async fn toggle_live_delivery(
                     .channel
                     .send(StreamingUpdate {
                         did_hash: session.did_hash.clone(),
-                        state: StreamingUpdateState::Stop,
+                        state: StreamingUpdateState::Stop(session.session_id.clone()),
                     })
                     .await
                     .map_err(|e| {
@@ -323,7 +327,7 @@ pub(crate) async fn delivery_request(
         let (recipient_did, limit): (String, usize) =
             _parse_and_validate_delivery_request_body(&session, msg)?;
 
-        let recipient_did_hash = digest(recipient_did.clone());
+        let recipient_did_hash = keys::did_hash(&session.tenant, &recipient_did);
 
         debug!(
             "Body: recipient_did: {}, limit: {}",
@@ -351,6 +355,12 @@ pub(crate) async fn delivery_request(
             .await?;
         debug!("msgs fetched: {}", messages.success.len());
 
+        // Fetched messages can no longer be recalled, and may need a delivery receipt
+        if recipient_did_hash == session.did_hash {
+            let fetched: Vec<&str> = messages.success.iter().map(|m| m.msg_id.as_str()).collect();
+            receipts::fetched(state, session, &fetched).await;
+        }
+
         if !messages.success.is_empty() {
             let response_msg = Message::build(
                 Uuid::new_v4().into(),
@@ -374,7 +384,7 @@ pub(crate) async fn delivery_request(
             let response_msg = response_msg
                 .attachments(attachments)
                 .to(session.did.clone())
-                .from(state.config.mediator_did.clone())
+                .from(state.config.tenant(&session.tenant).did.clone())
                 .created_time(now)
                 .expires_time(now + 300)
                 .finalize();
@@ -416,11 +426,15 @@ pub(crate) async fn messages_received(
 
         debug!("Messages Id list: {:?}", message_id_list);
 
+        let mut acknowledged: Vec<String> = Vec::new();
         for msg_id in &message_id_list {
             debug!("getting message with id: {}", msg_id);
             match state.database.get_message(&session.did_hash, msg_id).await {
                 Ok(msg) => {
                     debug!("Got message: {:?}", msg);
+                    // A message may be acknowledged without having been fetched or streamed first
+                    receipts::fetched(state, session, &[msg_id]).await;
+                    let read_receipt = receipts::read_requested(state, session, msg_id).await;
                     debug!("Deleting message: {}", msg_id);
                     match state
                         .database
@@ -429,6 +443,10 @@ pub(crate) async fn messages_received(
                     {
                         Ok(_) => {
                             info!("Deleted message: {}", msg_id);
+                            if let Some(request) = read_receipt {
+                                receipts::read(state, session, msg_id, &request).await;
+                            }
+                            acknowledged.push(msg_id.to_owned());
                         }
                         Err(err) => {
                             info!("Error deleting message: {:?}", err);
@@ -441,6 +459,19 @@ pub(crate) async fn messages_received(
             }
         }
 
+        // Live streamed messages no longer need to be tracked for redelivery
+        if !acknowledged.is_empty() {
+            if let Some(stream_task) = &state.streaming_task {
+                let _ = stream_task
+                    .channel
+                    .send(StreamingUpdate {
+                        did_hash: session.did_hash.clone(),
+                        state: StreamingUpdateState::Acknowledged(acknowledged),
+                    })
+                    .await;
+            }
+        }
+
         Ok(
             generate_status_reply(state, session, &session.did_hash, &thid, false, None)
                 .await
@@ -555,15 +586,13 @@ fn _validate_msg(
         ));
     };
 
-    // Must be addressed to ATM
-    if to != state.config.mediator_did {
-        debug!(
-            "to: ({}) doesn't match ATM DID ({})",
-            to, state.config.mediator_did
-        );
+    // Must be addressed to ATM (the tenant of the session)
+    let mediator_did = &state.config.tenant(&session.tenant).did;
+    if &to != mediator_did {
+        debug!("to: ({}) doesn't match ATM DID ({})", to, mediator_did);
         return Err(MediatorError::RequestDataError(session.session_id.clone(),
              format!("message to: ({}) didn't match ATM DID ({}). messages-received messages must be addressed directly to ATM!",
-              to, state.config.mediator_did)));
+              to, mediator_did)));
     }
 
     // Message can not be anonymous
@@ -1,3 +1,5 @@
+pub mod admin;
 pub mod message_pickup;
 pub mod ping;
+pub mod push;
 pub mod routing;
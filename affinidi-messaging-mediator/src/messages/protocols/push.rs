@@ -0,0 +1,191 @@
+use affinidi_messaging_didcomm::Message;
+use affinidi_messaging_sdk::protocols::push::{
+    PushEndpoint, PushFormat, PushRegistration, PushStatus, PushUnregister,
+    PUSH_STATUS_MESSAGE_TYPE,
+};
+use serde_json::json;
+use sha256::digest;
+use std::time::SystemTime;
+use tracing::{debug, info, span, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    common::errors::{MediatorError, Session},
+    database::push::StoredPushRegistration,
+    messages::{MessageResponse, ProcessMessageResponse},
+    SharedData,
+};
+
+/// Registers a push endpoint for the DID of the session
+/// Replies with the push endpoints of the DID
+pub(crate) async fn register(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "push_register",
+        session_id = session.session_id.as_str()
+    );
+    async move {
+        _check_enabled(state, session)?;
+
+        let registration: PushRegistration =
+            serde_json::from_value(msg.body.to_owned()).map_err(|err| {
+                MediatorError::RequestDataError(
+                    session.session_id.clone(),
+                    format!("Push registration body isn't valid. Reason: {}", err),
+                )
+            })?;
+
+        state
+            .config
+            .push_guard()
+            .check_url(&registration.endpoint)
+            .map_err(|err| {
+                MediatorError::RequestDataError(
+                    session.session_id.clone(),
+                    format!(
+                        "Push endpoint ({}) isn't allowed: {}",
+                        registration.endpoint, err
+                    ),
+                )
+            })?;
+
+        if registration.format == PushFormat::Fcm && registration.token.is_none() {
+            return Err(MediatorError::RequestDataError(
+                session.session_id.clone(),
+                "FCM push endpoints require a device token".into(),
+            ));
+        }
+
+        let stored = StoredPushRegistration {
+            id: digest(
+                [
+                    registration.endpoint.as_str(),
+                    registration.token.as_deref().unwrap_or_default(),
+                ]
+                .concat(),
+            ),
+            endpoint: registration.endpoint,
+            format: registration.format,
+            token: registration.token,
+            authorization: match (registration.authorization, &state.config.push_secret_box) {
+                (Some(authorization), Some(secret_box)) => Some(secret_box.seal(&authorization)?),
+                (Some(_), None) => {
+                    return Err(MediatorError::InternalError(
+                        session.session_id.clone(),
+                        "Push authorization can't be stored, no encryption key".into(),
+                    ))
+                }
+                (None, _) => None,
+            },
+        };
+
+        state
+            .database
+            .push_register(
+                &session.session_id,
+                &session.did_hash,
+                &stored,
+                state.config.push_max_registrations,
+            )
+            .await?;
+
+        info!(
+            "push endpoint registered did_hash({}) id({}) format({:?})",
+            session.did_hash, stored.id, stored.format
+        );
+
+        _status_reply(msg, state, session).await
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Removes push endpoint(s) of the DID of the session
+/// Replies with the remaining push endpoints of the DID
+pub(crate) async fn unregister(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let _span = span!(
+        tracing::Level::DEBUG,
+        "push_unregister",
+        session_id = session.session_id.as_str()
+    );
+    async move {
+        _check_enabled(state, session)?;
+
+        let body: PushUnregister = serde_json::from_value(msg.body.to_owned()).unwrap_or_default();
+
+        state
+            .database
+            .push_unregister(&session.session_id, &session.did_hash, body.id.as_deref())
+            .await?;
+
+        info!(
+            "push endpoint(s) removed did_hash({}) id({:?})",
+            session.did_hash, body.id
+        );
+
+        _status_reply(msg, state, session).await
+    }
+    .instrument(_span)
+    .await
+}
+
+fn _check_enabled(state: &SharedData, session: &Session) -> Result<(), MediatorError> {
+    if state.config.push_enabled {
+        Ok(())
+    } else {
+        Err(MediatorError::NotImplemented(
+            session.session_id.clone(),
+            "Push notifications are not enabled on this mediator".into(),
+        ))
+    }
+}
+
+/// Builds the push status reply, returned directly to the session
+async fn _status_reply(
+    msg: &Message,
+    state: &SharedData,
+    session: &Session,
+) -> Result<Option<ProcessMessageResponse>, MediatorError> {
+    let status = PushStatus {
+        registrations: state
+            .database
+            .push_registrations(&session.did_hash)
+            .await?
+            .iter()
+            .map(PushEndpoint::from)
+            .collect(),
+    };
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let status_msg = Message::build(
+        Uuid::new_v4().into(),
+        PUSH_STATUS_MESSAGE_TYPE.to_owned(),
+        json!(status),
+    )
+    .thid(msg.thid.clone().unwrap_or_else(|| msg.id.clone()))
+    .to(session.did.clone())
+    .from(state.config.tenant(&session.tenant).did.clone())
+    .created_time(now)
+    .expires_time(now + 300)
+    .finalize();
+
+    debug!("push status message =\n{:?}", status_msg);
+
+    Ok(Some(ProcessMessageResponse {
+        store_message: false,
+        force_live_delivery: false,
+        message_response: MessageResponse::Message(status_msg),
+    }))
+}
@@ -0,0 +1,240 @@
+//! Delivery and read receipts
+//!
+//! A sender asks for receipts with a `receipt_request` header on the forward message.
+//! The request is stored with the recipient copy of the message, and a signed receipt is
+//! queued to the sender when the message is first fetched/streamed (delivered) and when it is
+//! acknowledged via messages-received (read). The receipts policy limits what is sent.
+use std::time::SystemTime;
+
+use affinidi_messaging_didcomm::{Message, PackEncryptedOptions};
+use affinidi_messaging_sdk::protocols::receipts::{
+    Receipt, ReceiptRequest, ReceiptType, RECEIPT_MESSAGE_TYPE, RECEIPT_REQUEST_HEADER,
+};
+use tracing::{debug, span, warn, Instrument, Level};
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        config::ReceiptPolicy,
+        errors::{MediatorError, Session},
+    },
+    database::receipts::StoredReceiptRequest,
+    messages::inbound::_try_live_stream,
+    SharedData,
+};
+
+/// Is this type of receipt allowed by the receipts policy?
+fn _allowed(policy: ReceiptPolicy, receipt_type: ReceiptType) -> bool {
+    match policy {
+        ReceiptPolicy::Disabled => false,
+        ReceiptPolicy::Delivered => receipt_type == ReceiptType::Delivered,
+        ReceiptPolicy::All => true,
+    }
+}
+
+/// Reads the receipt request header of an inbound message
+/// Receipts are always sent to the authenticated sender of the message
+/// Returns None if no receipts were requested, or the policy doesn't allow any of the requested receipts
+pub(crate) fn requested(
+    state: &SharedData,
+    session: &Session,
+    msg: &Message,
+    to_did: &str,
+) -> Option<StoredReceiptRequest> {
+    let header = msg.extra_headers.get(RECEIPT_REQUEST_HEADER)?;
+    let request: ReceiptRequest = match serde_json::from_value(header.clone()) {
+        Ok(request) => request,
+        Err(err) => {
+            warn!("Invalid receipt request header, ignoring. Reason: {}", err);
+            return None;
+        }
+    };
+
+    let mut types: Vec<ReceiptType> = request
+        .types
+        .into_iter()
+        .filter(|t| _allowed(state.config.receipts_policy, *t))
+        .collect();
+    types.dedup();
+    if types.is_empty() {
+        return None;
+    }
+
+    Some(StoredReceiptRequest {
+        id: request.id,
+        types,
+        sender: session.did.clone(),
+        recipient: to_did.to_string(),
+    })
+}
+
+/// Marks messages as fetched by the recipient (they can no longer be recalled)
+/// and sends delivery receipts for messages that requested them
+/// - msg_ids: messages that have been handed to the recipient of this session
+pub(crate) async fn fetched(state: &SharedData, session: &Session, msg_ids: &[&str]) {
+    let receipts = match state
+        .database
+        .mark_fetched(&session.did_hash, msg_ids)
+        .await
+    {
+        Ok(receipts) => receipts,
+        Err(err) => {
+            warn!("Couldn't mark messages as fetched: {}", err);
+            return;
+        }
+    };
+
+    for (msg_id, request) in receipts {
+        if request.types.contains(&ReceiptType::Delivered) {
+            _send(state, session, &msg_id, &request, ReceiptType::Delivered).await;
+        }
+    }
+}
+
+/// Returns the read receipt request of a message
+/// Must be called before the message is deleted, as the request is stored with the message
+pub(crate) async fn read_requested(
+    state: &SharedData,
+    session: &Session,
+    msg_id: &str,
+) -> Option<StoredReceiptRequest> {
+    if !_allowed(state.config.receipts_policy, ReceiptType::Read) {
+        return None;
+    }
+
+    match state
+        .database
+        .get_receipt_request(&session.did_hash, msg_id)
+        .await
+    {
+        Ok(Some(request)) if request.types.contains(&ReceiptType::Read) => Some(request),
+        Ok(_) => None,
+        Err(err) => {
+            warn!(
+                "Couldn't get receipt request for msg_id({}): {}",
+                msg_id, err
+            );
+            None
+        }
+    }
+}
+
+/// Sends a read receipt for a message that the recipient has acknowledged
+pub(crate) async fn read(
+    state: &SharedData,
+    session: &Session,
+    msg_id: &str,
+    request: &StoredReceiptRequest,
+) {
+    _send(state, session, msg_id, request, ReceiptType::Read).await;
+}
+
+/// Creates a signed receipt and queues it for the sender
+/// Errors are logged only, a failed receipt doesn't fail the delivery of the message
+async fn _send(
+    state: &SharedData,
+    session: &Session,
+    msg_id: &str,
+    request: &StoredReceiptRequest,
+    status: ReceiptType,
+) {
+    let _span = span!(
+        Level::DEBUG,
+        "send_receipt",
+        msg_id = msg_id,
+        status = format!("{:?}", status)
+    );
+    async move {
+        if !_allowed(state.config.receipts_policy, status) {
+            debug!("receipt not allowed by policy, skipping");
+            return;
+        }
+
+        if let Err(err) = _queue(state, session, msg_id, request, status).await {
+            warn!("Couldn't send receipt to ({}): {}", request.sender, err);
+        }
+    }
+    .instrument(_span)
+    .await
+}
+
+async fn _queue(
+    state: &SharedData,
+    session: &Session,
+    msg_id: &str,
+    request: &StoredReceiptRequest,
+    status: ReceiptType,
+) -> Result<(), MediatorError> {
+    let tenant = state.config.tenant(&session.tenant);
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let receipt = Receipt {
+        message_id: request.id.clone(),
+        atm_message_id: msg_id.to_string(),
+        recipient: request.recipient.clone(),
+        status,
+        timestamp: state.config.receipts_include_timestamp.then_some(now),
+    };
+
+    let body = serde_json::to_value(&receipt).map_err(|err| {
+        MediatorError::InternalError(
+            session.session_id.clone(),
+            format!("Couldn't serialize receipt: {}", err),
+        )
+    })?;
+
+    let receipt = Message::build(Uuid::new_v4().into(), RECEIPT_MESSAGE_TYPE.to_owned(), body)
+        .thid(request.id.clone())
+        .to(request.sender.clone())
+        .from(tenant.did.clone())
+        .created_time(now)
+        .expires_time(now + state.config.message_expiry_minutes as u64 * 60)
+        .finalize();
+
+    // Receipts are signed by the mediator, and queued directly in the sender's inbox
+    let (packed, _) = receipt
+        .pack_encrypted(
+            &request.sender,
+            Some(&tenant.did),
+            Some(&tenant.did),
+            &state.did_resolver,
+            &tenant.secrets,
+            &PackEncryptedOptions {
+                forward: false,
+                to_kids_limit: state.config.to_keys_per_recipient_limit,
+                ..PackEncryptedOptions::default()
+            },
+        )
+        .await
+        .map_err(|err| {
+            MediatorError::MessagePackError(session.session_id.clone(), err.to_string())
+        })?;
+
+    let receipt_id = state
+        .database
+        .store_message(
+            &session.session_id,
+            &tenant.namespace,
+            &packed,
+            &request.sender,
+            Some(&tenant.did),
+        )
+        .await?;
+    debug!("receipt queued as msg_id({})", receipt_id);
+
+    _try_live_stream(
+        state,
+        &tenant.did_hash(&request.sender),
+        &packed,
+        false,
+        Some(&receipt_id),
+        None,
+    )
+    .await;
+
+    Ok(())
+}
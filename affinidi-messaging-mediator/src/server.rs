@@ -1,16 +1,21 @@
 use crate::{
+    common::{tenant::RequestTenant, tls},
     database::DatabaseHandler,
     handlers::{application_routes, health_checker_handler},
     init,
+    tasks::blob_expiry::blob_expiry,
+    tasks::message_expiry::message_expiry,
+    tasks::push_notifications::PushTask,
     tasks::statistics::statistics,
+    tasks::tls_reload::tls_reload,
     tasks::websocket_streaming::StreamingTask,
     SharedData,
 };
 use affinidi_did_resolver_cache_sdk::DIDCacheClient;
-use axum::{routing::get, Router};
+use axum::{routing::get, Extension, Router};
 use axum_server::tls_rustls::RustlsConfig;
 use http::Method;
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, sync::Arc};
 use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::{self, TraceLayer};
 use tracing::{event, Level};
@@ -89,13 +94,46 @@ pub async fn start() {
             .expect("Error starting statistics thread");
     });
 
+    // Start the message expiry thread
+    let _database = database.clone();
+    tokio::spawn(async move {
+        message_expiry(_database)
+            .await
+            .expect("Error starting message expiry thread");
+    });
+
+    // Start the blob expiry thread if blob storage is enabled
+    if let Some(storage) = config.blob_storage.clone() {
+        let _database = database.clone();
+        tokio::spawn(async move {
+            blob_expiry(_database, storage)
+                .await
+                .expect("Error starting blob expiry thread");
+        });
+    }
+
     // Start the streaming thread if enabled
     let (streaming_task, _) = if config.streaming_enabled {
         let _database = database.clone(); // Clone the database handler for the subscriber thread
         let uuid = config.streaming_uuid.clone();
-        let (_task, _handle) = StreamingTask::new(_database.clone(), &uuid)
+        let (_task, _handle) = StreamingTask::new(
+            _database.clone(),
+            &uuid,
+            config.streaming_heartbeat_interval,
+            config.streaming_heartbeat_ttl,
+        )
+        .await
+        .expect("Error starting streaming task");
+        (Some(_task), Some(_handle))
+    } else {
+        (None, None)
+    };
+
+    // Start the push notification thread if enabled
+    let (push_task, _) = if config.push_enabled {
+        let (_task, _handle) = PushTask::new(database.clone(), &config)
             .await
-            .expect("Error starting streaming task");
+            .expect("Error starting push notification task");
         (Some(_task), Some(_handle))
     } else {
         (None, None)
@@ -113,10 +151,29 @@ pub async fn start() {
         did_resolver,
         database,
         streaming_task,
+        push_task,
     };
 
     // build our application routes
-    let app: Router = application_routes(&config.api_prefix, &shared_state);
+    let mut app: Router = application_routes(&config.api_prefix, &shared_state);
+
+    // Tenants with their own api_prefix are served on their own routes
+    for tenant in config.tenants.iter().skip(1) {
+        event!(
+            Level::INFO,
+            "Serving tenant ({}) DID({}) api_prefix({:?}) host({:?})",
+            tenant.namespace,
+            tenant.did,
+            tenant.api_prefix,
+            tenant.host
+        );
+        if let Some(api_prefix) = &tenant.api_prefix {
+            app = app.merge(
+                application_routes(api_prefix, &shared_state)
+                    .layer(Extension(RequestTenant(tenant.namespace.clone()))),
+            );
+        }
+    }
 
     // Add middleware to all routes
     let app = Router::new()
@@ -124,6 +181,7 @@ pub async fn start() {
         .layer(
             config
                 .cors_allow_origin
+                .clone()
                 .allow_headers([http::header::CONTENT_TYPE])
                 .allow_methods([
                     Method::GET,
@@ -150,13 +208,33 @@ pub async fn start() {
             Level::INFO,
             "This mediator is using SSL/TLS for secure communication."
         );
-        // configure certificate and private key used by https
-        // TODO: Build a proper TLS Config
+        // configure the certificate, key and TLS settings used by https
         let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
-        let ssl_config =
-            RustlsConfig::from_pem_file(config.ssl_certificate_file, config.ssl_key_file)
-                .await
-                .expect("bad certificate/key");
+        let ssl_config = match tls::server_config(&config) {
+            Ok(server_config) => RustlsConfig::from_config(Arc::new(server_config)),
+            Err(err) => {
+                event!(Level::ERROR, "Error creating TLS configuration: {}", err);
+                event!(Level::ERROR, "Exiting...");
+                std::process::exit(1);
+            }
+        };
+        if config.tls_client_ca_file.is_some() {
+            event!(
+                Level::INFO,
+                "Client certificates are required (mutual TLS)."
+            );
+        }
+
+        // Reload the TLS configuration when the certificate files change
+        if config.tls_reload_interval > 0 {
+            let _config = config.clone();
+            let _ssl_config = ssl_config.clone();
+            tokio::spawn(async move {
+                tls_reload(_config, _ssl_config)
+                    .await
+                    .expect("Error starting TLS reload thread");
+            });
+        }
 
         axum_server::bind_rustls(config.listen_address.parse().unwrap(), ssl_config)
             .serve(app.into_make_service_with_connect_info::<SocketAddr>())
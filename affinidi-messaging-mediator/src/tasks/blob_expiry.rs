@@ -0,0 +1,70 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, info, span, warn, Instrument, Level};
+
+use crate::{
+    common::{blob_storage::BlobStorage, errors::MediatorError},
+    database::{keys, DatabaseHandler},
+};
+
+/// Maximum number of blobs removed per shard bucket on each run
+const BLOB_EXPIRY_BATCH: usize = 100;
+
+/// Periodically removes blobs that have expired, or are no longer referenced by any message.
+/// Is spawned as a task from main() when blob storage is enabled.
+pub async fn blob_expiry(
+    database: DatabaseHandler,
+    storage: BlobStorage,
+) -> Result<(), MediatorError> {
+    let _span = span!(Level::INFO, "blob_expiry");
+
+    async move {
+        debug!("Starting blob expiry thread...");
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+        loop {
+            interval.tick().await;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            let mut removed = 0;
+            for shard in keys::all_shards() {
+                let expired = match database.blob_expired(&shard, now, BLOB_EXPIRY_BATCH).await {
+                    Ok(expired) => expired,
+                    Err(err) => {
+                        warn!("Couldn't get expired blobs: {}", err);
+                        continue;
+                    }
+                };
+
+                for blob_id in expired {
+                    let upload_id = match database.blob_get("NA", &blob_id).await {
+                        Ok(record) => record.map(|r| r.upload_id).unwrap_or_default(),
+                        Err(err) => {
+                            warn!("Couldn't get blob({}): {}", blob_id, err);
+                            continue;
+                        }
+                    };
+                    // Keep the records if storage fails, so the removal is retried
+                    if let Err(err) = storage.delete(&blob_id, &upload_id).await {
+                        warn!("Couldn't remove expired blob({}): {}", blob_id, err);
+                        continue;
+                    }
+                    if let Err(err) = database.blob_delete("NA", &blob_id).await {
+                        warn!("Couldn't remove expired blob({}) records: {}", blob_id, err);
+                        continue;
+                    }
+                    removed += 1;
+                }
+            }
+
+            if removed > 0 {
+                info!("Removed ({}) expired blobs", removed);
+            }
+        }
+    }
+    .instrument(_span)
+    .await
+}
@@ -0,0 +1,78 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, info, span, warn, Instrument, Level};
+
+use crate::{
+    common::errors::MediatorError,
+    database::{keys, DatabaseHandler},
+};
+
+/// Maximum number of messages removed per shard bucket on each run
+const MESSAGE_EXPIRY_BATCH: usize = 100;
+
+/// Periodically removes messages that are older than the retention of the recipient's mailbox tier.
+/// The expiry time is set when the message is queued, fetching messages also applies the current tier.
+/// Is spawned as a task from main().
+pub async fn message_expiry(database: DatabaseHandler) -> Result<(), MediatorError> {
+    let _span = span!(Level::INFO, "message_expiry");
+
+    async move {
+        debug!("Starting message expiry thread...");
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+        loop {
+            interval.tick().await;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0);
+
+            let mut removed = 0;
+            for shard in keys::all_shards() {
+                let expired = match database
+                    .messages_expired(&shard, now, MESSAGE_EXPIRY_BATCH)
+                    .await
+                {
+                    Ok(expired) => expired,
+                    Err(err) => {
+                        warn!("Couldn't get expired messages: {}", err);
+                        continue;
+                    }
+                };
+
+                for (did_hash, msg_id) in expired {
+                    // expire_message also removes the expiry record
+                    if database
+                        .expire_message("NA", &did_hash, &msg_id)
+                        .await
+                        .is_ok()
+                    {
+                        removed += 1;
+                        continue;
+                    }
+
+                    // The record is kept (and the removal retried) unless the message no longer exists
+                    match database.get_message_recipient(&did_hash, &msg_id).await {
+                        Ok(Some(_)) => {
+                            warn!("Couldn't remove expired msg_id({})", msg_id);
+                        }
+                        Ok(None) => {
+                            if let Err(err) =
+                                database.message_expiry_remove(&did_hash, &msg_id).await
+                            {
+                                warn!("{}", err);
+                            }
+                        }
+                        Err(err) => warn!("{}", err),
+                    }
+                }
+            }
+
+            if removed > 0 {
+                info!("Removed ({}) expired messages", removed);
+            }
+        }
+    }
+    .instrument(_span)
+    .await
+}
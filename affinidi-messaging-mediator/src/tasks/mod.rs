@@ -1,3 +1,7 @@
 /// Any parallel task (thread) that needs to be spawned should be defined here.
+pub mod blob_expiry;
+pub mod message_expiry;
+pub mod push_notifications;
 pub mod statistics;
+pub mod tls_reload;
 pub mod websocket_streaming;
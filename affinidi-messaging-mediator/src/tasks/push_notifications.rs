@@ -0,0 +1,363 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use affinidi_messaging_sdk::protocols::push::PushFormat;
+use reqwest::{header::AUTHORIZATION, redirect::Policy, Client, RequestBuilder, StatusCode};
+use serde_json::json;
+use tokio::{
+    select,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Semaphore,
+    },
+    task::JoinHandle,
+    time::{interval, sleep},
+};
+use tracing::{debug, error, info, span, warn, Instrument, Level};
+
+use crate::{
+    common::{
+        config::Config, endpoint_guard::EndpointGuard, errors::MediatorError, secret_box::SecretBox,
+    },
+    database::{push::StoredPushRegistration, DatabaseHandler},
+};
+
+/// Wakes up recipients that are not live streaming by calling their registered push endpoints
+/// channel: DID hashes that have received a new message
+#[derive(Clone)]
+pub struct PushTask {
+    pub channel: mpsc::Sender<String>,
+}
+
+/// Settings of the push task, taken from the mediator configuration
+/// batch_interval: Milliseconds that notifications are collected before being sent
+/// rate_limit: Minimum seconds between notifications to the same DID
+/// retries: Number of retries for a failed notification
+/// guard: Checks endpoints again before they are called
+/// secret_box: Opens the stored Authorization header of endpoints
+/// workers: Limits the number of endpoints that are called at the same time
+#[derive(Clone)]
+struct PushSettings {
+    batch_interval: u64,
+    rate_limit: u64,
+    retries: u32,
+    guard: EndpointGuard,
+    secret_box: Option<SecretBox>,
+    workers: Arc<Semaphore>,
+}
+
+impl PushTask {
+    /// Creates the push notification task
+    pub async fn new(
+        database: DatabaseHandler,
+        config: &Config,
+    ) -> Result<(Self, JoinHandle<()>), MediatorError> {
+        let _span = span!(Level::INFO, "PushTask::new");
+
+        async move {
+            let client = Client::builder()
+                .timeout(Duration::from_secs(config.push_timeout.max(1)))
+                // Every resolved address is checked, and redirects could lead to internal services
+                .dns_resolver(Arc::new(config.push_guard()))
+                .redirect(Policy::none())
+                .build()
+                .map_err(|err| {
+                    MediatorError::InternalError(
+                        "NA".into(),
+                        format!("Couldn't create push HTTP client: {}", err),
+                    )
+                })?;
+
+            // Create the inter-task channel - allows up to 1000 queued notifications
+            let (tx, mut rx) = mpsc::channel(1000);
+            let task = PushTask { channel: tx };
+
+            let settings = PushSettings {
+                batch_interval: config.push_batch_interval,
+                rate_limit: config.push_rate_limit,
+                retries: config.push_retries,
+                guard: config.push_guard(),
+                secret_box: config.push_secret_box.clone(),
+                workers: Arc::new(Semaphore::new(config.push_max_concurrent.max(1))),
+            };
+
+            let handle = tokio::spawn(async move {
+                PushTask::push_task(database, client, settings, &mut rx)
+                    .await
+                    .expect("Error starting push_notifications thread");
+            });
+
+            Ok((task, handle))
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Queues a push notification for a DID
+    /// Never blocks, if the queue is full then the notification is dropped
+    pub fn notify(&self, did_hash: &str) {
+        match self.channel.try_send(did_hash.to_string()) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "push notification queue is full, dropping notification for did_hash({})",
+                    did_hash
+                );
+            }
+            Err(TrySendError::Closed(_)) => {
+                error!("push notification task has stopped");
+            }
+        }
+    }
+
+    /// Batches notifications per DID and sends them to the registered push endpoints
+    /// Is spawned as a task
+    async fn push_task(
+        database: DatabaseHandler,
+        client: Client,
+        settings: PushSettings,
+        channel: &mut mpsc::Receiver<String>,
+    ) -> Result<(), MediatorError> {
+        let _span = span!(Level::INFO, "push_task");
+
+        async move {
+            debug!("Starting...");
+
+            // did_hash -> number of new messages since the last notification
+            // DIDs that are rate limited stay here until they can be notified again
+            let mut pending: HashMap<String, u32> = HashMap::new();
+            let mut batch = interval(Duration::from_millis(settings.batch_interval.max(10)));
+
+            loop {
+                select! {
+                    value = channel.recv() => {
+                        if let Some(did_hash) = value {
+                            *pending.entry(did_hash).or_default() += 1;
+                        } else {
+                            info!("Channel closed, exiting...");
+                            return Ok(());
+                        }
+                    }
+                    _ = batch.tick() => {
+                        if !pending.is_empty() {
+                            Self::_flush(&database, &client, &settings, &mut pending).await;
+                        }
+                    }
+                }
+            }
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Sends a notification to each pending DID that isn't rate limited
+    async fn _flush(
+        database: &DatabaseHandler,
+        client: &Client,
+        settings: &PushSettings,
+        pending: &mut HashMap<String, u32>,
+    ) {
+        let did_hashes: Vec<String> = pending.keys().cloned().collect();
+        for did_hash in did_hashes {
+            let registrations = match database.push_registrations(&did_hash).await {
+                Ok(registrations) => registrations,
+                Err(err) => {
+                    error!("Couldn't get push registrations: {}", err);
+                    pending.remove(&did_hash);
+                    continue;
+                }
+            };
+
+            if registrations.is_empty() {
+                pending.remove(&did_hash);
+                continue;
+            }
+
+            match database
+                .push_rate_limit(&did_hash, settings.rate_limit)
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    debug!("push to did_hash({}) is rate limited, deferring", did_hash);
+                    continue;
+                }
+                Err(err) => {
+                    error!("Couldn't check push rate limit: {}", err);
+                    continue;
+                }
+            }
+
+            let count = pending.remove(&did_hash).unwrap_or_default();
+            for registration in registrations {
+                // Waits for a free worker, pending notifications keep collecting in the channel
+                let Ok(permit) = settings.workers.clone().acquire_owned().await else {
+                    return;
+                };
+                let database = database.clone();
+                let client = client.clone();
+                let did_hash = did_hash.clone();
+                let settings = settings.clone();
+                tokio::spawn(async move {
+                    Self::_deliver(
+                        &database,
+                        &client,
+                        &settings,
+                        &did_hash,
+                        &registration,
+                        count,
+                    )
+                    .await;
+                    drop(permit);
+                });
+            }
+        }
+    }
+
+    /// Calls a push endpoint, retrying with exponential backoff
+    /// Endpoints that no longer exist (404/410) are removed
+    async fn _deliver(
+        database: &DatabaseHandler,
+        client: &Client,
+        settings: &PushSettings,
+        did_hash: &str,
+        registration: &StoredPushRegistration,
+        count: u32,
+    ) {
+        if let Err(err) = settings.guard.check_url(&registration.endpoint) {
+            warn!(
+                "push endpoint did_hash({}) id({}) isn't allowed ({}), skipping",
+                did_hash, registration.id, err
+            );
+            return;
+        }
+
+        let authorization = match (&registration.authorization, &settings.secret_box) {
+            (Some(sealed), Some(secret_box)) => match secret_box.open(sealed) {
+                Ok(authorization) => Some(authorization),
+                Err(err) => {
+                    warn!(
+                        "push endpoint did_hash({}) id({}) authorization can't be read ({}), skipping",
+                        did_hash, registration.id, err
+                    );
+                    return;
+                }
+            },
+            _ => None,
+        };
+
+        let retries = settings.retries;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                sleep(Duration::from_millis(500 * 2_u64.pow(attempt - 1))).await;
+            }
+
+            match Self::_request(client, registration, authorization.as_deref(), count)
+                .send()
+                .await
+            {
+                Ok(response) if response.status().is_success() => {
+                    debug!(
+                        "push sent to did_hash({}) id({}) count({})",
+                        did_hash, registration.id, count
+                    );
+                    return;
+                }
+                Ok(response)
+                    if response.status() == StatusCode::NOT_FOUND
+                        || response.status() == StatusCode::GONE =>
+                {
+                    info!(
+                        "push endpoint did_hash({}) id({}) no longer exists ({}), removing",
+                        did_hash,
+                        registration.id,
+                        response.status()
+                    );
+                    if let Err(err) = database
+                        .push_unregister("NA", did_hash, Some(&registration.id))
+                        .await
+                    {
+                        error!("Couldn't remove push registration: {}", err);
+                    }
+                    return;
+                }
+                Ok(response)
+                    if response.status().is_client_error()
+                        && response.status() != StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    warn!(
+                        "push to did_hash({}) id({}) rejected ({})",
+                        did_hash,
+                        registration.id,
+                        response.status()
+                    );
+                    return;
+                }
+                Ok(response) => {
+                    warn!(
+                        "push to did_hash({}) id({}) failed ({}), attempt({})",
+                        did_hash,
+                        registration.id,
+                        response.status(),
+                        attempt + 1
+                    );
+                }
+                Err(err) => {
+                    warn!(
+                        "push to did_hash({}) id({}) failed ({}), attempt({})",
+                        did_hash,
+                        registration.id,
+                        err,
+                        attempt + 1
+                    );
+                }
+            }
+        }
+
+        warn!(
+            "push to did_hash({}) id({}) failed after {} retries",
+            did_hash, registration.id, retries
+        );
+    }
+
+    /// Builds the request for a push endpoint, no message content is ever included
+    fn _request(
+        client: &Client,
+        registration: &StoredPushRegistration,
+        authorization: Option<&str>,
+        count: u32,
+    ) -> RequestBuilder {
+        let mut request = client.post(&registration.endpoint);
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+
+        match registration.format {
+            PushFormat::Generic => request.json(&json!({
+                "type": "new_messages",
+                "count": count,
+            })),
+            PushFormat::Fcm => request.json(&json!({
+                "message": {
+                    "token": registration.token,
+                    "data": {
+                        "type": "new_messages",
+                        "count": count.to_string(),
+                    },
+                    "android": {
+                        "priority": "high",
+                    },
+                },
+            })),
+            PushFormat::Apns => request
+                .header("apns-push-type", "background")
+                .header("apns-priority", "5")
+                .json(&json!({
+                    "aps": {
+                        "content-available": 1,
+                    },
+                    "type": "new_messages",
+                    "count": count,
+                })),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{debug, info, span, warn, Instrument, Level};
+
+use crate::common::{config::Config, errors::MediatorError, tls};
+
+/// Periodically checks the certificate, key and client CA files, and reloads the TLS configuration when they change.
+/// Existing connections are unaffected, new connections use the reloaded configuration.
+/// Is spawned as a task from main() when TLS is enabled and `tls_reload_interval` is not 0.
+pub async fn tls_reload(config: Config, rustls_config: RustlsConfig) -> Result<(), MediatorError> {
+    let _span = span!(Level::INFO, "tls_reload");
+
+    async move {
+        debug!("Starting TLS reload thread...");
+        let mut interval = tokio::time::interval(Duration::from_secs(config.tls_reload_interval));
+
+        let mut files = vec![
+            config.ssl_certificate_file.clone(),
+            config.ssl_key_file.clone(),
+        ];
+        if let Some(ca_file) = &config.tls_client_ca_file {
+            files.push(ca_file.clone());
+        }
+        let mut previous = _modified(&files).await;
+
+        loop {
+            interval.tick().await;
+            let modified = _modified(&files).await;
+            if modified == previous {
+                continue;
+            }
+
+            // Files may be replaced one at a time, a failed reload is retried on the next change
+            match tls::server_config(&config) {
+                Ok(server_config) => {
+                    rustls_config.reload_from_config(Arc::new(server_config));
+                    info!("TLS configuration reloaded");
+                }
+                Err(err) => warn!("Couldn't reload TLS configuration: {}", err),
+            }
+            previous = modified;
+        }
+    }
+    .instrument(_span)
+    .await
+}
+
+/// Last modified time of each file, None if the file can't be read
+async fn _modified(files: &[String]) -> Vec<Option<SystemTime>> {
+    let mut modified = Vec::with_capacity(files.len());
+    for file in files {
+        modified.push(
+            tokio::fs::metadata(file)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok(),
+        );
+    }
+    modified
+}
@@ -1,8 +1,16 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use redis::aio::PubSub;
 use serde::{Deserialize, Serialize};
-use tokio::{select, sync::mpsc, task::JoinHandle, time::sleep};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Notify,
+    },
+    task::JoinHandle,
+    time::{interval, sleep},
+};
 use tokio_stream::StreamExt;
 use tracing::{debug, error, info, span, warn, Instrument, Level};
 
@@ -12,15 +20,75 @@ use crate::{common::errors::MediatorError, database::DatabaseHandler};
 // https://github.com/redis-rs/redis-rs/issues/509
 
 /// Used when updating the streaming state.
-/// Register: Creates the hash map entry for the DID hash and TX Channel
-/// Start: Start streaming messages to clients.
-/// Stop: Stop streaming messages to clients.
-/// Deregister: Remove the hash map entry for the DID hash.
+/// A DID can have multiple connections (devices) at the same time, each is tracked separately
+/// Register: Creates the hash map entry for the client connection
+/// Start: Start streaming messages to the connections of a session (session_id)
+/// Stop: Stop streaming messages to the connections of a session (session_id)
+/// Listen: Creates the hash map entry for a connection that is live straight away (e.g. SSE)
+///         resync is notified once the connection is live
+/// Wait: Creates the hash map entry for a request that waits for stored messages (e.g. long-polling fetch)
+///       It isn't live streaming, so ephemeral messages are never sent to it
+///       resync is notified once the request is registered
+/// Deregister: Remove the hash map entry for the client connection (conn_id)
+/// Acknowledged: Stored messages (msg_id's) that the client has acknowledged (deleted)
 pub enum StreamingUpdateState {
-    Register(mpsc::Sender<String>),
-    Start,
-    Stop,
-    Deregister,
+    Register(StreamingClient),
+    Listen(StreamingClient),
+    Wait(StreamingClient),
+    Start(String),
+    Stop(String),
+    Deregister(String),
+    Acknowledged(Vec<String>),
+}
+
+/// Messages sent from the streaming task to a websocket connection
+/// Deliver: A message to send to the client, msg_id is set if the message is stored
+///          and must be acknowledged by the client before it is removed
+/// Acknowledged: msg_id's that have been acknowledged and no longer need tracking
+#[derive(Debug)]
+pub enum StreamingMessage {
+    Deliver {
+        msg_id: Option<String>,
+        message: String,
+    },
+    Acknowledged(Vec<String>),
+}
+
+/// A websocket connection registered with the streaming task
+/// conn_id: Unique ID of this connection
+/// session_id: Session that the connection was authenticated with
+/// tx: Channel to send messages to the websocket
+/// resync: Notified when the websocket needs to (re)deliver stored messages from the database.
+///         Happens when live delivery is started, or the channel was full (backpressure)
+pub struct StreamingClient {
+    pub conn_id: String,
+    pub session_id: String,
+    pub tx: mpsc::Sender<StreamingMessage>,
+    pub resync: Arc<Notify>,
+}
+
+/// State of a connection in the streaming task
+/// Connected: Registered, only receives messages that force delivery
+/// Live: Receives every message
+/// Waiting: Only receives stored messages
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    Connected,
+    Live,
+    Waiting,
+}
+
+impl ConnectionState {
+    /// Should a message be sent to a connection in this state?
+    /// force_delivery: The message is sent to connections that aren't live
+    /// stored: The message is stored (has a msg_id)
+    fn wants(self, force_delivery: bool, stored: bool) -> bool {
+        match self {
+            ConnectionState::Live => true,
+            ConnectionState::Connected => force_delivery,
+            ConnectionState::Waiting => stored,
+        }
+    }
 }
 
 /// Used to update the streaming state.
@@ -40,6 +108,8 @@ pub struct StreamingTask {
 /// did_hash : SHA256 hash of the DID
 /// message : The message to send to the client
 /// force_delivery : If true, the message will be sent to the client even if they are not active.
+/// msg_id : ID of the stored message, None if the message is ephemeral (not stored)
+/// session_id : If set, the message is only sent to connections of this session (e.g. replies to a request)
 ///
 /// NOTE: The force_delivery is required as when changing live_delivery status, standard says to send a status message
 #[derive(Serialize, Deserialize, Debug)]
@@ -47,13 +117,21 @@ pub struct PubSubRecord {
     pub did_hash: String,
     pub message: String,
     pub force_delivery: bool,
+    #[serde(default)]
+    pub msg_id: Option<String>,
+    #[serde(default)]
+    pub session_id: Option<String>,
 }
 
 impl StreamingTask {
     /// Creates the streaming task handler
+    /// heartbeat_interval: Seconds between heartbeats of this streaming service
+    /// heartbeat_ttl: Seconds before a streaming service without a heartbeat is considered dead
     pub async fn new(
         database: DatabaseHandler,
         mediator_uuid: &str,
+        heartbeat_interval: u64,
+        heartbeat_ttl: u64,
     ) -> Result<(Self, JoinHandle<()>), MediatorError> {
         let _span = span!(Level::INFO, "StreamingTask::new");
 
@@ -71,7 +149,13 @@ impl StreamingTask {
                 let _task = task.clone();
                 tokio::spawn(async move {
                     _task
-                        .ws_streaming_task(database, &mut rx, _mediator_uuid)
+                        .ws_streaming_task(
+                            database,
+                            &mut rx,
+                            _mediator_uuid,
+                            heartbeat_interval,
+                            heartbeat_ttl,
+                        )
                         .await
                         .expect("Error starting websocket_streaming thread");
                 })
@@ -118,6 +202,8 @@ impl StreamingTask {
         database: DatabaseHandler,
         channel: &mut mpsc::Receiver<StreamingUpdate>,
         uuid: String,
+        heartbeat_interval: u64,
+        heartbeat_ttl: u64,
     ) -> Result<(), MediatorError> {
         let _span = span!(Level::INFO, "ws_streaming_task", uuid = uuid);
 
@@ -125,10 +211,14 @@ impl StreamingTask {
             debug!("Starting...");
 
             // Clean up any existing sessions left over from previous runs
-            database.streaming_clean_start(&uuid).await?;
+            database.streaming_clean_start(&uuid, heartbeat_ttl).await?;
 
-            // Create a hashmap to store the clients and if they are active (true = yes)
-            let mut clients: HashMap<String, (mpsc::Sender<String>, bool)> = HashMap::new();
+            // Heartbeats keep this streaming service alive, and reap any dead streaming services
+            let mut heartbeat = interval(Duration::from_secs(heartbeat_interval.max(1)));
+
+            // Create a hashmap to store the client connections per DID hash and their state
+            // did_hash -> conn_id -> (client, state)
+            let mut clients: HashMap<String, HashMap<String, (StreamingClient, ConnectionState)>> = HashMap::new();
 
             // Start streaming messages to clients
             let mut pubsub = self._start_pubsub(database.clone(), &uuid).await?;
@@ -138,26 +228,46 @@ impl StreamingTask {
                 // Listen for an update on either the redis pubsub stream, or the command channel
                 // stream: redis pubsub of incoming messages destined for a client
                 // channel: command channel to start/stop streaming for a client
+                // heartbeat: periodic heartbeat of this streaming service
                 select! {
+                    _ = heartbeat.tick() => {
+                        if let Err(err) = database.streaming_heartbeat(&uuid, heartbeat_ttl).await {
+                            error!("Error sending heartbeat: {}", err);
+                        }
+                        match database.streaming_reap_nodes(&uuid).await {
+                            Ok(count) if count > 0 => info!("Reaped {} dead streaming services", count),
+                            Ok(_) => {}
+                            Err(err) => error!("Error reaping dead streaming services: {}", err),
+                        }
+                    }
                     value = stream.next() => {
                         if let Some(msg) = value {
                             if let Ok(payload) = msg.get_payload::<String>() {
                                 let payload: PubSubRecord = serde_json::from_str(&payload).unwrap();
 
-                                // Find the MPSC transmit channel for the associated DID hash
-                                if let Some((tx, active)) = clients.get(&payload.did_hash) {
-                                    if payload.force_delivery ||  *active {
-                                        // Send the message to the client
-                                        if let Err(err) = tx.send(payload.message.clone()).await {
-                                            error!("Error sending message to client ({}): {}", payload.did_hash, err);
-                                        } else {
-                                            info!("Sent message to client ({})", payload.did_hash);
+                                // Fan out to every connection for the associated DID hash
+                                if let Some(connections) = clients.get_mut(&payload.did_hash) {
+                                    let mut delivered = false;
+                                    connections.retain(|_, (client, state)| {
+                                        if payload.session_id.as_ref().is_some_and(|session_id| *session_id != client.session_id) {
+                                            return true;
                                         }
-                                    } else {
-                                        warn!("pub/sub msg received for did_hash({}) but it is not active", payload.did_hash);
-                                        if let Err(err) = database.streaming_stop_live(&payload.did_hash, &uuid).await {
-                                            error!("Error stopping streaming for client ({}): {}", payload.did_hash, err);
+                                        let wanted = state.wants(payload.force_delivery, payload.msg_id.is_some());
+                                        if !wanted {
+                                            return true;
                                         }
+                                        // Send the message to the client
+                                        delivered = true;
+                                        Self::_send_to_client(client, &payload.did_hash, StreamingMessage::Deliver {
+                                            msg_id: payload.msg_id.clone(),
+                                            message: payload.message.clone(),
+                                        })
+                                    });
+                                    if connections.is_empty() {
+                                        clients.remove(&payload.did_hash);
+                                    }
+                                    if !delivered {
+                                        warn!("pub/sub msg received for did_hash({}) but no connection is active", payload.did_hash);
                                     }
                                 } else {
                                     warn!("pub/sub msg received for did_hash({}) but it doesn't exist in clients HashMap", payload.did_hash);
@@ -187,41 +297,66 @@ impl StreamingTask {
                     value = channel.recv() => {
                         if let Some(value) = value {
                             match value.state {
-                                StreamingUpdateState::Register(client_tx) => {
-                                    info!("Registered streaming for DID: ({}) registered_clients({})", value.did_hash, clients.len()+1);
-                                    clients.insert(value.did_hash.clone(), (client_tx, false));
+                                StreamingUpdateState::Register(client) => {
+                                    let conn_id = client.conn_id.clone();
+                                    let connections = clients.entry(value.did_hash.clone()).or_default();
+                                    connections.insert(conn_id.clone(), (client, ConnectionState::Connected));
+                                    info!("Registered streaming for DID: ({}) conn_id({}) connections({})", value.did_hash, conn_id, connections.len());
 
-                                    if let Err(err) = database.streaming_register_client(&value.did_hash, &uuid).await {
+                                    if let Err(err) = database.streaming_register_client(&value.did_hash, &conn_id, &uuid).await {
                                         error!("Error starting streaming to client ({}) streaming: {}",value.did_hash, err);
                                     }
                                 },
-                                StreamingUpdateState::Start => {
-                                    if let Some((_, active)) = clients.get_mut(&value.did_hash) {
-                                        info!("Starting streaming for DID: ({})", value.did_hash);
-                                        *active = true;
-                                    };
+                                StreamingUpdateState::Listen(client) => {
+                                    let conn_id = client.conn_id.clone();
+                                    let resync = client.resync.clone();
+                                    clients.entry(value.did_hash.clone()).or_default().insert(conn_id.clone(), (client, ConnectionState::Live));
+                                    debug!("Listening for DID: ({}) conn_id({})", value.did_hash, conn_id);
 
-                                    if let Err(err) = database.streaming_start_live(&value.did_hash, &uuid).await {
-                                        error!("Error starting streaming to client ({}) streaming: {}",value.did_hash, err);
+                                    if let Err(err) = database.streaming_register_client(&value.did_hash, &conn_id, &uuid).await {
+                                        error!("Error registering listener for client ({}): {}", value.did_hash, err);
+                                    } else if let Err(err) = database.streaming_set_live(&value.did_hash, &conn_id, &uuid, true).await {
+                                        error!("Error setting listener live for client ({}): {}", value.did_hash, err);
                                     }
+                                    resync.notify_one();
                                 },
-                                StreamingUpdateState::Stop => {
-                                    // Set active to false
-                                    if let Some((_, active)) = clients.get_mut(&value.did_hash) {
-                                        info!("Stopping streaming for DID: ({})", value.did_hash);
-                                        *active = false;
-                                    };
-
-                                    if let Err(err) = database.streaming_stop_live(&value.did_hash, &uuid).await {
-                                        error!("Error stopping streaming for client ({}): {}",value.did_hash, err);
+                                StreamingUpdateState::Wait(client) => {
+                                    let conn_id = client.conn_id.clone();
+                                    let resync = client.resync.clone();
+                                    clients.entry(value.did_hash.clone()).or_default().insert(conn_id.clone(), (client, ConnectionState::Waiting));
+                                    debug!("Waiting for stored messages for DID: ({}) conn_id({})", value.did_hash, conn_id);
+
+                                    if let Err(err) = database.streaming_register_waiter(&value.did_hash, &conn_id, &uuid).await {
+                                        error!("Error registering waiter for client ({}): {}", value.did_hash, err);
                                     }
+                                    resync.notify_one();
+                                },
+                                StreamingUpdateState::Start(session_id) => {
+                                    Self::_set_live(&database, &mut clients, &value.did_hash, &session_id, &uuid, true).await;
+                                },
+                                StreamingUpdateState::Stop(session_id) => {
+                                    Self::_set_live(&database, &mut clients, &value.did_hash, &session_id, &uuid, false).await;
                                 },
-                                StreamingUpdateState::Deregister => {
-                                    info!("Deregistering streaming for DID: ({}) registered_clients({})", value.did_hash, clients.len()-1);
-                                    if let Err(err) = database.streaming_deregister_client(&value.did_hash, &uuid).await {
+                                StreamingUpdateState::Deregister(conn_id) => {
+                                    if let Err(err) = database.streaming_deregister_client(&value.did_hash, &conn_id, &uuid).await {
                                         error!("Error stopping streaming for client ({}): {}",value.did_hash, err);
                                     }
-                                    clients.remove(value.did_hash.as_str());
+                                    // Other connections for the same DID are left as is
+                                    if let Some(connections) = clients.get_mut(&value.did_hash) {
+                                        connections.remove(&conn_id);
+                                        info!("Deregistered streaming for DID: ({}) conn_id({}) connections({})", value.did_hash, conn_id, connections.len());
+                                        if connections.is_empty() {
+                                            clients.remove(&value.did_hash);
+                                        }
+                                    }
+                                }
+                                StreamingUpdateState::Acknowledged(msg_ids) => {
+                                    if let Some(connections) = clients.get(&value.did_hash) {
+                                        for (client, _) in connections.values() {
+                                            // Best effort, connections forget the oldest unacknowledged messages
+                                            let _ = client.tx.try_send(StreamingMessage::Acknowledged(msg_ids.clone()));
+                                        }
+                                    }
                                 }
                             }
                         }
@@ -232,4 +367,114 @@ impl StreamingTask {
         .instrument(_span)
         .await
     }
+
+    /// Starts or stops live streaming for the connections of a session
+    async fn _set_live(
+        database: &DatabaseHandler,
+        clients: &mut HashMap<String, HashMap<String, (StreamingClient, ConnectionState)>>,
+        did_hash: &str,
+        session_id: &str,
+        uuid: &str,
+        live: bool,
+    ) {
+        let Some(connections) = clients.get_mut(did_hash) else {
+            warn!(
+                "live streaming change for did_hash({}) but it doesn't exist in clients HashMap",
+                did_hash
+            );
+            return;
+        };
+
+        for (conn_id, (client, state)) in connections.iter_mut() {
+            // Waiting requests of the same session are not streaming connections
+            if client.session_id != session_id || *state == ConnectionState::Waiting {
+                continue;
+            }
+            info!(
+                "Setting live streaming({}) for DID: ({}) conn_id({})",
+                live, did_hash, conn_id
+            );
+            *state = if live {
+                ConnectionState::Live
+            } else {
+                ConnectionState::Connected
+            };
+            if live {
+                // Deliver any messages that were stored while not live streaming
+                client.resync.notify_one();
+            }
+
+            if let Err(err) = database
+                .streaming_set_live(did_hash, conn_id, uuid, live)
+                .await
+            {
+                error!(
+                    "Error changing live streaming for client ({}): {}",
+                    did_hash, err
+                );
+            }
+        }
+    }
+
+    /// Sends a message to a client without blocking the streaming task
+    /// If the client channel is full, then the client is too slow to keep up:
+    /// - Stored messages remain in the database, and the client is told to resync once it has caught up
+    /// - Ephemeral messages can't be redelivered, so the slow client is disconnected
+    ///
+    /// Returns false if the client should be removed (disconnected or closed)
+    fn _send_to_client(
+        client: &StreamingClient,
+        did_hash: &str,
+        message: StreamingMessage,
+    ) -> bool {
+        match client.tx.try_send(message) {
+            Ok(_) => {
+                info!("Sent message to client ({})", did_hash);
+                true
+            }
+            Err(TrySendError::Full(StreamingMessage::Deliver {
+                msg_id: Some(msg_id),
+                ..
+            })) => {
+                warn!(
+                    "Client ({}) channel is full, msg_id({}) will be delivered on resync",
+                    did_hash, msg_id
+                );
+                client.resync.notify_one();
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                // Dropping the client closes its channel, which closes the connection
+                warn!(
+                    "Client ({}) conn_id({}) channel is full, disconnecting slow client",
+                    did_hash, client.conn_id
+                );
+                false
+            }
+            Err(TrySendError::Closed(_)) => {
+                // Stored messages are redelivered when the client reconnects
+                warn!("Client ({}) channel is closed", did_hash);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionState;
+
+    #[test]
+    fn waiting_connections_only_want_stored_messages() {
+        assert!(ConnectionState::Waiting.wants(false, true));
+        assert!(!ConnectionState::Waiting.wants(false, false));
+        assert!(!ConnectionState::Waiting.wants(true, false));
+    }
+
+    #[test]
+    fn connected_connections_only_want_forced_messages() {
+        assert!(ConnectionState::Connected.wants(true, false));
+        assert!(!ConnectionState::Connected.wants(false, true));
+        assert!(ConnectionState::Live.wants(false, false));
+    }
 }
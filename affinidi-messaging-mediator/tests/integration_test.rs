@@ -373,6 +373,7 @@ async fn test_mediator_server() {
             limit: 10,
             start_id: None,
             delete_policy: affinidi_messaging_sdk::messages::FetchDeletePolicy::DoNotDelete,
+            wait_seconds: None,
         },
     )
     .await;
@@ -87,6 +87,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
             limit: 10,
             start_id: None,
             delete_policy: FetchDeletePolicy::OnReceive,
+            wait_seconds: None,
         })
         .await?;
 
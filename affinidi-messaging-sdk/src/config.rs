@@ -1,8 +1,11 @@
 use crate::errors::ATMError;
 use affinidi_did_resolver_cache_sdk::DIDCacheClient;
 use affinidi_messaging_didcomm::secrets::Secret;
-use rustls::pki_types::CertificateDer;
-use std::{fs::File, io::BufReader};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::{
+    fs::{self, File},
+    io::BufReader,
+};
 use tracing::error;
 
 /// Configuration for the Affinidi Trusted Messaging (ATM) Service
@@ -17,11 +20,17 @@ use tracing::error;
 pub struct Config {
     pub my_did: Option<String>,
     pub(crate) ssl_certificates: Vec<CertificateDer<'static>>,
+    pub(crate) client_identity: Option<Vec<u8>>,
     pub(crate) atm_api: String,
     pub(crate) atm_api_ws: String,
+    pub(crate) atm_api_sse: String,
     pub(crate) atm_did: Option<String>,
     pub(crate) ssl_only: bool,
     pub(crate) ws_enabled: bool,
+    pub(crate) sse_enabled: bool,
+    pub(crate) ws_ping_interval: u64,
+    pub(crate) ws_idle_timeout: u64,
+    pub(crate) ws_binary_frames: bool,
     pub(crate) fetch_cache_limit_count: u32,
     pub(crate) fetch_cache_limit_bytes: u64,
     pub(crate) secrets: Vec<Secret>,
@@ -55,12 +64,18 @@ impl Config {
 /// ```
 pub struct ConfigBuilder {
     ssl_certificates: Vec<String>,
+    client_certificate: Option<(String, String)>,
     my_did: Option<String>,
     atm_api: Option<String>,
     atm_api_ws: Option<String>,
+    atm_api_sse: Option<String>,
     atm_did: Option<String>,
     ssl_only: bool,
     ws_enabled: bool,
+    sse_enabled: bool,
+    ws_ping_interval: u64,
+    ws_idle_timeout: u64,
+    ws_binary_frames: bool,
     fetch_cache_limit_count: u32,
     fetch_cache_limit_bytes: u64,
     secrets: Vec<Secret>,
@@ -71,12 +86,18 @@ impl Default for ConfigBuilder {
     fn default() -> Self {
         ConfigBuilder {
             ssl_certificates: vec![],
+            client_certificate: None,
             my_did: None,
             atm_api: None,
             atm_api_ws: None,
+            atm_api_sse: None,
             atm_did: None,
             ssl_only: true,
             ws_enabled: true,
+            sse_enabled: false,
+            ws_ping_interval: 30,
+            ws_idle_timeout: 90,
+            ws_binary_frames: false,
             fetch_cache_limit_count: 100,
             fetch_cache_limit_bytes: 1024 * 1024 * 10, // Defaults to 10MB Cache
             secrets: Vec::new(),
@@ -98,6 +119,14 @@ impl ConfigBuilder {
         self
     }
 
+    /// Add a client certificate for mutual TLS (mTLS), used when ATM requires client certificates
+    /// - certificate_file: file path to the PEM encoded client certificate (chain)
+    /// - key_file: file path to the PEM encoded private key of the client certificate
+    pub fn with_client_certificate(mut self, certificate_file: &str, key_file: &str) -> Self {
+        self.client_certificate = Some((certificate_file.to_owned(), key_file.to_owned()));
+        self
+    }
+
     /// Add the DID for the client itself to ATM
     pub fn with_my_did(mut self, my_did: &str) -> Self {
         self.my_did = Some(my_did.to_owned());
@@ -117,6 +146,13 @@ impl ConfigBuilder {
         self
     }
 
+    /// Add the URL for the ATM API Server-Sent Events endpoint
+    /// Defaults: ATM API URL with `/sse` appended
+    pub fn with_atm_sse_api(mut self, sse_api_url: &str) -> Self {
+        self.atm_api_sse = Some(sse_api_url.to_owned());
+        self
+    }
+
     /// Add the DID for the ATM service itself
     pub fn with_atm_did(mut self, atm_did: &str) -> Self {
         self.atm_did = Some(atm_did.to_owned());
@@ -139,6 +175,39 @@ impl ConfigBuilder {
         self
     }
 
+    /// Uses Server-Sent Events (SSE) instead of a WebSocket for live delivery of messages
+    /// Useful where proxies or serverless frontends can't hold a WebSocket open
+    /// Messages are sent via the REST API, and the WebSocket is disabled
+    /// Default: `false`
+    pub fn with_sse(mut self) -> Self {
+        self.sse_enabled = true;
+        self.ws_enabled = false;
+        self
+    }
+
+    /// Set the time in seconds between WebSocket pings sent to ATM, 0 disables pings
+    /// Default: 30
+    pub fn with_ws_ping_interval(mut self, seconds: u64) -> Self {
+        self.ws_ping_interval = seconds;
+        self
+    }
+
+    /// Set the time in seconds without any frame from ATM (including pongs) before the WebSocket is closed
+    /// Should be longer than the ping interval, 0 disables the idle timeout
+    /// Default: 90
+    pub fn with_ws_idle_timeout(mut self, seconds: u64) -> Self {
+        self.ws_idle_timeout = seconds;
+        self
+    }
+
+    /// Sends DIDComm messages as binary WebSocket frames (deflate compressed envelope) instead of text frames
+    /// ATM replies with binary frames once it has received one
+    /// Default: `false`
+    pub fn with_ws_binary_frames(mut self) -> Self {
+        self.ws_binary_frames = true;
+        self
+    }
+
     /// Add a secret to the SDK
     /// This is required to auto-start the websocket connection
     pub fn with_secret(mut self, secret: Secret) -> Self {
@@ -197,6 +266,35 @@ impl ConfigBuilder {
             ));
         }
 
+        // Client certificate and key are kept as a single PEM bundle
+        let client_identity = if let Some((certificate_file, key_file)) = &self.client_certificate
+        {
+            let mut identity = fs::read(certificate_file).map_err(|e| {
+                ATMError::SSLError(format!(
+                    "Couldn't read client certificate file ({})! Reason: {}",
+                    certificate_file, e
+                ))
+            })?;
+            let mut key = fs::read(key_file).map_err(|e| {
+                ATMError::SSLError(format!(
+                    "Couldn't read client key file ({})! Reason: {}",
+                    key_file, e
+                ))
+            })?;
+            identity.push(b'\n');
+            identity.append(&mut key);
+
+            if client_identity_parts(&identity).is_none() {
+                return Err(ATMError::SSLError(format!(
+                    "Couldn't parse client certificate ({}) and key ({})!",
+                    certificate_file, key_file
+                )));
+            }
+            Some(identity)
+        } else {
+            None
+        };
+
         let atm_api = if let Some(atm_url) = self.atm_api {
             atm_url
         } else {
@@ -217,14 +315,24 @@ impl ConfigBuilder {
             ));
         };
 
+        let atm_api_sse = self
+            .atm_api_sse
+            .unwrap_or_else(|| format!("{}/sse", atm_api));
+
         Ok(Config {
             ssl_certificates: certs,
+            client_identity,
             my_did: self.my_did,
             atm_api,
             atm_api_ws,
+            atm_api_sse,
             atm_did: self.atm_did,
             ssl_only: self.ssl_only,
             ws_enabled: self.ws_enabled,
+            sse_enabled: self.sse_enabled,
+            ws_ping_interval: self.ws_ping_interval,
+            ws_idle_timeout: self.ws_idle_timeout,
+            ws_binary_frames: self.ws_binary_frames,
             fetch_cache_limit_count: self.fetch_cache_limit_count,
             fetch_cache_limit_bytes: self.fetch_cache_limit_bytes,
             secrets: self.secrets,
@@ -232,3 +340,20 @@ impl ConfigBuilder {
         })
     }
 }
+
+/// Splits a PEM bundle into the client certificate chain and private key
+/// Returns None if the bundle doesn't contain at least one certificate and a private key
+pub(crate) fn client_identity_parts(
+    identity: &[u8],
+) -> Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(identity))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(identity)).ok()??;
+
+    if certs.is_empty() {
+        None
+    } else {
+        Some((certs, key))
+    }
+}
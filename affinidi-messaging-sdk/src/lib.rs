@@ -1,10 +1,10 @@
 use affinidi_did_resolver_cache_sdk::DIDCacheClient;
 use affinidi_messaging_didcomm::secrets::Secret;
 use affinidi_messaging_didcomm::{Attachment, AttachmentData, Message};
-use config::Config;
+use config::{client_identity_parts, Config};
 use errors::ATMError;
 use messages::AuthorizationResponse;
-use reqwest::{Certificate, Client};
+use reqwest::{Certificate, Client, Identity};
 use resolvers::secrets_resolver::AffinidiSecrets;
 use rkyv::bytecheck::CheckBytes;
 use rkyv::de::Pool;
@@ -48,6 +48,8 @@ pub struct ATM {
     ws_handler: Option<JoinHandle<()>>,
     ws_send_stream: Option<Sender<WSCommand>>,
     ws_recv_stream: Option<Receiver<WSCommand>>,
+    sse_handler: Option<JoinHandle<()>>,
+    sse_send_stream: Option<Sender<WSCommand>>,
 }
 
 /// Affinidi Trusted Messaging SDK
@@ -79,6 +81,12 @@ impl ATM {
             .https_only(config.ssl_only)
             .user_agent("Affinidi Trusted Messaging");
 
+        if let Some(identity) = &config.client_identity {
+            client = client.identity(Identity::from_pem(identity).map_err(|e| {
+                ATMError::SSLError(format!("Couldn't add client certificate. Reason: {}", e))
+            })?);
+        }
+
         for cert in config.get_ssl_certificates() {
             client = client.add_root_certificate(
                 Certificate::from_der(cert.to_vec().as_slice()).map_err(|e| {
@@ -120,9 +128,13 @@ impl ATM {
             }
         }
 
-        let ws_config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+        let ws_config = ClientConfig::builder().with_root_certificates(root_store);
+        let ws_config = match config.client_identity.as_deref().and_then(client_identity_parts) {
+            Some((certs, key)) => ws_config.with_client_auth_cert(certs, key).map_err(|e| {
+                ATMError::SSLError(format!("Couldn't add client certificate. Reason: {}", e))
+            })?,
+            None => ws_config.with_no_client_auth(),
+        };
 
         let ws_connector = Connector::Rustls(Arc::new(ws_config));
 
@@ -157,6 +169,8 @@ impl ATM {
             ws_handler: None,
             ws_send_stream: None,
             ws_recv_stream: None,
+            sse_handler: None,
+            sse_send_stream: None,
         };
 
         // Add our own DID to the DID_RESOLVER
@@ -175,6 +189,8 @@ impl ATM {
         // Start the websocket connection if enabled
         if atm.ws_enabled {
             atm.start_websocket_task().await?;
+        } else if atm.config.sse_enabled {
+            atm.start_sse_task().await?;
         }
         debug!("ATM SDK initialized");
 
@@ -0,0 +1,64 @@
+//! Deletion of all data the mediator holds for your DID
+//!
+//! `account_delete()` removes queued messages, outbox records, counters, sessions, streaming
+//! registrations, push endpoints and the forward consent of your DID.
+use tracing::{debug, span, Instrument, Level};
+
+use crate::{errors::ATMError, messages::SuccessResponse, ATM};
+
+use super::AccountDeletionReport;
+
+impl ATM {
+    /// Deletes everything the mediator holds for your DID
+    /// Messages you have sent stay with their recipients, only your outbox records are removed
+    ///
+    /// Returns a report of what was deleted
+    pub async fn account_delete(&mut self) -> Result<AccountDeletionReport, ATMError> {
+        let _span = span!(Level::DEBUG, "account_delete");
+
+        async move {
+            let tokens = self.authenticate().await?;
+
+            let res = self
+                .client
+                .delete(format!("{}/account", self.config.atm_api))
+                .header("Authorization", format!("Bearer {}", tokens.access_token))
+                .send()
+                .await
+                .map_err(|e| {
+                    ATMError::TransportError(format!(
+                        "Could not send account delete request: {:?}",
+                        e
+                    ))
+                })?;
+
+            let status = res.status();
+            debug!("API response: status({})", status);
+
+            let body = res
+                .text()
+                .await
+                .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+
+            if !status.is_success() {
+                return Err(ATMError::TransportError(format!(
+                    "Status not successful. status({}), response({})",
+                    status, body
+                )));
+            }
+
+            let body = serde_json::from_str::<SuccessResponse<AccountDeletionReport>>(&body)
+                .map_err(|e| {
+                    ATMError::TransportError(format!(
+                        "Couldn't parse account delete response: {:?}",
+                        e
+                    ))
+                })?;
+
+            body.data
+                .ok_or_else(|| ATMError::TransportError("No response data".to_string()))
+        }
+        .instrument(_span)
+        .await
+    }
+}
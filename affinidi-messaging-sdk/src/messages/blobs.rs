@@ -0,0 +1,392 @@
+//! Out-of-band storage of large attachments on the mediator
+//!
+//! `upload_attachment()` encrypts the content with a random key, uploads it to the mediator and returns a
+//! `Links` attachment to add to a message. The link carries the key in its fragment, so only recipients of
+//! the (encrypted) message can read the content. The attachment hash is a sha2-256 multihash of the
+//! encrypted blob, which `download_attachment()` verifies before decrypting.
+use affinidi_messaging_didcomm::{Attachment, AttachmentData};
+use askar_crypto::{
+    alg::aes::{A256Gcm, AesKey},
+    encrypt::{KeyAeadInPlace, KeyAeadMeta},
+    repr::{KeyGen, KeySecretBytes, ToSecretBytes},
+};
+use base64::prelude::*;
+use http::Method;
+use serde::Serialize;
+use sha256::digest;
+use tracing::{debug, span, warn, Instrument, Level};
+
+use crate::{errors::ATMError, messages::SuccessResponse, ATM};
+
+use super::{BlobBindRequest, BlobStatus, BlobUploadRequest};
+
+/// Number of passes over the missing chunks before an upload is abandoned
+const UPLOAD_ATTEMPTS: usize = 3;
+
+/// Multihash prefix of a sha2-256 digest (hex encoded)
+const SHA256_MULTIHASH_PREFIX: &str = "1220";
+
+impl ATM {
+    /// Starts an out-of-band blob upload
+    /// - size: Size of the blob in bytes
+    /// - recipients: DIDs that can download the blob (the uploader always can)
+    ///
+    /// Returns the status of the blob, upload the content in `chunk_size` chunks
+    pub async fn create_blob_upload(
+        &mut self,
+        size: u64,
+        recipients: &[String],
+    ) -> Result<BlobStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "create_blob_upload", size = size);
+
+        async move {
+            let request = BlobUploadRequest {
+                size,
+                recipients: recipients.to_vec(),
+            };
+            self._blob_request(Method::POST, "", Some(&request)).await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Uploads a chunk of a blob, chunks can be sent in any order and sent again
+    /// - index: Index of the chunk, starting at 0
+    /// - data: Content of the chunk, `chunk_size` bytes except for the last chunk
+    pub async fn upload_blob_chunk(
+        &mut self,
+        blob_id: &str,
+        index: u64,
+        data: Vec<u8>,
+    ) -> Result<BlobStatus, ATMError> {
+        let _span = span!(
+            Level::DEBUG,
+            "upload_blob_chunk",
+            blob_id = blob_id,
+            index = index
+        );
+
+        async move {
+            let tokens = self.authenticate().await?;
+
+            let res = self
+                .client
+                .put(format!(
+                    "{}/blobs/{}/chunks/{}",
+                    self.config.atm_api, blob_id, index
+                ))
+                .header("Content-Type", "application/octet-stream")
+                .header("Authorization", format!("Bearer {}", tokens.access_token))
+                .body(data)
+                .send()
+                .await
+                .map_err(|e| {
+                    ATMError::TransportError(format!("Could not send blob chunk: {:?}", e))
+                })?;
+
+            _blob_response(res).await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Returns the status of a blob you uploaded, including the chunks received
+    pub async fn blob_status(&mut self, blob_id: &str) -> Result<BlobStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "blob_status", blob_id = blob_id);
+
+        async move {
+            self._blob_request::<()>(Method::GET, &format!("/{}/status", blob_id), None)
+                .await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Completes a blob upload once all chunks have been uploaded
+    pub async fn complete_blob_upload(&mut self, blob_id: &str) -> Result<BlobStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "complete_blob_upload", blob_id = blob_id);
+
+        async move {
+            self._blob_request::<()>(Method::POST, &format!("/{}/complete", blob_id), None)
+                .await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Binds a blob to the messages that reference it
+    /// The recipients of the messages can download the blob, and the blob expires with the messages (deleting a message doesn't remove it)
+    /// - message_ids: ATM message IDs returned when sending the messages
+    pub async fn bind_blob(
+        &mut self,
+        blob_id: &str,
+        message_ids: &[String],
+    ) -> Result<BlobStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "bind_blob", blob_id = blob_id);
+
+        async move {
+            let request = BlobBindRequest {
+                message_ids: message_ids.to_vec(),
+            };
+            self._blob_request(Method::POST, &format!("/{}/bind", blob_id), Some(&request))
+                .await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Downloads a blob, available to the uploader and the recipients
+    /// Returns the blob as stored (encrypted if it was uploaded with `upload_attachment()`)
+    pub async fn download_blob(&mut self, blob_id: &str) -> Result<Vec<u8>, ATMError> {
+        let _span = span!(Level::DEBUG, "download_blob", blob_id = blob_id);
+
+        async move {
+            let url = format!("{}/blobs/{}", self.config.atm_api, blob_id);
+            self._blob_download(&url).await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Deletes a blob you uploaded
+    pub async fn delete_blob(&mut self, blob_id: &str) -> Result<BlobStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "delete_blob", blob_id = blob_id);
+
+        async move {
+            self._blob_request::<()>(Method::DELETE, &format!("/{}", blob_id), None)
+                .await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Encrypts and uploads a file, returns an attachment that links to it
+    /// Add the attachment to the message(s) you send, then `bind_blob()` the blob to the sent messages
+    /// so that their recipients can download it. The attachment ID is the blob ID.
+    /// - data: Content of the file
+    /// - recipients: DIDs that can download the file before it is bound to messages
+    pub async fn upload_attachment(
+        &mut self,
+        data: &[u8],
+        recipients: &[String],
+    ) -> Result<Attachment, ATMError> {
+        let _span = span!(Level::DEBUG, "upload_attachment", size = data.len());
+
+        async move {
+            // Encrypt the content with a random key, the blob is nonce || ciphertext
+            let key = AesKey::<A256Gcm>::random().map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't create attachment key: {:?}", e))
+            })?;
+            let nonce = AesKey::<A256Gcm>::random_nonce();
+            let mut buffer = data.to_vec();
+            key.encrypt_in_place(&mut buffer, &nonce, &[])
+                .map_err(|e| {
+                    ATMError::MsgSendError(format!("Couldn't encrypt attachment: {:?}", e))
+                })?;
+            let mut blob = nonce.to_vec();
+            blob.append(&mut buffer);
+
+            let hash = [SHA256_MULTIHASH_PREFIX, &digest(blob.as_slice())].concat();
+            let key = key.to_secret_bytes().map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't export attachment key: {:?}", e))
+            })?;
+
+            let mut status = self
+                .create_blob_upload(blob.len() as u64, recipients)
+                .await?;
+            let blob_id = status.blob_id.clone();
+            debug!("uploading blob({}) in ({}) chunks", blob_id, status.chunks);
+
+            // Upload the missing chunks, a failed upload is resumed from the chunks the mediator has received
+            for attempt in 1..=UPLOAD_ATTEMPTS {
+                for (index, chunk) in blob.chunks(status.chunk_size as usize).enumerate() {
+                    let index = index as u64;
+                    if status.received.contains(&index) {
+                        continue;
+                    }
+                    if let Err(err) = self
+                        .upload_blob_chunk(&blob_id, index, chunk.to_vec())
+                        .await
+                    {
+                        warn!(
+                            "blob({}) chunk({}) upload failed (attempt {}): {}",
+                            blob_id, index, attempt, err
+                        );
+                    }
+                }
+
+                status = self.blob_status(&blob_id).await?;
+                if status.received.len() as u64 == status.chunks {
+                    break;
+                }
+            }
+            if (status.received.len() as u64) < status.chunks {
+                return Err(ATMError::TransportError(format!(
+                    "blob({}) upload incomplete, received ({}) of ({}) chunks",
+                    blob_id,
+                    status.received.len(),
+                    status.chunks
+                )));
+            }
+            self.complete_blob_upload(&blob_id).await?;
+
+            let link = format!(
+                "{}/blobs/{}#{}",
+                self.config.atm_api,
+                blob_id,
+                BASE64_URL_SAFE_NO_PAD.encode(key.as_ref())
+            );
+            Ok(Attachment::links(vec![link], hash)
+                .id(blob_id)
+                .media_type("application/octet-stream".into())
+                .byte_count(data.len() as u64)
+                .finalize())
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Downloads the content of an attachment created by `upload_attachment()`
+    /// The hash of the downloaded blob is verified before it is decrypted
+    pub async fn download_attachment(
+        &mut self,
+        attachment: &Attachment,
+    ) -> Result<Vec<u8>, ATMError> {
+        let _span = span!(Level::DEBUG, "download_attachment", id = attachment.id);
+
+        async move {
+            let links = match &attachment.data {
+                AttachmentData::Links { value } => value,
+                _ => {
+                    return Err(ATMError::MsgReceiveError(
+                        "Attachment is not a links attachment".into(),
+                    ))
+                }
+            };
+
+            // Only send credentials to this mediator
+            let prefix = format!("{}/blobs/", self.config.atm_api);
+            let (url, key) = links
+                .links
+                .iter()
+                .filter(|link| link.starts_with(&prefix))
+                .find_map(|link| link.split_once('#'))
+                .ok_or_else(|| {
+                    ATMError::MsgReceiveError(
+                        "Attachment doesn't link to a blob on this mediator".into(),
+                    )
+                })?;
+
+            let mut blob = self._blob_download(url).await?;
+
+            let hash = [SHA256_MULTIHASH_PREFIX, &digest(blob.as_slice())].concat();
+            if !hash.eq_ignore_ascii_case(&links.hash) {
+                return Err(ATMError::MsgReceiveError(format!(
+                    "Attachment hash mismatch. Expected ({}), received ({})",
+                    links.hash, hash
+                )));
+            }
+
+            let key = BASE64_URL_SAFE_NO_PAD
+                .decode(key)
+                .ok()
+                .and_then(|key| AesKey::<A256Gcm>::from_secret_bytes(&key).ok())
+                .ok_or_else(|| ATMError::MsgReceiveError("Invalid attachment key".into()))?;
+            let nonce_length = key.aead_params().nonce_length;
+            if blob.len() < nonce_length {
+                return Err(ATMError::MsgReceiveError("Attachment is too short".into()));
+            }
+            let mut buffer = blob.split_off(nonce_length);
+            key.decrypt_in_place(&mut buffer, &blob, &[]).map_err(|e| {
+                ATMError::MsgReceiveError(format!("Couldn't decrypt attachment: {:?}", e))
+            })?;
+
+            Ok(buffer)
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Sends a blob API request
+    /// - path: Path after `/blobs`
+    async fn _blob_request<T: Serialize>(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<&T>,
+    ) -> Result<BlobStatus, ATMError> {
+        let tokens = self.authenticate().await?;
+
+        let mut request = self
+            .client
+            .request(method, format!("{}/blobs{}", self.config.atm_api, path))
+            .header("Authorization", format!("Bearer {}", tokens.access_token));
+        if let Some(body) = body {
+            let body = serde_json::to_string(body).map_err(|e| {
+                ATMError::TransportError(format!("Could not serialize blob request: {:?}", e))
+            })?;
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body);
+        }
+
+        let res = request.send().await.map_err(|e| {
+            ATMError::TransportError(format!("Could not send blob request: {:?}", e))
+        })?;
+
+        _blob_response(res).await
+    }
+
+    async fn _blob_download(&mut self, url: &str) -> Result<Vec<u8>, ATMError> {
+        let tokens = self.authenticate().await?;
+
+        let res = self
+            .client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", tokens.access_token))
+            .send()
+            .await
+            .map_err(|e| {
+                ATMError::TransportError(format!("Could not send blob download request: {:?}", e))
+            })?;
+
+        let status = res.status();
+        debug!("API response: status({})", status);
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            return Err(ATMError::TransportError(format!(
+                "Status not successful. status({}), response({})",
+                status, body
+            )));
+        }
+
+        let body = res
+            .bytes()
+            .await
+            .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+        Ok(body.to_vec())
+    }
+}
+
+async fn _blob_response(res: reqwest::Response) -> Result<BlobStatus, ATMError> {
+    let status = res.status();
+    debug!("API response: status({})", status);
+
+    let body = res
+        .text()
+        .await
+        .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+
+    if !status.is_success() {
+        return Err(ATMError::TransportError(format!(
+            "Status not successful. status({}), response({})",
+            status, body
+        )));
+    }
+
+    let body = serde_json::from_str::<SuccessResponse<BlobStatus>>(&body)
+        .map_err(|e| ATMError::TransportError(format!("Couldn't parse blob response: {:?}", e)))?;
+
+    body.data
+        .ok_or_else(|| ATMError::TransportError("No response data".to_string()))
+}
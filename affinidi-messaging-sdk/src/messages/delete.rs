@@ -1,4 +1,4 @@
-use tracing::{debug, span, Level};
+use tracing::{debug, span, Instrument, Level};
 
 use crate::{errors::ATMError, messages::SuccessResponse, ATM};
 
@@ -13,8 +13,15 @@ impl ATM {
         &mut self,
         messages: &DeleteMessageRequest,
     ) -> Result<DeleteMessageResponse, ATMError> {
-        let _span = span!(Level::DEBUG, "delete_messages").entered();
+        // The span is not held across awaits, so that the future can be sent between threads
+        let _span = span!(Level::DEBUG, "delete_messages");
+        self._delete_messages(messages).instrument(_span).await
+    }
 
+    async fn _delete_messages(
+        &mut self,
+        messages: &DeleteMessageRequest,
+    ) -> Result<DeleteMessageResponse, ATMError> {
         // Check if authenticated
         let tokens = self.authenticate().await?;
         if messages.message_ids.len() > MAX_DELETED_MESSAGES {
@@ -59,9 +66,10 @@ impl ATM {
             )));
         }
 
-        let body = serde_json::from_str::<SuccessResponse<DeleteMessageResponse>>(&body)
-            .ok()
-            .unwrap();
+        let body =
+            serde_json::from_str::<SuccessResponse<DeleteMessageResponse>>(&body).map_err(|e| {
+                ATMError::TransportError(format!("Couldn't parse delete response: {:?}", e))
+            })?;
 
         let list = if let Some(list) = body.data {
             list
@@ -18,6 +18,10 @@ pub struct FetchOptions {
     pub start_id: Option<String>,
     /// Delete policy for messages after fetching. Default: DoNotDelete
     pub delete_policy: FetchDeletePolicy,
+    /// If no messages are available, wait up to this many seconds for messages to arrive (long-polling). Default: None
+    /// The mediator may cap the wait time, and returns straight away if it has streaming disabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_seconds: Option<u64>,
 }
 
 impl Default for FetchOptions {
@@ -26,6 +30,7 @@ impl Default for FetchOptions {
             limit: 10,
             start_id: None,
             delete_policy: FetchDeletePolicy::DoNotDelete,
+            wait_seconds: None,
         }
     }
 }
@@ -39,6 +44,7 @@ impl ATM {
     /// * `limit`         - The maximum number of messages to fetch (default: 10, minimum: 1, maximum: 100)
     /// * `start_id`      - The message_id to start fetching from (default: Starts with oldest message)
     /// * `delete_policy` - Delete policy for messages after fetching (default: DoNotDelete)
+    /// * `wait_seconds`  - If no messages are available, wait up to this many seconds for messages to arrive (default: None)
     ///
     /// Calling fetch with no start_id and default delete_policy will result in the same messages being retrieved again and again
     ///
@@ -59,7 +65,8 @@ impl ATM {
             "fetch_messages",
             limit = options.limit,
             start_id = options.start_id,
-            delete_policy = options.delete_policy.to_string()
+            delete_policy = options.delete_policy.to_string(),
+            wait_seconds = options.wait_seconds
         )
         .entered();
 
@@ -0,0 +1,78 @@
+//! Consent to receive anonymous forward messages
+//!
+//! Other agents can deliver DIDComm `forward` messages to ATM without an ATM account (`/forward`).
+//! A DID only receives these messages after it has given consent with `set_forward_acl()`.
+use http::Method;
+use tracing::{debug, span, Instrument, Level};
+
+use crate::{errors::ATMError, messages::SuccessResponse, ATM};
+
+use super::ForwardAcl;
+
+impl ATM {
+    /// Returns who can deliver anonymous forward messages to your DID
+    pub async fn forward_acl(&mut self) -> Result<ForwardAcl, ATMError> {
+        let _span = span!(Level::DEBUG, "forward_acl");
+
+        async move { self._forward_acl_request(Method::GET, None).await }
+            .instrument(_span)
+            .await
+    }
+
+    /// Sets who can deliver anonymous forward messages to your DID
+    /// - acl: Consent and the allowed senders, `ForwardConsent::None` removes the consent
+    pub async fn set_forward_acl(&mut self, acl: &ForwardAcl) -> Result<ForwardAcl, ATMError> {
+        let _span = span!(Level::DEBUG, "set_forward_acl");
+
+        async move { self._forward_acl_request(Method::PUT, Some(acl)).await }
+            .instrument(_span)
+            .await
+    }
+
+    async fn _forward_acl_request(
+        &mut self,
+        method: Method,
+        acl: Option<&ForwardAcl>,
+    ) -> Result<ForwardAcl, ATMError> {
+        let tokens = self.authenticate().await?;
+
+        let mut request = self
+            .client
+            .request(method, format!("{}/forward/acl", self.config.atm_api))
+            .header("Authorization", format!("Bearer {}", tokens.access_token));
+        if let Some(acl) = acl {
+            let body = serde_json::to_string(acl).map_err(|e| {
+                ATMError::TransportError(format!("Could not serialize forward ACL: {:?}", e))
+            })?;
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body);
+        }
+
+        let res = request.send().await.map_err(|e| {
+            ATMError::TransportError(format!("Could not send forward ACL request: {:?}", e))
+        })?;
+
+        let status = res.status();
+        debug!("API response: status({})", status);
+
+        let body = res
+            .text()
+            .await
+            .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+
+        if !status.is_success() {
+            return Err(ATMError::TransportError(format!(
+                "Status not successful. status({}), response({})",
+                status, body
+            )));
+        }
+
+        let body = serde_json::from_str::<SuccessResponse<ForwardAcl>>(&body).map_err(|e| {
+            ATMError::TransportError(format!("Couldn't parse forward ACL response: {:?}", e))
+        })?;
+
+        body.data
+            .ok_or_else(|| ATMError::TransportError("No response data".to_string()))
+    }
+}
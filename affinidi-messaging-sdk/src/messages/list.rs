@@ -1,13 +1,80 @@
-use super::{Folder, MessageList};
+use super::{Folder, ListMessagesResponse, MessageList};
 use crate::{errors::ATMError, messages::SuccessResponse, ATM};
+use serde::{Deserialize, Serialize};
 use sha256::digest;
 use tracing::{debug, span, Level};
 
+/// list_messages_pages() and list_messages_page() options
+/// All filters are optional and can be combined
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ListOptions {
+    /// Continue listing after this cursor (`next_cursor` from a previous page). Default: None. Starts with oldest message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    /// The maximum number of messages to return per page. Default: None (mediator maximum)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    /// Only messages stored at or after this time (milliseconds since epoch)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<u64>,
+    /// Only messages stored at or before this time (milliseconds since epoch)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<u64>,
+    /// Only messages sent from this DID (Inbox only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_did: Option<String>,
+    /// Only messages of at least this size in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<u64>,
+    /// Only messages of at most this size in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Only messages that the recipient hasn't fetched yet, these can still be recalled (Outbox only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unfetched: Option<bool>,
+}
+
+/// Paginating iterator over a message folder, created by `ATM::list_messages_pages()`
+/// Each call to `next_page()` retrieves the next page of messages from ATM
+pub struct MessageListPages {
+    did: String,
+    folder: Folder,
+    options: ListOptions,
+    finished: bool,
+}
+
+impl MessageListPages {
+    /// Retrieves the next page of messages
+    /// Returns None when there are no more messages
+    pub async fn next_page(&mut self, atm: &mut ATM) -> Result<Option<MessageList>, ATMError> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let page = atm
+            .list_messages_page(&self.did, self.folder.clone(), &self.options)
+            .await?;
+
+        match page.next_cursor {
+            Some(cursor) => self.options.cursor = Some(cursor),
+            None => self.finished = true,
+        }
+
+        if page.messages.is_empty() && self.finished {
+            Ok(None)
+        } else {
+            Ok(Some(page.messages))
+        }
+    }
+}
+
 impl ATM {
     /// Returns a list of messages that are stored in the ATM
     /// # Parameters
     /// - `did`: The DID to list messages for
     /// - `folder`: The folder to list messages from
+    ///
+    /// Returns up to the mediator maximum, use `list_messages_pages()` to page through all messages
     pub async fn list_messages(
         &mut self,
         did: &str,
@@ -64,4 +131,100 @@ impl ATM {
 
         Ok(list)
     }
+
+    /// Returns a paginating iterator over the messages that are stored in the ATM
+    /// # Parameters
+    /// - `did`: The DID to list messages for
+    /// - `folder`: The folder to list messages from
+    /// - `options`: Filters and page size, see `ListOptions`
+    ///
+    /// # Example
+    /// ```ignore
+    /// let mut pages = atm.list_messages_pages(&my_did, Folder::Inbox, ListOptions::default());
+    /// while let Some(messages) = pages.next_page(&mut atm).await? {
+    ///     // Process the page of messages
+    /// }
+    /// ```
+    pub fn list_messages_pages(
+        &self,
+        did: &str,
+        folder: Folder,
+        options: ListOptions,
+    ) -> MessageListPages {
+        MessageListPages {
+            did: did.to_string(),
+            folder,
+            options,
+            finished: false,
+        }
+    }
+
+    /// Returns a single page of messages that are stored in the ATM
+    /// # Parameters
+    /// - `did`: The DID to list messages for
+    /// - `folder`: The folder to list messages from
+    /// - `options`: Filters, page size and cursor, see `ListOptions`
+    pub async fn list_messages_page(
+        &mut self,
+        did: &str,
+        folder: Folder,
+        options: &ListOptions,
+    ) -> Result<ListMessagesResponse, ATMError> {
+        let _span = span!(
+            Level::DEBUG,
+            "list_messages_page",
+            folder = folder.to_string()
+        )
+        .entered();
+        debug!("listing folder({}) for DID({})", did, folder);
+
+        // Check if authenticated
+        let tokens = self.authenticate().await?;
+
+        let res = self
+            .client
+            .get(format!(
+                "{}/list/{}/{}/page",
+                self.config.atm_api,
+                digest(did),
+                folder,
+            ))
+            .query(options)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", tokens.access_token))
+            .send()
+            .await
+            .map_err(|e| {
+                ATMError::TransportError(format!("Could not send list_messages request: {:?}", e))
+            })?;
+
+        let status = res.status();
+        debug!("API response: status({})", status);
+
+        let body = res
+            .text()
+            .await
+            .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+
+        if !status.is_success() {
+            return Err(ATMError::TransportError(format!(
+                "Status not successful. status({}), response({})",
+                status, body
+            )));
+        }
+
+        let body = serde_json::from_str::<SuccessResponse<ListMessagesResponse>>(&body)
+            .ok()
+            .unwrap();
+
+        let list = if let Some(list) = body.data {
+            list
+        } else {
+            return Err(ATMError::TransportError("No messages found".to_string()));
+        };
+
+        debug!("List contains ({}) messages", list.messages.len());
+
+        Ok(list)
+    }
 }
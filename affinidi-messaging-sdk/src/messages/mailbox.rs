@@ -0,0 +1,137 @@
+//! Export and import of the mailbox of a DID
+//!
+//! `mailbox_export()` returns the inbox and outbox of your DID as archive pages signed by the mediator.
+//! `mailbox_import()` recreates each page on another mediator (or Redis cluster), existing messages and
+//! outbox records are skipped.
+//! The importing mediator only accepts archives signed by itself or by a mediator it trusts.
+use http::Method;
+use tracing::{debug, span, Instrument, Level};
+
+use crate::{errors::ATMError, messages::SuccessResponse, ATM};
+
+use super::{
+    GenericDataStruct, MailboxArchive, MailboxImportReport, SignedMailboxArchive,
+    MAILBOX_ARCHIVE_MESSAGE_TYPE,
+};
+
+impl ATM {
+    /// Exports a page of the mailbox (inbox, then outbox) of your DID from the mediator
+    /// - cursor: None for the first page, then the `cursor` of the previous page
+    ///
+    /// Returns the page as a DIDComm message signed by the mediator, `cursor` is None on the last page
+    pub async fn mailbox_export(
+        &mut self,
+        cursor: Option<&str>,
+    ) -> Result<SignedMailboxArchive, ATMError> {
+        let _span = span!(Level::DEBUG, "mailbox_export");
+
+        async move {
+            let path = match cursor {
+                Some(cursor) => format!("export?cursor={}", cursor),
+                None => "export".to_string(),
+            };
+            self._mailbox_request::<_, SignedMailboxArchive>(Method::GET, &path, None)
+                .await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Imports a signed mailbox archive (page) into the mailbox of your DID
+    /// - archive: Archive returned by `mailbox_export()`, must be an archive of your DID
+    ///
+    /// Returns what was imported, messages that already exist are skipped
+    pub async fn mailbox_import(&mut self, archive: &str) -> Result<MailboxImportReport, ATMError> {
+        let _span = span!(Level::DEBUG, "mailbox_import");
+
+        async move {
+            let body = SignedMailboxArchive {
+                archive: archive.to_string(),
+                cursor: None,
+            };
+            self._mailbox_request(Method::POST, "import", Some(&body))
+                .await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Verifies the signature of a mailbox archive and returns its content
+    /// - archive: Archive returned by `mailbox_export()`
+    pub async fn mailbox_archive(&mut self, archive: &str) -> Result<MailboxArchive, ATMError> {
+        let (message, metadata) = self.unpack(archive).await?;
+
+        if message.type_ != MAILBOX_ARCHIVE_MESSAGE_TYPE {
+            return Err(ATMError::MsgReceiveError(format!(
+                "Expected a mailbox archive, received ({})",
+                message.type_
+            )));
+        }
+
+        let signer = metadata
+            .sign_from
+            .as_deref()
+            .map(|kid| kid.split('#').next().unwrap_or(kid));
+        if signer.is_none() || signer != message.from.as_deref() {
+            return Err(ATMError::MsgReceiveError(
+                "Mailbox archive isn't signed by the mediator that exported it".into(),
+            ));
+        }
+
+        serde_json::from_value(message.body).map_err(|err| {
+            ATMError::MsgReceiveError(format!("Mailbox archive isn't valid. Reason: {}", err))
+        })
+    }
+
+    async fn _mailbox_request<T, B>(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<T, ATMError>
+    where
+        T: GenericDataStruct,
+        B: GenericDataStruct,
+    {
+        let tokens = self.authenticate().await?;
+
+        let mut request = self
+            .client
+            .request(method, format!("{}/mailbox/{}", self.config.atm_api, path))
+            .header("Authorization", format!("Bearer {}", tokens.access_token));
+        if let Some(body) = body {
+            let body = serde_json::to_string(body).map_err(|e| {
+                ATMError::TransportError(format!("Could not serialize mailbox request: {:?}", e))
+            })?;
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body);
+        }
+
+        let res = request.send().await.map_err(|e| {
+            ATMError::TransportError(format!("Could not send mailbox request: {:?}", e))
+        })?;
+
+        let status = res.status();
+        debug!("API response: status({})", status);
+
+        let body = res
+            .text()
+            .await
+            .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+
+        if !status.is_success() {
+            return Err(ATMError::TransportError(format!(
+                "Status not successful. status({}), response({})",
+                status, body
+            )));
+        }
+
+        let body = serde_json::from_str::<SuccessResponse<T>>(&body).map_err(|e| {
+            ATMError::TransportError(format!("Couldn't parse mailbox response: {:?}", e))
+        })?;
+
+        body.data
+            .ok_or_else(|| ATMError::TransportError("No response data".to_string()))
+    }
+}
@@ -1,12 +1,18 @@
 use std::fmt::Display;
 
+use affinidi_messaging_didcomm::{Attachment, Message};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+pub mod account;
+pub mod blobs;
 pub mod delete;
 pub mod fetch;
+pub mod forward;
 pub mod get;
 pub mod list;
+pub mod mailbox;
 pub mod pack;
+pub mod recall;
 pub mod sending;
 pub mod unpack;
 
@@ -53,8 +59,217 @@ pub struct DeleteMessageRequest {
 }
 impl GenericDataStruct for DeleteMessageRequest {}
 
+/// Response from recall_messages
+/// - recalled: Contains list of message_id's that were recalled before the recipient fetched them
+/// - too_late: Contains a list of message_id's and the reason they could no longer be recalled
+/// - errors: Contains a list of message_id's and error messages for failed recalls
+#[derive(Default, Serialize, Deserialize)]
+pub struct RecallMessageResponse {
+    pub recalled: Vec<String>,
+    pub too_late: Vec<(String, String)>,
+    pub errors: Vec<(String, String)>,
+}
+impl GenericDataStruct for RecallMessageResponse {}
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RecallMessageRequest {
+    pub message_ids: Vec<String>,
+}
+impl GenericDataStruct for RecallMessageRequest {}
+
+/// Request to start an out-of-band blob upload
+/// - size: Total size of the blob in bytes (the encrypted content)
+/// - recipients: DIDs that can download the blob, the uploader always can
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BlobUploadRequest {
+    pub size: u64,
+    pub recipients: Vec<String>,
+}
+impl GenericDataStruct for BlobUploadRequest {}
+
+/// Status of an out-of-band blob
+/// - chunk_size: Size of each upload chunk in bytes, the last chunk holds the remainder
+/// - chunks: Number of chunks the blob is uploaded in
+/// - received: Chunk indexes that have been received, missing chunks can be (re)sent to resume an upload
+/// - complete: The upload has been completed and the blob can be downloaded
+/// - expires_at: When the blob is removed (seconds since epoch)
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BlobStatus {
+    pub blob_id: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunks: u64,
+    pub received: Vec<u64>,
+    pub complete: bool,
+    pub expires_at: u64,
+}
+impl GenericDataStruct for BlobStatus {}
+
+/// Binds a blob to the messages that reference it
+/// The recipients of the messages can download the blob, and the blob expires with the messages (deleting a message doesn't remove it)
+/// - message_ids: ATM message IDs of the sent messages
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BlobBindRequest {
+    pub message_ids: Vec<String>,
+}
+impl GenericDataStruct for BlobBindRequest {}
+
+/// Request to host an Out-of-Band 2.0 invitation on the mediator, the invitation is from the authenticated DID
+/// - goal_code: Machine readable goal of the invitation (e.g. `connect`)
+/// - goal: Human readable goal of the invitation
+/// - accept: Media types (profiles) the inviter accepts, in order of preference
+/// - attachments: Messages to attach to the invitation (e.g. a request to start with)
+/// - expiry: Seconds until the invitation expires, None uses the maximum allowed by the mediator
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct OOBInvitationRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub goal_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub goal: Option<String>,
+    #[serde(default)]
+    pub accept: Vec<String>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<u64>,
+}
+impl GenericDataStruct for OOBInvitationRequest {}
+
+/// Out-of-Band invitation hosted by the mediator
+/// - oobid: Short ID of the invitation, served at `<atm_api>/oob?_oobid=<oobid>`
+/// - invitation: The invitation message
+/// - expires_at: When the invitation is removed (seconds since epoch)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OOBInvitationResponse {
+    pub oobid: String,
+    pub invitation: Message,
+    pub expires_at: u64,
+}
+impl GenericDataStruct for OOBInvitationResponse {}
+
+/// Who can deliver anonymous forward messages (`/forward`) to a DID
+/// - None: Anonymous forward messages are rejected (default)
+/// - All: Any sender can deliver, including anonymous senders
+/// - Senders: Only the listed senders, the forward message to the mediator must be authcrypted or signed by one of them
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardConsent {
+    #[default]
+    None,
+    All,
+    Senders,
+}
+
+/// Consent of a DID to receive anonymous forward messages
+/// - consent: Who can deliver anonymous forward messages
+/// - senders: DIDs that can deliver when consent is `Senders`
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ForwardAcl {
+    pub consent: ForwardConsent,
+    #[serde(default)]
+    pub senders: Vec<String>,
+}
+impl GenericDataStruct for ForwardAcl {}
+
+/// DIDComm message type of a mailbox archive, signed by the mediator that exported it
+pub const MAILBOX_ARCHIVE_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/mailbox/archive";
+
+/// Inbox message of a mailbox archive
+/// - msg_id       : The unique identifier of the message (kept on import)
+/// - timestamp    : The date the message was stored (milliseconds since epoch)
+/// - from_address : Address the message was sent from (if applicable)
+/// - msg          : The message itself
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ArchivedMessage {
+    pub msg_id: String,
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_address: Option<String>,
+    pub msg: String,
+}
+
+/// Outbox record of a mailbox archive, the message itself is held in the recipient's inbox
+/// - msg_id     : The unique identifier of the message
+/// - timestamp  : The date the message was sent (milliseconds since epoch)
+/// - to_address : Address the message was sent to
+/// - size       : The size of the message in bytes
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ArchivedSentMessage {
+    pub msg_id: String,
+    pub timestamp: u64,
+    pub to_address: String,
+    pub size: u64,
+}
+
+/// Portable archive of the mailbox of a DID, the body of a signed mailbox archive message
+/// An export is split into pages, each page is a separate archive (the inbox is exported before the outbox)
+/// - did          : DID that owns the mailbox
+/// - mediator_did : DID of the mediator that exported (and signed) the archive
+/// - created      : Time the archive was created (seconds since epoch)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MailboxArchive {
+    pub did: String,
+    pub mediator_did: String,
+    pub created: u64,
+    pub inbox: Vec<ArchivedMessage>,
+    #[serde(default)]
+    pub outbox: Vec<ArchivedSentMessage>,
+}
+
+/// Signed mailbox archive (page), returned by an export and sent to import it
+/// - archive : The signed archive
+/// - cursor  : Pass to the next export to get the next page, None on the last page (not used by an import)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SignedMailboxArchive {
+    pub archive: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+impl GenericDataStruct for SignedMailboxArchive {}
+
+/// Result of a mailbox import
+/// - imported    : Inbox messages that were queued
+/// - sent        : Outbox records that were recreated
+/// - duplicates  : Messages and records that already existed and were skipped
+/// - errors      : Messages that couldn't be imported (msg_id, error)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MailboxImportReport {
+    pub imported: usize,
+    pub sent: usize,
+    pub duplicates: usize,
+    pub errors: Vec<(String, String)>,
+}
+impl GenericDataStruct for MailboxImportReport {}
+
+/// Result of deleting all data the mediator holds for a DID
+/// - did                   : DID that was deleted
+/// - messages              : Inbox messages that were deleted
+/// - bytes                 : Size of the deleted inbox messages in bytes
+/// - sent                  : Outbox records that were deleted (the messages stay with their recipients)
+/// - sessions              : Authentication sessions that were removed
+/// - streaming_connections : Live streaming registrations that were removed
+/// - push_registrations    : Push endpoints that were removed
+/// - forward_acl           : True if a forward consent was removed
+/// - blobs                 : Uploaded blobs that were removed (storage is freed by the blob expiry task)
+/// - oob_invitations       : Hosted Out-of-Band invitations that were removed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AccountDeletionReport {
+    pub did: String,
+    pub messages: usize,
+    pub bytes: u64,
+    pub sent: usize,
+    pub sessions: usize,
+    pub streaming_connections: usize,
+    pub push_registrations: usize,
+    pub forward_acl: bool,
+    pub blobs: usize,
+    pub oob_invitations: usize,
+}
+impl GenericDataStruct for AccountDeletionReport {}
+
 /// A list of messages that are stored in the ATM for a given DID
-/// - msg_id        : The unique identifier of the message
+/// - msg_id        : The unique identifier of the message, unique per recipient copy
+/// - content_hash  : sha256 hash of the message content (the same message sent to multiple recipients shares this)
 /// - send_id       : The unique identifier of the element in the senders stream
 /// - receive_id    : The unique identifier of the element in the senders stream
 /// - size          : The size of the message in bytes
@@ -67,6 +282,8 @@ impl GenericDataStruct for DeleteMessageRequest {}
 pub struct MessageListElement {
     pub msg_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub send_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub receive_id: Option<String>,
@@ -84,6 +301,17 @@ impl GenericDataStruct for MessageListElement {}
 pub type MessageList = Vec<MessageListElement>;
 impl GenericDataStruct for MessageList {}
 
+/// A page of messages returned from list_messages
+/// - messages      : The messages in this page
+/// - next_cursor   : Cursor to retrieve the next page, None if there are no more messages
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ListMessagesResponse {
+    pub messages: MessageList,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+impl GenericDataStruct for ListMessagesResponse {}
+
 /// enum of ATM folder types
 /// inbox = messages inbound to the caller
 /// outbox = messages outbound to the caller
@@ -0,0 +1,89 @@
+use tracing::{debug, span, Level};
+
+use crate::{errors::ATMError, messages::SuccessResponse, ATM};
+
+use super::{RecallMessageRequest, RecallMessageResponse};
+
+const MAX_RECALLED_MESSAGES: usize = 100;
+
+impl ATM {
+    /// Recall messages that you have sent, before the recipient has fetched them
+    /// Use `list_messages_pages()` on the Outbox with `ListOptions.unfetched` to find messages that can be recalled
+    /// - messages: List of message_ids to recall
+    pub async fn recall_messages(
+        &mut self,
+        messages: &RecallMessageRequest,
+    ) -> Result<RecallMessageResponse, ATMError> {
+        let _span = span!(Level::DEBUG, "recall_messages").entered();
+
+        // Check if authenticated
+        let tokens = self.authenticate().await?;
+        if messages.message_ids.len() > MAX_RECALLED_MESSAGES {
+            return Err(ATMError::MsgSendError(format!(
+                "Operation exceeds the allowed limit. You may recall a maximum of 100 messages per request. Received {} ids.",
+                messages.message_ids.len()
+            )));
+        }
+        let msg = serde_json::to_string(messages).map_err(|e| {
+            ATMError::TransportError(format!(
+                "Could not serialize recall message request: {:?}",
+                e
+            ))
+        })?;
+
+        debug!("Sending recall_messages request: {:?}", msg);
+
+        let res = self
+            .client
+            .post(format!("{}/outbox/recall", self.config.atm_api))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", tokens.access_token))
+            .body(msg)
+            .send()
+            .await
+            .map_err(|e| {
+                ATMError::TransportError(format!("Could not send recall_messages request: {:?}", e))
+            })?;
+
+        let status = res.status();
+        debug!("API response: status({})", status);
+
+        let body = res
+            .text()
+            .await
+            .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+
+        if !status.is_success() {
+            return Err(ATMError::TransportError(format!(
+                "Status not successful. status({}), response({})",
+                status, body
+            )));
+        }
+
+        let body =
+            serde_json::from_str::<SuccessResponse<RecallMessageResponse>>(&body).map_err(|e| {
+                ATMError::TransportError(format!("Couldn't parse recall response: {:?}", e))
+            })?;
+
+        let list = if let Some(list) = body.data {
+            list
+        } else {
+            return Err(ATMError::TransportError("No response data".to_string()));
+        };
+
+        debug!(
+            "response: recalled({}) messages, too_late({}) messages, failed({}) messages",
+            list.recalled.len(),
+            list.too_late.len(),
+            list.errors.len()
+        );
+        for (msg, reason) in &list.too_late {
+            debug!("too late: msg({}) reason({})", msg, reason);
+        }
+        for (msg, err) in &list.errors {
+            debug!("failed: msg({}) error({})", msg, err);
+        }
+
+        Ok(list)
+    }
+}
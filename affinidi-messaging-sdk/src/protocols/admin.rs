@@ -0,0 +1,493 @@
+use std::time::{Duration, SystemTime};
+
+use affinidi_messaging_didcomm::{Message, PackEncryptedOptions};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, span, Instrument, Level};
+use uuid::Uuid;
+
+use crate::{
+    errors::ATMError,
+    messages::{sending::InboundMessageResponse, AccountDeletionReport, EmptyResponse},
+    protocols::message_pickup::MessagePickup,
+    transports::SendMessageResponse,
+    ATM,
+};
+
+/// DIDComm message type to assign a mailbox tier to a DID (admin DIDs only)
+pub const ADMIN_TIER_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/tier";
+
+/// DIDComm message type of the mediator reply, contains the tier of the DID
+pub const ADMIN_TIER_STATUS_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/tier-status";
+
+/// DIDComm message type to delete all data the mediator holds for a DID (admin DIDs only)
+pub const ADMIN_ACCOUNT_DELETE_MESSAGE_TYPE: &str =
+    "https://affinidi.com/atm/1.0/admin/account-delete";
+
+/// DIDComm message type of the mediator reply, contains the deletion report
+pub const ADMIN_ACCOUNT_DELETE_REPORT_MESSAGE_TYPE: &str =
+    "https://affinidi.com/atm/1.0/admin/account-delete-report";
+
+/// DIDComm message type to list the accounts of the mediator (admin DIDs only)
+pub const ADMIN_ACCOUNTS_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/accounts";
+
+/// DIDComm message type of the mediator reply, contains a page of accounts
+pub const ADMIN_ACCOUNT_LIST_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/account-list";
+
+/// DIDComm message type to get the mailbox statistics of a DID (admin DIDs only)
+pub const ADMIN_STATS_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/stats";
+
+/// DIDComm message type of the mediator reply, contains the mailbox statistics of the DID
+pub const ADMIN_STATS_REPORT_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/stats-report";
+
+/// DIDComm message type to block or unblock a DID (admin DIDs only)
+pub const ADMIN_BLOCK_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/block";
+
+/// DIDComm message type of the mediator reply, contains the block status of the DID
+pub const ADMIN_BLOCK_STATUS_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/block-status";
+
+/// DIDComm message type to force the expiry of messages queued for a DID (admin DIDs only)
+pub const ADMIN_EXPIRE_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/admin/expire";
+
+/// DIDComm message type of the mediator reply, contains the number of expired messages
+pub const ADMIN_EXPIRE_REPORT_MESSAGE_TYPE: &str =
+    "https://affinidi.com/atm/1.0/admin/expire-report";
+
+#[derive(Default)]
+pub struct Admin {}
+
+/// Mailbox policy of a DID, configured on the mediator as a named tier
+/// - name                   : Name of the tier, `default` is built from the global limits
+/// - max_queued_messages    : Maximum number of messages queued for (or sent by) the DID (0 = unlimited)
+/// - max_queued_bytes       : Maximum bytes queued for the DID (0 = unlimited)
+/// - message_expiry_minutes : Retention of queued messages in minutes
+/// - max_message_size       : Maximum size of a message to the DID in bytes (0 = unlimited)
+/// - message_rate_limit     : Maximum messages queued for the DID per minute (0 = unlimited)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MailboxTier {
+    pub name: String,
+    pub max_queued_messages: u32,
+    pub max_queued_bytes: u64,
+    pub message_expiry_minutes: u32,
+    pub max_message_size: u32,
+    pub message_rate_limit: u64,
+}
+
+/// Body of an admin tier message
+/// - did  : DID to assign the tier to
+/// - tier : Name of the tier, None resets the DID to the default tier
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminTier {
+    pub did: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tier: Option<String>,
+}
+
+/// Mailbox statistics of a DID
+/// - did                   : DID the statistics are for
+/// - did_hash              : Hash of the DID within the tenant, the DID's records are keyed by it
+/// - receive_queue_count   : Messages queued for the DID
+/// - receive_queue_bytes   : Bytes queued for the DID
+/// - send_queue_count      : Messages sent by the DID that haven't been removed by the recipient
+/// - send_queue_bytes      : Bytes sent by the DID that haven't been removed by the recipient
+/// - tier                  : Name of the mailbox tier of the DID
+/// - streaming_connections : Live streaming connections of the DID
+/// - blocked               : Time the DID was blocked (seconds since epoch), None if it isn't blocked
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminDidStats {
+    pub did: String,
+    pub did_hash: String,
+    pub receive_queue_count: u64,
+    pub receive_queue_bytes: u64,
+    pub send_queue_count: u64,
+    pub send_queue_bytes: u64,
+    pub tier: String,
+    pub streaming_connections: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocked: Option<u64>,
+}
+
+/// Body of an admin account delete message
+/// - did : DID to delete all data for
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminAccountDelete {
+    pub did: String,
+}
+
+/// Body of an admin accounts message
+/// - cursor : Cursor returned by the previous page, None starts from the beginning
+/// - limit  : Maximum number of accounts to return (the mediator applies its own maximum)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminAccounts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+/// An account of the mediator, a DID that has authenticated with the mediator
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminAccount {
+    pub did: String,
+    pub did_hash: String,
+}
+
+/// Body of an admin account list message
+/// - accounts : Accounts in this page
+/// - cursor   : Cursor of the next page, None if there are no more accounts
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminAccountList {
+    pub accounts: Vec<AdminAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+/// Body of an admin stats message
+/// - did : DID to get the mailbox statistics for
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminStats {
+    pub did: String,
+}
+
+/// Body of an admin block message
+/// - did     : DID to block or unblock
+/// - blocked : true blocks the DID, false unblocks it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminBlock {
+    pub did: String,
+    pub blocked: bool,
+}
+
+/// Body of an admin block status message
+/// - changed : true if the block status of the DID was changed by the request
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminBlockStatus {
+    pub did: String,
+    pub blocked: bool,
+    pub changed: bool,
+}
+
+/// Body of an admin expire message
+/// - did                : DID whose queued messages are expired
+/// - older_than_minutes : Messages queued longer than this are deleted, None uses the retention of the DID's tier
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminExpire {
+    pub did: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub older_than_minutes: Option<u64>,
+}
+
+/// Body of an admin expire report message
+/// - older_than_minutes : Retention that was applied
+/// - deleted            : Number of messages that were deleted
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminExpireReport {
+    pub did: String,
+    pub older_than_minutes: u64,
+    pub deleted: usize,
+}
+
+/// Body of an admin tier status message
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminTierStatus {
+    pub did: String,
+    pub tier: MailboxTier,
+}
+
+impl Admin {
+    /// Assigns a mailbox tier to a DID, the SDK DID must be an admin DID of the mediator
+    /// - `did` - DID to assign the tier to
+    /// - `tier` - Name of a tier configured on the mediator, None resets the DID to the default tier
+    /// - `wait` - Time Duration to wait for a response from websocket. Default (10 Seconds)
+    ///
+    /// Returns the tier that now applies to the DID
+    pub async fn set_tier(
+        &self,
+        atm: &mut ATM,
+        did: &str,
+        tier: Option<&str>,
+        wait: Option<Duration>,
+    ) -> Result<AdminTierStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "admin_set_tier");
+
+        async move {
+            debug!("Assigning tier ({:?}) to DID ({})", tier, did);
+            let body = serde_json::to_value(AdminTier {
+                did: did.to_string(),
+                tier: tier.map(|tier| tier.to_string()),
+            })
+            .map_err(|e| ATMError::MsgSendError(format!("Couldn't serialize admin tier: {}", e)))?;
+
+            let message = self._send(atm, ADMIN_TIER_MESSAGE_TYPE, body, wait).await?;
+            _reply(message, ADMIN_TIER_STATUS_MESSAGE_TYPE, "admin tier status")
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Deletes all data the mediator holds for a DID, the SDK DID must be an admin DID of the mediator
+    /// - `did` - DID to delete
+    /// - `wait` - Time Duration to wait for a response from websocket. Default (10 Seconds)
+    ///
+    /// Returns a report of what was deleted
+    pub async fn delete_account(
+        &self,
+        atm: &mut ATM,
+        did: &str,
+        wait: Option<Duration>,
+    ) -> Result<AccountDeletionReport, ATMError> {
+        let _span = span!(Level::DEBUG, "admin_delete_account");
+
+        async move {
+            debug!("Deleting account of DID ({})", did);
+            let body = serde_json::to_value(AdminAccountDelete {
+                did: did.to_string(),
+            })
+            .map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't serialize admin account delete: {}", e))
+            })?;
+
+            let message = self
+                ._send(atm, ADMIN_ACCOUNT_DELETE_MESSAGE_TYPE, body, wait)
+                .await?;
+            _reply(
+                message,
+                ADMIN_ACCOUNT_DELETE_REPORT_MESSAGE_TYPE,
+                "admin account delete report",
+            )
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Lists the accounts of the mediator (DIDs that have authenticated), the SDK DID must be an admin DID of the mediator
+    /// - `cursor` - Cursor returned by the previous page, None starts from the beginning
+    /// - `limit` - Maximum number of accounts to return, None uses the mediator default
+    /// - `wait` - Time Duration to wait for a response from websocket. Default (10 Seconds)
+    ///
+    /// Returns a page of accounts, and the cursor of the next page if there are more accounts
+    pub async fn list_accounts(
+        &self,
+        atm: &mut ATM,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+        wait: Option<Duration>,
+    ) -> Result<AdminAccountList, ATMError> {
+        let _span = span!(Level::DEBUG, "admin_list_accounts");
+
+        async move {
+            debug!("Listing accounts cursor({:?}) limit({:?})", cursor, limit);
+            let body = serde_json::to_value(AdminAccounts {
+                cursor: cursor.map(|cursor| cursor.to_string()),
+                limit,
+            })
+            .map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't serialize admin accounts: {}", e))
+            })?;
+
+            let message = self
+                ._send(atm, ADMIN_ACCOUNTS_MESSAGE_TYPE, body, wait)
+                .await?;
+            _reply(
+                message,
+                ADMIN_ACCOUNT_LIST_MESSAGE_TYPE,
+                "admin account list",
+            )
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Returns the mailbox statistics of a DID, the SDK DID must be an admin DID of the mediator
+    /// - `did` - DID to get the statistics for
+    /// - `wait` - Time Duration to wait for a response from websocket. Default (10 Seconds)
+    pub async fn did_stats(
+        &self,
+        atm: &mut ATM,
+        did: &str,
+        wait: Option<Duration>,
+    ) -> Result<AdminDidStats, ATMError> {
+        let _span = span!(Level::DEBUG, "admin_did_stats");
+
+        async move {
+            debug!("Getting stats of DID ({})", did);
+            let body = serde_json::to_value(AdminStats {
+                did: did.to_string(),
+            })
+            .map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't serialize admin stats: {}", e))
+            })?;
+
+            let message = self
+                ._send(atm, ADMIN_STATS_MESSAGE_TYPE, body, wait)
+                .await?;
+            _reply(
+                message,
+                ADMIN_STATS_REPORT_MESSAGE_TYPE,
+                "admin stats report",
+            )
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Blocks or unblocks a DID, the SDK DID must be an admin DID of the mediator
+    /// A blocked DID can't authenticate and its tokens are rejected, its queued messages are kept
+    /// - `did` - DID to block or unblock
+    /// - `blocked` - true blocks the DID, false unblocks it
+    /// - `wait` - Time Duration to wait for a response from websocket. Default (10 Seconds)
+    ///
+    /// Returns the block status of the DID, and whether it was changed
+    pub async fn set_blocked(
+        &self,
+        atm: &mut ATM,
+        did: &str,
+        blocked: bool,
+        wait: Option<Duration>,
+    ) -> Result<AdminBlockStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "admin_set_blocked");
+
+        async move {
+            debug!("Setting blocked ({}) for DID ({})", blocked, did);
+            let body = serde_json::to_value(AdminBlock {
+                did: did.to_string(),
+                blocked,
+            })
+            .map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't serialize admin block: {}", e))
+            })?;
+
+            let message = self
+                ._send(atm, ADMIN_BLOCK_MESSAGE_TYPE, body, wait)
+                .await?;
+            _reply(
+                message,
+                ADMIN_BLOCK_STATUS_MESSAGE_TYPE,
+                "admin block status",
+            )
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Deletes the messages queued for a DID that are older than a retention, the SDK DID must be an admin DID of the mediator
+    /// - `did` - DID whose queued messages are expired
+    /// - `older_than_minutes` - Retention to apply, None uses the retention of the DID's mailbox tier (0 deletes all messages)
+    /// - `wait` - Time Duration to wait for a response from websocket. Default (10 Seconds)
+    ///
+    /// Returns the number of messages that were deleted
+    pub async fn expire_messages(
+        &self,
+        atm: &mut ATM,
+        did: &str,
+        older_than_minutes: Option<u64>,
+        wait: Option<Duration>,
+    ) -> Result<AdminExpireReport, ATMError> {
+        let _span = span!(Level::DEBUG, "admin_expire_messages");
+
+        async move {
+            debug!(
+                "Expiring messages of DID ({}) older than ({:?}) minutes",
+                did, older_than_minutes
+            );
+            let body = serde_json::to_value(AdminExpire {
+                did: did.to_string(),
+                older_than_minutes,
+            })
+            .map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't serialize admin expire: {}", e))
+            })?;
+
+            let message = self
+                ._send(atm, ADMIN_EXPIRE_MESSAGE_TYPE, body, wait)
+                .await?;
+            _reply(
+                message,
+                ADMIN_EXPIRE_REPORT_MESSAGE_TYPE,
+                "admin expire report",
+            )
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Sends an admin message to the mediator and waits for the reply
+    async fn _send(
+        &self,
+        atm: &mut ATM,
+        type_: &str,
+        body: Value,
+        wait: Option<Duration>,
+    ) -> Result<Message, ATMError> {
+        let (my_did, atm_did) = atm.dids()?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let msg = Message::build(Uuid::new_v4().into(), type_.to_owned(), body)
+            .header("return_route".into(), Value::String("all".into()))
+            .to(atm_did.clone())
+            .from(my_did.clone())
+            .created_time(now)
+            .expires_time(now + 300)
+            .finalize();
+        let msg_id = msg.id.clone();
+
+        // Pack the message, admin messages must be authcrypted so the mediator knows the sender
+        let (msg, _) = msg
+            .pack_encrypted(
+                atm_did,
+                Some(my_did),
+                Some(my_did),
+                &atm.did_resolver,
+                &atm.secrets_resolver,
+                &PackEncryptedOptions::default(),
+            )
+            .await
+            .map_err(|e| ATMError::MsgSendError(format!("Error packing message: {}", e)))?;
+
+        if atm.ws_send_stream.is_some() {
+            atm.ws_send_didcomm_message::<EmptyResponse>(&msg, &msg_id)
+                .await?;
+            match MessagePickup::default()
+                .live_stream_get(
+                    atm,
+                    &msg_id,
+                    wait.unwrap_or_else(|| Duration::from_secs(10)),
+                )
+                .await?
+            {
+                Some((message, _)) => Ok(message),
+                None => Err(ATMError::MsgSendError("No response from API".into())),
+            }
+        } else {
+            match atm
+                .send_didcomm_message::<InboundMessageResponse>(&msg, true)
+                .await?
+            {
+                SendMessageResponse::RestAPI(Some(InboundMessageResponse::Ephemeral(message))) => {
+                    Ok(atm.unpack(&message).await?.0)
+                }
+                _ => Err(ATMError::MsgSendError("No response from API".into())),
+            }
+        }
+    }
+}
+
+/// Checks the type of an admin reply and deserializes its body
+fn _reply<T: DeserializeOwned>(message: Message, type_: &str, name: &str) -> Result<T, ATMError> {
+    if message.type_ != type_ {
+        return Err(ATMError::MsgReceiveError(format!(
+            "Expected an {} message, received ({})",
+            name, message.type_
+        )));
+    }
+
+    serde_json::from_value(message.body).map_err(|err| {
+        ATMError::MsgReceiveError(format!(
+            "{} message body isn't valid. Reason: {}",
+            name, err
+        ))
+    })
+}
@@ -236,7 +236,7 @@ impl MessagePickup {
     /// wait : How long to wait (in milliseconds) for a message before returning None
     ///        If 0, will block forever until a message is received
     /// Returns a tuple of the message and metadata, or None if no message was received
-    /// NOTE: You still need to delete the message from the server after receiving it
+    /// NOTE: You still need to acknowledge the message (`live_stream_ack()`) or delete it from the server after receiving it
     pub async fn live_stream_next(
         &self,
         atm: &mut ATM,
@@ -370,6 +370,44 @@ impl MessagePickup {
         .await
     }
 
+    /// Acknowledges messages received via websocket (or SSE) live delivery, the mediator will then remove them
+    /// Unacknowledged messages are redelivered by the mediator when live delivery is restarted (e.g. after reconnecting)
+    /// atm     : The ATM SDK to use
+    /// msg_ids : The DIDComm message ID's (`message.id`) of the received messages
+    ///
+    /// This is a lightweight alternative to `send_messages_received()` for live-streamed messages
+    pub async fn live_stream_ack(&self, atm: &mut ATM, msg_ids: &[String]) -> Result<(), ATMError> {
+        let _span = span!(Level::DEBUG, "live_stream_ack", count = msg_ids.len()).entered();
+
+        if let Some(tx_stream) = &atm.ws_send_stream {
+            tx_stream
+                .send(WSCommand::Ack(msg_ids.to_vec()))
+                .await
+                .map_err(|err| {
+                    ATMError::TransportError(format!(
+                        "Could not send ack message to ws_handler: {:?}",
+                        err
+                    ))
+                })?;
+            debug!("sent ack request to ws_handler");
+            Ok(())
+        } else if let Some(tx_stream) = &atm.sse_send_stream {
+            tx_stream
+                .send(WSCommand::Ack(msg_ids.to_vec()))
+                .await
+                .map_err(|err| {
+                    ATMError::TransportError(format!(
+                        "Could not send ack message to sse_handler: {:?}",
+                        err
+                    ))
+                })?;
+            debug!("sent ack request to sse_handler");
+            Ok(())
+        } else {
+            Err(ATMError::TransportError("No websocket send stream".into()))
+        }
+    }
+
     /// Sends a Message Pickup 3.0 `Delivery Request` message
     /// atm           : The ATM SDK to use
     /// recipient_did : Optional, allows you to ask for status for a specific DID. If none, will ask for default DID in ATM
@@ -1,16 +1,28 @@
 #[derive(Default)]
 pub struct Protocols {
+    pub admin: admin::Admin,
     pub message_pickup: message_pickup::MessagePickup,
+    pub oob: oob::OOB,
+    pub push: push::Push,
+    pub receipts: receipts::Receipts,
     pub trust_ping: trust_ping::TrustPing,
 }
 
+pub mod admin;
 pub mod message_pickup;
+pub mod oob;
+pub mod push;
+pub mod receipts;
 pub mod trust_ping;
 
 impl Protocols {
     pub fn new() -> Protocols {
         Protocols {
+            admin: admin::Admin::default(),
             message_pickup: message_pickup::MessagePickup::default(),
+            oob: oob::OOB::default(),
+            push: push::Push::default(),
+            receipts: receipts::Receipts::default(),
             trust_ping: trust_ping::TrustPing::default(),
         }
     }
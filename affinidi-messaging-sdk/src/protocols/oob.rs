@@ -0,0 +1,775 @@
+//! Out-of-Band 2.0 invitations
+//!
+//! An invitation is a plaintext DIDComm message from the inviter's DID, shared as a URL (`_oob=<base64url JSON>`)
+//! or hosted by the mediator behind a short URL (`_oobid=<oobid>`). The invitee accepts an invitation by sending
+//! the first message of the thread to the inviter, with `pthid` set to the invitation ID.
+use std::time::SystemTime;
+
+use affinidi_messaging_didcomm::{
+    protocols::routing::DIDCommMessagingService, Attachment, Message, PackEncryptedOptions,
+};
+use base64::prelude::*;
+use serde_json::{json, Map, Value};
+use sha256::digest;
+use ssi::dids::{document::service::Endpoint, Document};
+use tracing::{debug, span, Instrument, Level};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    errors::ATMError,
+    messages::{
+        sending::InboundMessageResponse, EmptyResponse, OOBInvitationRequest,
+        OOBInvitationResponse, SuccessResponse,
+    },
+    transports::SendMessageResponse,
+    ATM,
+};
+
+/// DIDComm message type of an Out-of-Band 2.0 invitation
+pub const OOB_INVITATION_TYPE: &str = "https://didcomm.org/out-of-band/2.0/invitation";
+
+const FORWARD_MSG_TYPE: &str = "https://didcomm.org/routing/2.0/forward";
+
+/// Where the first message to an inviter is sent, taken from the inviter's DIDCommMessaging service
+#[derive(Debug, PartialEq)]
+enum InviterRoute {
+    /// The inviter's service points to our mediator, which stores the message for the inviter
+    Mediator,
+    /// The inviter is reached through another service, `uri` is its endpoint (URL or mediator DID)
+    Service(String),
+}
+
+#[derive(Default)]
+pub struct OOB {}
+
+/// Used to construct the response for an accepted invitation
+/// - `message_id` - The ID of the message sent to the inviter
+/// - `inviter` - DID of the inviter
+/// - `message_hash` - The sha256 hash of the message sent
+/// - `response` - The ATM message ID if the message was stored by the mediator
+pub struct OOBAccepted {
+    pub message_id: String,
+    pub inviter: String,
+    pub message_hash: String,
+    pub response: Option<String>,
+}
+
+impl OOB {
+    /// Creates an Out-of-Band invitation from your DID
+    /// - `request` - The goal, accepted profiles, attachments and expiry (seconds) of the invitation
+    ///
+    /// Returns the invitation, use `encode_url()` to share it or `host_invitation()` to share a short URL
+    pub fn create_invitation(
+        &self,
+        atm: &ATM,
+        request: &OOBInvitationRequest,
+    ) -> Result<Message, ATMError> {
+        let (my_did, _) = atm.dids()?;
+
+        let mut body = Map::new();
+        if let Some(goal_code) = &request.goal_code {
+            body.insert("goal_code".into(), json!(goal_code));
+        }
+        if let Some(goal) = &request.goal {
+            body.insert("goal".into(), json!(goal));
+        }
+        if !request.accept.is_empty() {
+            body.insert("accept".into(), json!(request.accept));
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut invitation = Message::build(
+            Uuid::new_v4().into(),
+            OOB_INVITATION_TYPE.to_owned(),
+            Value::Object(body),
+        )
+        .from(my_did.clone())
+        .created_time(now);
+        if let Some(expiry) = request.expiry {
+            invitation = invitation.expires_time(now + expiry);
+        }
+        if !request.attachments.is_empty() {
+            invitation = invitation.attachments(request.attachments.clone());
+        }
+
+        Ok(invitation.finalize())
+    }
+
+    /// Encodes an invitation as an `_oob` URL
+    /// - `base_url` - URL the invitation is added to (e.g. `https://example.com/invite`)
+    pub fn encode_url(&self, base_url: &str, invitation: &Message) -> Result<String, ATMError> {
+        let mut url = Url::parse(base_url).map_err(|e| {
+            ATMError::ConfigError(format!("Invalid base URL ({}): {}", base_url, e))
+        })?;
+        let invitation = serde_json::to_string(invitation)
+            .map_err(|e| ATMError::MsgSendError(format!("Couldn't serialize invitation: {}", e)))?;
+
+        url.query_pairs_mut()
+            .append_pair("_oob", &BASE64_URL_SAFE_NO_PAD.encode(invitation));
+        Ok(url.to_string())
+    }
+
+    /// Parses and validates an invitation from an `_oob` URL
+    /// Use `fetch_url()` for short (`_oobid`) URLs
+    pub fn parse_url(&self, url: &str) -> Result<Message, ATMError> {
+        let url = Url::parse(url)
+            .map_err(|e| ATMError::MsgReceiveError(format!("Invalid invitation URL: {}", e)))?;
+        let encoded = url
+            .query_pairs()
+            .find(|(name, _)| name == "_oob")
+            .map(|(_, value)| value.into_owned())
+            .ok_or_else(|| {
+                ATMError::MsgReceiveError("Invitation URL has no `_oob` parameter".into())
+            })?;
+
+        // Padding is optional
+        let decoded = BASE64_URL_SAFE_NO_PAD
+            .decode(encoded.trim_end_matches('='))
+            .map_err(|e| {
+                ATMError::MsgReceiveError(format!("Invalid invitation encoding: {}", e))
+            })?;
+        let invitation = serde_json::from_slice::<Message>(&decoded)
+            .map_err(|e| ATMError::MsgReceiveError(format!("Invalid invitation: {}", e)))?;
+
+        self.validate(&invitation)?;
+        Ok(invitation)
+    }
+
+    /// Parses and validates an invitation from a URL
+    /// Short (`_oobid`) URLs are fetched from the mediator hosting them, `_oob` URLs are parsed directly
+    pub async fn fetch_url(&self, atm: &ATM, url: &str) -> Result<Message, ATMError> {
+        let _span = span!(Level::DEBUG, "oob_fetch_url");
+
+        async move {
+            let parsed = Url::parse(url)
+                .map_err(|e| ATMError::MsgReceiveError(format!("Invalid invitation URL: {}", e)))?;
+            if !parsed.query_pairs().any(|(name, _)| name == "_oobid") {
+                return self.parse_url(url);
+            }
+
+            debug!("Fetching hosted invitation ({})", url);
+            let res = atm
+                .client
+                .get(url)
+                .header("Accept", "application/json")
+                .send()
+                .await
+                .map_err(|e| {
+                    ATMError::TransportError(format!("Could not fetch invitation: {:?}", e))
+                })?;
+
+            let status = res.status();
+            let body = res
+                .text()
+                .await
+                .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+            if !status.is_success() {
+                return Err(ATMError::TransportError(format!(
+                    "Status not successful. status({}), response({})",
+                    status, body
+                )));
+            }
+
+            let invitation = serde_json::from_str::<Message>(&body)
+                .map_err(|e| ATMError::MsgReceiveError(format!("Invalid invitation: {}", e)))?;
+            self.validate(&invitation)?;
+            Ok(invitation)
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Validates a received invitation
+    /// - The message type is an Out-of-Band 2.0 invitation
+    /// - It has an ID and is from a DID
+    /// - It hasn't expired
+    /// - `goal_code`, `goal` and `accept` (if present) are well formed
+    pub fn validate(&self, invitation: &Message) -> Result<(), ATMError> {
+        if invitation.type_ != OOB_INVITATION_TYPE {
+            return Err(ATMError::MsgReceiveError(format!(
+                "Not an Out-of-Band invitation, type ({})",
+                invitation.type_
+            )));
+        }
+        if invitation.id.is_empty() {
+            return Err(ATMError::MsgReceiveError("Invitation has no ID".into()));
+        }
+        match &invitation.from {
+            Some(from) if from.starts_with("did:") => {}
+            _ => {
+                return Err(ATMError::MsgReceiveError(
+                    "Invitation is not from a DID".into(),
+                ))
+            }
+        }
+
+        if let Some(expires) = invitation.expires_time {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            if expires <= now {
+                return Err(ATMError::MsgReceiveError(format!(
+                    "Invitation expired at ({})",
+                    expires
+                )));
+            }
+        }
+
+        let body = invitation
+            .body
+            .as_object()
+            .ok_or_else(|| ATMError::MsgReceiveError("Invitation body is not an object".into()))?;
+        for field in ["goal_code", "goal"] {
+            if body.get(field).is_some_and(|value| !value.is_string()) {
+                return Err(ATMError::MsgReceiveError(format!(
+                    "Invitation `{}` is not a string",
+                    field
+                )));
+            }
+        }
+        if let Some(accept) = body.get("accept") {
+            let valid = accept
+                .as_array()
+                .is_some_and(|accept| accept.iter().all(|a| a.is_string()));
+            if !valid {
+                return Err(ATMError::MsgReceiveError(
+                    "Invitation `accept` is not a list of strings".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Accepts an invitation by sending the first message of the thread to the inviter
+    /// The inviter DID is resolved first, and the message is sent with `pthid` set to the invitation ID
+    /// The message is encrypted for the inviter and routed through the inviter's DIDCommMessaging service:
+    /// - If the service points to our mediator, it is wrapped in a forward to the mediator, which stores it for the inviter
+    /// - Otherwise it is wrapped for the service's routing keys and posted to the service endpoint (HTTP(S) only)
+    /// - `invitation` - The invitation, validated before it is accepted
+    /// - `type_` - Message type of the first message (e.g. the protocol of the invitation `goal_code`)
+    /// - `body` - Body of the first message
+    pub async fn accept_invitation(
+        &self,
+        atm: &mut ATM,
+        invitation: &Message,
+        type_: &str,
+        body: Value,
+    ) -> Result<OOBAccepted, ATMError> {
+        let _span = span!(
+            Level::DEBUG,
+            "oob_accept_invitation",
+            invitation = invitation.id
+        );
+
+        async move {
+            self.validate(invitation)?;
+            // validate() ensures the invitation is from a DID
+            let inviter = invitation.from.clone().unwrap_or_default();
+
+            let resolved = atm.did_resolver.resolve(&inviter).await.map_err(|e| {
+                ATMError::DIDError(format!(
+                    "Couldn't resolve inviter did ({}). Reason: {}",
+                    inviter, e
+                ))
+            })?;
+
+            let (my_did, atm_did) = atm.dids()?;
+            let my_did = my_did.clone();
+            let atm_did = atm_did.clone();
+
+            let route = self
+                ._inviter_route(&resolved.doc, &atm_did)
+                .ok_or_else(|| {
+                    ATMError::DIDError(format!(
+                        "Inviter did ({}) has no DIDCommMessaging service",
+                        inviter
+                    ))
+                })?;
+            debug!("Inviter route: {:?}", route);
+
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let msg = Message::build(Uuid::new_v4().into(), type_.to_owned(), body)
+                .pthid(invitation.id.clone())
+                .to(inviter.clone())
+                .from(my_did.clone())
+                .created_time(now)
+                .finalize();
+            let mut accepted = OOBAccepted {
+                message_id: msg.id.clone(),
+                inviter: inviter.clone(),
+                message_hash: "".to_string(),
+                response: None,
+            };
+
+            debug!("Accepting invitation with message: {:?}", msg);
+
+            // Pack the message, the library wraps it for the inviter's service unless our mediator routes it
+            let (msg, metadata) = msg
+                .pack_encrypted(
+                    &inviter,
+                    Some(&my_did),
+                    Some(&my_did),
+                    &atm.did_resolver,
+                    &atm.secrets_resolver,
+                    &PackEncryptedOptions {
+                        forward: route != InviterRoute::Mediator,
+                        ..PackEncryptedOptions::default()
+                    },
+                )
+                .await
+                .map_err(|e| ATMError::MsgSendError(format!("Error packing message: {}", e)))?;
+
+            accepted.message_hash = digest(&msg).to_string();
+
+            if let InviterRoute::Service(uri) = route {
+                // A mediator DID in the service is resolved to its endpoint when the message is packed
+                let endpoint = metadata
+                    .messaging_service
+                    .map(|service| service.service_endpoint)
+                    .unwrap_or(uri);
+                self._send_to_service(atm, &endpoint, msg).await?;
+                return Ok(accepted);
+            }
+
+            // Only the inviter can read the message, the mediator routes it on the forward
+            let forward = self._forward(&my_did, &atm_did, &inviter, &msg, now)?;
+            let (msg, _) = forward
+                .pack_encrypted(
+                    &atm_did,
+                    Some(&my_did),
+                    Some(&my_did),
+                    &atm.did_resolver,
+                    &atm.secrets_resolver,
+                    &PackEncryptedOptions::default(),
+                )
+                .await
+                .map_err(|e| ATMError::MsgSendError(format!("Error packing forward: {}", e)))?;
+
+            if atm.ws_send_stream.is_some() {
+                atm.ws_send_didcomm_message::<EmptyResponse>(&msg, &accepted.message_id)
+                    .await?;
+            } else if let SendMessageResponse::RestAPI(Some(InboundMessageResponse::Stored(m))) =
+                atm.send_didcomm_message::<InboundMessageResponse>(&msg, true)
+                    .await?
+            {
+                accepted.response = m.messages.first().map(|(_, msg_id)| msg_id.to_owned());
+            }
+
+            Ok(accepted)
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Picks the route to the inviter from the first DIDCommMessaging service (didcomm/v2) in their DID Document
+    /// The service points to our mediator when its `uri` is the mediator DID, or a routing key belongs to the mediator DID
+    /// Returns None if the inviter has no DIDCommMessaging service
+    fn _inviter_route(&self, doc: &Document, atm_did: &str) -> Option<InviterRoute> {
+        let service = doc
+            .service
+            .iter()
+            .filter(|service| service.type_.any(|t| t == "DIDCommMessaging"))
+            .filter_map(|service| service.service_endpoint.as_ref())
+            .flat_map(|endpoints| endpoints.into_iter())
+            .find_map(|endpoint| match endpoint {
+                Endpoint::Map(value) => {
+                    serde_json::from_value::<DIDCommMessagingService>(value.clone())
+                        .ok()
+                        .filter(|service| {
+                            service
+                                .accept
+                                .as_ref()
+                                .is_none_or(|accept| accept.iter().any(|a| a == "didcomm/v2"))
+                        })
+                }
+                Endpoint::Uri(_) => None,
+            })?;
+
+        let routed_by_mediator = service.uri == atm_did
+            || service
+                .routing_keys
+                .iter()
+                .any(|key| key.split('#').next() == Some(atm_did));
+
+        if routed_by_mediator {
+            Some(InviterRoute::Mediator)
+        } else {
+            Some(InviterRoute::Service(service.uri))
+        }
+    }
+
+    /// Posts a packed message to the inviter's service endpoint
+    async fn _send_to_service(
+        &self,
+        atm: &ATM,
+        endpoint: &str,
+        msg: String,
+    ) -> Result<(), ATMError> {
+        if !endpoint.starts_with("https://") && !endpoint.starts_with("http://") {
+            return Err(ATMError::TransportError(format!(
+                "Inviter service endpoint ({}) isn't an HTTP(S) URL",
+                endpoint
+            )));
+        }
+
+        let res = atm
+            .client
+            .post(endpoint)
+            .header("Content-Type", "application/didcomm-encrypted+json")
+            .body(msg)
+            .send()
+            .await
+            .map_err(|e| {
+                ATMError::TransportError(format!(
+                    "Couldn't send message to inviter service ({}): {:?}",
+                    endpoint, e
+                ))
+            })?;
+
+        let status = res.status();
+        if !status.is_success() {
+            return Err(ATMError::TransportError(format!(
+                "Inviter service ({}) returned an error: status({})",
+                endpoint, status
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Wraps a message packed for the inviter in a routing/2.0 forward to the mediator
+    fn _forward(
+        &self,
+        my_did: &str,
+        atm_did: &str,
+        inviter: &str,
+        packed: &str,
+        now: u64,
+    ) -> Result<Message, ATMError> {
+        let packed = serde_json::from_str::<Value>(packed)
+            .map_err(|e| ATMError::MsgSendError(format!("Packed message isn't JSON: {}", e)))?;
+
+        Ok(Message::build(
+            Uuid::new_v4().into(),
+            FORWARD_MSG_TYPE.to_owned(),
+            json!({ "next": inviter }),
+        )
+        .to(atm_did.to_owned())
+        .from(my_did.to_owned())
+        .attachment(Attachment::json(packed).finalize())
+        .created_time(now)
+        .expires_time(now + 300)
+        .finalize())
+    }
+
+    /// Creates an invitation from your DID that is hosted by the mediator
+    /// The invitation is served at `<atm_api>/oob?_oobid=<oobid>` until it expires
+    /// - `request` - The goal, accepted profiles, attachments and expiry (seconds) of the invitation
+    pub async fn host_invitation(
+        &self,
+        atm: &mut ATM,
+        request: &OOBInvitationRequest,
+    ) -> Result<OOBInvitationResponse, ATMError> {
+        let _span = span!(Level::DEBUG, "oob_host_invitation");
+
+        async move {
+            let tokens = atm.authenticate().await?;
+            let body = serde_json::to_string(request).map_err(|e| {
+                ATMError::TransportError(format!("Could not serialize invitation request: {:?}", e))
+            })?;
+
+            let res = atm
+                .client
+                .post(format!("{}/oob", atm.config.atm_api))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", tokens.access_token))
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| {
+                    ATMError::TransportError(format!("Could not send invitation request: {:?}", e))
+                })?;
+
+            let status = res.status();
+            debug!("API response: status({})", status);
+            let body = res
+                .text()
+                .await
+                .map_err(|e| ATMError::TransportError(format!("Couldn't get body: {:?}", e)))?;
+            if !status.is_success() {
+                return Err(ATMError::TransportError(format!(
+                    "Status not successful. status({}), response({})",
+                    status, body
+                )));
+            }
+
+            serde_json::from_str::<SuccessResponse<OOBInvitationResponse>>(&body)
+                .map_err(|e| {
+                    ATMError::TransportError(format!("Couldn't parse invitation response: {:?}", e))
+                })?
+                .data
+                .ok_or_else(|| ATMError::TransportError("No response data".to_string()))
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Short URL of an invitation hosted by the mediator
+    pub fn hosted_url(&self, atm: &ATM, oobid: &str) -> String {
+        format!("{}/oob?_oobid={}", atm.config.atm_api, oobid)
+    }
+
+    /// Removes an invitation hosted by the mediator before it expires
+    pub async fn delete_hosted_invitation(
+        &self,
+        atm: &mut ATM,
+        oobid: &str,
+    ) -> Result<(), ATMError> {
+        let _span = span!(Level::DEBUG, "oob_delete_hosted_invitation", oobid = oobid);
+
+        async move {
+            let tokens = atm.authenticate().await?;
+
+            let res = atm
+                .client
+                .delete(self.hosted_url(atm, oobid))
+                .header("Authorization", format!("Bearer {}", tokens.access_token))
+                .send()
+                .await
+                .map_err(|e| {
+                    ATMError::TransportError(format!("Could not send invitation delete: {:?}", e))
+                })?;
+
+            let status = res.status();
+            debug!("API response: status({})", status);
+            if !status.is_success() {
+                let body = res.text().await.unwrap_or_default();
+                return Err(ATMError::TransportError(format!(
+                    "Status not successful. status({}), response({})",
+                    status, body
+                )));
+            }
+
+            Ok(())
+        }
+        .instrument(_span)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InviterRoute, OOB, OOB_INVITATION_TYPE};
+    use affinidi_messaging_didcomm::{protocols::routing::try_parse_forward, Message};
+    use base64::prelude::*;
+    use serde_json::{json, Value};
+    use ssi::dids::Document;
+    use std::time::SystemTime;
+
+    const INVITER: &str = "did:example:alice";
+    const MEDIATOR: &str = "did:example:mediator";
+
+    fn inviter_doc(services: Value) -> Document {
+        serde_json::from_value(json!({
+            "id": INVITER,
+            "service": services
+        }))
+        .unwrap()
+    }
+
+    fn didcomm_service(endpoint: Value) -> Value {
+        json!({
+            "id": format!("{}#service", INVITER),
+            "type": "DIDCommMessaging",
+            "serviceEndpoint": endpoint
+        })
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn invitation(body: Value) -> Message {
+        Message::build("1234".into(), OOB_INVITATION_TYPE.to_owned(), body)
+            .from(INVITER.into())
+            .expires_time(now() + 300)
+            .finalize()
+    }
+
+    #[test]
+    fn validates_invitation() {
+        let oob = OOB::default();
+        assert!(oob
+            .validate(&invitation(json!({
+                "goal_code": "connect",
+                "goal": "Connect with Alice",
+                "accept": ["didcomm/v2"]
+            })))
+            .is_ok());
+        assert!(oob.validate(&invitation(json!({}))).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_invitations() {
+        let oob = OOB::default();
+
+        let mut msg = invitation(json!({}));
+        msg.type_ = "https://didcomm.org/trust-ping/2.0/ping".into();
+        assert!(oob.validate(&msg).is_err());
+
+        let mut msg = invitation(json!({}));
+        msg.id = "".into();
+        assert!(oob.validate(&msg).is_err());
+
+        let mut msg = invitation(json!({}));
+        msg.from = None;
+        assert!(oob.validate(&msg).is_err());
+        msg.from = Some("alice".into());
+        assert!(oob.validate(&msg).is_err());
+
+        let mut msg = invitation(json!({}));
+        msg.expires_time = Some(now() - 1);
+        assert!(oob.validate(&msg).is_err());
+
+        assert!(oob.validate(&invitation(json!("connect"))).is_err());
+        assert!(oob.validate(&invitation(json!({"goal_code": 1}))).is_err());
+        assert!(oob.validate(&invitation(json!({"goal": ["a"]}))).is_err());
+        assert!(oob
+            .validate(&invitation(json!({"accept": "didcomm/v2"})))
+            .is_err());
+        assert!(oob.validate(&invitation(json!({"accept": [2]}))).is_err());
+    }
+
+    #[test]
+    fn parses_encoded_url() {
+        let oob = OOB::default();
+        let msg = invitation(json!({"goal_code": "connect"}));
+        let url = oob
+            .encode_url("https://example.com/invite?a=b", &msg)
+            .unwrap();
+
+        let parsed = oob.parse_url(&url).unwrap();
+        assert_eq!(parsed.id, msg.id);
+        assert_eq!(parsed.from.as_deref(), Some(INVITER));
+        assert_eq!(parsed.body, msg.body);
+    }
+
+    #[test]
+    fn parses_padded_url() {
+        let oob = OOB::default();
+        let msg = invitation(json!({}));
+        let encoded = BASE64_URL_SAFE.encode(serde_json::to_string(&msg).unwrap());
+
+        let parsed = oob
+            .parse_url(&format!("https://example.com/?_oob={}", encoded))
+            .unwrap();
+        assert_eq!(parsed.id, msg.id);
+    }
+
+    #[test]
+    fn rejects_invalid_urls() {
+        let oob = OOB::default();
+        assert!(oob.parse_url("not a url").is_err());
+        assert!(oob.parse_url("https://example.com/?_oobid=1234").is_err());
+        assert!(oob.parse_url("https://example.com/?_oob=%%%").is_err());
+        let encoded = BASE64_URL_SAFE_NO_PAD.encode("{\"id\": 1}");
+        assert!(oob
+            .parse_url(&format!("https://example.com/?_oob={}", encoded))
+            .is_err());
+
+        let mut msg = invitation(json!({}));
+        msg.expires_time = Some(now() - 1);
+        let url = oob.encode_url("https://example.com/", &msg).unwrap();
+        assert!(oob.parse_url(&url).is_err());
+    }
+
+    #[test]
+    fn forwards_reply_to_inviter() {
+        let oob = OOB::default();
+        let packed = json!({"protected": "abc", "ciphertext": "def"});
+        let forward = oob
+            ._forward(
+                "did:example:bob",
+                "did:example:mediator",
+                INVITER,
+                &packed.to_string(),
+                now(),
+            )
+            .unwrap();
+
+        assert_eq!(forward.to, Some(vec!["did:example:mediator".to_string()]));
+        let parsed = try_parse_forward(&forward).unwrap();
+        assert_eq!(parsed.next, INVITER);
+        assert_eq!(parsed.forwarded_msg, packed);
+
+        assert!(oob
+            ._forward(
+                "did:example:bob",
+                "did:example:mediator",
+                INVITER,
+                "abc",
+                now()
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn routes_reply_via_inviter_service() {
+        let oob = OOB::default();
+
+        // Inviter is reached through our mediator, by its DID or its routing keys
+        let doc = inviter_doc(json!([didcomm_service(json!({"uri": MEDIATOR}))]));
+        assert_eq!(
+            oob._inviter_route(&doc, MEDIATOR),
+            Some(InviterRoute::Mediator)
+        );
+        let doc = inviter_doc(json!([didcomm_service(json!({
+            "uri": "https://mediator.example.com",
+            "routingKeys": [format!("{}#key-2", MEDIATOR)]
+        }))]));
+        assert_eq!(
+            oob._inviter_route(&doc, MEDIATOR),
+            Some(InviterRoute::Mediator)
+        );
+
+        // Inviter is reached through another mediator or their own endpoint
+        let doc = inviter_doc(json!([didcomm_service(json!({
+            "uri": "did:example:other-mediator",
+            "accept": ["didcomm/v2"]
+        }))]));
+        assert_eq!(
+            oob._inviter_route(&doc, MEDIATOR),
+            Some(InviterRoute::Service("did:example:other-mediator".into()))
+        );
+        let doc = inviter_doc(json!([didcomm_service(json!([
+            {"uri": "https://old.example.com", "accept": ["didcomm/aip2;env=rfc19"]},
+            {"uri": "https://alice.example.com"}
+        ]))]));
+        assert_eq!(
+            oob._inviter_route(&doc, MEDIATOR),
+            Some(InviterRoute::Service("https://alice.example.com".into()))
+        );
+
+        // No DIDCommMessaging service to route through
+        assert_eq!(oob._inviter_route(&inviter_doc(json!([])), MEDIATOR), None);
+        let doc = inviter_doc(json!([{
+            "id": format!("{}#linked", INVITER),
+            "type": "LinkedDomains",
+            "serviceEndpoint": {"uri": "https://alice.example.com"}
+        }]));
+        assert_eq!(oob._inviter_route(&doc, MEDIATOR), None);
+    }
+}
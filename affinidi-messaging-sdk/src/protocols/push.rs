@@ -0,0 +1,219 @@
+use std::time::{Duration, SystemTime};
+
+use affinidi_messaging_didcomm::{Message, PackEncryptedOptions};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, span, Instrument, Level};
+use uuid::Uuid;
+
+use crate::{
+    errors::ATMError,
+    messages::{sending::InboundMessageResponse, EmptyResponse},
+    protocols::message_pickup::MessagePickup,
+    transports::SendMessageResponse,
+    ATM,
+};
+
+/// DIDComm message type to register a push endpoint with the mediator
+pub const PUSH_REGISTER_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/push/register";
+
+/// DIDComm message type to remove push endpoint(s) from the mediator
+pub const PUSH_UNREGISTER_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/push/unregister";
+
+/// DIDComm message type of the mediator reply, lists the push endpoints of the DID
+pub const PUSH_STATUS_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/push/status";
+
+#[derive(Default)]
+pub struct Push {}
+
+/// Payload shape that the mediator uses when calling a push endpoint
+/// Payloads never contain message content, only that new messages are waiting
+/// - Generic : `{"type": "new_messages", "count": <n>}`
+/// - Fcm     : FCM HTTP v1 data message, `token` is required
+/// - Apns    : APNs background notification (`content-available`), the endpoint is the full device URL
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PushFormat {
+    #[default]
+    Generic,
+    Fcm,
+    Apns,
+}
+
+/// Body of a push register message
+/// - endpoint      : HTTPS URL that the mediator POSTs to when new messages arrive
+/// - format        : Payload shape to use
+/// - token         : Device token (required for FCM)
+/// - authorization : Optional value of the `Authorization` header sent to the endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PushRegistration {
+    pub endpoint: String,
+    #[serde(default)]
+    pub format: PushFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization: Option<String>,
+}
+
+/// Body of a push unregister message
+/// - id : ID of the registration to remove, if None then all registrations of the DID are removed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PushUnregister {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// A push endpoint registered with the mediator (the authorization value is never returned)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PushEndpoint {
+    pub id: String,
+    pub endpoint: String,
+    pub format: PushFormat,
+}
+
+/// Body of a push status message
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PushStatus {
+    pub registrations: Vec<PushEndpoint>,
+}
+
+impl Push {
+    /// Registers a push endpoint that the mediator calls when a message arrives and the DID isn't live streaming
+    /// Registering the same endpoint and token again replaces the existing registration
+    /// - `registration` - The push endpoint to register
+    /// - `wait` - Time Duration to wait for a response from websocket. Default (10 Seconds)
+    ///
+    /// Returns the push endpoints of the DID
+    pub async fn register(
+        &self,
+        atm: &mut ATM,
+        registration: &PushRegistration,
+        wait: Option<Duration>,
+    ) -> Result<PushStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "push_register");
+
+        async move {
+            debug!(
+                "Registering push endpoint ({}) format({:?})",
+                registration.endpoint, registration.format
+            );
+            let body = serde_json::to_value(registration).map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't serialize push registration: {}", e))
+            })?;
+            self._send(atm, PUSH_REGISTER_MESSAGE_TYPE, body, wait)
+                .await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Removes push endpoint(s) from the mediator
+    /// - `id` - ID of the registration to remove, if None then removes all registrations of the DID
+    /// - `wait` - Time Duration to wait for a response from websocket. Default (10 Seconds)
+    ///
+    /// Returns the remaining push endpoints of the DID
+    pub async fn unregister(
+        &self,
+        atm: &mut ATM,
+        id: Option<&str>,
+        wait: Option<Duration>,
+    ) -> Result<PushStatus, ATMError> {
+        let _span = span!(Level::DEBUG, "push_unregister");
+
+        async move {
+            debug!("Removing push endpoint(s) id({:?})", id);
+            let body = serde_json::to_value(PushUnregister {
+                id: id.map(|id| id.to_string()),
+            })
+            .map_err(|e| {
+                ATMError::MsgSendError(format!("Couldn't serialize push unregister: {}", e))
+            })?;
+            self._send(atm, PUSH_UNREGISTER_MESSAGE_TYPE, body, wait)
+                .await
+        }
+        .instrument(_span)
+        .await
+    }
+
+    /// Sends a push message to the mediator and waits for the status reply
+    async fn _send(
+        &self,
+        atm: &mut ATM,
+        type_: &str,
+        body: Value,
+        wait: Option<Duration>,
+    ) -> Result<PushStatus, ATMError> {
+        let (my_did, atm_did) = atm.dids()?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let msg = Message::build(Uuid::new_v4().into(), type_.to_owned(), body)
+            .header("return_route".into(), Value::String("all".into()))
+            .to(atm_did.clone())
+            .from(my_did.clone())
+            .created_time(now)
+            .expires_time(now + 300)
+            .finalize();
+        let msg_id = msg.id.clone();
+
+        // Pack the message
+        let (msg, _) = msg
+            .pack_encrypted(
+                atm_did,
+                Some(my_did),
+                Some(my_did),
+                &atm.did_resolver,
+                &atm.secrets_resolver,
+                &PackEncryptedOptions::default(),
+            )
+            .await
+            .map_err(|e| ATMError::MsgSendError(format!("Error packing message: {}", e)))?;
+
+        let message = if atm.ws_send_stream.is_some() {
+            atm.ws_send_didcomm_message::<EmptyResponse>(&msg, &msg_id)
+                .await?;
+            match MessagePickup::default()
+                .live_stream_get(
+                    atm,
+                    &msg_id,
+                    wait.unwrap_or_else(|| Duration::from_secs(10)),
+                )
+                .await?
+            {
+                Some((message, _)) => message,
+                None => return Err(ATMError::MsgSendError("No response from API".into())),
+            }
+        } else {
+            match atm
+                .send_didcomm_message::<InboundMessageResponse>(&msg, true)
+                .await?
+            {
+                SendMessageResponse::RestAPI(Some(InboundMessageResponse::Ephemeral(message))) => {
+                    atm.unpack(&message).await?.0
+                }
+                _ => return Err(ATMError::MsgSendError("No response from API".into())),
+            }
+        };
+
+        self._parse_status(&message)
+    }
+
+    fn _parse_status(&self, message: &Message) -> Result<PushStatus, ATMError> {
+        if message.type_ != PUSH_STATUS_MESSAGE_TYPE {
+            return Err(ATMError::MsgReceiveError(format!(
+                "Expected a push status message, received ({})",
+                message.type_
+            )));
+        }
+
+        serde_json::from_value(message.body.clone()).map_err(|err| {
+            ATMError::MsgReceiveError(format!(
+                "Push status message body isn't valid. Reason: {}",
+                err
+            ))
+        })
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use affinidi_messaging_didcomm::{Message, PackEncryptedMetadata, PackEncryptedOptions};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, span, Instrument, Level};
+
+use crate::{errors::ATMError, ATM};
+
+/// Header added to the forward message that asks the mediator for receipts
+pub const RECEIPT_REQUEST_HEADER: &str = "receipt_request";
+
+/// DIDComm message type of a receipt sent by the mediator
+pub const RECEIPT_MESSAGE_TYPE: &str = "https://affinidi.com/atm/1.0/receipt";
+
+#[derive(Default)]
+pub struct Receipts {}
+
+/// Types of receipts that a sender can ask for
+/// - Delivered : The recipient has fetched the message, or it was live streamed to them
+/// - Read      : The recipient has acknowledged the message (Message Pickup `messages-received`)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptType {
+    Delivered,
+    Read,
+}
+
+/// Value of the `receipt_request` header
+/// - id    : ID of the original message, receipts are threaded (`thid`) on this ID
+/// - types : Receipts that are requested
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReceiptRequest {
+    pub id: String,
+    pub types: Vec<ReceiptType>,
+}
+
+/// Body of a receipt message
+/// - message_id     : ID of the original message (as set in the receipt request)
+/// - atm_message_id : ID of the recipient copy of the message in ATM
+/// - recipient      : DID that the message was sent to
+/// - status         : What happened to the message
+/// - timestamp      : When it happened (seconds since epoch), only if the mediator policy allows it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Receipt {
+    pub message_id: String,
+    pub atm_message_id: String,
+    pub recipient: String,
+    pub status: ReceiptType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+impl Receipts {
+    /// Creates the forward headers that ask the mediator for receipts on a message
+    /// - `message_id` - ID of the original message, returned in each receipt
+    /// - `types` - Receipts that are requested
+    pub fn request_headers(
+        &self,
+        message_id: &str,
+        types: &[ReceiptType],
+    ) -> HashMap<String, Value> {
+        let request = ReceiptRequest {
+            id: message_id.to_string(),
+            types: types.to_vec(),
+        };
+        HashMap::from([(
+            RECEIPT_REQUEST_HEADER.to_string(),
+            serde_json::to_value(request).unwrap_or_default(),
+        )])
+    }
+
+    /// Packs a message for a recipient, asking the recipient's mediator for receipts
+    /// The receipt request is added to the forward message, so the recipient must be reached via a mediator
+    /// - `message` - The message to send, receipts are correlated on the message ID
+    /// - `to` - DID of the recipient
+    /// - `from` - If None, then will use anonymous encryption
+    /// - `sign_by` - If None, then will not sign the message
+    /// - `types` - Receipts that are requested
+    pub async fn pack_encrypted(
+        &self,
+        atm: &mut ATM,
+        message: &Message,
+        to: &str,
+        from: Option<&str>,
+        sign_by: Option<&str>,
+        types: &[ReceiptType],
+    ) -> Result<(String, PackEncryptedMetadata), ATMError> {
+        let _span = span!(Level::DEBUG, "pack_encrypted_with_receipts",);
+
+        async move {
+            debug!(
+                "Requesting receipts ({:?}) for message ({})",
+                types, message.id
+            );
+            message
+                .pack_encrypted(
+                    to,
+                    from,
+                    sign_by,
+                    &atm.did_resolver,
+                    &atm.secrets_resolver,
+                    &PackEncryptedOptions {
+                        forward_headers: Some(self.request_headers(&message.id, types)),
+                        ..PackEncryptedOptions::default()
+                    },
+                )
+                .await
+        }
+        .instrument(_span)
+        .await
+        .map_err(|e| {
+            ATMError::DidcommError(
+                "SDK".to_string(),
+                format!("pack_encrypted() failed. Reason: {}", e),
+            )
+        })
+    }
+
+    /// Reads a receipt from an unpacked message
+    /// Returns None if the message isn't a receipt
+    /// The original message ID is `Receipt.message_id` (also the `thid` of the receipt message)
+    pub fn parse(&self, message: &Message) -> Result<Option<Receipt>, ATMError> {
+        if message.type_ != RECEIPT_MESSAGE_TYPE {
+            return Ok(None);
+        }
+
+        let receipt: Receipt = serde_json::from_value(message.body.clone()).map_err(|err| {
+            ATMError::MsgReceiveError(format!("Receipt message body isn't valid. Reason: {}", err))
+        })?;
+
+        if message.thid.as_ref() != Some(&receipt.message_id) {
+            return Err(ATMError::MsgReceiveError(format!(
+                "Receipt thid({:?}) doesn't match message_id({})",
+                message.thid, receipt.message_id
+            )));
+        }
+
+        Ok(Some(receipt))
+    }
+}
@@ -1,6 +1,44 @@
+use std::collections::{HashMap, VecDeque};
+
 pub mod http;
+pub mod sse;
 pub mod websockets;
 
+/// Maximum number of unacknowledged messages that are tracked for a live stream
+const ACK_LOOKUP_LIMIT: usize = 1000;
+
+/// Lookup of received message IDs to the ID that the mediator accepts as an acknowledgement
+/// Holds at most ACK_LOOKUP_LIMIT messages, the oldest are forgotten first
+#[derive(Default)]
+pub(crate) struct AckLookup {
+    ids: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl AckLookup {
+    pub(crate) fn insert(&mut self, id: String, ack_id: String) {
+        if self.ids.insert(id.clone(), ack_id).is_none() {
+            self.order.push_back(id);
+        }
+        while self.ids.len() > ACK_LOOKUP_LIMIT {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.ids.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        // Acknowledged IDs are removed lazily from the order
+        if self.order.len() > ACK_LOOKUP_LIMIT * 2 {
+            self.order.retain(|id| self.ids.contains_key(id));
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: &str) -> Option<String> {
+        self.ids.remove(id)
+    }
+}
+
 /// WebSocketSendResponse is the response from sending a message over a WebSocket connection
 /// message_digest is sha256 digest of the message sent
 /// bytes_sent is the number of bytes sent
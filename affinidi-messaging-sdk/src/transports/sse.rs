@@ -0,0 +1,349 @@
+use crate::{
+    config::Config, errors::ATMError, messages::DeleteMessageRequest, transports::AckLookup,
+    websockets::ws_handler::WSCommand, ATM,
+};
+use http::header::{ACCEPT, AUTHORIZATION};
+use reqwest::Response;
+use std::time::Duration;
+use tokio::{
+    select,
+    sync::mpsc::{self, Receiver, Sender},
+    time::sleep,
+};
+use tracing::{debug, error, info, span, warn, Instrument, Level};
+
+/// Name of the SSE event that carries a packed DIDComm message
+const MESSAGE_EVENT: &str = "message";
+
+/// Time to wait before reconnecting a dropped SSE connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A single Server-Sent Event
+/// id: msg_id of the message in ATM (if the message is stored)
+#[derive(Default, Debug)]
+struct SseEvent {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+}
+
+/// Splits the SSE byte stream into events
+#[derive(Default)]
+struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    /// Adds a chunk of the stream, returns any events that are complete
+    fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer
+            .extend(chunk.iter().filter(|b| **b != b'\r').copied());
+
+        let mut events = Vec::new();
+        while let Some(pos) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let block: Vec<u8> = self.buffer.drain(..pos + 2).collect();
+            let block = String::from_utf8_lossy(&block);
+
+            let mut event = SseEvent::default();
+            let mut has_data = false;
+            for line in block.lines() {
+                // Empty lines and comments (keep-alive) are ignored
+                if line.is_empty() || line.starts_with(':') {
+                    continue;
+                }
+                let (field, value) = match line.split_once(':') {
+                    Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                    None => (line, ""),
+                };
+                match field {
+                    "id" => event.id = Some(value.to_string()),
+                    "event" => event.event = Some(value.to_string()),
+                    "data" => {
+                        if has_data {
+                            event.data.push('\n');
+                        }
+                        event.data.push_str(value);
+                        has_data = true;
+                    }
+                    _ => {}
+                }
+            }
+            if has_data {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+impl ATM {
+    /// Starts a Server-Sent Events (SSE) connection to the ATM API
+    /// Live streamed messages are received the same as with the websocket (e.g. `next_message()`)
+    /// Messages are sent via the REST API, as SSE is receive only
+    /// Example:
+    /// ```ignore
+    /// use affinidi_messaging_sdk::ATM;
+    /// use affinidi_messaging_sdk::config::Config;
+    ///
+    /// // SSE is started automatically when enabled in the config
+    /// let config = Config::builder().with_sse().build()?;
+    /// let mut atm = ATM::new(config).await?;
+    ///
+    /// let message = atm.next_message().await;
+    /// ```
+    pub async fn start_sse_task(&mut self) -> Result<(), ATMError> {
+        // Create a copy of ATM with owned values
+        let mut config = Config {
+            ssl_certificates: Vec::new(),
+            ..self.config.clone()
+        };
+
+        for cert in &self.config.ssl_certificates {
+            config.ssl_certificates.push(cert.clone().into_owned())
+        }
+
+        let mut atm = ATM {
+            config,
+            did_resolver: self.did_resolver.clone(),
+            secrets_resolver: self.secrets_resolver.clone(),
+            client: self.client.clone(),
+            authenticated: self.authenticated,
+            jwt_tokens: self.jwt_tokens.clone(),
+            ws_connector: self.ws_connector.clone(),
+            ws_enabled: false,
+            ws_handler: None,
+            ws_send_stream: None,
+            ws_recv_stream: None,
+            sse_handler: None,
+            sse_send_stream: None,
+        };
+
+        // Communicates from SDK to the SSE handler
+        let (tx, mut rx) = mpsc::channel::<WSCommand>(32);
+        self.sse_send_stream = Some(tx);
+
+        // Communicates from SSE handler to SDK
+        let (tx2, rx2) = mpsc::channel::<WSCommand>(32);
+        self.ws_recv_stream = Some(rx2);
+
+        self.sse_handler = Some(tokio::spawn(async move {
+            if let Err(err) = ATM::sse_handler(&mut atm, &mut rx, &tx2).await {
+                error!("SSE handler stopped: {}", err);
+            }
+        }));
+
+        if let Some(recv) = self.ws_recv_stream.as_mut() {
+            // Wait for Started message
+            match recv.recv().await {
+                Some(WSCommand::Started) => {
+                    debug!("SSE connection started");
+                }
+                Some(msg) => {
+                    warn!("Unknown message from sse_handler: {:?}", msg);
+                }
+                None => {
+                    return Err(ATMError::TransportError(
+                        "Couldn't start SSE connection".into(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Close the SSE task gracefully
+    pub async fn abort_sse_task(&mut self) -> Result<(), ATMError> {
+        if let Some(channel) = self.sse_send_stream.as_mut() {
+            let _ = channel.send(WSCommand::Exit).await;
+        }
+
+        Ok(())
+    }
+
+    /// Opens the SSE connection to the ATM API
+    async fn _create_sse(&mut self) -> Result<Response, ATMError> {
+        let tokens = self.authenticate().await?;
+
+        debug!("Creating SSE connection");
+        let response = self
+            .client
+            .get(&self.config.atm_api_sse)
+            .header(AUTHORIZATION, format!("Bearer {}", tokens.access_token))
+            .header(ACCEPT, "text/event-stream")
+            .send()
+            .await
+            .map_err(|e| {
+                ATMError::TransportError(format!("Could not open SSE connection: {:?}", e))
+            })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(ATMError::TransportError(format!(
+                "SSE connection not successful. status({}), response({})",
+                status, body
+            )));
+        }
+
+        debug!("Completed SSE connection");
+        Ok(response)
+    }
+
+    /// SSE streaming handler, reconnects if the connection is dropped
+    /// from_sdk is an MPSC channel used to receive commands from the main thread (Ack, Exit)
+    /// to_sdk is an MPSC channel used to send messages to the main thread that were received from the stream
+    pub(crate) async fn sse_handler(
+        atm: &mut ATM,
+        from_sdk: &mut Receiver<WSCommand>,
+        to_sdk: &Sender<WSCommand>,
+    ) -> Result<(), ATMError> {
+        let _span = span!(Level::INFO, "sse_handler");
+        async move {
+            debug!("Starting SSE handler");
+
+            // Lookup of received message ID to the msg_id in ATM, used to acknowledge (delete) the message
+            let mut ack_lookup = AckLookup::default();
+
+            let mut response = Some(atm._create_sse().await?);
+            to_sdk.send(WSCommand::Started).await.map_err(|err| {
+                ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
+            })?;
+
+            'connection: loop {
+                let mut stream = match response.take() {
+                    Some(stream) => stream,
+                    None => match atm._create_sse().await {
+                        Ok(stream) => {
+                            info!("SSE connection re-established");
+                            stream
+                        }
+                        Err(err) => {
+                            warn!("Couldn't reconnect SSE: {}", err);
+                            sleep(RECONNECT_DELAY).await;
+                            continue;
+                        }
+                    },
+                };
+                let mut parser = SseParser::default();
+
+                loop {
+                    select! {
+                        chunk = stream.chunk() => {
+                            match chunk {
+                                Ok(Some(chunk)) => {
+                                    for event in parser.push(&chunk) {
+                                        if event.event.as_deref().is_some_and(|e| e != MESSAGE_EVENT) {
+                                            continue;
+                                        }
+                                        let (message, meta) = match atm.unpack(&event.data).await {
+                                            Ok((msg, meta)) => (msg, meta),
+                                            Err(err) => {
+                                                error!("Error unpacking message: {:?}", err);
+                                                continue;
+                                            }
+                                        };
+                                        if let Some(msg_id) = event.id {
+                                            ack_lookup.insert(message.id.clone(), msg_id);
+                                        }
+                                        to_sdk.send(WSCommand::MessageReceived(message, Box::new(meta))).await.map_err(|err| {
+                                            ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
+                                        })?;
+                                    }
+                                }
+                                Ok(None) => {
+                                    warn!("SSE connection closed by the mediator, reconnecting");
+                                    break;
+                                }
+                                Err(err) => {
+                                    warn!("SSE connection error ({}), reconnecting", err);
+                                    break;
+                                }
+                            }
+                        }
+                        value = from_sdk.recv() => {
+                            match value {
+                                Some(WSCommand::Ack(ids)) => {
+                                    let message_ids: Vec<String> = ids.iter().filter_map(|id| ack_lookup.remove(id)).collect();
+                                    if !message_ids.is_empty() {
+                                        debug!("Acknowledging {} messages", message_ids.len());
+                                        if let Err(err) = atm.delete_messages(&DeleteMessageRequest { message_ids }).await {
+                                            warn!("Couldn't acknowledge messages: {}", err);
+                                        }
+                                    }
+                                }
+                                Some(WSCommand::Exit) => {
+                                    debug!("Received EXIT message, closing channel");
+                                    break 'connection;
+                                }
+                                Some(_) => {
+                                    debug!("Received unknown command");
+                                }
+                                None => {
+                                    info!("Channel Closed");
+                                    break 'connection;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                sleep(RECONNECT_DELAY).await;
+            }
+
+            from_sdk.close();
+            debug!("Channel closed, stopping SSE handler");
+            Ok(())
+        }
+        .instrument(_span)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SseParser;
+
+    #[test]
+    fn parses_complete_events() {
+        let mut parser = SseParser::default();
+        let events = parser.push(b"id: 1-0\nevent: message\ndata: hello\n\nevent: ping\ndata:\n\n");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].id.as_deref(), Some("1-0"));
+        assert_eq!(events[0].event.as_deref(), Some("message"));
+        assert_eq!(events[0].data, "hello");
+        assert_eq!(events[1].id, None);
+        assert_eq!(events[1].event.as_deref(), Some("ping"));
+        assert_eq!(events[1].data, "");
+    }
+
+    #[test]
+    fn buffers_events_split_across_chunks() {
+        let mut parser = SseParser::default();
+        assert!(parser.push(b"event: message\nda").is_empty());
+        assert!(parser.push(b"ta: {\"a\":1}\n").is_empty());
+        let events = parser.push(b"\nid: 2-0\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "{\"a\":1}");
+        assert_eq!(events[0].id, None);
+
+        let events = parser.push(b"data: next\n\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id.as_deref(), Some("2-0"));
+    }
+
+    #[test]
+    fn handles_crlf_multiline_data_and_comments() {
+        let mut parser = SseParser::default();
+        let events = parser.push(b": keep-alive\r\n\r\ndata: line1\r\ndata:line2\r\n\r\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "line1\nline2");
+    }
+
+    #[test]
+    fn ignores_events_without_data() {
+        let mut parser = SseParser::default();
+        assert!(parser.push(b"id: 3-0\nevent: message\n\n").is_empty());
+        assert!(parser.push(b"retry: 1000\nunknown\n\n").is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+//! Binary websocket frames
+//!
+//! DIDComm envelopes are JSON, with the ciphertext base64url encoded. A binary frame carries the
+//! envelope compressed with deflate, which removes most of the text encoding overhead.
+//! Text frames carry the envelope as is.
+use crate::errors::ATMError;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::io::{Read, Write};
+
+/// Largest envelope that is accepted from a binary frame (matches the default websocket message limit)
+pub const MAX_ENVELOPE_SIZE: usize = 64 << 20;
+
+/// Compresses an envelope into the payload of a binary frame
+pub fn encode_binary(envelope: &str) -> Result<Vec<u8>, ATMError> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder
+        .write_all(envelope.as_bytes())
+        .and_then(|_| encoder.finish())
+        .map_err(|e| ATMError::TransportError(format!("Couldn't compress envelope: {}", e)))
+}
+
+/// Decompresses the payload of a binary frame into an envelope
+/// - limit: Maximum size of the decompressed envelope in bytes
+pub fn decode_binary(frame: &[u8], limit: usize) -> Result<String, ATMError> {
+    let mut envelope = Vec::new();
+    DeflateDecoder::new(frame)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut envelope)
+        .map_err(|e| ATMError::TransportError(format!("Couldn't decompress frame: {}", e)))?;
+
+    if envelope.len() > limit {
+        return Err(ATMError::TransportError(format!(
+            "Envelope is larger than the limit ({} bytes)",
+            limit
+        )));
+    }
+
+    String::from_utf8(envelope)
+        .map_err(|_| ATMError::TransportError("Envelope isn't valid UTF-8".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let envelope = r#"{"protected":"eyJ0eXAiOiJhcHBsaWNhdGlvbi9kaWRjb21tLWVuY3J5cHRlZCtqc29uIn0","ciphertext":"QUJDREVGR0hJSktMTU5PUFFSU1RVVldYWVo"}"#;
+        let frame = encode_binary(envelope).unwrap();
+        assert_eq!(decode_binary(&frame, MAX_ENVELOPE_SIZE).unwrap(), envelope);
+    }
+
+    #[test]
+    fn compresses_base64_ciphertext() {
+        // base64 only uses 6 of the 8 bits in each byte
+        let ciphertext: String = (0..30_000u32)
+            .map(|i| {
+                let c = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_"
+                    [(i.wrapping_mul(2_654_435_761) >> 26) as usize];
+                c as char
+            })
+            .collect();
+        let envelope = format!(r#"{{"ciphertext":"{}"}}"#, ciphertext);
+        assert!(encode_binary(&envelope).unwrap().len() < envelope.len() * 85 / 100);
+    }
+
+    #[test]
+    fn rejects_envelopes_over_the_limit() {
+        let frame = encode_binary(&"a".repeat(1000)).unwrap();
+        assert!(decode_binary(&frame, 999).is_err());
+        assert!(decode_binary(&frame, 1000).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        assert!(decode_binary(b"{\"not\":\"compressed\"}", MAX_ENVELOPE_SIZE).is_err());
+        let frame = encode_binary("ok").unwrap();
+        assert!(decode_binary(&frame[..frame.len() - 1], MAX_ENVELOPE_SIZE).is_err());
+    }
+}
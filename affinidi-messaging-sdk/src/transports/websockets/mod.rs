@@ -3,6 +3,7 @@ use tokio::sync::mpsc;
 use tracing::{debug, error, warn};
 use ws_handler::WSCommand;
 
+pub mod frames;
 pub mod sending;
 pub mod ws_handler;
 
@@ -44,6 +45,8 @@ impl ATM {
             ws_handler: None,
             ws_send_stream: None,
             ws_recv_stream: None,
+            sse_handler: None,
+            sse_send_stream: None,
         };
 
         debug!("secrets: {}", atm.secrets_resolver.len());
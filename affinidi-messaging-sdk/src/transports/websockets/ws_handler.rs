@@ -1,20 +1,26 @@
-use crate::{errors::ATMError, ATM};
+use super::frames::{decode_binary, encode_binary, MAX_ENVELOPE_SIZE};
+use crate::{errors::ATMError, transports::AckLookup, ATM};
 use affinidi_messaging_didcomm::{Message, UnpackMetadata};
 use futures_util::sink::SinkExt;
 use http::header::AUTHORIZATION;
+use serde_json::json;
+use sha256::digest;
 use std::{
     collections::{HashMap, HashSet},
     mem::size_of_val,
+    time::Duration,
 };
 use tokio::{
     net::TcpStream,
     select,
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{error::TrySendError, Receiver, Sender},
+    time::{interval, Instant, MissedTickBehavior},
 };
 use tokio_stream::StreamExt;
 use tokio_tungstenite::{
-    connect_async_tls_with_config, tungstenite::client::IntoClientRequest, MaybeTlsStream,
-    WebSocketStream,
+    connect_async_tls_with_config,
+    tungstenite::{client::IntoClientRequest, Message as WsMessage},
+    MaybeTlsStream, WebSocketStream,
 };
 use tracing::{debug, error, info, span, warn, Instrument, Level};
 
@@ -150,6 +156,7 @@ pub(crate) enum WSCommand {
     MessageReceived(Message, Box<UnpackMetadata>), // Message received from the websocket
     NotFound,     // Message not found in the cache
     TimeOut(String), // SDK request timed out, contains msg_id we were looking for
+    Ack(Vec<String>), // Acknowledges live-streamed messages (by message ID) so the mediator removes them
 }
 
 impl ATM {
@@ -218,73 +225,118 @@ impl ATM {
                 ..Default::default()
             };
 
+            // Lookup of received message ID to the sha256 hash of the message as it was received
+            // The mediator accepts the hash as an acknowledgement of the message
+            let mut ack_lookup = AckLookup::default();
+
+            // Heartbeat, pings ATM and closes the websocket if nothing has been received for ws_idle_timeout
+            // Ticks every ws_ping_interval, or every ws_idle_timeout if pings are disabled
+            let ping_interval = atm.config.ws_ping_interval;
+            let idle_timeout = atm.config.ws_idle_timeout;
+            let heartbeat_period = if ping_interval > 0 { ping_interval } else { idle_timeout };
+            let mut heartbeat = interval(Duration::from_secs(heartbeat_period.max(1)));
+            heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            heartbeat.reset();
+            let mut last_seen = Instant::now();
+
             let mut web_socket = atm._create_socket().await?;
             to_sdk.send(WSCommand::Started).await.map_err(|err| {
                 ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
             })?;
             loop {
                 select! {
-                    value = web_socket.next(), if !cache.is_full() => {
+                    // Frames are always read, so that pings/pongs keep the connection alive while the SDK is busy
+                    value = web_socket.next() => {
                         if let Some(msg) = value {
-                            if let Ok(payload) = msg {
-                                if payload.is_text() {
-                                    if let Ok(msg) = payload.to_text() {
-                                        debug!("Received text message ({})", msg);
-                                        let (message, meta) = match atm.unpack(msg).await {
-                                            Ok((msg, meta)) => (msg, meta),
-                                            Err(err) => {
-                                                error!("Error unpacking message: {:?}", err);
-                                                continue;
-                                            }
-                                        };
-                                        // Check if we are searching for this message via a get request
-                                        if let Some(thid) = &message.thid {
-                                            if cache.search_list.contains(thid) {
-                                                cache.remove(thid);
-                                                to_sdk.send(WSCommand::MessageReceived(message.clone(), Box::new(meta.clone()))).await.map_err(|err| {
-                                                    ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
-                                                })?;
-                                            }
-                                        } else if cache.search_list.contains(&message.id) {
-                                            to_sdk.send(WSCommand::MessageReceived(message.clone(), Box::new(meta.clone()))).await.map_err(|err| {
-                                                ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
-                                            })?;
-                                            cache.remove(&message.id);
-                                        }
+                            last_seen = Instant::now();
+                            let msg = match msg {
+                                Ok(WsMessage::Text(msg)) => msg,
+                                // Binary frames carry a compressed envelope
+                                Ok(WsMessage::Binary(bytes)) => match decode_binary(&bytes, MAX_ENVELOPE_SIZE) {
+                                    Ok(msg) => msg,
+                                    Err(err) => {
+                                        error!("Error decoding binary frame: {:?}", err);
+                                        continue;
+                                    }
+                                },
+                                Ok(_) => {
+                                    debug!("Received non-message frame, ignoring");
+                                    continue;
+                                }
+                                Err(_) => {
+                                    error!("Error getting payload from message");
+                                    continue;
+                                }
+                            };
 
-                                        // Send the message to the SDK if next_flag is set
-                                        if true //cache.next_flag
-                                        {
-                                            cache.next_flag = false;
-                                            to_sdk.send(WSCommand::MessageReceived(message, Box::new(meta))).await.map_err(|err| {
-                                                ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
-                                            })?;
-                                        } else {
-                                            // Add to cache
-                                            cache.insert(message, meta);
-                                        }
-                                    } else {
-                                        error!("Error getting text from message")
+                            debug!("Received text message ({})", msg);
+                            let (message, meta) = match atm.unpack(&msg).await {
+                                Ok((msg, meta)) => (msg, meta),
+                                Err(err) => {
+                                    error!("Error unpacking message: {:?}", err);
+                                    continue;
+                                }
+                            };
+                            ack_lookup.insert(message.id.clone(), digest(&msg));
+                            // Check if we are searching for this message via a get request
+                            if let Some(thid) = &message.thid {
+                                if cache.search_list.contains(thid) {
+                                    cache.remove(thid);
+                                    to_sdk.send(WSCommand::MessageReceived(message.clone(), Box::new(meta.clone()))).await.map_err(|err| {
+                                        ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
+                                    })?;
+                                }
+                            } else if cache.search_list.contains(&message.id) {
+                                to_sdk.send(WSCommand::MessageReceived(message.clone(), Box::new(meta.clone()))).await.map_err(|err| {
+                                    ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
+                                })?;
+                                cache.remove(&message.id);
+                            }
+
+                            // Hand the message to the SDK if it keeps up, otherwise cache it (in order) until it has caught up
+                            let message = if cache.ordered_list.is_empty() {
+                                match to_sdk.try_send(WSCommand::MessageReceived(message, Box::new(meta))) {
+                                    Ok(_) => continue,
+                                    Err(TrySendError::Full(WSCommand::MessageReceived(message, meta))) => (message, *meta),
+                                    Err(err) => {
+                                        return Err(ATMError::TransportError(format!("Could not send message to SDK: {:?}", err)));
                                     }
-                                } else {
-                                    error!("Received non-text message");
                                 }
                             } else {
-                                error!("Error getting payload from message");
-                                continue;
+                                (message, meta)
+                            };
+                            if cache.is_full() {
+                                // Not acknowledged, so ATM keeps stored messages and delivers them again
+                                warn!("Message cache is full, dropping message ({})", message.0.id);
+                                ack_lookup.remove(&message.0.id);
+                            } else {
+                                cache.insert(message.0, message.1);
                             }
                         } else {
-
                             error!("Error getting message");
                             break;
                         }
                     }
+                    // Cached messages are handed to the SDK as it makes room
+                    permit = to_sdk.reserve(), if !cache.ordered_list.is_empty() => {
+                        let permit = permit.map_err(|err| {
+                            ATMError::TransportError(format!("Could not send message to SDK: {:?}", err))
+                        })?;
+                        if let Some((message, meta)) = cache.next() {
+                            permit.send(WSCommand::MessageReceived(message, Box::new(meta)));
+                        }
+                    }
                     value = from_sdk.recv() => {
                         if let Some(cmd) = value {
                             match cmd {
                                 WSCommand::Send(msg) => {
                                     debug!("Sending message: {}", msg);
-                                    web_socket.send(msg.into()).await.map_err(|err| {
+                                    let frame = if atm.config.ws_binary_frames {
+                                        WsMessage::Binary(encode_binary(&msg)?)
+                                    } else {
+                                        WsMessage::Text(msg)
+                                    };
+                                    web_socket.send(frame).await.map_err(|err| {
                                         ATMError::TransportError(format!("Could not send websocket message: {:?}", err))
                                     })?;
                                 }
@@ -313,6 +365,15 @@ impl ATM {
                                 WSCommand::TimeOut(id) => {
                                     cache.search_list.remove(&id);
                                 }
+                                WSCommand::Ack(ids) => {
+                                    let hashes: Vec<String> = ids.iter().filter_map(|id| ack_lookup.remove(id)).collect();
+                                    if !hashes.is_empty() {
+                                        debug!("Acknowledging {} messages", hashes.len());
+                                        web_socket.send(json!({"ack": hashes}).to_string().into()).await.map_err(|err| {
+                                            ATMError::TransportError(format!("Could not send websocket message: {:?}", err))
+                                        })?;
+                                    }
+                                }
                                 _ => {
                                     debug!("Received unknown command");
                                 }
@@ -322,6 +383,17 @@ impl ATM {
                             break;
                         }
                     }
+                    _ = heartbeat.tick(), if heartbeat_period > 0 => {
+                        if idle_timeout > 0 && last_seen.elapsed() > Duration::from_secs(idle_timeout) {
+                            warn!("No frames received for {} seconds, closing websocket", idle_timeout);
+                            break;
+                        }
+                        if ping_interval > 0 {
+                            web_socket.send(WsMessage::Ping(Vec::new())).await.map_err(|err| {
+                                ATMError::TransportError(format!("Could not send websocket ping: {:?}", err))
+                            })?;
+                        }
+                    }
                 }
             }
 